@@ -0,0 +1,132 @@
+//! Compares the symbol interner against the one it replaced, which took a lock to find the text
+//! of a symbol and hashed symbols by their text. Run with `cargo bench --bench symbol`.
+
+use std::{
+    hint::black_box,
+    thread,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use fxhash::FxHashMap;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/script/symbol.rs"]
+mod symbol;
+
+/// The interner as it was before
+mod locked {
+    use core::hash::{Hash, Hasher};
+    use core::num::NonZeroU64;
+
+    use fxhash::FxHashMap;
+    use parking_lot::RwLock;
+
+    #[derive(Copy, Clone, PartialEq, Eq)]
+    pub struct Symbol(NonZeroU64);
+
+    type Maps = (
+        FxHashMap<&'static str, NonZeroU64>,
+        FxHashMap<NonZeroU64, &'static str>,
+    );
+
+    lazy_static::lazy_static! {
+        static ref MAP: RwLock<Maps> = RwLock::new(Default::default());
+    }
+
+    impl Symbol {
+        pub fn intern(st: &str) -> Self {
+            if let Some(&sym) = MAP.read().0.get(st) {
+                return Symbol(sym);
+            }
+            let mut map = MAP.write();
+            let sym = NonZeroU64::new(map.0.len() as u64 + 1).unwrap();
+            let leaked: &'static str = Box::leak(Box::from(st));
+            map.0.insert(leaked, sym);
+            map.1.insert(sym, leaked);
+            Symbol(sym)
+        }
+
+        pub fn as_str(&self) -> &str {
+            MAP.read().1[&self.0]
+        }
+    }
+
+    impl Hash for Symbol {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.as_str().hash(state)
+        }
+    }
+}
+
+const SYMBOLS: usize = 1000;
+
+const THREADS: usize = 4;
+
+fn names() -> Vec<String> {
+    (0..SYMBOLS).map(|i| format!("name_{}", i)).collect()
+}
+
+/// Benchmarks one interner, given how to intern a string and how to find the length of the
+/// text of a symbol
+fn bench_interner<S: Copy + Eq + core::hash::Hash + Send + Sync>(
+    c: &mut Criterion,
+    name: &str,
+    intern: impl Fn(&str) -> S,
+    len: fn(S) -> usize,
+) {
+    let syms = names().iter().map(|s| intern(s)).collect::<Vec<_>>();
+
+    c.bench_function(&format!("{}/as_str", name), |b| {
+        b.iter(|| syms.iter().map(|&sym| len(black_box(sym))).sum::<usize>())
+    });
+
+    let map = syms
+        .iter()
+        .enumerate()
+        .map(|(idx, &sym)| (sym, idx))
+        .collect::<FxHashMap<_, _>>();
+    c.bench_function(&format!("{}/map_lookup", name), |b| {
+        b.iter(|| syms.iter().map(|sym| map[black_box(sym)]).sum::<usize>())
+    });
+
+    c.bench_function(&format!("{}/intern_existing", name), |b| {
+        let names = names();
+        b.iter(|| {
+            names
+                .iter()
+                .map(|s| intern(black_box(s)))
+                .collect::<Vec<_>>()
+        })
+    });
+
+    c.bench_function(&format!("{}/as_str_{}_threads", name, THREADS), |b| {
+        b.iter_custom(|iters| {
+            let start = Instant::now();
+            thread::scope(|scope| {
+                for _ in 0..THREADS {
+                    scope.spawn(|| {
+                        for _ in 0..iters {
+                            black_box(syms.iter().map(|&sym| len(black_box(sym))).sum::<usize>());
+                        }
+                    });
+                }
+            });
+            start.elapsed()
+        })
+    });
+}
+
+fn benches(c: &mut Criterion) {
+    bench_interner(c, "arena", symbol::Symbol::intern, |sym| sym.as_str().len());
+    bench_interner(c, "locked", locked::Symbol::intern, |sym| {
+        sym.as_str().len()
+    });
+}
+
+criterion_group! {
+    name = symbol_benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(3));
+    targets = benches
+}
+criterion_main!(symbol_benches);
@@ -0,0 +1,39 @@
+// Draws one layer as a textured quad. Layer pixels are premultiplied by their alpha, and are
+// blended over what is already drawn by the pipeline.
+
+struct Quad {
+    // The top left corner and the size of the quad, as fractions of the screen
+    pos: vec2<f32>,
+    size: vec2<f32>,
+    // The part of the texture drawn on the quad, as fractions of the texture
+    uv_pos: vec2<f32>,
+    uv_size: vec2<f32>,
+}
+
+@group(0) @binding(0)
+var layer: texture_2d<f32>;
+@group(0) @binding(1)
+var layer_sampler: sampler;
+@group(1) @binding(0)
+var<uniform> quad: Quad;
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    // A triangle strip over the corners (0, 0), (1, 0), (0, 1), (1, 1)
+    let corner = vec2<f32>(f32(index & 1u), f32(index >> 1u));
+    let screen = quad.pos + corner * quad.size;
+    var out: VertexOutput;
+    out.position = vec4<f32>(screen.x * 2.0 - 1.0, 1.0 - screen.y * 2.0, 0.0, 1.0);
+    out.uv = quad.uv_pos + corner * quad.uv_size;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return textureSample(layer, layer_sampler, in.uv);
+}
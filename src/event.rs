@@ -1,5 +1,4 @@
 #[derive(Clone, Debug)]
 pub enum GameEvent {
-    ScriptNotify(u32, String),
-    Periodic(f32),
+    Periodic,
 }
@@ -1,13 +1,26 @@
+use std::path::PathBuf;
+
 use wgpu::{
-    Buffer, BufferDescriptor, BufferUsages, Color, CommandBuffer, CommandEncoder,
-    CommandEncoderDescriptor, Device, Operations, Queue, RenderPassColorAttachment,
-    RenderPassDescriptor, Surface, Texture, TextureDimension, TextureFormat, TextureViewDescriptor,
+    Color, CommandEncoder, CommandEncoderDescriptor, Device, LoadOp, Operations, Queue, RenderPass,
+    RenderPassColorAttachment, RenderPassDescriptor, Surface, TextureFormat, TextureView,
+    TextureViewDescriptor,
 };
+
 use winit::dpi::PhysicalSize;
 
+use cache::{AssetCache, CacheStats, Gpu, ImageHandle};
+use compositor::{Compositor, LayerSlot, LayerTexture, Quad};
+use layer::Layer;
+use text::{Text, TextBox};
+
+use crate::script::symbol::Symbol;
+
+pub mod cache;
+pub mod compositor;
 pub mod framebuf;
 pub mod image;
 pub mod layer;
+pub mod text;
 
 pub type Result<T> = core::result::Result<T, wgpu::SurfaceError>;
 
@@ -28,12 +41,67 @@ impl Colour {
         a: !0,
     };
 
+    pub const BLACK: Colour = Colour {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: !0,
+    };
+
+    pub const TRANSPARENT: Colour = Colour {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 0,
+    };
+
     pub const HALFWHITE: Colour = Colour {
         r: 0xBF,
         g: 0xBF,
         b: 0xBF,
         a: 0xFF,
     };
+
+    /// The colour with its red, green and blue scaled by its alpha, as layers are drawn
+    pub fn premultiplied(self) -> Colour {
+        let scale = |channel: u8| ((channel as u16 * self.a as u16 + 127) / 255) as u8;
+        Colour {
+            r: scale(self.r),
+            g: scale(self.g),
+            b: scale(self.b),
+            a: self.a,
+        }
+    }
+
+    /// Parses a colour written as `#rgb`, `#rgba`, `#rrggbb`, or `#rrggbbaa`
+    pub fn from_hex(hex: &str) -> Option<Colour> {
+        let digits = hex.strip_prefix('#')?;
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let (width, alpha) = match digits.len() {
+            3 => (1, false),
+            4 => (1, true),
+            6 => (2, false),
+            8 => (2, true),
+            _ => return None,
+        };
+        let channel = |i: usize| {
+            let value = u8::from_str_radix(&digits[i * width..(i + 1) * width], 16).unwrap();
+            // A single digit is repeated, so `f` is `ff`
+            if width == 1 {
+                value * 0x11
+            } else {
+                value
+            }
+        };
+        Some(Colour {
+            r: channel(0),
+            g: channel(1),
+            b: channel(2),
+            a: if alpha { channel(3) } else { 0xFF },
+        })
+    }
 }
 
 #[repr(C, align(4))]
@@ -82,24 +150,96 @@ pub struct GraphicsState {
     surface: Surface,
     queue: Queue,
     screen_dimension: ScreenDimension,
+    compositor: Compositor,
+    assets: AssetCache,
+    text_box: TextBox,
+    /// What the text box shows, kept to draw it again when the screen is resized
+    text: Option<Text>,
 }
 
 impl GraphicsState {
-    pub fn new(device: Device, surface: Surface, queue: Queue, dim: ScreenDimension) -> Self {
+    /// The format of the surface, which must be configured with it
+    pub const SURFACE_FORMAT: TextureFormat = TextureFormat::Bgra8Unorm;
+
+    /// Images are loaded from paths relative to `asset_root`, the root of the game, and text is
+    /// shown in `text_box`
+    pub fn new(
+        device: Device,
+        surface: Surface,
+        queue: Queue,
+        dim: ScreenDimension,
+        asset_root: PathBuf,
+        text_box: TextBox,
+    ) -> Self {
+        let compositor = Compositor::new(&device, Self::SURFACE_FORMAT);
         Self {
             device,
             surface,
             queue,
             screen_dimension: dim,
+            compositor,
+            assets: AssetCache::new(asset_root, cache::DEFAULT_BUDGET),
+            text_box,
+            text: None,
         }
     }
 
-    pub fn set_dimension(&mut self, dim: ScreenDimension) {
-        self.screen_dimension = dim.into();
+    /// Loads the image at `path`, relative to the root of the game, onto the GPU, or shares it
+    /// if it is already there
+    pub fn load_image(&mut self, path: Symbol) -> core::result::Result<ImageHandle, image::Error> {
+        let mut gpu = Gpu {
+            device: &self.device,
+            queue: &self.queue,
+            compositor: &self.compositor,
+        };
+        self.assets.load(&mut gpu, path)
+    }
+
+    /// Sets how many bytes of video memory the image cache aims to stay under
+    pub fn set_vram_budget(&mut self, budget: u64) {
+        self.assets.set_budget(budget);
+    }
+
+    pub fn asset_stats(&self) -> CacheStats {
+        self.assets.stats()
+    }
+
+    pub fn layer(&self, slot: LayerSlot) -> Option<&Layer> {
+        self.compositor.layer(slot)
+    }
+
+    /// Replaces the layer in `slot`, taking effect from the next frame
+    pub fn set_layer(&mut self, slot: LayerSlot, layer: Layer) {
+        self.compositor.set_layer(slot, layer);
+    }
+
+    pub fn clear_layer(&mut self, slot: LayerSlot) -> Option<Layer> {
+        self.compositor.clear_layer(slot)
+    }
+
+    /// Shows `text` in the text box on the UI layer, in place of what it showed before
+    pub fn set_text(&mut self, text: Text) {
+        self.text = Some(text);
+        self.draw_text_box();
+    }
+
+    fn draw_text_box(&mut self) {
+        let dim = self.screen_dimension;
+        // A minimized window has no room for the text box
+        if dim.width == 0 || dim.height == 0 {
+            return;
+        }
+        let Some(text) = &self.text else {
+            return;
+        };
+        let framebuffer = self.text_box.render(text, dim);
+        let layer = Layer::FrameBuffer(Box::new(framebuffer));
+        self.compositor.set_layer(LayerSlot::Ui, layer);
     }
 
-    pub fn render_with<F: Fn(&mut RenderState) -> Result<()>>(&mut self, f: F) -> Result<()> {
-        self.render(&f)
+    pub fn set_dimension(&mut self, dim: ScreenDimension) {
+        self.screen_dimension = dim;
+        self.draw_text_box();
     }
 
     pub fn render<'a, R: Renderable>(&mut self, target: &'a R) -> Result<R::Output<'a>> {
@@ -110,12 +250,21 @@ impl GraphicsState {
             });
         let surface_texture = self.surface.get_current_texture()?;
 
-        let texture = &surface_texture.texture;
+        let view = surface_texture.texture.create_view(&TextureViewDescriptor {
+            label: Some("Surface View"),
+            format: Some(Self::SURFACE_FORMAT),
+            dimension: Some(wgpu::TextureViewDimension::D2),
+            aspect: wgpu::TextureAspect::All,
+            base_mip_level: 0,
+            mip_level_count: None,
+            base_array_layer: 0,
+            array_layer_count: None,
+        });
 
         let mut state = RenderState {
             inner: self,
             cmd_encoder: encoder,
-            texture,
+            view,
         };
 
         let res = target.render(&mut state)?;
@@ -133,33 +282,105 @@ impl GraphicsState {
 pub struct RenderState<'a> {
     inner: &'a mut GraphicsState,
     cmd_encoder: CommandEncoder,
-    texture: &'a Texture,
+    /// The frame being drawn
+    view: TextureView,
 }
 
 impl<'a> RenderState<'a> {
-    pub fn draw_solid_color(&mut self, colour: Colour) -> Result<()> {
-        let view = self.texture.create_view(&TextureViewDescriptor {
-            label: Some("draw colour view"),
-            format: Some(TextureFormat::Bgra8Unorm),
-            dimension: Some(wgpu::TextureViewDimension::D2),
-            aspect: wgpu::TextureAspect::All,
-            base_mip_level: 0,
-            mip_level_count: None,
-            base_array_layer: 0,
-            array_layer_count: None,
-        });
-        let render = self.cmd_encoder.begin_render_pass(&RenderPassDescriptor {
-            label: Some("Draw Solid Colour"),
-            color_attachments: &[Some(RenderPassColorAttachment {
-                view: &view,
-                resolve_target: None,
-                ops: Operations {
-                    load: wgpu::LoadOp::Clear(colour.into()),
-                    store: true,
-                },
-            })],
-            depth_stencil_attachment: None,
-        });
+    pub fn queue(&self) -> &Queue {
+        &self.inner.queue
+    }
+
+    pub fn screen_dimension(&self) -> ScreenDimension {
+        self.inner.screen_dimension
+    }
+
+    /// A texture of the given size that can be drawn with [`RenderState::draw_texture`]
+    pub fn create_texture(&self, dim: ScreenDimension) -> LayerTexture {
+        self.inner
+            .compositor
+            .create_texture(&self.inner.device, dim)
+    }
+
+    /// Clears the frame to `clear`, then composites the layer stack over it
+    pub fn draw_frame(&mut self, clear: Colour) -> Result<()> {
+        let inner = &mut *self.inner;
+        let screen = inner.screen_dimension;
+        inner.compositor.upload(&inner.device, &inner.queue, screen);
+        let mut gpu = Gpu {
+            device: &inner.device,
+            queue: &inner.queue,
+            compositor: &inner.compositor,
+        };
+        for image in inner.compositor.images() {
+            inner
+                .assets
+                .prepare(&mut gpu, image, screen, Quad::FULL_SCREEN);
+        }
+        let mut render = begin_pass(
+            &mut self.cmd_encoder,
+            &self.view,
+            "Draw Frame",
+            LoadOp::Clear(clear.into()),
+        );
+        inner.compositor.draw(&mut render, &inner.assets);
         Ok(())
     }
+
+    /// Blends `texture` over what has been drawn so far
+    pub fn draw_texture(&mut self, texture: &LayerTexture) -> Result<()> {
+        let mut render = begin_pass(
+            &mut self.cmd_encoder,
+            &self.view,
+            "Draw Texture",
+            LoadOp::Load,
+        );
+        self.inner.compositor.draw_texture(&mut render, texture);
+        Ok(())
+    }
+}
+
+/// Starts a pass drawing to `view`, which is first cleared or kept as `load` says
+fn begin_pass<'a>(
+    encoder: &'a mut CommandEncoder,
+    view: &'a TextureView,
+    label: &str,
+    load: LoadOp<Color>,
+) -> RenderPass<'a> {
+    encoder.begin_render_pass(&RenderPassDescriptor {
+        label: Some(label),
+        color_attachments: &[Some(RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: Operations { load, store: true },
+        })],
+        depth_stencil_attachment: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn premultiplication() {
+        let colour = |a| Colour {
+            r: 255,
+            g: 100,
+            b: 1,
+            a,
+        };
+        assert_eq!(colour(255).premultiplied(), colour(255));
+        assert_eq!(colour(0).premultiplied(), Colour::TRANSPARENT);
+        assert_eq!(
+            colour(128).premultiplied(),
+            Colour {
+                r: 128,
+                g: 50,
+                b: 1,
+                a: 128
+            }
+        );
+        assert_eq!(Colour::TRANSPARENT.premultiplied(), Colour::TRANSPARENT);
+    }
 }
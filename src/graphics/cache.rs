@@ -0,0 +1,730 @@
+use core::fmt;
+use std::{path::PathBuf, rc::Rc};
+
+use fxhash::FxHashMap;
+use wgpu::{Device, Queue};
+
+use super::{
+    compositor::{Compositor, GpuTexture, Quad, QuadBinding},
+    image::{self, screen_size, Image},
+    Colour, ScreenDimension,
+};
+use crate::script::symbol::Symbol;
+
+/// Images no larger than this on either side are packed into atlases
+pub const ATLAS_MAX_IMAGE: u32 = 256;
+/// The width and height of an atlas page
+pub const ATLAS_SIZE: u32 = 2048;
+/// Transparent pixels left around each image in an atlas, so that filtering does not pull in
+/// its neighbours
+const GUTTER: u32 = 1;
+/// The video memory the cache aims to stay under unless told otherwise
+pub const DEFAULT_BUDGET: u64 = 256 << 20;
+
+/// An image loaded into an [`AssetCache`]. The image stays on the GPU for as long as any clone
+/// of its handle is held.
+#[derive(Clone)]
+pub struct ImageHandle(Rc<Symbol>);
+
+impl ImageHandle {
+    pub fn path(&self) -> Symbol {
+        *self.0
+    }
+}
+
+impl fmt::Debug for ImageHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ImageHandle({})", self.0.as_str())
+    }
+}
+
+/// How full the cache is and how well it is doing
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Images on the GPU
+    pub images: usize,
+    /// Images with a handle held outside the cache, which are never evicted
+    pub in_use: usize,
+    pub atlas_pages: usize,
+    /// Images too large for an atlas, with a texture of their own
+    pub own_textures: usize,
+    /// Video memory taken by atlas pages and textures
+    pub bytes: u64,
+    pub budget: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MIB: f64 = (1 << 20) as f64;
+        write!(
+            f,
+            "{} images ({} in use), {} atlas pages, {} own textures, {:.1} of {:.1} MiB, \
+             {} hits, {} misses, {} evictions",
+            self.images,
+            self.in_use,
+            self.atlas_pages,
+            self.own_textures,
+            self.bytes as f64 / MIB,
+            self.budget as f64 / MIB,
+            self.hits,
+            self.misses,
+            self.evictions,
+        )
+    }
+}
+
+/// Makes and fills the textures of an [`AssetCache`]. The cache only reaches the GPU through
+/// this, so that its bookkeeping can be tested without one.
+pub trait TextureStore {
+    type Texture;
+    type Quad;
+
+    fn create_texture(&mut self, dim: ScreenDimension) -> Self::Texture;
+
+    fn create_quad(&mut self) -> Self::Quad;
+
+    /// Replaces the `dim` pixels with their top left corner at `origin` with `pixels`
+    fn write(
+        &mut self,
+        texture: &Self::Texture,
+        origin: [u32; 2],
+        dim: ScreenDimension,
+        pixels: &[Colour],
+    );
+
+    fn set_quad(&mut self, binding: &Self::Quad, quad: Quad);
+}
+
+/// The GPU, as the [`TextureStore`] of an [`AssetCache`]
+pub struct Gpu<'a> {
+    pub device: &'a Device,
+    pub queue: &'a Queue,
+    pub compositor: &'a Compositor,
+}
+
+impl TextureStore for Gpu<'_> {
+    type Texture = GpuTexture;
+    type Quad = QuadBinding;
+
+    fn create_texture(&mut self, dim: ScreenDimension) -> GpuTexture {
+        self.compositor.create_gpu_texture(self.device, dim)
+    }
+
+    fn create_quad(&mut self) -> QuadBinding {
+        self.compositor.create_quad(self.device)
+    }
+
+    fn write(
+        &mut self,
+        texture: &GpuTexture,
+        origin: [u32; 2],
+        dim: ScreenDimension,
+        pixels: &[Colour],
+    ) {
+        texture.write_region(self.queue, origin, dim, pixels);
+    }
+
+    fn set_quad(&mut self, binding: &QuadBinding, quad: Quad) {
+        binding.set(self.queue, quad);
+    }
+}
+
+/// The video memory taken by a texture of `dim` pixels
+fn texture_bytes(dim: ScreenDimension) -> u64 {
+    4 * dim.width as u64 * dim.height as u64
+}
+
+/// Images on the GPU, keyed by their path from the root of the game. Small images share atlas
+/// pages, so that drawing many sprites does not need a bind group for each. Once the cache takes
+/// more video memory than its budget, memory no one holds a handle to is freed, least recently
+/// used first: an image with a texture of its own, or an atlas page none of whose images are
+/// held. The budget is not a hard limit: memory still in use is kept even when it is exceeded.
+///
+/// Images that stay sharp at any size, such as SVGs, are rasterized again whenever the size they
+/// are drawn at changes. An atlas slot they move out of is not reused until its page is freed.
+pub struct AssetCache<T = GpuTexture, Q = QuadBinding> {
+    /// The directory image paths are relative to
+    root: PathBuf,
+    entries: FxHashMap<Symbol, Entry<T, Q>>,
+    /// Pages are left empty when freed, so that entries can keep their page's index
+    atlases: Vec<Option<AtlasPage<T>>>,
+    budget: u64,
+    stats: CacheStats,
+    /// Counts draws, so that entries can tell when they were last used
+    clock: u64,
+}
+
+struct Entry<T, Q> {
+    /// The cache's own clone, which is the only one when no one else holds the image
+    handle: ImageHandle,
+    placement: Placement<T>,
+    /// The size of the image's pixels on the GPU
+    dim: ScreenDimension,
+    /// The image itself, kept to be rasterized again at the size it is drawn at if it is
+    /// scalable
+    source: Option<Box<dyn Image>>,
+    /// Where the image is drawn. An image is drawn at one place per frame.
+    quad: Q,
+    last_used: u64,
+}
+
+impl<T, Q> Entry<T, Q> {
+    /// Whether a handle to the image is held outside the cache
+    fn in_use(&self) -> bool {
+        Rc::strong_count(&self.handle.0) > 1
+    }
+}
+
+enum Placement<T> {
+    Atlas { page: usize, origin: [u32; 2] },
+    Own(T),
+}
+
+struct AtlasPage<T> {
+    texture: T,
+    packer: ShelfPacker,
+}
+
+/// Memory that can be freed to bring the cache back within its budget
+enum Victim {
+    Own(Symbol),
+    Page(usize),
+}
+
+impl<T, Q> AssetCache<T, Q> {
+    /// An empty cache of images in the directory `root`
+    pub fn new(root: impl Into<PathBuf>, budget: u64) -> Self {
+        Self {
+            root: root.into(),
+            entries: FxHashMap::default(),
+            atlases: Vec::new(),
+            budget,
+            stats: CacheStats::default(),
+            clock: 0,
+        }
+    }
+
+    /// Returns the image at `path`, relative to the root of the game, loading it onto the GPU
+    /// if it is not already there
+    pub fn load<S: TextureStore<Texture = T, Quad = Q>>(
+        &mut self,
+        store: &mut S,
+        path: Symbol,
+    ) -> Result<ImageHandle, image::Error> {
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(&path) {
+            self.stats.hits += 1;
+            entry.last_used = self.clock;
+            return Ok(entry.handle.clone());
+        }
+        self.stats.misses += 1;
+        let image = image::open(self.root.join(path.as_str()))?;
+        self.insert(store, path, image)
+    }
+
+    /// Puts `image` on the GPU as the image at `path`
+    fn insert<S: TextureStore<Texture = T, Quad = Q>>(
+        &mut self,
+        store: &mut S,
+        path: Symbol,
+        image: Box<dyn Image>,
+    ) -> Result<ImageHandle, image::Error> {
+        // Until it is drawn, the image is rasterized at the size it was made at
+        let (width, height) = image.natural_size();
+        let dim = ScreenDimension {
+            width: width.ceil() as u32,
+            height: height.ceil() as u32,
+        };
+        let raster = image
+            .to_framebuffer(dim)
+            .ok_or_else(|| image::Error::Empty(path.as_str().into()))?;
+        let dim = raster.dimension();
+        let placement = self.place(store, dim, raster.pixels());
+
+        let handle = ImageHandle(Rc::new(path));
+        self.entries.insert(
+            path,
+            Entry {
+                handle: handle.clone(),
+                placement,
+                dim,
+                source: image.scalable().then_some(image),
+                quad: store.create_quad(),
+                last_used: self.clock,
+            },
+        );
+        self.evict();
+        Ok(handle)
+    }
+
+    /// Puts `pixels`, an image of `dim` pixels, in an atlas page if it is small enough, or in a
+    /// texture of its own if not
+    fn place<S: TextureStore<Texture = T, Quad = Q>>(
+        &mut self,
+        store: &mut S,
+        dim: ScreenDimension,
+        pixels: &[Colour],
+    ) -> Placement<T> {
+        if dim.width <= ATLAS_MAX_IMAGE && dim.height <= ATLAS_MAX_IMAGE {
+            let (page, origin) = self.allocate(store, dim);
+            let atlas = self.atlases[page].as_ref().unwrap();
+            store.write(&atlas.texture, origin, dim, pixels);
+            Placement::Atlas { page, origin }
+        } else {
+            let texture = store.create_texture(dim);
+            store.write(&texture, [0, 0], dim, pixels);
+            self.stats.bytes += texture_bytes(dim);
+            Placement::Own(texture)
+        }
+    }
+
+    /// Rasterizes the scalable image at `path` again, for `quad` on a screen of `screen` pixels,
+    /// if its pixels on the GPU are not already that size
+    fn rescale<S: TextureStore<Texture = T, Quad = Q>>(
+        &mut self,
+        store: &mut S,
+        path: Symbol,
+        quad: Quad,
+        screen: ScreenDimension,
+    ) {
+        let Some(entry) = self.entries.get_mut(&path) else {
+            return;
+        };
+        let Some(source) = &mut entry.source else {
+            return;
+        };
+        if screen_size(quad.size, screen) == entry.dim {
+            return;
+        }
+        source.set_placement(quad.pos, quad.size);
+        // Too small to see, so the pixels there are drawn stretched instead
+        let Some(raster) = source.to_framebuffer(screen) else {
+            return;
+        };
+        let dim = raster.dimension();
+        if let Placement::Own(_) = entry.placement {
+            self.stats.bytes -= texture_bytes(entry.dim);
+        }
+        let placement = self.place(store, dim, raster.pixels());
+        let entry = self.entries.get_mut(&path).unwrap();
+        entry.placement = placement;
+        entry.dim = dim;
+        self.evict();
+    }
+
+    /// Finds room for an image of `dim` pixels in an atlas page, starting a page if none has
+    /// room
+    fn allocate<S: TextureStore<Texture = T, Quad = Q>>(
+        &mut self,
+        store: &mut S,
+        dim: ScreenDimension,
+    ) -> (usize, [u32; 2]) {
+        for (page, atlas) in self.atlases.iter_mut().enumerate() {
+            if let Some(origin) = atlas.as_mut().and_then(|atlas| atlas.packer.allocate(dim)) {
+                return (page, origin);
+            }
+        }
+        let size = ScreenDimension {
+            width: ATLAS_SIZE,
+            height: ATLAS_SIZE,
+        };
+        let mut atlas = AtlasPage {
+            texture: store.create_texture(size),
+            packer: ShelfPacker::new(ATLAS_SIZE),
+        };
+        self.stats.bytes += texture_bytes(size);
+        let origin = atlas.packer.allocate(dim).unwrap();
+        let page = match self.atlases.iter().position(Option::is_none) {
+            Some(page) => {
+                self.atlases[page] = Some(atlas);
+                page
+            }
+            None => {
+                self.atlases.push(Some(atlas));
+                self.atlases.len() - 1
+            }
+        };
+        (page, origin)
+    }
+
+    /// The least recently used memory that can be freed: an image with its own texture that no
+    /// one holds, or an atlas page none of whose images are held, last used when the most
+    /// recent of them was
+    fn victim(&self) -> Option<Victim> {
+        // Whether each page can be freed, and when it was last used
+        let mut pages = vec![(true, 0); self.atlases.len()];
+        let mut victim = None;
+        for (&path, entry) in &self.entries {
+            match entry.placement {
+                Placement::Atlas { page, .. } => {
+                    let (free, last_used) = &mut pages[page];
+                    *free &= !entry.in_use();
+                    *last_used = entry.last_used.max(*last_used);
+                }
+                Placement::Own(_) if !entry.in_use() => {
+                    if victim
+                        .as_ref()
+                        .is_none_or(|(_, last_used)| entry.last_used < *last_used)
+                    {
+                        victim = Some((Victim::Own(path), entry.last_used));
+                    }
+                }
+                Placement::Own(_) => {}
+            }
+        }
+        for (page, (free, last_used)) in pages.into_iter().enumerate() {
+            let live = self.atlases[page].is_some();
+            if live && free && victim.as_ref().is_none_or(|(_, lru)| last_used < *lru) {
+                victim = Some((Victim::Page(page), last_used));
+            }
+        }
+        victim.map(|(victim, _)| victim)
+    }
+
+    /// Frees memory no one holds, least recently used first, until the cache is within its
+    /// budget or everything left is in use
+    fn evict(&mut self) {
+        while self.stats.bytes > self.budget {
+            match self.victim() {
+                Some(Victim::Own(path)) => {
+                    let entry = self.entries.remove(&path).unwrap();
+                    self.stats.bytes -= texture_bytes(entry.dim);
+                    self.stats.evictions += 1;
+                }
+                Some(Victim::Page(page)) => {
+                    let before = self.entries.len();
+                    self.entries.retain(|_, entry| {
+                        !matches!(entry.placement, Placement::Atlas { page: p, .. } if p == page)
+                    });
+                    self.stats.evictions += (before - self.entries.len()) as u64;
+                    self.atlases[page] = None;
+                    self.stats.bytes -= texture_bytes(ScreenDimension {
+                        width: ATLAS_SIZE,
+                        height: ATLAS_SIZE,
+                    });
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Changes how much video memory the cache aims to use, evicting images if it is over the
+    /// new budget
+    pub fn set_budget(&mut self, budget: u64) {
+        self.budget = budget;
+        self.evict();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            images: self.entries.len(),
+            in_use: self.entries.values().filter(|entry| entry.in_use()).count(),
+            atlas_pages: self.atlases.iter().flatten().count(),
+            own_textures: self
+                .entries
+                .values()
+                .filter(|entry| matches!(entry.placement, Placement::Own(_)))
+                .count(),
+            budget: self.budget,
+            ..self.stats
+        }
+    }
+
+    /// Places `image` on `quad`, on a screen of `screen` pixels, returning the texture and quad
+    /// to draw it with. A scalable image is first rasterized at the size it is drawn at. The uv
+    /// of `quad` is given as fractions of the image, which are turned into fractions of its atlas
+    /// page.
+    pub fn prepare<S: TextureStore<Texture = T, Quad = Q>>(
+        &mut self,
+        store: &mut S,
+        image: &ImageHandle,
+        screen: ScreenDimension,
+        mut quad: Quad,
+    ) -> Option<(&T, &Q)> {
+        self.clock += 1;
+        self.rescale(store, image.path(), quad, screen);
+        let entry = self.entries.get_mut(&image.path())?;
+        entry.last_used = self.clock;
+        if let Placement::Atlas { origin, .. } = entry.placement {
+            let dim = entry.dim;
+            let atlas = ATLAS_SIZE as f32;
+            quad.uv_pos = [
+                (origin[0] as f32 + quad.uv_pos[0] * dim.width as f32) / atlas,
+                (origin[1] as f32 + quad.uv_pos[1] * dim.height as f32) / atlas,
+            ];
+            quad.uv_size = [
+                quad.uv_size[0] * dim.width as f32 / atlas,
+                quad.uv_size[1] * dim.height as f32 / atlas,
+            ];
+        }
+        store.set_quad(&entry.quad, quad);
+        self.binding(image)
+    }
+
+    /// The texture and quad `image` was last prepared to be drawn with
+    pub fn binding(&self, image: &ImageHandle) -> Option<(&T, &Q)> {
+        let entry = self.entries.get(&image.path())?;
+        let texture = match &entry.placement {
+            Placement::Atlas { page, .. } => &self.atlases[*page].as_ref()?.texture,
+            Placement::Own(texture) => texture,
+        };
+        Some((texture, &entry.quad))
+    }
+}
+
+/// Packs rectangles into a square in rows, called shelves, each as tall as the tallest
+/// rectangle put in it
+struct ShelfPacker {
+    size: u32,
+    shelves: Vec<Shelf>,
+    /// The top of the space below the last shelf
+    next_y: u32,
+}
+
+struct Shelf {
+    y: u32,
+    height: u32,
+    /// The left of the space after the last rectangle in the shelf
+    next_x: u32,
+}
+
+impl ShelfPacker {
+    fn new(size: u32) -> Self {
+        Self {
+            size,
+            shelves: Vec::new(),
+            next_y: 0,
+        }
+    }
+
+    /// Finds room for `dim` pixels and a gutter around them, returning the top left corner
+    /// of the pixels themselves
+    fn allocate(&mut self, dim: ScreenDimension) -> Option<[u32; 2]> {
+        let width = dim.width + 2 * GUTTER;
+        let height = dim.height + 2 * GUTTER;
+        if width > self.size || height > self.size {
+            return None;
+        }
+        // The shortest shelf with room wastes the least space above the rectangle
+        let fits = self
+            .shelves
+            .iter_mut()
+            .filter(|shelf| shelf.height >= height && shelf.next_x + width <= self.size)
+            .min_by_key(|shelf| shelf.height);
+        let shelf = match fits {
+            Some(shelf) => shelf,
+            None => {
+                if self.next_y + height > self.size {
+                    return None;
+                }
+                self.shelves.push(Shelf {
+                    y: self.next_y,
+                    height,
+                    next_x: 0,
+                });
+                self.next_y += height;
+                self.shelves.last_mut().unwrap()
+            }
+        };
+        let origin = [shelf.next_x + GUTTER, shelf.y + GUTTER];
+        shelf.next_x += width;
+        Some(origin)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graphics::image::{RasterImage, SvgImage};
+
+    fn dim(width: u32, height: u32) -> ScreenDimension {
+        ScreenDimension { width, height }
+    }
+
+    #[test]
+    fn shelves_are_filled_left_to_right_then_top_to_bottom() {
+        let mut packer = ShelfPacker::new(100);
+        // Each rectangle takes a gutter of one pixel on every side
+        assert_eq!(packer.allocate(dim(30, 20)), Some([1, 1]));
+        assert_eq!(packer.allocate(dim(30, 10)), Some([33, 1]));
+        // Too wide for what is left of the first shelf
+        assert_eq!(packer.allocate(dim(40, 10)), Some([1, 23]));
+        // The shortest shelf with room is chosen
+        assert_eq!(packer.allocate(dim(10, 10)), Some([43, 23]));
+        assert_eq!(packer.allocate(dim(30, 20)), Some([65, 1]));
+        assert_eq!(packer.allocate(dim(99, 1)), None);
+        assert_eq!(packer.allocate(dim(98, 70)), None);
+        assert_eq!(packer.allocate(dim(98, 64)), Some([1, 35]));
+        assert_eq!(packer.allocate(dim(1, 1)), Some([55, 23]));
+    }
+
+    /// Counts what would be done on the GPU
+    #[derive(Default)]
+    struct Store {
+        textures: usize,
+        writes: usize,
+    }
+
+    impl TextureStore for Store {
+        type Texture = ScreenDimension;
+        type Quad = ();
+
+        fn create_texture(&mut self, dim: ScreenDimension) -> ScreenDimension {
+            self.textures += 1;
+            dim
+        }
+
+        fn create_quad(&mut self) {}
+
+        fn write(&mut self, _: &ScreenDimension, _: [u32; 2], _: ScreenDimension, _: &[Colour]) {
+            self.writes += 1;
+        }
+
+        fn set_quad(&mut self, _: &(), _: Quad) {}
+    }
+
+    type Cache = AssetCache<ScreenDimension, ()>;
+
+    const PAGE: u64 = 4 * ATLAS_SIZE as u64 * ATLAS_SIZE as u64;
+    const LARGE: u32 = ATLAS_MAX_IMAGE + 1;
+    const LARGE_BYTES: u64 = 4 * LARGE as u64 * LARGE as u64;
+
+    fn insert(cache: &mut Cache, store: &mut Store, path: &str, size: u32) -> ImageHandle {
+        let pixels = vec![Colour::WHITE; (size * size) as usize];
+        let image = RasterImage::from_pixels(pixels, dim(size, size));
+        cache.insert(store, path.into(), Box::new(image)).unwrap()
+    }
+
+    #[test]
+    fn small_images_share_an_atlas_page() {
+        let mut cache = Cache::new("", u64::MAX);
+        let mut store = Store::default();
+        let a = insert(&mut cache, &mut store, "a.png", 16);
+        let b = insert(&mut cache, &mut store, "b.png", ATLAS_MAX_IMAGE);
+        let c = insert(&mut cache, &mut store, "c.png", LARGE);
+        assert_eq!(store.textures, 2);
+        assert_eq!(store.writes, 3);
+        let stats = cache.stats();
+        assert_eq!((stats.images, stats.in_use), (3, 3));
+        assert_eq!((stats.atlas_pages, stats.own_textures), (1, 1));
+        assert_eq!(stats.bytes, PAGE + LARGE_BYTES);
+        assert_eq!(cache.binding(&a).unwrap().0, &dim(ATLAS_SIZE, ATLAS_SIZE));
+        assert_eq!(cache.binding(&c).unwrap().0, &dim(LARGE, LARGE));
+        drop((a, b, c));
+        assert_eq!(cache.stats().in_use, 0);
+    }
+
+    #[test]
+    fn eviction_frees_the_least_recently_used_memory_no_one_holds() {
+        let mut cache = Cache::new("", u64::MAX);
+        let mut store = Store::default();
+        let small = insert(&mut cache, &mut store, "small.png", 16);
+        let held = insert(&mut cache, &mut store, "held.png", LARGE);
+        for path in ["old.png", "new.png"] {
+            insert(&mut cache, &mut store, path, LARGE);
+        }
+        let cached = |cache: &Cache, path: &str| cache.entries.contains_key(&path.into());
+
+        // The oldest image no one holds goes first
+        cache.set_budget(PAGE + 2 * LARGE_BYTES);
+        let stats = cache.stats();
+        assert_eq!((stats.images, stats.evictions), (3, 1));
+        assert_eq!(stats.bytes, PAGE + 2 * LARGE_BYTES);
+        assert!(!cached(&cache, "old.png") && cached(&cache, "new.png"));
+
+        // The atlas page is held, so only the last unheld image can be freed
+        cache.set_budget(0);
+        let stats = cache.stats();
+        assert_eq!((stats.images, stats.evictions), (2, 2));
+        assert_eq!(stats.bytes, PAGE + LARGE_BYTES);
+        assert!(cached(&cache, "small.png") && cached(&cache, "held.png"));
+
+        // Nothing can be freed while it is held, however far over budget the cache is
+        cache.set_budget(0);
+        assert_eq!(cache.stats().evictions, 2);
+
+        // A page is freed once none of its images are held, taking them all with it
+        insert(&mut cache, &mut store, "other.png", 16);
+        drop(small);
+        cache.set_budget(0);
+        let stats = cache.stats();
+        assert_eq!((stats.images, stats.evictions), (1, 4));
+        assert_eq!((stats.atlas_pages, stats.bytes), (0, LARGE_BYTES));
+
+        drop(held);
+        cache.set_budget(0);
+        let stats = cache.stats();
+        assert_eq!((stats.images, stats.evictions, stats.bytes), (0, 5, 0));
+    }
+
+    #[test]
+    fn scalable_images_are_rasterized_at_the_size_they_are_drawn() {
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10">
+            <rect width="10" height="10" fill="red"/>
+        </svg>"#;
+        let image = SvgImage::parse("red.svg".as_ref(), svg).unwrap();
+        let mut cache = Cache::new("", u64::MAX);
+        let mut store = Store::default();
+        let handle = cache
+            .insert(&mut store, "red.svg".into(), Box::new(image))
+            .unwrap();
+        assert_eq!(cache.entries[&handle.path()].dim, dim(10, 10));
+        let screen = dim(1280, 720);
+
+        // A quarter of the screen is too large for an atlas
+        let quad = Quad::new([0.5, 0.5], [0.25, 0.25]);
+        let (texture, _) = cache.prepare(&mut store, &handle, screen, quad).unwrap();
+        assert_eq!(texture, &dim(320, 180));
+        assert_eq!((store.textures, store.writes), (2, 2));
+        assert_eq!(cache.stats().bytes, PAGE + 4 * 320 * 180);
+
+        // The same size again is not rasterized again, wherever it is
+        let quad = Quad::new([0.0, 0.0], [0.25, 0.25]);
+        cache.prepare(&mut store, &handle, screen, quad).unwrap();
+        assert_eq!(store.writes, 2);
+
+        // Back into the atlas, freeing the texture of its own
+        let quad = Quad::new([0.0, 0.0], [0.1, 0.1]);
+        let (texture, _) = cache.prepare(&mut store, &handle, screen, quad).unwrap();
+        assert_eq!(texture, &dim(ATLAS_SIZE, ATLAS_SIZE));
+        assert_eq!((store.textures, store.writes), (2, 3));
+        let stats = cache.stats();
+        assert_eq!((stats.own_textures, stats.bytes), (0, PAGE));
+
+        // Raster images keep their resolution
+        let dot = insert(&mut cache, &mut store, "dot.png", 4);
+        cache.prepare(&mut store, &dot, screen, Quad::FULL_SCREEN);
+        assert_eq!(store.writes, 4);
+    }
+
+    #[test]
+    fn loaded_images_are_shared() {
+        let root = std::env::temp_dir().join(format!("vn-engine-cache-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("art")).unwrap();
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, 1, 1);
+        encoder.set_color(png::ColorType::Rgba);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[1, 2, 3, 255]).unwrap();
+        writer.finish().unwrap();
+        std::fs::write(root.join("art/dot.png"), png).unwrap();
+
+        let mut cache = Cache::new(&root, u64::MAX);
+        let mut store = Store::default();
+        let first = cache.load(&mut store, "art/dot.png".into()).unwrap();
+        let second = cache.load(&mut store, "art/dot.png".into()).unwrap();
+        assert_eq!(first.path(), second.path());
+        assert_eq!(cache.entries[&first.path()].dim, dim(1, 1));
+        assert!(matches!(
+            cache.load(&mut store, "dot.png".into()),
+            Err(image::Error::Read(..))
+        ));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.images), (1, 2, 1));
+        assert_eq!(store.writes, 1);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
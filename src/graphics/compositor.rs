@@ -0,0 +1,430 @@
+use std::borrow::Cow;
+
+use wgpu::{
+    AddressMode, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+    BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, BlendState,
+    Buffer, BufferBindingType, BufferDescriptor, BufferUsages, ColorTargetState, ColorWrites,
+    Device, Extent3d, FilterMode, FragmentState, ImageCopyTexture, ImageDataLayout,
+    MultisampleState, Origin3d, PipelineLayoutDescriptor, PrimitiveState, PrimitiveTopology, Queue,
+    RenderPass, RenderPipeline, RenderPipelineDescriptor, Sampler, SamplerBindingType,
+    SamplerDescriptor, ShaderModuleDescriptor, ShaderSource, ShaderStages, Texture, TextureAspect,
+    TextureDescriptor, TextureDimension, TextureFormat, TextureSampleType, TextureUsages,
+    TextureViewDescriptor, TextureViewDimension, VertexState,
+};
+
+use super::{
+    cache::{AssetCache, ImageHandle},
+    layer::Layer,
+    Colour, ScreenDimension,
+};
+
+/// The slots of the layer stack, from the bottom up
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LayerSlot {
+    Background,
+    Characters,
+    Ui,
+    Overlay,
+}
+
+impl LayerSlot {
+    pub const ALL: [LayerSlot; 4] = [Self::Background, Self::Characters, Self::Ui, Self::Overlay];
+}
+
+/// Where a texture is drawn, as fractions of the screen, and which part of it is drawn, as
+/// fractions of the texture
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct Quad {
+    /// The top left corner
+    pub pos: [f32; 2],
+    pub size: [f32; 2],
+    pub uv_pos: [f32; 2],
+    pub uv_size: [f32; 2],
+}
+
+impl Quad {
+    pub const FULL_SCREEN: Quad = Quad::new([0.0, 0.0], [1.0, 1.0]);
+
+    /// A quad showing the whole texture
+    pub const fn new(pos: [f32; 2], size: [f32; 2]) -> Self {
+        Self {
+            pos,
+            size,
+            uv_pos: [0.0, 0.0],
+            uv_size: [1.0, 1.0],
+        }
+    }
+}
+
+/// A texture ready to be drawn by a [`Compositor`], in whole or in part
+pub struct GpuTexture {
+    texture: Texture,
+    dim: ScreenDimension,
+    bind_group: BindGroup,
+}
+
+impl GpuTexture {
+    fn new(
+        device: &Device,
+        layout: &BindGroupLayout,
+        sampler: &Sampler,
+        dim: ScreenDimension,
+    ) -> Self {
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("Layer Texture"),
+            size: extent(dim),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8Unorm,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Texture Bind Group"),
+            layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&view),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::Sampler(sampler),
+                },
+            ],
+        });
+        Self {
+            texture,
+            dim,
+            bind_group,
+        }
+    }
+
+    /// Replaces the whole texture with `pixels`, which must be rows of the texture's width
+    pub fn write(&self, queue: &Queue, pixels: &[Colour]) {
+        self.write_region(queue, [0, 0], self.dim, pixels);
+    }
+
+    /// Replaces the `dim` pixels with their top left corner at `origin` with `pixels`
+    pub fn write_region(
+        &self,
+        queue: &Queue,
+        origin: [u32; 2],
+        dim: ScreenDimension,
+        pixels: &[Colour],
+    ) {
+        queue.write_texture(
+            ImageCopyTexture {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: Origin3d {
+                    x: origin[0],
+                    y: origin[1],
+                    z: 0,
+                },
+                aspect: TextureAspect::All,
+            },
+            bytemuck::cast_slice(pixels),
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * dim.width),
+                rows_per_image: Some(dim.height),
+            },
+            extent(dim),
+        );
+    }
+}
+
+/// The [`Quad`] one thing is drawn on
+pub struct QuadBinding {
+    buffer: Buffer,
+    bind_group: BindGroup,
+}
+
+impl QuadBinding {
+    fn new(device: &Device, layout: &BindGroupLayout) -> Self {
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Quad"),
+            size: core::mem::size_of::<Quad>() as u64,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Quad Bind Group"),
+            layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+        });
+        Self { buffer, bind_group }
+    }
+
+    pub fn set(&self, queue: &Queue, quad: Quad) {
+        queue.write_buffer(&self.buffer, 0, bytemuck::bytes_of(&quad));
+    }
+}
+
+/// A texture with the quad it is drawn on
+pub struct LayerTexture {
+    texture: GpuTexture,
+    quad: QuadBinding,
+}
+
+impl LayerTexture {
+    /// Replaces the whole texture with `pixels`, which must be rows of the texture's width
+    pub fn write(&self, queue: &Queue, pixels: &[Colour]) {
+        self.texture.write(queue, pixels);
+    }
+
+    pub fn set_quad(&self, queue: &Queue, quad: Quad) {
+        self.quad.set(queue, quad);
+    }
+}
+
+struct Slot {
+    layer: Layer,
+    /// The texture of a solid colour. A framebuffer keeps its own.
+    texture: Option<LayerTexture>,
+    /// Whether `layer` has been replaced since it was last uploaded
+    dirty: bool,
+}
+
+impl Slot {
+    fn texture(&self) -> Option<&LayerTexture> {
+        match &self.layer {
+            Layer::SolidColour(_) => self.texture.as_ref(),
+            Layer::FrameBuffer(framebuffer) => framebuffer.texture(),
+            // Drawn from the asset cache
+            Layer::Image(_) => None,
+        }
+    }
+}
+
+/// Owns the layer stack, and draws it over the frame with alpha blending
+pub struct Compositor {
+    pipeline: RenderPipeline,
+    texture_layout: BindGroupLayout,
+    quad_layout: BindGroupLayout,
+    sampler: Sampler,
+    slots: [Option<Slot>; LayerSlot::ALL.len()],
+}
+
+impl Compositor {
+    /// Builds the pipeline for drawing to targets of the given format
+    pub fn new(device: &Device, format: TextureFormat) -> Self {
+        let shader = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("Composite Shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!(
+                "../../builtin-shaders/composite.wgsl"
+            ))),
+        });
+        let texture_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Texture Bind Group Layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let quad_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Quad Bind Group Layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Composite Pipeline Layout"),
+            bind_group_layouts: &[&texture_layout, &quad_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: Some("Composite Pipeline"),
+            layout: Some(&layout),
+            vertex: VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            primitive: PrimitiveState {
+                topology: PrimitiveTopology::TriangleStrip,
+                ..Default::default()
+            },
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            fragment: Some(FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(ColorTargetState {
+                    format,
+                    blend: Some(BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            multiview: None,
+        });
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("Layer Sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+        Self {
+            pipeline,
+            texture_layout,
+            quad_layout,
+            sampler,
+            slots: Default::default(),
+        }
+    }
+
+    pub fn layer(&self, slot: LayerSlot) -> Option<&Layer> {
+        self.slots[slot as usize].as_ref().map(|slot| &slot.layer)
+    }
+
+    /// Replaces the layer in `slot`, which is uploaded before the next frame is drawn
+    pub fn set_layer(&mut self, slot: LayerSlot, layer: Layer) {
+        let texture = self.slots[slot as usize]
+            .take()
+            .and_then(|slot| slot.texture);
+        self.slots[slot as usize] = Some(Slot {
+            layer,
+            texture,
+            dirty: true,
+        });
+    }
+
+    pub fn clear_layer(&mut self, slot: LayerSlot) -> Option<Layer> {
+        self.slots[slot as usize].take().map(|slot| slot.layer)
+    }
+
+    /// Uploads every layer that changed since the last frame, including framebuffers whose
+    /// pixels were drawn to, for a screen of `screen` pixels
+    pub fn upload(&mut self, device: &Device, queue: &Queue, screen: ScreenDimension) {
+        let create = |dim| LayerTexture {
+            texture: GpuTexture::new(device, &self.texture_layout, &self.sampler, dim),
+            quad: QuadBinding::new(device, &self.quad_layout),
+        };
+        for slot in self.slots.iter_mut().flatten() {
+            let texture = match &slot.layer {
+                Layer::SolidColour(colour) if slot.dirty => {
+                    let texture = slot.texture.get_or_insert_with(|| {
+                        create(ScreenDimension {
+                            width: 1,
+                            height: 1,
+                        })
+                    });
+                    texture.write(queue, core::slice::from_ref(colour));
+                    texture
+                }
+                Layer::SolidColour(_) | Layer::Image(_) => continue,
+                Layer::FrameBuffer(framebuffer) => {
+                    // Where a framebuffer is drawn changes with the size of the screen
+                    let texture = framebuffer.upload(queue, create);
+                    texture.set_quad(queue, framebuffer.quad(screen));
+                    slot.dirty = false;
+                    continue;
+                }
+            };
+            if slot.dirty {
+                texture.set_quad(queue, Quad::FULL_SCREEN);
+                slot.dirty = false;
+            }
+        }
+    }
+
+    /// The images shown on layers, which must be prepared in `assets` before they are drawn
+    pub fn images(&self) -> impl Iterator<Item = &ImageHandle> {
+        self.slots
+            .iter()
+            .flatten()
+            .filter_map(|slot| match &slot.layer {
+                Layer::Image(image) => Some(image),
+                _ => None,
+            })
+    }
+
+    /// Draws every uploaded layer, from the bottom of the stack up, taking images from
+    /// `assets`
+    pub fn draw<'a>(&'a self, pass: &mut RenderPass<'a>, assets: &'a AssetCache) {
+        for slot in self.slots.iter().flatten() {
+            let binding = match &slot.layer {
+                Layer::Image(image) => assets.binding(image),
+                _ => slot
+                    .texture()
+                    .map(|texture| (&texture.texture, &texture.quad)),
+            };
+            if let Some((texture, quad)) = binding {
+                self.draw_quad(pass, texture, quad);
+            }
+        }
+    }
+
+    /// A texture of the given size that can be drawn with [`Compositor::draw_quad`]
+    pub fn create_gpu_texture(&self, device: &Device, dim: ScreenDimension) -> GpuTexture {
+        GpuTexture::new(device, &self.texture_layout, &self.sampler, dim)
+    }
+
+    pub fn create_quad(&self, device: &Device) -> QuadBinding {
+        QuadBinding::new(device, &self.quad_layout)
+    }
+
+    /// A texture of the given size that can be drawn with [`Compositor::draw_texture`]
+    pub fn create_texture(&self, device: &Device, dim: ScreenDimension) -> LayerTexture {
+        LayerTexture {
+            texture: self.create_gpu_texture(device, dim),
+            quad: self.create_quad(device),
+        }
+    }
+
+    /// Draws one texture over what is already drawn
+    pub fn draw_texture<'a>(&'a self, pass: &mut RenderPass<'a>, texture: &'a LayerTexture) {
+        self.draw_quad(pass, &texture.texture, &texture.quad);
+    }
+
+    /// Draws the part of `texture` that `quad` shows over what is already drawn
+    pub fn draw_quad<'a>(
+        &'a self,
+        pass: &mut RenderPass<'a>,
+        texture: &'a GpuTexture,
+        quad: &'a QuadBinding,
+    ) {
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &texture.bind_group, &[]);
+        pass.set_bind_group(1, &quad.bind_group, &[]);
+        pass.draw(0..4, 0..1);
+    }
+}
+
+fn extent(dim: ScreenDimension) -> Extent3d {
+    Extent3d {
+        width: dim.width,
+        height: dim.height,
+        depth_or_array_layers: 1,
+    }
+}
@@ -1,21 +1,63 @@
 use core::{
+    cell::{Cell, OnceCell},
     ops::{Index, IndexMut},
     slice::SliceIndex,
 };
 
-use wgpu::{RenderPass, ShaderModule, TextureView};
+use wgpu::Queue;
 
-use crate::graphics::{Colour, Renderable, Result};
+use crate::graphics::{
+    compositor::{LayerTexture, Quad},
+    Colour, RenderState, Renderable, Result, ScreenDimension,
+};
 
-pub struct LayerFramebuffer(Vec<Colour>);
+/// Pixels drawn by the CPU, which are copied to a texture when they change and drawn as a
+/// quad over the frame. Pixels are premultiplied by their alpha.
+pub struct LayerFramebuffer {
+    pixels: Vec<Colour>,
+    dim: ScreenDimension,
+    /// The top left corner on screen, as fractions of the screen
+    pub pos: [f32; 2],
+    /// Screen pixels per framebuffer pixel
+    pub scale: f32,
+    /// Whether `pixels` has changed since it was last uploaded
+    dirty: Cell<bool>,
+    texture: OnceCell<LayerTexture>,
+}
 
 impl LayerFramebuffer {
-    pub fn as_bytes(&self) -> &[u8] {
-        bytemuck::cast_slice(&self.0)
+    /// A framebuffer holding `pixels`, which are rows from the top down, drawn unscaled in the
+    /// top left corner
+    pub fn from_pixels(pixels: Vec<Colour>, dim: ScreenDimension) -> Self {
+        assert_eq!(pixels.len(), dim.width as usize * dim.height as usize);
+        Self {
+            pixels,
+            dim,
+            pos: [0.0, 0.0],
+            scale: 1.0,
+            dirty: Cell::new(true),
+            texture: OnceCell::new(),
+        }
+    }
+
+    pub fn dimension(&self) -> ScreenDimension {
+        self.dim
+    }
+
+    /// The pixels, in rows from the top down
+    pub fn pixels(&self) -> &[Colour] {
+        &self.pixels
     }
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        bytemuck::cast_slice_mut(&mut self.0)
+    /// Where the framebuffer is drawn on a screen of the given size
+    pub fn quad(&self, screen: ScreenDimension) -> Quad {
+        Quad::new(
+            self.pos,
+            [
+                self.dim.width as f32 * self.scale / screen.width as f32,
+                self.dim.height as f32 * self.scale / screen.height as f32,
+            ],
+        )
     }
 }
 
@@ -23,20 +65,78 @@ impl<I: SliceIndex<[Colour]>> Index<I> for LayerFramebuffer {
     type Output = I::Output;
 
     fn index(&self, idx: I) -> &I::Output {
-        &self.0[idx]
+        &self.pixels[idx]
     }
 }
 
 impl<I: SliceIndex<[Colour]>> IndexMut<I> for LayerFramebuffer {
     fn index_mut(&mut self, idx: I) -> &mut I::Output {
-        &mut self.0[idx]
+        self.dirty.set(true);
+        &mut self.pixels[idx]
+    }
+}
+
+impl LayerFramebuffer {
+    /// Passes the pixels to `write` if they have changed since they were last passed
+    fn flush(&self, write: impl FnOnce(&[Colour])) {
+        if self.dirty.replace(false) {
+            write(&self.pixels);
+        }
+    }
+
+    /// Copies the pixels to the texture they are drawn from if they have changed, making the
+    /// texture with `create` the first time
+    pub fn upload(
+        &self,
+        queue: &Queue,
+        create: impl FnOnce(ScreenDimension) -> LayerTexture,
+    ) -> &LayerTexture {
+        let texture = self.texture.get_or_init(|| create(self.dim));
+        self.flush(|pixels| texture.write(queue, pixels));
+        texture
+    }
+
+    /// The texture the pixels were last uploaded to, if they have been
+    pub fn texture(&self) -> Option<&LayerTexture> {
+        self.texture.get()
+    }
+
+    /// Draws the framebuffer on `quad`, ignoring its own position and scale
+    pub fn draw_at(&self, state: &mut RenderState, quad: Quad) -> Result<()> {
+        let texture = self.upload(state.queue(), |dim| state.create_texture(dim));
+        texture.set_quad(state.queue(), quad);
+        state.draw_texture(texture)
     }
 }
 
 impl Renderable for LayerFramebuffer {
     type Output<'a> = ();
 
-    fn render(&self, state: &mut super::RenderState) -> Result<()> {
-        Ok(())
+    fn render(&self, state: &mut RenderState) -> Result<()> {
+        self.draw_at(state, self.quad(state.screen_dimension()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unchanged_pixels_are_not_uploaded_again() {
+        let dim = ScreenDimension {
+            width: 2,
+            height: 2,
+        };
+        let mut framebuffer = LayerFramebuffer::from_pixels(vec![Colour::TRANSPARENT; 4], dim);
+        let mut uploads = Vec::new();
+        framebuffer.flush(|pixels| uploads.push(pixels.to_vec()));
+        framebuffer.flush(|pixels| uploads.push(pixels.to_vec()));
+        assert_eq!(uploads, [vec![Colour::TRANSPARENT; 4]]);
+
+        framebuffer[3] = Colour::WHITE;
+        framebuffer.flush(|pixels| uploads.push(pixels.to_vec()));
+        framebuffer.flush(|pixels| uploads.push(pixels.to_vec()));
+        assert_eq!(uploads.len(), 2);
+        assert_eq!(uploads[1][3], Colour::WHITE);
     }
 }
@@ -1,11 +1,474 @@
-use std::path::Path;
+use core::{cell::RefCell, fmt};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
 
-pub struct SvgImage(resvg::Tree);
+use resvg::{
+    tiny_skia::{Pixmap, Transform},
+    usvg::{self, TreeParsing},
+};
+
+use super::{
+    compositor::Quad, framebuf::LayerFramebuffer, Colour, RenderState, Renderable, Result,
+    ScreenDimension,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Read(PathBuf, io::Error),
+    Svg(PathBuf, usvg::Error),
+    Png(PathBuf, png::DecodingError),
+    Jpeg(PathBuf, jpeg_decoder::Error),
+    /// The file is not in a format that can be loaded
+    Unsupported(PathBuf),
+    /// The image is too small to have any pixels
+    Empty(PathBuf),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(path, err) => write!(f, "could not read `{}`: {}", path.display(), err),
+            Self::Svg(path, err) => write!(f, "could not parse `{}`: {}", path.display(), err),
+            Self::Png(path, err) => write!(f, "could not decode `{}`: {}", path.display(), err),
+            Self::Jpeg(path, err) => write!(f, "could not decode `{}`: {}", path.display(), err),
+            Self::Unsupported(path) => {
+                write!(f, "`{}` is not in a supported image format", path.display())
+            }
+            Self::Empty(path) => write!(f, "`{}` has no pixels", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// What every kind of image can do, so that layers and scripts can use them alike
+pub trait Image {
+    /// The size the image was made at, in its own pixels
+    fn natural_size(&self) -> (f32, f32);
+
+    /// Places the image on screen, at `pos` and `size` as fractions of the screen
+    fn set_placement(&mut self, pos: [f32; 2], size: [f32; 2]);
+
+    /// The pixels of the image where it is placed on a screen of `dim` pixels, which can be
+    /// drawn or made a [`Layer::FrameBuffer`](super::layer::Layer::FrameBuffer). Images with
+    /// a fixed resolution keep it, and are stretched when drawn.
+    fn to_framebuffer(&self, dim: ScreenDimension) -> Option<LayerFramebuffer>;
+
+    /// Draws the image where it was placed
+    fn draw(&self, state: &mut RenderState) -> Result<()>;
+
+    /// Whether the image stays sharp at any size, so that it is worth rasterizing again
+    /// whenever the size it is drawn at changes
+    fn scalable(&self) -> bool {
+        false
+    }
+}
+
+/// Loads the image at `path`, choosing how to decode it from its extension. The image is drawn
+/// over the whole screen until it is placed elsewhere.
+///
+/// SVG, PNG and JPEG images can be loaded. WebP cannot, as there is no WebP decoder among the
+/// engine's dependencies, so WebP art has to be converted to PNG.
+pub fn open<P: AsRef<Path>>(path: P) -> core::result::Result<Box<dyn Image>, Error> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("svg" | "svgz") => Ok(Box::new(SvgImage::open(path)?)),
+        Some("png" | "jpg" | "jpeg") => Ok(Box::new(RasterImage::open(path)?)),
+        _ => Err(Error::Unsupported(path.into())),
+    }
+}
+
+/// The pixels of an image, for where it is drawn on a screen of the given size
+pub(super) fn screen_size(size: [f32; 2], screen: ScreenDimension) -> ScreenDimension {
+    ScreenDimension {
+        width: (size[0] * screen.width as f32).round() as u32,
+        height: (size[1] * screen.height as f32).round() as u32,
+    }
+}
+
+/// A vector image, rasterized at the size it is drawn on screen so that it stays sharp at any
+/// window size. Text in the image is not drawn.
+pub struct SvgImage {
+    tree: resvg::Tree,
+    /// The top left corner on screen, as fractions of the screen
+    pub pos: [f32; 2],
+    /// The size on screen, as fractions of the screen
+    pub size: [f32; 2],
+    /// The image as last rasterized, which is kept on the GPU until the size it is drawn at
+    /// changes
+    raster: RefCell<Option<LayerFramebuffer>>,
+}
 
 impl SvgImage {
-    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
-        let data = std::fs::read_to_string(path)?;
+    /// Loads the image at `path`, to be drawn over the whole screen
+    pub fn open<P: AsRef<Path>>(path: P) -> core::result::Result<Self, Error> {
+        let path = path.as_ref();
+        let data = std::fs::read(path).map_err(|err| Error::Read(path.into(), err))?;
+        Self::parse(path, &data)
+    }
+
+    /// Parses `data`, the contents of the SVG file at `path`
+    pub fn parse(path: &Path, data: &[u8]) -> core::result::Result<Self, Error> {
+        let tree = usvg::Tree::from_data(data, &usvg::Options::default())
+            .map_err(|err| Error::Svg(path.into(), err))?;
+        Ok(Self {
+            tree: resvg::Tree::from_usvg(&tree),
+            pos: [0.0, 0.0],
+            size: [1.0, 1.0],
+            raster: RefCell::new(None),
+        })
+    }
+
+    /// Rasterizes the image at `dim` pixels, stretching it to fit
+    fn rasterize(&self, dim: ScreenDimension) -> Option<Vec<Colour>> {
+        let mut pixmap = Pixmap::new(dim.width, dim.height)?;
+        let (width, height) = self.natural_size();
+        let transform = Transform::from_scale(dim.width as f32 / width, dim.height as f32 / height);
+        self.tree.render(transform, &mut pixmap.as_mut());
+        // Pixmaps are premultiplied, like framebuffers
+        let pixels = pixmap
+            .pixels()
+            .iter()
+            .map(|pixel| Colour {
+                r: pixel.red(),
+                g: pixel.green(),
+                b: pixel.blue(),
+                a: pixel.alpha(),
+            })
+            .collect();
+        Some(pixels)
+    }
+}
+
+impl Image for SvgImage {
+    /// The size in the units of the SVG
+    fn natural_size(&self) -> (f32, f32) {
+        (self.tree.size.width(), self.tree.size.height())
+    }
+
+    fn set_placement(&mut self, pos: [f32; 2], size: [f32; 2]) {
+        self.pos = pos;
+        self.size = size;
+    }
+
+    fn to_framebuffer(&self, dim: ScreenDimension) -> Option<LayerFramebuffer> {
+        let dim = screen_size(self.size, dim);
+        Some(LayerFramebuffer::from_pixels(self.rasterize(dim)?, dim))
+    }
+
+    fn draw(&self, state: &mut RenderState) -> Result<()> {
+        let dim = screen_size(self.size, state.screen_dimension());
+        let mut raster = self.raster.borrow_mut();
+        if raster.as_ref().map(LayerFramebuffer::dimension) != Some(dim) {
+            *raster = self
+                .rasterize(dim)
+                .map(|pixels| LayerFramebuffer::from_pixels(pixels, dim));
+        }
+        match &*raster {
+            Some(raster) => raster.draw_at(state, Quad::new(self.pos, self.size)),
+            // Too small to see
+            None => Ok(()),
+        }
+    }
+
+    fn scalable(&self) -> bool {
+        true
+    }
+}
+
+impl Renderable for SvgImage {
+    type Output<'a> = ();
+
+    fn render(&self, state: &mut RenderState) -> Result<()> {
+        self.draw(state)
+    }
+}
+
+/// An image made of pixels, a PNG or JPEG, which is scaled on the GPU to the size it is drawn
+/// at
+pub struct RasterImage {
+    /// The pixels, premultiplied by their alpha
+    pixels: LayerFramebuffer,
+    /// The top left corner on screen, as fractions of the screen
+    pub pos: [f32; 2],
+    /// The size on screen, as fractions of the screen
+    pub size: [f32; 2],
+}
+
+impl RasterImage {
+    /// Loads the PNG or JPEG image at `path`, to be drawn over the whole screen
+    pub fn open<P: AsRef<Path>>(path: P) -> core::result::Result<Self, Error> {
+        let path = path.as_ref();
+        let data = std::fs::read(path).map_err(|err| Error::Read(path.into(), err))?;
+        Self::decode(path, &data)
+    }
+
+    /// Decodes `data`, the contents of the PNG or JPEG file at `path`. The format is told from
+    /// the start of the data, whatever the extension of the file.
+    pub fn decode(path: &Path, data: &[u8]) -> core::result::Result<Self, Error> {
+        let (pixels, dim) = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            decode_png(data).map_err(|err| Error::Png(path.into(), err))?
+        } else if data.starts_with(&[0xFF, 0xD8]) {
+            // The start of image marker, which every JPEG begins with
+            match decode_jpeg(data) {
+                Ok(Some(image)) => image,
+                Ok(None) => return Err(Error::Unsupported(path.into())),
+                Err(err) => return Err(Error::Jpeg(path.into(), err)),
+            }
+        } else {
+            return Err(Error::Unsupported(path.into()));
+        };
+        Ok(Self::from_pixels(pixels, dim))
+    }
+
+    /// An image of `pixels`, which are rows from the top down with straight alpha
+    pub fn from_pixels(pixels: Vec<Colour>, dim: ScreenDimension) -> Self {
+        let pixels = pixels.into_iter().map(Colour::premultiplied).collect();
+        Self {
+            pixels: LayerFramebuffer::from_pixels(pixels, dim),
+            pos: [0.0, 0.0],
+            size: [1.0, 1.0],
+        }
+    }
+}
+
+impl Image for RasterImage {
+    fn natural_size(&self) -> (f32, f32) {
+        let dim = self.pixels.dimension();
+        (dim.width as f32, dim.height as f32)
+    }
+
+    fn set_placement(&mut self, pos: [f32; 2], size: [f32; 2]) {
+        self.pos = pos;
+        self.size = size;
+    }
+
+    fn to_framebuffer(&self, _: ScreenDimension) -> Option<LayerFramebuffer> {
+        Some(LayerFramebuffer::from_pixels(
+            self.pixels.pixels().to_vec(),
+            self.pixels.dimension(),
+        ))
+    }
+
+    fn draw(&self, state: &mut RenderState) -> Result<()> {
+        self.pixels.draw_at(state, Quad::new(self.pos, self.size))
+    }
+}
+
+impl Renderable for RasterImage {
+    type Output<'a> = ();
+
+    fn render(&self, state: &mut RenderState) -> Result<()> {
+        self.draw(state)
+    }
+}
+
+/// Decodes a PNG into pixels with straight alpha
+fn decode_png(
+    data: &[u8],
+) -> core::result::Result<(Vec<Colour>, ScreenDimension), png::DecodingError> {
+    let mut decoder = png::Decoder::new(data);
+    // Palettes, transparency chunks, and depths other than 8 bits are turned into plain
+    // 8-bit channels
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    let buf = &buf[..info.buffer_size()];
+    let pixels = match info.color_type {
+        png::ColorType::Rgba => buf
+            .chunks_exact(4)
+            .map(|p| Colour {
+                r: p[0],
+                g: p[1],
+                b: p[2],
+                a: p[3],
+            })
+            .collect(),
+        png::ColorType::Rgb => buf.chunks_exact(3).map(|p| rgb(p[0], p[1], p[2])).collect(),
+        png::ColorType::GrayscaleAlpha => buf
+            .chunks_exact(2)
+            .map(|p| Colour {
+                a: p[1],
+                ..rgb(p[0], p[0], p[0])
+            })
+            .collect(),
+        // Indexed images are expanded to RGB
+        png::ColorType::Grayscale | png::ColorType::Indexed => {
+            buf.iter().map(|&l| rgb(l, l, l)).collect()
+        }
+    };
+    let dim = ScreenDimension {
+        width: info.width,
+        height: info.height,
+    };
+    Ok((pixels, dim))
+}
+
+/// Decodes a JPEG into opaque pixels, or `None` if its pixel format is not supported
+fn decode_jpeg(
+    data: &[u8],
+) -> core::result::Result<Option<(Vec<Colour>, ScreenDimension)>, jpeg_decoder::Error> {
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    let data = decoder.decode()?;
+    let info = decoder.info().unwrap();
+    let pixels = match info.pixel_format {
+        jpeg_decoder::PixelFormat::RGB24 => data
+            .chunks_exact(3)
+            .map(|p| rgb(p[0], p[1], p[2]))
+            .collect(),
+        jpeg_decoder::PixelFormat::L8 => data.iter().map(|&l| rgb(l, l, l)).collect(),
+        jpeg_decoder::PixelFormat::L16 | jpeg_decoder::PixelFormat::CMYK32 => return Ok(None),
+    };
+    let dim = ScreenDimension {
+        width: info.width.into(),
+        height: info.height.into(),
+    };
+    Ok(Some((pixels, dim)))
+}
+
+fn rgb(r: u8, g: u8, b: u8) -> Colour {
+    Colour { r, g, b, a: 0xFF }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(data: &[u8]) -> core::result::Result<(Vec<Colour>, ScreenDimension), Error> {
+        let image = RasterImage::decode(Path::new("test"), data)?;
+        Ok((image.pixels.pixels().to_vec(), image.pixels.dimension()))
+    }
+
+    fn png(
+        dim: ScreenDimension,
+        colour: png::ColorType,
+        edit: impl FnOnce(&mut png::Encoder<&mut Vec<u8>>),
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, dim.width, dim.height);
+        encoder.set_color(colour);
+        encoder.set_depth(png::BitDepth::Eight);
+        edit(&mut encoder);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(data).unwrap();
+        writer.finish().unwrap();
+        out
+    }
+
+    const TWO_BY_ONE: ScreenDimension = ScreenDimension {
+        width: 2,
+        height: 1,
+    };
+
+    #[test]
+    fn pngs_are_premultiplied() {
+        let rgba = png(
+            TWO_BY_ONE,
+            png::ColorType::Rgba,
+            |_| {},
+            &[255, 0, 0, 255, 0, 100, 255, 128],
+        );
+        let expected = [
+            rgb(255, 0, 0),
+            Colour {
+                r: 0,
+                g: 50,
+                b: 128,
+                a: 128,
+            },
+        ];
+        assert_eq!(decode(&rgba).unwrap(), (expected.to_vec(), TWO_BY_ONE));
+
+        let grey = png(
+            TWO_BY_ONE,
+            png::ColorType::GrayscaleAlpha,
+            |_| {},
+            &[200, 255, 200, 0],
+        );
+        let expected = [rgb(200, 200, 200), Colour::TRANSPARENT];
+        assert_eq!(decode(&grey).unwrap().0, expected);
+    }
+
+    #[test]
+    fn indexed_pngs_are_expanded() {
+        let indexed = png(
+            TWO_BY_ONE,
+            png::ColorType::Indexed,
+            |encoder| {
+                encoder.set_palette(vec![10, 20, 30, 40, 50, 60]);
+                // The second entry of the palette is fully transparent
+                encoder.set_trns(vec![255, 0]);
+            },
+            &[0, 1],
+        );
+        let expected = [rgb(10, 20, 30), Colour::TRANSPARENT];
+        assert_eq!(decode(&indexed).unwrap().0, expected);
+    }
+
+    /// A greyscale JPEG of two 8 by 8 blocks, the left of 200 and the right of 50, written by
+    /// hand as there is no JPEG encoder to make one with. Every coefficient but the first of
+    /// each block is zero.
+    fn two_block_jpeg() -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8];
+        let mut segment = |marker: u8, payload: &[u8]| {
+            jpeg.extend([0xFF, marker]);
+            jpeg.extend((payload.len() as u16 + 2).to_be_bytes());
+            jpeg.extend(payload);
+        };
+        // Every quantizer is 1
+        segment(0xDB, &[[0].as_slice(), &[1; 64]].concat());
+        // 16 by 8 pixels of one component
+        segment(0xC0, &[8, 0, 8, 0, 16, 1, 1, 0x11, 0]);
+        // The first coefficients differ by 10 and 11 bit numbers, with the codes 00 and 01
+        let mut dc = [0; 19];
+        dc[2] = 2;
+        dc[17..].copy_from_slice(&[10, 11]);
+        segment(0xC4, &dc);
+        // The end of a block has the code 0
+        let mut ac = [0; 18];
+        ac[0] = 0x10;
+        ac[1] = 1;
+        segment(0xC4, &ac);
+        segment(0xDA, &[1, 1, 0x00, 0, 63, 0]);
+        // 00, 576 as 10 bits, which is 8 * (200 - 128), then 0 to end the block. Then 01, the
+        // difference -1200 as 11 bits, and 0 again, padded with ones.
+        jpeg.extend([0x24, 0x02, 0xD3, 0xDF, 0xFF, 0xD9]);
+        jpeg
+    }
+
+    #[test]
+    fn jpegs_are_decoded() {
+        let (pixels, dim) = decode(&two_block_jpeg()).unwrap();
+        assert_eq!(
+            dim,
+            ScreenDimension {
+                width: 16,
+                height: 8
+            }
+        );
+        for (row, pixels) in pixels.chunks(16).enumerate() {
+            assert_eq!(pixels[..8], [rgb(200, 200, 200); 8], "row {}", row);
+            assert_eq!(pixels[8..], [rgb(50, 50, 50); 8], "row {}", row);
+        }
+    }
 
-        todo!()
+    #[test]
+    fn unknown_formats_are_unsupported() {
+        for data in [b"GIF89a".as_slice(), b"RIFF\0\0\0\0WEBPVP8 ", b""] {
+            assert!(matches!(decode(data), Err(Error::Unsupported(_))));
+        }
+        assert!(matches!(
+            decode(&[0xFF, 0xD8, 0xFF, 0xD9]),
+            Err(Error::Jpeg(..))
+        ));
+        assert!(matches!(open("art/cg.webp"), Err(Error::Unsupported(_))));
     }
 }
@@ -1,6 +1,10 @@
-use super::{Colour, ScreenDimension};
+use super::{cache::ImageHandle, framebuf::LayerFramebuffer, Colour};
 
+/// One layer of the scene, stretched over the screen. Pixels are premultiplied by their alpha.
 pub enum Layer {
     SolidColour(Colour),
-    FrameBuffer(Vec<Colour>, ScreenDimension),
+    /// Pixels drawn by the CPU, which are uploaded again whenever they change
+    FrameBuffer(Box<LayerFramebuffer>),
+    /// An image from the asset cache, which is kept on the GPU while it is shown
+    Image(ImageHandle),
 }
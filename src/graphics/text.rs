@@ -0,0 +1,314 @@
+use std::path::Path;
+
+use ab_glyph::{point, Font, FontVec, PxScale, ScaleFont};
+
+use super::{framebuf::LayerFramebuffer, Colour, ScreenDimension};
+use crate::script::markup::{Piece, Style};
+
+/// The height of the text box, as a fraction of the screen
+const BOX_HEIGHT: f32 = 0.28;
+/// The height of the text, as a fraction of the screen. The text box has this much space
+/// around the text.
+const TEXT_HEIGHT: f32 = 0.035;
+/// How far italic text leans, in pixels across per pixel up
+const ITALIC_SLANT: f32 = 0.2;
+
+const BOX_COLOUR: Colour = Colour {
+    r: 0x10,
+    g: 0x10,
+    b: 0x18,
+    a: 0xC0,
+};
+const NAME_COLOUR: Colour = Colour {
+    r: 0xFF,
+    g: 0xD8,
+    b: 0x66,
+    a: 0xFF,
+};
+
+/// What the text box shows
+#[derive(Clone, Debug, PartialEq)]
+pub enum Text {
+    /// A line of dialogue, and the name of whoever says it
+    Dialogue {
+        name: Option<String>,
+        pieces: Vec<Piece>,
+    },
+    /// The options of a menu, which are numbered from 1 for the player to pick
+    Choice(Vec<Vec<Piece>>),
+}
+
+/// A character placed in the text box
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Placed {
+    c: char,
+    style: Style,
+    /// The distance from the left of the text, in pixels
+    x: f32,
+    line: usize,
+}
+
+/// Lays `runs` out in lines no wider than `width`, breaking at spaces and at each `\n`. A word
+/// wider than a line is broken wherever it reaches the edge. `advance` gives the width of a
+/// character.
+fn layout<'a>(
+    runs: impl IntoIterator<Item = (&'a str, Style)>,
+    width: f32,
+    advance: impl Fn(char) -> f32,
+) -> Vec<Placed> {
+    let mut placed = Vec::new();
+    let mut x = 0.0;
+    let mut line = 0;
+    let mut word = Vec::new();
+    let mut place_word = |word: &mut Vec<(char, Style)>, x: &mut f32, line: &mut usize| {
+        let word_width = word.iter().map(|&(c, _)| advance(c)).sum::<f32>();
+        if *x > 0.0 && *x + word_width > width {
+            *x = 0.0;
+            *line += 1;
+        }
+        for (c, style) in word.drain(..) {
+            let advance = advance(c);
+            if *x > 0.0 && *x + advance > width {
+                *x = 0.0;
+                *line += 1;
+            }
+            placed.push(Placed {
+                c,
+                style,
+                x: *x,
+                line: *line,
+            });
+            *x += advance;
+        }
+    };
+    let chars = runs
+        .into_iter()
+        .flat_map(|(text, style)| text.chars().map(move |c| (c, style)));
+    for (c, style) in chars {
+        if c == '\n' {
+            place_word(&mut word, &mut x, &mut line);
+            x = 0.0;
+            line += 1;
+        } else if c.is_whitespace() {
+            place_word(&mut word, &mut x, &mut line);
+            // Spaces at the start of a wrapped line are dropped
+            if x > 0.0 {
+                x += advance(c);
+            }
+        } else {
+            word.push((c, style));
+        }
+    }
+    place_word(&mut word, &mut x, &mut line);
+    placed
+}
+
+/// The text of `pieces` in runs of the same style
+fn runs_of(pieces: &[Piece]) -> impl Iterator<Item = (&str, Style)> {
+    pieces.iter().filter_map(|piece| match piece {
+        // The whole text is shown at once, so pauses have nothing to wait for
+        Piece::Text(run) => Some((&*run.text, run.style)),
+        Piece::Wait(_) | Piece::NoWait => None,
+    })
+}
+
+/// Blends `colour`, which is premultiplied, over `pixel` with `coverage` from 0 to 1
+fn blend(pixel: &mut Colour, colour: Colour, coverage: f32) {
+    let coverage = coverage.clamp(0.0, 1.0);
+    let under = 1.0 - colour.a as f32 / 255.0 * coverage;
+    let mix = |src: u8, dst: u8| (src as f32 * coverage + dst as f32 * under).round() as u8;
+    *pixel = Colour {
+        r: mix(colour.r, pixel.r),
+        g: mix(colour.g, pixel.g),
+        b: mix(colour.b, pixel.b),
+        a: mix(colour.a, pixel.a),
+    };
+}
+
+/// Draws dialogue and menus, for the player to read, over the bottom of the screen
+pub struct TextBox {
+    font: FontVec,
+}
+
+impl TextBox {
+    pub fn new(font: FontVec) -> Self {
+        Self { font }
+    }
+
+    /// Loads the font of the game at `root`, which is `font.ttf` or `font.otf` there, or a sans
+    /// serif font of the system if the game has none
+    pub fn load(root: &Path) -> Option<Self> {
+        let own = ["font.ttf", "font.otf"]
+            .iter()
+            .find_map(|name| std::fs::read(root.join(name)).ok())
+            .and_then(|data| FontVec::try_from_vec(data).ok());
+        if let Some(font) = own {
+            return Some(Self::new(font));
+        }
+        let mut db = fontdb::Database::new();
+        db.load_system_fonts();
+        let families = [
+            fontdb::Family::SansSerif,
+            fontdb::Family::Name("DejaVu Sans"),
+            fontdb::Family::Name("Noto Sans"),
+            fontdb::Family::Name("Liberation Sans"),
+        ];
+        let id = db
+            .query(&fontdb::Query {
+                families: &families,
+                ..fontdb::Query::default()
+            })
+            .or_else(|| db.faces().next().map(|face| face.id))?;
+        db.with_face_data(id, |data, index| {
+            FontVec::try_from_vec_and_index(data.to_vec(), index).ok()
+        })
+        .flatten()
+        .map(Self::new)
+    }
+
+    /// Draws `text` in a text box across the bottom of a screen of `screen` pixels
+    pub fn render(&self, text: &Text, screen: ScreenDimension) -> LayerFramebuffer {
+        let dim = ScreenDimension {
+            width: screen.width,
+            height: (screen.height as f32 * BOX_HEIGHT).round().max(1.0) as u32,
+        };
+        let len = dim.width as usize * dim.height as usize;
+        let mut framebuffer =
+            LayerFramebuffer::from_pixels(vec![BOX_COLOUR.premultiplied(); len], dim);
+        framebuffer.pos = [0.0, 1.0 - dim.height as f32 / screen.height.max(1) as f32];
+
+        let height = screen.height as f32 * TEXT_HEIGHT;
+        let font = self.font.as_scaled(PxScale::from(height));
+        let name_style = Style {
+            bold: true,
+            colour: Some(NAME_COLOUR),
+            ..Style::default()
+        };
+        let numbers;
+        let mut runs = Vec::new();
+        match text {
+            Text::Dialogue { name, pieces } => {
+                if let Some(name) = name {
+                    runs.extend([(&**name, name_style), ("\n", Style::default())]);
+                }
+                runs.extend(runs_of(pieces));
+            }
+            Text::Choice(options) => {
+                numbers = (1..=options.len())
+                    .map(|i| format!("{}. ", i))
+                    .collect::<Vec<_>>();
+                for (number, option) in numbers.iter().zip(options) {
+                    runs.push((&**number, name_style));
+                    runs.extend(runs_of(option));
+                    runs.push(("\n", Style::default()));
+                }
+            }
+        }
+
+        // The text box has a line's height of space around the text
+        let left = height;
+        let top = height + font.ascent();
+        let width = dim.width as f32 - 2.0 * left;
+        let placed = layout(runs, width, |c| font.h_advance(font.glyph_id(c)));
+        for placed in placed {
+            let baseline = top + placed.line as f32 * (font.height() + font.line_gap());
+            let glyph = font
+                .glyph_id(placed.c)
+                .with_scale_and_position(height, point(left + placed.x, baseline));
+            let Some(outline) = self.font.outline_glyph(glyph) else {
+                continue;
+            };
+            let colour = placed.style.colour.unwrap_or(Colour::WHITE).premultiplied();
+            let bounds = outline.px_bounds();
+            // Bold text is drawn twice, the second time a little to the right
+            let strikes = if placed.style.bold { 2 } else { 1 };
+            let offset = (height / 24.0).max(1.0);
+            for strike in 0..strikes {
+                outline.draw(|x, y, coverage| {
+                    let mut x = bounds.min.x + x as f32 + strike as f32 * offset;
+                    let y = bounds.min.y + y as f32;
+                    if placed.style.italic {
+                        x += (baseline - y) * ITALIC_SLANT;
+                    }
+                    let (x, y) = (x.round(), y.round());
+                    if x < 0.0 || y < 0.0 || x >= dim.width as f32 || y >= dim.height as f32 {
+                        return;
+                    }
+                    let idx = y as usize * dim.width as usize + x as usize;
+                    blend(&mut framebuffer[idx], colour, coverage);
+                });
+            }
+        }
+        framebuffer
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Where each character is placed, as `(char, x, line)`
+    fn positions(runs: &[&str], width: f32) -> Vec<(char, f32, usize)> {
+        let runs = runs.iter().map(|text| (*text, Style::default()));
+        layout(runs, width, |_| 1.0)
+            .into_iter()
+            .map(|placed| (placed.c, placed.x, placed.line))
+            .collect()
+    }
+
+    #[test]
+    fn lines_break_between_words() {
+        assert_eq!(
+            positions(&["ab cd", " ef"], 5.0),
+            [
+                ('a', 0.0, 0),
+                ('b', 1.0, 0),
+                ('c', 3.0, 0),
+                ('d', 4.0, 0),
+                ('e', 0.0, 1),
+                ('f', 1.0, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn words_can_span_runs() {
+        let placed = positions(&["abc", "de f"], 4.0);
+        let lines = placed.iter().map(|&(_, _, line)| line).collect::<Vec<_>>();
+        assert_eq!(lines, [0, 0, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn long_words_break_at_the_edge() {
+        let placed = positions(&["a bcdef"], 3.0);
+        let lines = placed.iter().map(|&(_, _, line)| line).collect::<Vec<_>>();
+        assert_eq!(lines, [0, 1, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn newlines_start_lines() {
+        assert_eq!(
+            positions(&["a\n", "\nb"], 10.0),
+            [('a', 0.0, 0), ('b', 0.0, 2)]
+        );
+    }
+
+    #[test]
+    fn blending_is_premultiplied() {
+        let mut pixel = Colour::BLACK;
+        blend(&mut pixel, Colour::WHITE, 1.0);
+        assert_eq!(pixel, Colour::WHITE);
+
+        let mut pixel = Colour::TRANSPARENT;
+        blend(&mut pixel, Colour::WHITE, 0.5);
+        assert_eq!(
+            pixel,
+            Colour {
+                r: 128,
+                g: 128,
+                b: 128,
+                a: 128
+            }
+        );
+    }
+}
@@ -1,39 +1,364 @@
 use std::{
-    path::Path,
-    time::{Duration, Instant},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
 };
 
 use event::GameEvent;
 use futures::future::FutureExt;
 
-use graphics::{Colour, GraphicsState, RenderState};
+use graphics::{
+    compositor::LayerSlot,
+    layer::Layer,
+    text::{Text, TextBox},
+    Colour, GraphicsState, RenderState,
+};
+use script::{
+    bundle::{Bundle, BUNDLE_EXTENSION},
+    bytecode::Program,
+    check, compile,
+    diag::{Diagnostic, Diagnostics},
+    module::Loader,
+    span::SourceMap,
+    symbol::Scope,
+    value::{Input, Value, Yield},
+    vm::{Vm, SAVE_EXTENSION},
+};
 use wgpu::{
-    Backends, CommandEncoderDescriptor, DeviceDescriptor, Dx12Compiler, Extent3d, Features,
-    ImageCopyTexture, Instance, InstanceDescriptor, Label, Limits, Origin3d, PowerPreference,
-    RenderPassDescriptor, RequestAdapterOptions, SurfaceConfiguration, TextureDescriptor,
-    TextureFormat, TextureUsages,
+    Backends, DeviceDescriptor, Dx12Compiler, Features, Instance, InstanceDescriptor, Limits,
+    PowerPreference, RequestAdapterOptions, SurfaceConfiguration, TextureUsages,
+};
+use winit::{
+    event::{ElementState, MouseButton, VirtualKeyCode},
+    event_loop::EventLoopBuilder,
+    window::Window,
 };
-use winit::{event_loop::EventLoop, event_loop::EventLoopBuilder, window::Window};
 
 mod event;
 mod graphics;
 mod script;
 
-fn main() {
-    let entry_point = Path::new("main.vns");
+/// Prints any diagnostics, exiting if there were errors
+fn report(diags: &Diagnostics, sources: &SourceMap) {
+    eprint!("{}", diags.render(sources));
+    if diags.has_errors() {
+        std::process::exit(1)
+    }
+}
+
+/// The root of the game whose entry script is at `path`, which imports and images are
+/// relative to
+fn game_root(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new(""))
+}
+
+/// A loader for the script at `path`, with imports relative to the directory containing it
+fn script_loader(path: &Path) -> Loader {
+    Loader::new(game_root(path))
+}
+
+/// Loads and compiles the script at `path` and the files it imports, printing any warnings.
+/// On failure the diagnostics are returned rather than shown.
+fn try_compile_script(
+    loader: &mut Loader,
+    path: &Path,
+) -> (Result<Program, Diagnostics>, SourceMap) {
+    let mut sources = SourceMap::new();
+    let mut diags = Diagnostics::new();
+
+    let items = loader.load(path, &mut sources).unwrap_or_else(|errs| {
+        diags.extend(errs);
+        Vec::new()
+    });
+    if !diags.has_errors() {
+        diags.extend(check::check(&items, "main".into()));
+    }
+    if diags.has_errors() {
+        return (Err(diags), sources);
+    }
+    eprint!("{}", diags.render(&sources));
+
+    let program = compile::compile(&items).map_err(|errs| {
+        let mut diags = Diagnostics::new();
+        diags.extend(errs);
+        diags
+    });
+    (program, sources)
+}
+
+/// Loads and compiles the script at `path` and the files it imports, exiting with diagnostics
+/// on failure
+fn compile_script(loader: &mut Loader, path: &Path) -> (Program, SourceMap) {
+    match try_compile_script(loader, path) {
+        (Ok(program), sources) => (program, sources),
+        (Err(diags), sources) => {
+            report(&diags, &sources);
+            unreachable!()
+        }
+    }
+}
+
+/// Loads the script at `path` from the bundle beside it, or compiles it from source if there is
+/// no bundle or it is out of date
+fn load_program(loader: &mut Loader, path: &Path) -> (Program, SourceMap) {
+    let bundle_path = path.with_extension(BUNDLE_EXTENSION);
+    let Ok(bytes) = std::fs::read(&bundle_path) else {
+        return compile_script(loader, path);
+    };
+    // SAFETY: The names interned from a bundle that is not used are only kept in the bundle
+    // and the diagnostic about it, which are dropped before the scope
+    let scope = unsafe { Scope::enter() };
+    let mut sources = SourceMap::new();
+    let bundle = Bundle::decode(&bytes).and_then(|bundle| {
+        bundle.check_fresh(&mut sources)?;
+        Ok(bundle)
+    });
+    match bundle {
+        Ok(bundle) => {
+            scope.keep();
+            (bundle.program, sources)
+        }
+        Err(err) => {
+            let diag = Diagnostic::warning(format!(
+                "not using `{}`: {}",
+                bundle_path.to_string_lossy(),
+                err
+            ))
+            .with_note("compiling from source instead; run with --bundle to rebuild it");
+            eprint!("{}", diag.render(&sources));
+            drop((diag, err, sources, scope));
+            compile_script(loader, path)
+        }
+    }
+}
+
+/// Compiles the script at `path` from source, and writes it to the bundle beside it
+fn write_bundle(path: &Path) {
+    let (program, sources) = compile_script(&mut script_loader(path), path);
+    let bundle_path = path.with_extension(BUNDLE_EXTENSION);
+    let bytes = Bundle::new(program, &sources).encode();
+    if let Err(err) = std::fs::write(&bundle_path, bytes) {
+        let diag = Diagnostic::error(format!(
+            "could not write `{}`: {}",
+            bundle_path.to_string_lossy(),
+            err
+        ));
+        eprint!("{}", diag.render(&sources));
+        std::process::exit(1)
+    }
+}
+
+/// Defines the items of the compiled script and starts it at its `main` scene, exiting with
+/// diagnostics on failure
+fn start_script(path: &Path, program: Program, sources: &SourceMap) -> Vm {
+    let mut diags = Diagnostics::new();
+
+    let mut script = Vm::new(Rc::new(program)).unwrap_or_else(|errs| {
+        diags.extend(errs);
+        report(&diags, sources);
+        unreachable!()
+    });
+
+    if let Err(e) = script.start("main".into()) {
+        diags.push(Diagnostic::from(e).with_note(format!(
+            "execution starts at the `main` scene of {}",
+            path.to_string_lossy()
+        )));
+        report(&diags, sources);
+    }
+
+    script
+}
+
+/// Compiles the script at `path` again and restarts it from the beginning, so that edits can be
+/// tried without restarting the game. The loader only lexes the files that have changed. If
+/// the new script has errors they are shown and the old one keeps running.
+fn reload_script(
+    loader: &mut Loader,
+    path: &Path,
+    script: &mut Vm,
+    sources: &mut SourceMap,
+    graphics: &mut GraphicsState,
+) {
+    let (program, new_sources) = try_compile_script(loader, path);
+    let mut diags = Diagnostics::new();
+    let new_script = program.and_then(|program| {
+        Vm::new(Rc::new(program)).map_err(|errs| {
+            diags.extend(errs);
+            diags
+        })
+    });
+    let mut new_script = match new_script {
+        Ok(new_script) => new_script,
+        Err(diags) => {
+            eprint!("{}", diags.render(&new_sources));
+            return;
+        }
+    };
+    if let Err(e) = new_script.start("main".into()) {
+        eprint!("{}", Diagnostic::from(e).render(&new_sources));
+        return;
+    }
+    *script = new_script;
+    *sources = new_sources;
+    advance_script(sources, script, graphics, Input::Continue);
+}
+
+/// Shows a line of dialogue or a menu in the text box. Options are picked with the number
+/// keys.
+fn show_text(graphics: &mut GraphicsState, shown: Yield) {
+    let text = match shown {
+        Yield::Dialogue(line) => Text::Dialogue {
+            name: line.name,
+            pieces: line.markup,
+        },
+        Yield::Choice(options) => Text::Choice(options),
+        Yield::Busy | Yield::Finished => return,
+    };
+    graphics.set_text(text);
+}
+
+/// Puts the images the script shows on the layers they are shown on, loading any that are new.
+/// An image that cannot be loaded is reported, and the layer is left as it was.
+fn show_layers(sources: &SourceMap, script: &Vm, graphics: &mut GraphicsState) {
+    let evictions = graphics.asset_stats().evictions;
+    for (slot, shown) in LayerSlot::ALL.into_iter().zip(&script.state().layers) {
+        let current = graphics.layer(slot);
+        match shown {
+            Value::Image(path) => {
+                if matches!(current, Some(Layer::Image(image)) if image.path() == *path) {
+                    continue;
+                }
+                match graphics.load_image(*path) {
+                    Ok(image) => graphics.set_layer(slot, Layer::Image(image)),
+                    Err(e) => eprint!(
+                        "{}",
+                        Diagnostic::error(format!("could not show an image: {}", e))
+                            .render(sources)
+                    ),
+                }
+            }
+            // Until the script shows a background, the scene is a plain colour
+            _ if slot == LayerSlot::Background => {
+                if !matches!(current, Some(Layer::SolidColour(_))) {
+                    graphics.set_layer(slot, Layer::SolidColour(Colour::HALFWHITE));
+                }
+            }
+            _ => {
+                graphics.clear_layer(slot);
+            }
+        }
+    }
+    // Images being evicted means the game shows more than fits in the video memory budget
+    let stats = graphics.asset_stats();
+    if stats.evictions != evictions {
+        eprintln!("image cache: {}", stats);
+    }
+}
+
+/// Writes the state of the script to the save beside the entry script at `path`, so it can be
+/// picked up again with [`load_game`]
+fn save_game(path: &Path, script: &Vm, sources: &SourceMap) {
+    let save_path = path.with_extension(SAVE_EXTENSION);
+    if let Err(err) = std::fs::write(&save_path, script.save()) {
+        let diag = Diagnostic::error(format!(
+            "could not write `{}`: {}",
+            save_path.to_string_lossy(),
+            err
+        ));
+        eprint!("{}", diag.render(sources));
+    }
+}
+
+/// Picks up the script from the save beside the entry script at `path`, and shows the player
+/// what they were shown when it was saved. If the save cannot be loaded the error is shown and
+/// the script keeps running.
+fn load_game(path: &Path, script: &mut Vm, sources: &SourceMap, graphics: &mut GraphicsState) {
+    let save_path = path.with_extension(SAVE_EXTENSION);
+    let restored = match std::fs::read(&save_path) {
+        Ok(bytes) => Vm::restore(script.program().clone(), &bytes).map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    match restored {
+        Ok(restored) => *script = restored,
+        Err(err) => {
+            let diag = Diagnostic::error(format!(
+                "could not load `{}`: {}",
+                save_path.to_string_lossy(),
+                err
+            ));
+            eprint!("{}", diag.render(sources));
+            return;
+        }
+    }
+    show_layers(sources, script, graphics);
+    // A script saved while busy shows its next line once it is resumed
+    if let Some(shown) = script.shown() {
+        show_text(graphics, shown);
+    }
+}
 
+/// Resumes the script with the player's input, and shows the player whatever it stops on next
+fn advance_script(
+    sources: &SourceMap,
+    script: &mut Vm,
+    graphics: &mut GraphicsState,
+    input: Input,
+) {
+    match script.resume(input) {
+        Ok(Yield::Finished) => std::process::exit(0),
+        // Resumed on the next tick, so the window keeps responding
+        Ok(Yield::Busy) => {}
+        Ok(shown) => {
+            show_layers(sources, script, graphics);
+            show_text(graphics, shown)
+        }
+        Err(e) => {
+            eprint!("{}", Diagnostic::from(e).render(sources));
+            std::process::exit(1)
+        }
+    }
+}
+
+fn main() {
     let mut args = std::env::args();
 
     let prg_name = args.next().unwrap();
 
+    let mut entry_point = PathBuf::from("main.vns");
+
     let mut backends = Backends::PRIMARY;
 
     let mut dx12_compiler = None::<Dx12Compiler>;
 
     let mut power_preference = PowerPreference::LowPower;
 
+    let mut check_only = false;
+
+    let mut disassemble = false;
+
+    let mut bundle = false;
+
+    let mut vram_budget = None::<u64>;
+
     while let Some(arg) = args.next() {
         match &*arg {
+            "--entry" => {
+                entry_point = args.next().map(PathBuf::from).unwrap_or_else(|| {
+                    eprintln!("{}: --entry requires an argument", prg_name);
+                    std::process::exit(1)
+                });
+            }
+            "--check" => check_only = true,
+            "--disassemble" => disassemble = true,
+            "--bundle" => bundle = true,
+            "--vram-budget" => {
+                let mib = args.next().and_then(|arg| arg.parse::<u64>().ok());
+                vram_budget = Some(mib.map(|mib| mib << 20).unwrap_or_else(|| {
+                    eprintln!("{}: --vram-budget requires a size in MiB", prg_name);
+                    std::process::exit(1)
+                }));
+            }
             "--enable-wpgu-secondary-backeds" => backends |= Backends::SECONDARY,
             "--wgpu-backend" => {
                 let input = args.next().unwrap_or_else(|| {
@@ -74,6 +399,32 @@ fn main() {
         }
     }
 
+    if bundle {
+        write_bundle(&entry_point);
+        std::process::exit(0)
+    }
+
+    let mut loader = script_loader(&entry_point);
+
+    // Checking always starts from source, so that warnings are shown
+    let (program, mut sources) = if check_only {
+        compile_script(&mut loader, &entry_point)
+    } else {
+        load_program(&mut loader, &entry_point)
+    };
+
+    if disassemble {
+        print!("{}", program.disassemble());
+        std::process::exit(0)
+    }
+
+    let mut script = start_script(&entry_point, program, &sources);
+
+    // The script has loaded without errors, and any warnings have been shown
+    if check_only {
+        std::process::exit(0)
+    }
+
     let dx12_shader_compiler = if let Some(dx12_compiler) = dx12_compiler {
         dx12_compiler
     } else {
@@ -127,36 +478,53 @@ fn main() {
             std::process::exit(1)
         });
 
-    let mut cur_dimensions = window.inner_size();
-
     let config = SurfaceConfiguration {
         usage: TextureUsages::all(),
-        format: TextureFormat::Bgra8Unorm,
+        format: GraphicsState::SURFACE_FORMAT,
         width: 1920,
         height: 1080,
         present_mode: wgpu::PresentMode::Fifo,
         alpha_mode: wgpu::CompositeAlphaMode::Auto,
-        view_formats: vec![TextureFormat::Bgra8Unorm],
+        view_formats: vec![GraphicsState::SURFACE_FORMAT],
     };
 
     surface.configure(&device, &config);
 
-    let mut state = GraphicsState::new(device, surface, queue, cur_dimensions.into());
+    let text_box = TextBox::load(game_root(&entry_point)).unwrap_or_else(|| {
+        eprintln!(
+            "{}: Could not find a font to show text with, add one to the game as font.ttf",
+            prg_name
+        );
+        std::process::exit(1)
+    });
+
+    let mut state = GraphicsState::new(
+        device,
+        surface,
+        queue,
+        window.inner_size().into(),
+        game_root(&entry_point).into(),
+        text_box,
+    );
+
+    if let Some(budget) = vram_budget {
+        state.set_vram_budget(budget);
+    }
 
     let periodic_proxy = eloop.create_proxy();
 
-    let hdl = std::thread::spawn(move || loop {
-        let time = Instant::now();
+    std::thread::spawn(move || loop {
         std::thread::sleep(Duration::from_millis(50));
-        let dt = time.elapsed().as_secs_f32();
-        if periodic_proxy.send_event(GameEvent::Periodic(dt)).is_err() {
+        if periodic_proxy.send_event(GameEvent::Periodic).is_err() {
             break;
         }
     });
 
     window.set_title("VN Engine");
 
-    eloop.run(move |event, targ, cf| {
+    advance_script(&sources, &mut script, &mut state, Input::Continue);
+
+    eloop.run(move |event, _, _| {
         let state = &mut state;
         match event {
             winit::event::Event::NewEvents(_) => {}
@@ -174,14 +542,62 @@ fn main() {
                 winit::event::WindowEvent::HoveredFileCancelled => {}
                 winit::event::WindowEvent::ReceivedCharacter(_) => {}
                 winit::event::WindowEvent::Focused(_) => {}
-                winit::event::WindowEvent::KeyboardInput { .. } => {}
+                winit::event::WindowEvent::KeyboardInput { input, .. } => {
+                    if let (ElementState::Pressed, Some(key)) = (input.state, input.virtual_keycode)
+                    {
+                        let choice = match key {
+                            VirtualKeyCode::Key1 => Some(0),
+                            VirtualKeyCode::Key2 => Some(1),
+                            VirtualKeyCode::Key3 => Some(2),
+                            VirtualKeyCode::Key4 => Some(3),
+                            VirtualKeyCode::Key5 => Some(4),
+                            VirtualKeyCode::Key6 => Some(5),
+                            VirtualKeyCode::Key7 => Some(6),
+                            VirtualKeyCode::Key8 => Some(7),
+                            VirtualKeyCode::Key9 => Some(8),
+                            _ => None,
+                        };
+                        match (script.awaiting_choice(), choice, key) {
+                            (_, _, VirtualKeyCode::F5) => reload_script(
+                                &mut loader,
+                                &entry_point,
+                                &mut script,
+                                &mut sources,
+                                state,
+                            ),
+                            (_, _, VirtualKeyCode::F6) => {
+                                save_game(&entry_point, &script, &sources)
+                            }
+                            (_, _, VirtualKeyCode::F9) => {
+                                load_game(&entry_point, &mut script, &sources, state)
+                            }
+                            (true, Some(idx), _) => {
+                                advance_script(&sources, &mut script, state, Input::Choose(idx))
+                            }
+                            (false, _, VirtualKeyCode::Space | VirtualKeyCode::Return) => {
+                                advance_script(&sources, &mut script, state, Input::Continue)
+                            }
+                            _ => {}
+                        }
+                    }
+                }
                 winit::event::WindowEvent::ModifiersChanged(_) => {}
                 winit::event::WindowEvent::Ime(_) => {}
                 winit::event::WindowEvent::CursorMoved { .. } => {}
                 winit::event::WindowEvent::CursorEntered { .. } => {}
                 winit::event::WindowEvent::CursorLeft { .. } => {}
                 winit::event::WindowEvent::MouseWheel { .. } => {}
-                winit::event::WindowEvent::MouseInput { .. } => {}
+                winit::event::WindowEvent::MouseInput {
+                    state: pressed,
+                    button,
+                    ..
+                } => {
+                    if let (ElementState::Pressed, MouseButton::Left) = (pressed, button) {
+                        if !script.awaiting_choice() {
+                            advance_script(&sources, &mut script, state, Input::Continue);
+                        }
+                    }
+                }
                 winit::event::WindowEvent::TouchpadMagnify { .. } => {}
                 winit::event::WindowEvent::SmartMagnify { .. } => {}
                 winit::event::WindowEvent::TouchpadRotate { .. } => {}
@@ -194,8 +610,10 @@ fn main() {
             },
             winit::event::Event::DeviceEvent { .. } => {}
             winit::event::Event::UserEvent(ge) => match ge {
-                GameEvent::ScriptNotify(_, _) => {}
-                GameEvent::Periodic(f) => {
+                GameEvent::Periodic => {
+                    if script.is_busy() {
+                        advance_script(&sources, &mut script, state, Input::Continue);
+                    }
                     window.request_redraw();
                 }
             },
@@ -204,7 +622,7 @@ fn main() {
             winit::event::Event::MainEventsCleared => {}
             winit::event::Event::RedrawRequested(_) => state
                 .render(&|r: &mut RenderState| {
-                    r.draw_solid_color(Colour::HALFWHITE)?;
+                    r.draw_frame(Colour::BLACK)?;
                     Ok(())
                 })
                 .unwrap(),
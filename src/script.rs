@@ -1,4 +1,14 @@
+pub mod bundle;
+pub mod bytecode;
+pub mod check;
+pub mod compile;
+pub mod diag;
+pub mod encode;
 pub mod lex;
+pub mod markup;
+pub mod module;
 pub mod parse;
 pub mod span;
 pub mod symbol;
+pub mod value;
+pub mod vm;
@@ -0,0 +1,672 @@
+use core::fmt;
+use std::io;
+
+use super::{
+    bytecode::{Op, Place, Program, SceneInfo, Step, Var},
+    encode::{self, Decoder, Encoder},
+    parse::{BinaryOp, UnaryOp},
+    span::{Pos, SourceMap, Span},
+    symbol::Symbol,
+    value::{op_name, Value, LAYERS},
+    vm::characters_known,
+};
+
+/// The start of every bundle
+const MAGIC: &[u8; 4] = b"VNSB";
+
+/// The version of the format written by [`Bundle::encode`]. Bundles of any other version are
+/// compiled again from source.
+pub const BUNDLE_VERSION: u32 = 4;
+
+/// The extension of a bundle, which is kept beside the entry script it was compiled from
+pub const BUNDLE_EXTENSION: &str = "vnsb";
+
+#[derive(Debug)]
+pub enum Error {
+    Decode(encode::Error),
+    NotABundle,
+    /// The bundle was written by another version of the engine
+    Version(u32),
+    /// The named file has changed since the bundle was written
+    Stale(Symbol),
+    /// The named file could not be read to check it is unchanged
+    Read(Symbol, io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Decode(err) => write!(f, "the bundle is corrupt: {}", err),
+            Self::NotABundle => f.write_str("the file is not a script bundle"),
+            Self::Version(version) => write!(
+                f,
+                "the bundle has version {}, but version {} is required",
+                version, BUNDLE_VERSION
+            ),
+            Self::Stale(name) => write!(f, "`{}` has changed since the bundle was built", name),
+            Self::Read(name, err) => write!(f, "could not read `{}`: {}", name, err),
+        }
+    }
+}
+
+impl From<encode::Error> for Error {
+    fn from(err: encode::Error) -> Self {
+        Self::Decode(err)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// A source file compiled into a bundle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceFile {
+    pub name: Symbol,
+    /// A hash of the text of the file, see [`hash_text`]
+    pub hash: u64,
+}
+
+/// A compiled script, stored so that it can be run without lexing or parsing its files again.
+///
+/// Symbols are written once, to a snapshot of their text at the start of the bundle, and referred to by
+/// their index in it. The span of every instruction is kept so runtime errors point into the
+/// source as they would had it been compiled on the spot.
+pub struct Bundle {
+    pub program: Program,
+    /// Every file the program was compiled from, by name
+    pub files: Vec<SourceFile>,
+}
+
+/// The FNV-1a hash of `text`, which unlike the hashes of `std` is the same on every platform
+/// and version
+pub fn hash_text(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl Bundle {
+    /// Bundles `program`, which was compiled from the files in `sources`
+    pub fn new(program: Program, sources: &SourceMap) -> Self {
+        let mut files = sources
+            .iter()
+            .map(|(name, text)| SourceFile {
+                name,
+                hash: hash_text(text),
+            })
+            .collect::<Vec<_>>();
+        files.sort_by_key(|file| file.name);
+        Self { program, files }
+    }
+
+    /// Checks that none of the files the bundle was compiled from have changed, adding their
+    /// text to `sources` so errors can show it. A file that no longer exists is taken to be
+    /// unchanged, so a game can be shipped with only its bundle.
+    pub fn check_fresh(&self, sources: &mut SourceMap) -> Result<()> {
+        for file in &self.files {
+            let text = match std::fs::read_to_string(&*file.name) {
+                Ok(text) => text,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(Error::Read(file.name, err)),
+            };
+            if hash_text(&text) != file.hash {
+                return Err(Error::Stale(file.name));
+            }
+            sources.add(file.name, text);
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Encoder::with_symbol_table();
+        body.len(self.files.len());
+        for file in &self.files {
+            body.symbol(file.name);
+            body.u64(file.hash);
+        }
+        encode_program(&mut body, &self.program);
+        let (body, symbols) = body.into_parts();
+
+        let mut enc = Encoder::new();
+        enc.raw(MAGIC);
+        enc.u32(BUNDLE_VERSION);
+        enc.snapshot(&symbols);
+        enc.raw(&body);
+        enc.finish()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut dec = Decoder::new(bytes);
+        if dec.raw(MAGIC.len()).ok() != Some(MAGIC) {
+            return Err(Error::NotABundle);
+        }
+        let version = dec.u32()?;
+        if version != BUNDLE_VERSION {
+            return Err(Error::Version(version));
+        }
+        let symbols = dec.snapshot()?.restore();
+
+        let mut dec = dec.with_symbols(&symbols);
+        let files = (0..dec.len()?)
+            .map(|_| {
+                Ok(SourceFile {
+                    name: dec.symbol()?,
+                    hash: dec.u64()?,
+                })
+            })
+            .collect::<encode::Result<_>>()?;
+        let program = decode_program(&mut dec)?;
+        if !dec.is_empty() {
+            return Err(encode::Error::InvalidValue("bundle").into());
+        }
+        validate(&program)?;
+        Ok(Self { program, files })
+    }
+}
+
+fn encode_program(enc: &mut Encoder, program: &Program) {
+    enc.len(program.code.len());
+    for op in &program.code {
+        encode_op(enc, *op);
+    }
+    for span in &program.spans {
+        encode_span(enc, *span);
+    }
+    enc.len(program.consts.len());
+    for val in &program.consts {
+        enc.value(val);
+    }
+    enc.len(program.places.len());
+    for place in &program.places {
+        match place.var {
+            Var::Local(slot) => {
+                enc.u8(0);
+                enc.u32(slot);
+            }
+            Var::Global(idx) => {
+                enc.u8(1);
+                enc.u32(idx);
+            }
+        }
+        enc.len(place.steps.len());
+        for step in &place.steps {
+            match *step {
+                Step::Index(span) => {
+                    enc.u8(0);
+                    encode_span(enc, span);
+                }
+                Step::Field(field, span) => {
+                    enc.u8(1);
+                    enc.symbol(field);
+                    encode_span(enc, span);
+                }
+            }
+        }
+    }
+    for scenes in [&program.scenes, &program.inits] {
+        enc.len(scenes.len());
+        for scene in scenes {
+            enc.symbol(scene.name);
+            enc.u32(scene.entry);
+            enc.u32(scene.locals);
+        }
+    }
+    for names in [&program.globals, &program.characters] {
+        enc.len(names.len());
+        for name in names {
+            enc.symbol(*name);
+        }
+    }
+}
+
+fn decode_program(dec: &mut Decoder) -> encode::Result<Program> {
+    let mut program = Program::default();
+    let code_len = dec.len()?;
+    for _ in 0..code_len {
+        program.code.push(decode_op(dec)?);
+    }
+    for _ in 0..code_len {
+        program.spans.push(decode_span(dec)?);
+    }
+    for _ in 0..dec.len()? {
+        program.consts.push(dec.value()?);
+    }
+    for _ in 0..dec.len()? {
+        let var = match dec.u8()? {
+            0 => Var::Local(dec.u32()?),
+            1 => Var::Global(dec.u32()?),
+            tag => return Err(encode::Error::InvalidTag("variable", tag)),
+        };
+        let mut steps = Vec::new();
+        for _ in 0..dec.len()? {
+            steps.push(match dec.u8()? {
+                0 => Step::Index(decode_span(dec)?),
+                1 => Step::Field(dec.symbol()?, decode_span(dec)?),
+                tag => return Err(encode::Error::InvalidTag("step", tag)),
+            });
+        }
+        program.places.push(Place { var, steps });
+    }
+    for scenes in [&mut program.scenes, &mut program.inits] {
+        for _ in 0..dec.len()? {
+            scenes.push(SceneInfo {
+                name: dec.symbol()?,
+                entry: dec.u32()?,
+                locals: dec.u32()?,
+            });
+        }
+    }
+    for names in [&mut program.globals, &mut program.characters] {
+        for _ in 0..dec.len()? {
+            names.push(dec.symbol()?);
+        }
+    }
+    Ok(program)
+}
+
+fn encode_pos(enc: &mut Encoder, pos: Pos) {
+    enc.len(pos.row);
+    enc.len(pos.col);
+    enc.len(pos.idx);
+}
+
+/// Spans never cross files, so the file is written once for both ends
+fn encode_span(enc: &mut Encoder, span: Span) {
+    enc.symbol(span.start.file);
+    encode_pos(enc, span.start);
+    encode_pos(enc, span.end);
+}
+
+fn decode_pos(dec: &mut Decoder, file: Symbol) -> encode::Result<Pos> {
+    Ok(Pos::new(dec.len()?, dec.len()?, dec.len()?, file))
+}
+
+fn decode_span(dec: &mut Decoder) -> encode::Result<Span> {
+    let file = dec.symbol()?;
+    Ok(Span::new_simple(
+        decode_pos(dec, file)?,
+        decode_pos(dec, file)?,
+    ))
+}
+
+fn encode_binary_op(enc: &mut Encoder, op: BinaryOp) {
+    enc.str(op_name(op));
+}
+
+fn decode_binary_op(dec: &mut Decoder) -> encode::Result<BinaryOp> {
+    BinaryOp::from_punct(dec.str()?).ok_or(encode::Error::InvalidValue("operator"))
+}
+
+fn encode_op(enc: &mut Encoder, op: Op) {
+    let (tag, operands): (u8, &[u32]) = match op {
+        Op::Const(idx) => (0, &[idx]),
+        Op::Pop => (1, &[]),
+        Op::LoadLocal(slot) => (2, &[slot]),
+        Op::StoreLocal(slot) => (3, &[slot]),
+        Op::LoadGlobal(idx) => (4, &[idx]),
+        Op::StoreGlobal(idx) => (5, &[idx]),
+        Op::Assign(idx) => (6, &[idx]),
+        Op::Update(idx, op) => {
+            enc.u8(7);
+            enc.u32(idx);
+            return encode_binary_op(enc, op);
+        }
+        Op::List(n) => (8, &[n]),
+        Op::Format(n) => (9, &[n]),
+        Op::Field(idx) => (10, &[idx]),
+        Op::Index => (11, &[]),
+        Op::Unary(op) => {
+            enc.u8(12);
+            return enc.u8(match op {
+                UnaryOp::Neg => 0,
+                UnaryOp::Not => 1,
+                UnaryOp::BitNot => 2,
+            });
+        }
+        Op::Binary(op) => {
+            enc.u8(13);
+            return encode_binary_op(enc, op);
+        }
+        Op::ShortCircuit(op, target) => {
+            enc.u8(14);
+            encode_binary_op(enc, op);
+            return enc.u32(target);
+        }
+        Op::CheckBool(op) => {
+            enc.u8(15);
+            return encode_binary_op(enc, op);
+        }
+        Op::Matches => (16, &[]),
+        Op::Builtin(name, n) => (17, &[name, n]),
+        Op::Jump(target) => (18, &[target]),
+        Op::JumpIfFalse(target) => (19, &[target]),
+        Op::Say(None) => (20, &[]),
+        Op::Say(Some(speaker)) => (21, &[speaker]),
+        Op::AddOption(target) => (22, &[target]),
+        Op::Choose => (23, &[]),
+        Op::Call(scene) => (24, &[scene]),
+        Op::Goto(scene) => (25, &[scene]),
+        Op::Return => (26, &[]),
+        Op::DefineCharacter(idx) => (27, &[idx]),
+        Op::Show(layer) => (28, &[layer]),
+        Op::Hide(layer) => (29, &[layer]),
+        Op::Escape => (30, &[]),
+    };
+    enc.u8(tag);
+    for operand in operands {
+        enc.u32(*operand);
+    }
+}
+
+fn decode_op(dec: &mut Decoder) -> encode::Result<Op> {
+    Ok(match dec.u8()? {
+        0 => Op::Const(dec.u32()?),
+        1 => Op::Pop,
+        2 => Op::LoadLocal(dec.u32()?),
+        3 => Op::StoreLocal(dec.u32()?),
+        4 => Op::LoadGlobal(dec.u32()?),
+        5 => Op::StoreGlobal(dec.u32()?),
+        6 => Op::Assign(dec.u32()?),
+        7 => Op::Update(dec.u32()?, decode_binary_op(dec)?),
+        8 => Op::List(dec.u32()?),
+        9 => Op::Format(dec.u32()?),
+        10 => Op::Field(dec.u32()?),
+        11 => Op::Index,
+        12 => Op::Unary(match dec.u8()? {
+            0 => UnaryOp::Neg,
+            1 => UnaryOp::Not,
+            2 => UnaryOp::BitNot,
+            tag => return Err(encode::Error::InvalidTag("unary operator", tag)),
+        }),
+        13 => Op::Binary(decode_binary_op(dec)?),
+        14 => Op::ShortCircuit(decode_binary_op(dec)?, dec.u32()?),
+        15 => Op::CheckBool(decode_binary_op(dec)?),
+        16 => Op::Matches,
+        17 => Op::Builtin(dec.u32()?, dec.u32()?),
+        18 => Op::Jump(dec.u32()?),
+        19 => Op::JumpIfFalse(dec.u32()?),
+        20 => Op::Say(None),
+        21 => Op::Say(Some(dec.u32()?)),
+        22 => Op::AddOption(dec.u32()?),
+        23 => Op::Choose,
+        24 => Op::Call(dec.u32()?),
+        25 => Op::Goto(dec.u32()?),
+        26 => Op::Return,
+        27 => Op::DefineCharacter(dec.u32()?),
+        28 => Op::Show(dec.u32()?),
+        29 => Op::Hide(dec.u32()?),
+        30 => Op::Escape,
+        tag => return Err(encode::Error::InvalidTag("instruction", tag)),
+    })
+}
+
+/// Checks every operand refers to something in the program, and that the code of each scene
+/// keeps to its locals and never pops more than it pushed, so a corrupt bundle is reported
+/// rather than crashing the engine when it is run
+fn validate(program: &Program) -> encode::Result<()> {
+    let invalid = |what| Err(encode::Error::InvalidValue(what));
+    let code_len = program.code.len() as u32;
+    let symbol_const =
+        |idx: u32| matches!(program.consts.get(idx as usize), Some(Value::Symbol(_)));
+    for op in &program.code {
+        let ok = match *op {
+            Op::Const(idx) => (idx as usize) < program.consts.len(),
+            Op::LoadGlobal(idx) | Op::StoreGlobal(idx) => (idx as usize) < program.globals.len(),
+            Op::Assign(idx) | Op::Update(idx, _) => (idx as usize) < program.places.len(),
+            Op::Field(idx) | Op::Builtin(idx, _) | Op::Say(Some(idx)) => symbol_const(idx),
+            Op::ShortCircuit(_, target)
+            | Op::Jump(target)
+            | Op::JumpIfFalse(target)
+            | Op::AddOption(target) => target < code_len,
+            Op::Call(scene) | Op::Goto(scene) => (scene as usize) < program.scenes.len(),
+            Op::DefineCharacter(idx) => (idx as usize) < program.characters.len(),
+            Op::Show(layer) | Op::Hide(layer) => (layer as usize) < LAYERS.len(),
+            _ => true,
+        };
+        if !ok {
+            return invalid("instruction");
+        }
+    }
+    let places_ok = program.places.iter().all(|place| match place.var {
+        Var::Global(idx) => (idx as usize) < program.globals.len(),
+        Var::Local(_) => true,
+    });
+    if !places_ok {
+        return invalid("place");
+    }
+    let scenes_ok = program
+        .scenes
+        .iter()
+        .chain(&program.inits)
+        .all(|scene| scene.entry < code_len);
+    if !scenes_ok {
+        return invalid("scene");
+    }
+    let characters = program.character_indices();
+    if !program
+        .consts
+        .iter()
+        .all(|val| characters_known(val, &characters))
+    {
+        return invalid("constant");
+    }
+    let mut entries = program
+        .scenes
+        .iter()
+        .chain(&program.inits)
+        .map(|scene| scene.entry)
+        .collect::<Vec<_>>();
+    entries.sort_unstable();
+    for scene in program.scenes.iter().chain(&program.inits) {
+        // A scene's code runs up to the start of the next one
+        let end = entries
+            .iter()
+            .copied()
+            .find(|&entry| entry > scene.entry)
+            .unwrap_or(code_len);
+        if !validate_scene(program, scene, end) {
+            return invalid("code");
+        }
+    }
+    Ok(())
+}
+
+/// Follows every path through the code of `scene`, which ends before `end`, checking the
+/// stack holds the same number of values each time an instruction is reached
+fn validate_scene(program: &Program, scene: &SceneInfo, end: u32) -> bool {
+    let code = scene.entry..end;
+    // The number of values above the scene's locals when each instruction is reached
+    let mut depths = vec![None; code.len()];
+    let mut pending = vec![(scene.entry, 0u32)];
+    while let Some((pc, depth)) = pending.pop() {
+        if !code.contains(&pc) {
+            return false;
+        }
+        let seen = &mut depths[(pc - scene.entry) as usize];
+        match *seen {
+            Some(seen) if seen == depth => continue,
+            Some(_) => return false,
+            None => *seen = Some(depth),
+        }
+        let op = program.code[pc as usize];
+        let local_ok = |slot| slot < scene.locals;
+        let (pops, pushes) = match op {
+            Op::Const(_) | Op::LoadGlobal(_) => (0, 1),
+            Op::LoadLocal(slot) if local_ok(slot) => (0, 1),
+            Op::StoreLocal(slot) if local_ok(slot) => (1, 0),
+            Op::LoadLocal(_) | Op::StoreLocal(_) => return false,
+            Op::Assign(idx) | Op::Update(idx, _) => {
+                let place = &program.places[idx as usize];
+                match place.var {
+                    Var::Local(slot) if !local_ok(slot) => return false,
+                    _ => (place.index_count() + 1, 0),
+                }
+            }
+            Op::List(n) | Op::Format(n) | Op::Builtin(_, n) => (n, 1),
+            Op::Field(_) | Op::Unary(_) | Op::CheckBool(_) | Op::Escape => (1, 1),
+            Op::Index | Op::Binary(_) | Op::Matches => (2, 1),
+            Op::Say(Some(_)) => (2, 0),
+            Op::Pop
+            | Op::StoreGlobal(_)
+            | Op::ShortCircuit(..)
+            | Op::JumpIfFalse(_)
+            | Op::Say(None)
+            | Op::AddOption(_)
+            | Op::DefineCharacter(_)
+            | Op::Show(_) => (1, 0),
+            Op::Jump(_) | Op::Choose | Op::Call(_) | Op::Goto(_) | Op::Return | Op::Hide(_) => {
+                (0, 0)
+            }
+        };
+        let Some(after) = depth.checked_sub(pops).map(|rest| rest + pushes) else {
+            return false;
+        };
+        match op {
+            // The left-hand side is left on the stack when it decides the result
+            Op::ShortCircuit(_, target) => pending.extend([(target, depth), (pc + 1, after)]),
+            Op::Jump(target) => pending.push((target, after)),
+            // An option's arm starts from where the choice was offered, which is where its text
+            // was popped
+            Op::JumpIfFalse(target) | Op::AddOption(target) => {
+                pending.extend([(target, after), (pc + 1, after)])
+            }
+            Op::Goto(_) | Op::Return => {}
+            _ => pending.push((pc + 1, after)),
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::{compile, lex, parse};
+
+    const SRC: &str = r#"
+        character alice = "Alice";
+        let seen = map();
+        scene main {
+            let n = 0;
+            while n < 3 && !seen.done {
+                n += 1;
+                if n == 2 { continue; }
+                alice "Round {n}."
+            }
+            match n {
+                1 | 2 => "Few.",
+                k if k > 2 || false => { seen.done = true; }
+                _ => {}
+            }
+            choice {
+                "Again" if !seen.done => jump main,
+                "On" => call end,
+            }
+            show background image("bg.png");
+            hide background;
+        }
+        scene end {
+            let xs = [1, [2, 3]];
+            xs[1][0] *= 2;
+            "Done, {len(xs)} {xs}."
+        }
+    "#;
+
+    fn compile_src(src: &str, sources: &mut SourceMap) -> Program {
+        sources.add("test", src);
+        let mut lexemes = lex::lex(&mut src.chars(), "test").unwrap();
+        lex::filter_comments(&mut lexemes);
+        let items = parse::do_file(&mut parse::TokenStream::new(lexemes)).unwrap();
+        compile::compile(&items).unwrap()
+    }
+
+    fn bundle(src: &str) -> Bundle {
+        let mut sources = SourceMap::new();
+        let program = compile_src(src, &mut sources);
+        Bundle::new(program, &sources)
+    }
+
+    #[test]
+    fn round_trip() {
+        let bundle = bundle(SRC);
+        let decoded = Bundle::decode(&bundle.encode()).unwrap();
+        let (program, decoded_program) = (&bundle.program, &decoded.program);
+        assert_eq!(decoded.files, bundle.files);
+        assert_eq!(decoded_program.code, program.code);
+        assert_eq!(decoded_program.spans, program.spans);
+        assert_eq!(decoded_program.consts, program.consts);
+        assert_eq!(decoded_program.scenes, program.scenes);
+        assert_eq!(decoded_program.inits, program.inits);
+        assert_eq!(decoded_program.globals, program.globals);
+        assert_eq!(decoded_program.characters, program.characters);
+        assert_eq!(decoded_program.disassemble(), program.disassemble());
+        assert_eq!(decoded_program.fingerprint(), program.fingerprint());
+    }
+
+    #[test]
+    fn other_versions_and_files_are_refused() {
+        let mut bytes = bundle(SRC).encode();
+        bytes[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&2u32.to_le_bytes());
+        assert!(matches!(Bundle::decode(&bytes), Err(Error::Version(2))));
+        assert!(matches!(
+            Bundle::decode(b"not a bundle"),
+            Err(Error::NotABundle)
+        ));
+    }
+
+    #[test]
+    fn truncated_bundles_are_refused() {
+        let bytes = bundle(SRC).encode();
+        for len in 0..bytes.len() {
+            assert!(Bundle::decode(&bytes[..len]).is_err(), "{} bytes", len);
+        }
+        let mut longer = bytes;
+        longer.push(0);
+        assert!(Bundle::decode(&longer).is_err());
+    }
+
+    #[test]
+    fn edited_sources_make_the_bundle_stale() {
+        let path =
+            std::env::temp_dir().join(format!("vn-engine-bundle-{}.vns", std::process::id()));
+        let name = Symbol::from(path.to_str().unwrap());
+        std::fs::write(&path, "scene main {}").unwrap();
+        let mut sources = SourceMap::new();
+        sources.add(name, "scene main {}");
+        let bundle = Bundle::new(Program::default(), &sources);
+
+        assert!(bundle.check_fresh(&mut SourceMap::new()).is_ok());
+        std::fs::write(&path, "scene main { \"Hi.\" }").unwrap();
+        let stale = bundle.check_fresh(&mut SourceMap::new());
+        assert!(matches!(stale, Err(Error::Stale(file)) if file == name));
+        // A game may be shipped without its scripts
+        std::fs::remove_file(&path).unwrap();
+        assert!(bundle.check_fresh(&mut SourceMap::new()).is_ok());
+    }
+
+    #[test]
+    fn corrupt_code_is_refused() {
+        let program = bundle(SRC).program;
+        assert!(validate(&program).is_ok());
+        let corrupt = |edit: &dyn Fn(&mut Program)| {
+            let mut program = program.clone();
+            edit(&mut program);
+            validate(&program).is_err()
+        };
+        let find = |op: fn(&Op) -> bool| program.code.iter().position(op).unwrap();
+
+        let load = find(|op| matches!(op, Op::LoadLocal(_)));
+        assert!(corrupt(&|program| program.code[load] = Op::LoadLocal(99)));
+        let list = find(|op| matches!(op, Op::List(_)));
+        assert!(corrupt(&|program| program.code[list] = Op::List(99)));
+        let say = find(|op| matches!(op, Op::Say(None)));
+        assert!(corrupt(&|program| program.code[say - 1] = Op::Pop));
+        // A jump to another scene would run it with the wrong locals
+        let jump = find(|op| matches!(op, Op::Jump(_)));
+        let end = program.scenes[program.scene("end".into()).unwrap() as usize].entry;
+        assert!(corrupt(&|program| program.code[jump] = Op::Jump(end)));
+        let ret = program.code.len() - 1;
+        assert!(corrupt(&|program| program.code[ret] = Op::Pop));
+        assert!(corrupt(&|program| program
+            .consts
+            .push(Value::Character("bob".into()))));
+    }
+}
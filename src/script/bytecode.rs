@@ -0,0 +1,252 @@
+use core::fmt::{self, Write};
+use core::hash::{Hash, Hasher};
+
+use fxhash::{FxHashMap, FxHasher};
+
+use super::{
+    parse::{BinaryOp, UnaryOp},
+    span::Span,
+    symbol::Symbol,
+    value::{op_name, Value, LAYERS},
+};
+
+/// One instruction of the stack machine. Operands index the tables of the [`Program`] or are
+/// addresses in its code.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Op {
+    /// Pushes a constant
+    Const(u32),
+    Pop,
+    LoadLocal(u32),
+    StoreLocal(u32),
+    LoadGlobal(u32),
+    StoreGlobal(u32),
+    /// Pops the index values of a place, innermost first, then the value to store in it
+    Assign(u32),
+    /// Like [`Op::Assign`], but combines the value with the one already in the place
+    Update(u32, BinaryOp),
+    /// Pushes a list of the top `n` values
+    List(u32),
+    /// Pushes the top `n` values written one after another as a string
+    Format(u32),
+    /// Replaces a value with its text escaped, so it is not read as markup
+    Escape,
+    /// Replaces a value with its field named by a constant
+    Field(u32),
+    /// Pops an index, then replaces the value it indexes
+    Index,
+    Unary(UnaryOp),
+    Binary(BinaryOp),
+    /// The left-hand side of `&&` or `||`. If it decides the result it is left as the result,
+    /// and execution continues at the address. Otherwise it is popped.
+    ShortCircuit(BinaryOp, u32),
+    /// Checks the right-hand side of `&&` or `||` is a `bool`
+    CheckBool(BinaryOp),
+    /// Pops a value and a pattern, pushing whether they are equal
+    Matches,
+    /// Calls the builtin named by a constant with the top `n` values
+    Builtin(u32, u32),
+    Jump(u32),
+    /// Pops a value, jumping to the address if it is falsy
+    JumpIfFalse(u32),
+    /// Pops the text of a line of dialogue, then its speaker if the constant naming the speaker
+    /// is given, and shows it to the player
+    Say(Option<u32>),
+    /// Pops the text of an option of the next [`Op::Choose`], which continues at the address
+    AddOption(u32),
+    /// Offers the options added since the last choice to the player, continuing at the next
+    /// instruction if there are none
+    Choose,
+    /// Starts the scene, returning to the next instruction when it finishes
+    Call(u32),
+    /// Starts the scene in place of the current one
+    Goto(u32),
+    Return,
+    /// Pops the display name of the character
+    DefineCharacter(u32),
+    /// Pops an image and puts it on the layer, an index into [`LAYERS`]
+    Show(u32),
+    /// Clears the layer, an index into [`LAYERS`]
+    Hide(u32),
+}
+
+/// One step from a variable to the part of it that is assigned to
+#[derive(Clone, Copy, Debug)]
+pub enum Step {
+    /// The index is taken from the stack
+    Index(Span),
+    Field(Symbol, Span),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Var {
+    Local(u32),
+    Global(u32),
+}
+
+/// Something that can be assigned to, other than a plain variable
+#[derive(Clone, Debug)]
+pub struct Place {
+    pub var: Var,
+    /// Innermost first
+    pub steps: Vec<Step>,
+}
+
+impl Place {
+    /// The number of index values taken from the stack
+    pub fn index_count(&self) -> u32 {
+        self.steps
+            .iter()
+            .filter(|step| matches!(step, Step::Index(_)))
+            .count() as u32
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneInfo {
+    pub name: Symbol,
+    pub entry: u32,
+    /// The number of local variable slots
+    pub locals: u32,
+}
+
+/// A compiled script
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    pub code: Vec<Op>,
+    /// The span of the source of each instruction, for errors
+    pub spans: Vec<Span>,
+    pub consts: Vec<Value>,
+    pub places: Vec<Place>,
+    pub scenes: Vec<SceneInfo>,
+    pub globals: Vec<Symbol>,
+    pub characters: Vec<Symbol>,
+    /// Code that defines each character and global, in order
+    pub inits: Vec<SceneInfo>,
+}
+
+impl Program {
+    pub fn scene(&self, name: Symbol) -> Option<u32> {
+        self.scenes
+            .iter()
+            .position(|scene| scene.name == name)
+            .map(|idx| idx as u32)
+    }
+
+    /// The index of every character by its name
+    pub fn character_indices(&self) -> FxHashMap<Symbol, u32> {
+        self.characters
+            .iter()
+            .enumerate()
+            .map(|(idx, &name)| (name, idx as u32))
+            .collect()
+    }
+
+    /// A hash of the parts of the program that execution state refers to, so state saved with
+    /// one program is not restored into another
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = FxHasher::default();
+        self.code.hash(&mut hasher);
+        for scene in self.scenes.iter().chain(&self.inits) {
+            scene.name.as_str().hash(&mut hasher);
+            (scene.entry, scene.locals).hash(&mut hasher);
+        }
+        for name in self.globals.iter().chain(&self.characters) {
+            name.as_str().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Writes the code of the program as text, with each scene labelled and constants shown
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        self.write_disassembly(&mut out).unwrap();
+        out
+    }
+
+    fn write_disassembly(&self, out: &mut String) -> fmt::Result {
+        let mut labels = FxHashMap::default();
+        for (idx, init) in self.inits.iter().enumerate() {
+            labels.insert(init.entry, format!("init {} ({} locals)", idx, init.locals));
+        }
+        for scene in &self.scenes {
+            labels.insert(
+                scene.entry,
+                format!("scene {} ({} locals)", scene.name, scene.locals),
+            );
+        }
+        for (pc, op) in self.code.iter().enumerate() {
+            if let Some(label) = labels.get(&(pc as u32)) {
+                if pc != 0 {
+                    writeln!(out)?;
+                }
+                writeln!(out, "{}:", label)?;
+            }
+            write!(out, "  {:04}  ", pc)?;
+            self.write_op(out, *op)?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    fn write_op(&self, out: &mut String, op: Op) -> fmt::Result {
+        let konst = |idx: u32| match &self.consts[idx as usize] {
+            Value::String(s) => format!("{:?}", s),
+            val => val.to_string(),
+        };
+        match op {
+            Op::Const(idx) => write!(out, "const {}", konst(idx)),
+            Op::Pop => write!(out, "pop"),
+            Op::LoadLocal(slot) => write!(out, "load.local {}", slot),
+            Op::StoreLocal(slot) => write!(out, "store.local {}", slot),
+            Op::LoadGlobal(idx) => write!(out, "load.global {}", self.globals[idx as usize]),
+            Op::StoreGlobal(idx) => write!(out, "store.global {}", self.globals[idx as usize]),
+            Op::Assign(idx) => write!(out, "assign {}", self.place_name(idx)),
+            Op::Update(idx, op) => {
+                write!(out, "update {} {}", self.place_name(idx), op_name(op))
+            }
+            Op::List(n) => write!(out, "list {}", n),
+            Op::Format(n) => write!(out, "format {}", n),
+            Op::Escape => write!(out, "escape"),
+            Op::Field(idx) => write!(out, "field {}", konst(idx)),
+            Op::Index => write!(out, "index"),
+            Op::Unary(op) => write!(out, "unary {:?}", op),
+            Op::Binary(op) => write!(out, "binary {}", op_name(op)),
+            Op::ShortCircuit(op, target) => {
+                write!(out, "short {} -> {:04}", op_name(op), target)
+            }
+            Op::CheckBool(op) => write!(out, "check.bool {}", op_name(op)),
+            Op::Matches => write!(out, "matches"),
+            Op::Builtin(name, n) => write!(out, "builtin {} {}", konst(name), n),
+            Op::Jump(target) => write!(out, "jump -> {:04}", target),
+            Op::JumpIfFalse(target) => write!(out, "jump.false -> {:04}", target),
+            Op::Say(Some(speaker)) => write!(out, "say {}", konst(speaker)),
+            Op::Say(None) => write!(out, "say"),
+            Op::AddOption(target) => write!(out, "option -> {:04}", target),
+            Op::Choose => write!(out, "choose"),
+            Op::Call(scene) => write!(out, "call {}", self.scenes[scene as usize].name),
+            Op::Goto(scene) => write!(out, "goto {}", self.scenes[scene as usize].name),
+            Op::Return => write!(out, "return"),
+            Op::DefineCharacter(idx) => {
+                write!(out, "character {}", self.characters[idx as usize])
+            }
+            Op::Show(layer) => write!(out, "show {}", LAYERS[layer as usize]),
+            Op::Hide(layer) => write!(out, "hide {}", LAYERS[layer as usize]),
+        }
+    }
+
+    fn place_name(&self, idx: u32) -> String {
+        let place = &self.places[idx as usize];
+        let mut name = match place.var {
+            Var::Local(slot) => format!("local {}", slot),
+            Var::Global(idx) => format!("global {}", self.globals[idx as usize]),
+        };
+        for step in &place.steps {
+            match step {
+                Step::Index(_) => name += "[_]",
+                Step::Field(field, _) => write!(name, ".{}", field).unwrap(),
+            }
+        }
+        name
+    }
+}
@@ -0,0 +1,535 @@
+use core::fmt;
+use std::collections::VecDeque;
+
+use fxhash::{FxHashMap, FxHashSet};
+
+use super::{
+    diag::{Diagnostic, Level},
+    parse::{BinaryOp, Block, Expr, Item, Pattern, Spanned, Stmt},
+    span::Span,
+    symbol::Symbol,
+    value::BUILTINS,
+};
+
+/// A problem found in a script without running it
+#[derive(Debug)]
+pub enum Problem {
+    UndefinedName(Symbol, Span),
+    UndefinedScene(Symbol, Span),
+    /// The second span is the previous definition
+    DuplicateDefinition(Symbol, Span, Box<Span>),
+    /// No `jump` or `call` from the entry scene leads to the scene
+    UnreachableScene(Symbol, Span),
+    /// A variable, or a binding of a `match` pattern, that is never read
+    UnusedVariable(Symbol, Span),
+}
+
+impl Problem {
+    pub fn level(&self) -> Level {
+        match self {
+            Self::UndefinedName(..) | Self::UndefinedScene(..) | Self::DuplicateDefinition(..) => {
+                Level::Error
+            }
+            Self::UnreachableScene(..) | Self::UnusedVariable(..) => Level::Warning,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UndefinedName(name, _) => write!(f, "cannot find `{}` in this scope", name),
+            Self::UndefinedScene(name, _) => write!(f, "no scene named `{}`", name),
+            Self::DuplicateDefinition(name, _, _) => {
+                write!(f, "`{}` is defined multiple times", name)
+            }
+            Self::UnreachableScene(name, _) => write!(f, "scene `{}` is never reached", name),
+            Self::UnusedVariable(name, _) => write!(f, "unused variable `{}`", name),
+        }
+    }
+}
+
+impl From<Problem> for Diagnostic {
+    fn from(problem: Problem) -> Self {
+        let diag = Diagnostic::new(problem.level(), problem.to_string());
+        match problem {
+            Problem::UndefinedName(_, span) | Problem::UndefinedScene(_, span) => {
+                diag.with_label(span, "")
+            }
+            Problem::DuplicateDefinition(_, span, prev) => diag
+                .with_label(span, "")
+                .with_secondary(*prev, "previously defined here"),
+            Problem::UnreachableScene(_, span) => diag
+                .with_label(span, "")
+                .with_note("no `jump` or `call` leads here from the entry scene"),
+            Problem::UnusedVariable(name, span) => diag.with_label(span, "").with_note(format!(
+                "if this is intentional, prefix it with an underscore: `_{}`",
+                name
+            )),
+        }
+    }
+}
+
+/// A variable in scope while checking
+struct Local {
+    span: Span,
+    used: bool,
+}
+
+/// State shared by the whole of a [`check`]
+struct CheckState {
+    scenes: FxHashMap<Symbol, Span>,
+    characters: FxHashMap<Symbol, Span>,
+    globals: FxHashMap<Symbol, Local>,
+    /// The scene being checked
+    scene: Symbol,
+    /// The scopes of the scene being checked, innermost last
+    scopes: Vec<FxHashMap<Symbol, Local>>,
+    /// The scenes named by a `jump` or `call` in each scene
+    edges: FxHashMap<Symbol, Vec<Symbol>>,
+    problems: Vec<Problem>,
+}
+
+impl CheckState {
+    /// Marks the variable `name` as read, returning whether it is defined
+    fn use_var(&mut self, name: Symbol) -> bool {
+        let local = self
+            .scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(&name))
+            .or_else(|| self.globals.get_mut(&name));
+        match local {
+            Some(local) => {
+                local.used = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn is_var(&self, name: Symbol) -> bool {
+        self.scopes.iter().any(|scope| scope.contains_key(&name))
+            || self.globals.contains_key(&name)
+    }
+
+    /// Declares a variable in the innermost scope, reporting the one it replaces if that was
+    /// never read
+    fn declare(&mut self, name: Spanned<Symbol>) {
+        let local = Local {
+            span: name.span,
+            used: false,
+        };
+        let scope = self.scopes.last_mut().unwrap();
+        if let Some(prev) = scope.insert(name.body, local) {
+            unused(&mut self.problems, name.body, prev);
+        }
+    }
+
+    fn push_scope(&mut self) {
+        self.scopes.push(FxHashMap::default());
+    }
+
+    fn pop_scope(&mut self) {
+        let scope = self.scopes.pop().unwrap();
+        for (name, local) in sorted(scope) {
+            unused(&mut self.problems, name, local);
+        }
+    }
+}
+
+fn unused(problems: &mut Vec<Problem>, name: Symbol, local: Local) {
+    if !local.used && !name.as_str().starts_with('_') {
+        problems.push(Problem::UnusedVariable(name, local.span));
+    }
+}
+
+/// The variables of a scope in the order they were declared, so problems are reported in order
+fn sorted(scope: FxHashMap<Symbol, Local>) -> Vec<(Symbol, Local)> {
+    let mut vars = scope.into_iter().collect::<Vec<_>>();
+    vars.sort_by_key(|(_, local)| (local.span.start.row, local.span.start.col));
+    vars
+}
+
+fn check_expr(state: &mut CheckState, expr: &Spanned<Expr>) {
+    match &expr.body {
+        Expr::Literal(_) | Expr::Markup(_) => {}
+        Expr::Ident(name) => {
+            if !state.use_var(*name) && !state.characters.contains_key(name) {
+                state
+                    .problems
+                    .push(Problem::UndefinedName(*name, expr.span));
+            }
+        }
+        Expr::Path(segments) => {
+            let name = segments
+                .iter()
+                .map(|seg| seg.as_str())
+                .collect::<Vec<_>>()
+                .join("::");
+            state
+                .problems
+                .push(Problem::UndefinedName(name.into(), expr.span));
+        }
+        Expr::List(elems) | Expr::Format(elems) => {
+            for elem in elems {
+                check_expr(state, elem);
+            }
+        }
+        Expr::Call(callee, args) => {
+            match &callee.body {
+                Expr::Ident(name) if BUILTINS.contains(&name.as_str()) => {}
+                _ => check_expr(state, callee),
+            }
+            for arg in args {
+                check_expr(state, arg);
+            }
+        }
+        Expr::Field(base, _) => check_expr(state, base),
+        Expr::Index(base, idx) => {
+            check_expr(state, base);
+            check_expr(state, idx);
+        }
+        Expr::Unary(_, operand) => check_expr(state, operand),
+        Expr::Binary(op, lhs, rhs) => {
+            check_expr(state, rhs);
+            match &lhs.body {
+                // Assigning to a variable does not read it
+                Expr::Ident(name) if op.body == BinaryOp::Assign => {
+                    if !state.is_var(*name) {
+                        state.problems.push(Problem::UndefinedName(*name, lhs.span));
+                    }
+                }
+                _ => check_expr(state, lhs),
+            }
+        }
+    }
+}
+
+fn pattern_bindings(pattern: &Spanned<Pattern>, bindings: &mut Vec<Spanned<Symbol>>) {
+    match &pattern.body {
+        Pattern::Wildcard | Pattern::Literal(_) => {}
+        // Each alternative of an or pattern can bind the same name
+        Pattern::Binding(name) if bindings.iter().any(|binding| binding.body == *name) => {}
+        Pattern::Binding(name) => bindings.push(Spanned::new(*name, pattern.span)),
+        Pattern::Or(alts) => {
+            for alt in alts {
+                pattern_bindings(alt, bindings);
+            }
+        }
+    }
+}
+
+/// Checks a block in a new scope, which starts with `bindings` and `guard` is checked in
+fn check_block(
+    state: &mut CheckState,
+    block: &Block,
+    bindings: Vec<Spanned<Symbol>>,
+    guard: Option<&Spanned<Expr>>,
+) {
+    state.push_scope();
+    for binding in bindings {
+        state.declare(binding);
+    }
+    if let Some(guard) = guard {
+        check_expr(state, guard);
+    }
+    for stmt in block.stmts.iter() {
+        check_stmt(state, stmt);
+    }
+    state.pop_scope();
+}
+
+fn check_target(state: &mut CheckState, scene: Symbol, target: &Spanned<Vec<Spanned<Symbol>>>) {
+    let name = match &target.body[..] {
+        [name] => name.body,
+        segments => segments
+            .iter()
+            .map(|seg| seg.as_str())
+            .collect::<Vec<_>>()
+            .join("::")
+            .into(),
+    };
+    if state.scenes.contains_key(&name) {
+        state.edges.entry(scene).or_default().push(name);
+    } else {
+        state
+            .problems
+            .push(Problem::UndefinedScene(name, target.span));
+    }
+}
+
+fn check_stmt(state: &mut CheckState, stmt: &Spanned<Stmt>) {
+    let scene = state.scene;
+    match &stmt.body {
+        Stmt::Empty | Stmt::Break | Stmt::Continue | Stmt::Return | Stmt::Hide(_) => {}
+        Stmt::Expr(expr) | Stmt::Show(_, expr) => check_expr(state, expr),
+        Stmt::Let(name, init) => {
+            if let Some(init) = init {
+                check_expr(state, init);
+            }
+            state.declare(*name);
+        }
+        Stmt::Dialogue(speaker, text) => {
+            if let Some(speaker) = speaker {
+                if !state.use_var(speaker.body) && !state.characters.contains_key(&speaker.body) {
+                    state
+                        .problems
+                        .push(Problem::UndefinedName(speaker.body, speaker.span));
+                }
+            }
+            check_expr(state, text);
+        }
+        Stmt::Block(block) | Stmt::Loop(block) => check_block(state, block, Vec::new(), None),
+        Stmt::If(cond, then, else_branch) => {
+            check_expr(state, cond);
+            check_block(state, then, Vec::new(), None);
+            if let Some(else_branch) = else_branch {
+                check_stmt(state, else_branch);
+            }
+        }
+        Stmt::Match(scrutinee, arms) => {
+            check_expr(state, scrutinee);
+            for arm in arms {
+                let mut bindings = Vec::new();
+                pattern_bindings(&arm.pattern, &mut bindings);
+                check_block(state, &arm.body, bindings, arm.guard.as_ref());
+            }
+        }
+        Stmt::Choice(arms) => {
+            for arm in arms {
+                check_expr(state, &arm.text);
+                if let Some(guard) = &arm.guard {
+                    check_expr(state, guard);
+                }
+                check_block(state, &arm.body, Vec::new(), None);
+            }
+        }
+        Stmt::While(cond, body) => {
+            check_expr(state, cond);
+            check_block(state, body, Vec::new(), None);
+        }
+        Stmt::Jump(target) | Stmt::Call(target) => check_target(state, scene, target),
+    }
+}
+
+/// Finds the problems in a script before it is run, reporting every one. Execution is expected
+/// to start at the scene `entry`.
+pub fn check(items: &[Spanned<Item>], entry: Symbol) -> Vec<Problem> {
+    let mut state = CheckState {
+        scenes: FxHashMap::default(),
+        characters: FxHashMap::default(),
+        globals: FxHashMap::default(),
+        scene: Symbol::empty(),
+        scopes: Vec::new(),
+        edges: FxHashMap::default(),
+        problems: Vec::new(),
+    };
+
+    for item in items {
+        if let Item::Scene(scene) = &item.body {
+            let name = scene.name;
+            if let Some(prev) = state.scenes.get(&name.body) {
+                state.problems.push(Problem::DuplicateDefinition(
+                    name.body,
+                    name.span,
+                    Box::new(*prev),
+                ));
+            } else {
+                state.scenes.insert(name.body, name.span);
+            }
+        }
+    }
+
+    // Items are defined in order, so their initializers only see the items before them
+    for item in items {
+        match &item.body {
+            Item::Character(name, display) => {
+                check_expr(&mut state, display);
+                if let Some(prev) = state.characters.get(&name.body) {
+                    state.problems.push(Problem::DuplicateDefinition(
+                        name.body,
+                        name.span,
+                        Box::new(*prev),
+                    ));
+                } else {
+                    state.characters.insert(name.body, name.span);
+                }
+            }
+            Item::Let(name, init) => {
+                if let Some(init) = init {
+                    check_expr(&mut state, init);
+                }
+                if let Some(prev) = state.globals.get(&name.body) {
+                    state.problems.push(Problem::DuplicateDefinition(
+                        name.body,
+                        name.span,
+                        Box::new(prev.span),
+                    ));
+                } else {
+                    let local = Local {
+                        span: name.span,
+                        used: false,
+                    };
+                    state.globals.insert(name.body, local);
+                }
+            }
+            Item::Scene(_) | Item::Import(_) | Item::Use(_) => {}
+        }
+    }
+
+    for item in items {
+        if let Item::Scene(scene) = &item.body {
+            state.scene = scene.name.body;
+            check_block(&mut state, &scene.body, Vec::new(), None);
+        }
+    }
+
+    for (name, global) in sorted(core::mem::take(&mut state.globals)) {
+        unused(&mut state.problems, name, global);
+    }
+
+    if state.scenes.contains_key(&entry) {
+        let mut reached = FxHashSet::default();
+        let mut queue = VecDeque::from([entry]);
+        while let Some(scene) = queue.pop_front() {
+            if reached.insert(scene) {
+                queue.extend(state.edges.get(&scene).into_iter().flatten().copied());
+            }
+        }
+        let mut unreached = state
+            .scenes
+            .iter()
+            .filter(|(name, _)| !reached.contains(*name))
+            .map(|(&name, &span)| (name, span))
+            .collect::<Vec<_>>();
+        unreached.sort_by_key(|(_, span)| (span.start.file, span.start.row));
+        let unreached = unreached
+            .into_iter()
+            .map(|(name, span)| Problem::UnreachableScene(name, span));
+        state.problems.extend(unreached);
+    }
+
+    state.problems
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::{lex, parse};
+
+    /// Each problem found in `src`, with the line and column it is reported at
+    fn problems(src: &str) -> Vec<String> {
+        let mut lexemes =
+            lex::lex(&mut src.chars(), "test").unwrap_or_else(|errs| panic!("{:?}", errs));
+        lex::filter_comments(&mut lexemes);
+        let items = parse::do_file(&mut parse::TokenStream::new(lexemes))
+            .unwrap_or_else(|errs| panic!("{:?}", errs));
+        check(&items, "main".into())
+            .into_iter()
+            .map(|problem| {
+                let span = match &problem {
+                    Problem::UndefinedName(_, span)
+                    | Problem::UndefinedScene(_, span)
+                    | Problem::DuplicateDefinition(_, span, _)
+                    | Problem::UnreachableScene(_, span)
+                    | Problem::UnusedVariable(_, span) => *span,
+                };
+                format!("{}:{} {}", span.start.row, span.start.col, problem)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn undefined_names() {
+        let src =
+            "scene main {\n  x = 1;\n  f(y, len([]));\n  nobody \"Hi\"\n  jump nowhere;\n  a::b\n}";
+        assert_eq!(
+            problems(src),
+            [
+                "2:3 cannot find `x` in this scope",
+                "3:3 cannot find `f` in this scope",
+                "3:5 cannot find `y` in this scope",
+                "4:3 cannot find `nobody` in this scope",
+                "5:8 no scene named `nowhere`",
+                "6:3 cannot find `a::b` in this scope",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_definitions() {
+        let src = concat!(
+            "character c = \"C\";\n",
+            "character c = \"D\";\n",
+            "let g = 1;\n",
+            "let g = 2;\n",
+            "scene main { c \"x\"; g }\n",
+            "scene main {}\n",
+        );
+        assert_eq!(
+            problems(src),
+            [
+                "6:7 `main` is defined multiple times",
+                "2:11 `c` is defined multiple times",
+                "4:5 `g` is defined multiple times",
+            ]
+        );
+    }
+
+    #[test]
+    fn unused_variables_and_unreachable_scenes() {
+        let src = concat!(
+            "let unused_global = 0;\n",
+            "let _ignored = 0;\n",
+            "scene main {\n",
+            "  let a = 1;\n",
+            "  let _b = 2;\n",
+            "  let c = 3;\n",
+            "  let c = 4;\n",
+            "  match c { n => {} }\n",
+            "}\n",
+            "scene lost { jump also_lost }\n",
+            "scene also_lost { jump lost }\n",
+        );
+        assert_eq!(
+            problems(src),
+            [
+                "6:7 unused variable `c`",
+                "8:13 unused variable `n`",
+                "4:7 unused variable `a`",
+                "1:5 unused variable `unused_global`",
+                "10:7 scene `lost` is never reached",
+                "11:7 scene `also_lost` is never reached",
+            ]
+        );
+    }
+
+    #[test]
+    fn scopes_shadow_and_end() {
+        let src = concat!(
+            "scene main {\n",
+            "  let a = 1;\n",
+            "  if true { let a = 2; a }\n",
+            "  { let b = 1; b }\n",
+            "  b\n",
+            "  let d = 1;\n",
+            "  let d = d + 1;\n",
+            "  match d { n | n if n > 0 => {}, m => m }\n",
+            "}\n",
+        );
+        assert_eq!(
+            problems(src),
+            [
+                "5:3 cannot find `b` in this scope",
+                "2:7 unused variable `a`",
+            ]
+        );
+    }
+
+    #[test]
+    fn shown_images_are_checked() {
+        let src = "let g = 1;\nscene main { show ui image(g); show ui missing }";
+        assert_eq!(problems(src), ["2:40 cannot find `missing` in this scope"]);
+    }
+}
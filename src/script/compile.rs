@@ -0,0 +1,708 @@
+use core::time::Duration;
+
+use fxhash::{FxHashMap, FxHashSet};
+
+use super::{
+    bytecode::{Op, Place, Program, SceneInfo, Step, Var},
+    markup::{self, Tag},
+    parse::{BinaryOp, Block, Expr, Item, Literal, Pattern, Spanned, Stmt},
+    span::Span,
+    symbol::Symbol,
+    value::{Error, Value, BUILTINS, LAYERS},
+};
+
+/// The loop that `break` and `continue` apply to
+struct Loop {
+    /// The address `continue` jumps to
+    start: u32,
+    /// The jumps of each `break`, to be pointed at the end of the loop
+    breaks: Vec<usize>,
+}
+
+/// A constant in a form that can be hashed, so that each one is only added to the program once.
+/// Floats are compared by their bits, so `0.0` and `-0.0` stay distinct.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstKey {
+    Unit,
+    Int(i64),
+    Float(u64),
+    Duration(Duration),
+    Bool(bool),
+    String(String),
+    Symbol(Symbol),
+    Character(Symbol),
+    Image(Symbol),
+}
+
+impl ConstKey {
+    /// The key of `val`, or `None` for lists and maps, which are never constants
+    fn of(val: &Value) -> Option<Self> {
+        Some(match val {
+            Value::Unit => Self::Unit,
+            Value::Int(i) => Self::Int(*i),
+            Value::Float(f) => Self::Float(f.to_bits()),
+            Value::Duration(d) => Self::Duration(*d),
+            Value::Bool(b) => Self::Bool(*b),
+            Value::String(s) => Self::String(s.clone()),
+            Value::Symbol(sym) => Self::Symbol(*sym),
+            Value::Character(id) => Self::Character(*id),
+            Value::Image(path) => Self::Image(*path),
+            Value::List(_) | Value::Map(_) => return None,
+        })
+    }
+}
+
+/// State shared by the whole of a [`compile`]
+#[derive(Default)]
+struct CompileState {
+    program: Program,
+    scenes: FxHashMap<Symbol, u32>,
+    /// The globals and characters that can be named, which are only those defined so far while
+    /// compiling initializers
+    globals: FxHashMap<Symbol, u32>,
+    characters: FxHashMap<Symbol, u32>,
+    /// The index of each constant in the program
+    consts: FxHashMap<ConstKey, u32>,
+    /// The local variables of the scene being compiled by slot, innermost scope last
+    scopes: Vec<FxHashMap<Symbol, u32>>,
+    /// The number of slots the scene being compiled uses
+    locals: u32,
+    loops: Vec<Loop>,
+    errors: Vec<Error>,
+}
+
+impl CompileState {
+    fn here(&self) -> u32 {
+        self.program.code.len() as u32
+    }
+
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        self.program.code.push(op);
+        self.program.spans.push(span);
+        self.program.code.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction
+    fn patch(&mut self, at: usize) {
+        let here = self.here();
+        match &mut self.program.code[at] {
+            Op::Jump(target) | Op::JumpIfFalse(target) | Op::ShortCircuit(_, target) => {
+                *target = here
+            }
+            Op::AddOption(target) => *target = here,
+            op => unreachable!("{:?} is not a jump", op),
+        }
+    }
+
+    fn konst(&mut self, val: Value) -> u32 {
+        let key = ConstKey::of(&val);
+        if let Some(&idx) = key.as_ref().and_then(|key| self.consts.get(key)) {
+            return idx;
+        }
+        let idx = self.program.consts.len() as u32;
+        self.program.consts.push(val);
+        if let Some(key) = key {
+            self.consts.insert(key, idx);
+        }
+        idx
+    }
+
+    fn var(&self, name: Symbol) -> Option<Var> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name))
+            .map(|&slot| Var::Local(slot))
+            .or_else(|| self.globals.get(&name).map(|&idx| Var::Global(idx)))
+    }
+
+    /// Gives `name` a slot in the innermost scope. A name declared again in the same scope
+    /// keeps its slot.
+    fn declare(&mut self, name: Symbol) -> u32 {
+        let scope = self.scopes.last_mut().unwrap();
+        *scope.entry(name).or_insert_with(|| {
+            self.locals += 1;
+            self.locals - 1
+        })
+    }
+
+    /// A slot for a value that is not named in the script
+    fn temp(&mut self) -> u32 {
+        self.locals += 1;
+        self.locals - 1
+    }
+}
+
+fn join_path(segments: &[Spanned<Symbol>]) -> Symbol {
+    segments
+        .iter()
+        .map(|seg| seg.as_str())
+        .collect::<Vec<_>>()
+        .join("::")
+        .into()
+}
+
+fn compile_load(state: &mut CompileState, var: Var, span: Span) {
+    match var {
+        Var::Local(slot) => state.emit(Op::LoadLocal(slot), span),
+        Var::Global(idx) => state.emit(Op::LoadGlobal(idx), span),
+    };
+}
+
+fn compile_expr(state: &mut CompileState, expr: &Spanned<Expr>) {
+    let span = expr.span;
+    match &expr.body {
+        Expr::Literal(lit) => {
+            let idx = state.konst(lit.clone().into());
+            state.emit(Op::Const(idx), span);
+        }
+        Expr::Format(parts) => {
+            for part in parts {
+                compile_expr(state, part);
+            }
+            state.emit(Op::Format(parts.len() as u32), span);
+        }
+        Expr::Markup(_) => state.errors.push(Error::MisplacedMarkup(span)),
+        Expr::Ident(name) => {
+            if let Some(var) = state.var(*name) {
+                compile_load(state, var, span);
+            } else if state.characters.contains_key(name) {
+                let idx = state.konst(Value::Character(*name));
+                state.emit(Op::Const(idx), span);
+            } else {
+                state.errors.push(Error::UndefinedName(*name, span));
+            }
+        }
+        Expr::Path(segments) => {
+            state
+                .errors
+                .push(Error::UndefinedName(join_path(segments), span));
+        }
+        Expr::List(elems) => {
+            for elem in elems {
+                compile_expr(state, elem);
+            }
+            state.emit(Op::List(elems.len() as u32), span);
+        }
+        Expr::Call(callee, args) => {
+            for arg in args {
+                compile_expr(state, arg);
+            }
+            match &callee.body {
+                Expr::Ident(name) if BUILTINS.contains(&name.as_str()) => {
+                    let idx = state.konst(Value::Symbol(*name));
+                    state.emit(Op::Builtin(idx, args.len() as u32), span);
+                }
+                Expr::Ident(name) => state.errors.push(Error::UndefinedName(*name, callee.span)),
+                _ => state.errors.push(Error::NotCallable(callee.span)),
+            }
+        }
+        Expr::Field(base, field) => {
+            compile_expr(state, base);
+            let idx = state.konst(Value::Symbol(field.body));
+            state.emit(Op::Field(idx), field.span);
+        }
+        Expr::Index(base, idx) => {
+            compile_expr(state, base);
+            compile_expr(state, idx);
+            state.emit(Op::Index, span);
+        }
+        Expr::Unary(op, operand) => {
+            compile_expr(state, operand);
+            state.emit(Op::Unary(op.body), span);
+        }
+        Expr::Binary(op, lhs, rhs) => match op.body {
+            BinaryOp::LogicAnd | BinaryOp::LogicOr => {
+                compile_expr(state, lhs);
+                let short = state.emit(Op::ShortCircuit(op.body, 0), lhs.span);
+                compile_expr(state, rhs);
+                state.emit(Op::CheckBool(op.body), rhs.span);
+                state.patch(short);
+            }
+            op if op.is_assignment() => {
+                compile_assign(state, op, lhs, rhs, span);
+                let idx = state.konst(Value::Unit);
+                state.emit(Op::Const(idx), span);
+            }
+            op => {
+                compile_expr(state, lhs);
+                compile_expr(state, rhs);
+                state.emit(Op::Binary(op), span);
+            }
+        },
+    }
+}
+
+/// Compiles the text of a line of dialogue or an option, leaving it on the stack as markup for
+/// [`markup::parse`]. Only tags written in the string are markup, so its literal text and the
+/// values embedded in it are escaped.
+fn compile_text(state: &mut CompileState, text: &Spanned<Expr>) {
+    let parts = match &text.body {
+        Expr::Format(parts) => &parts[..],
+        _ => core::slice::from_ref(text),
+    };
+    // The markup of the literal pieces since the last embedded expression
+    let mut source = String::new();
+    // The markup of every literal piece, which is checked once they have all been seen
+    let mut all_source = String::new();
+    let mut count = 0;
+    let flush = |state: &mut CompileState, source: &mut String, count: &mut u32| {
+        if !source.is_empty() {
+            let idx = state.konst(Value::String(core::mem::take(source)));
+            state.emit(Op::Const(idx), text.span);
+            *count += 1;
+        }
+    };
+    for part in parts {
+        let piece = match &part.body {
+            Expr::Literal(Literal::String(s)) => markup::escape(s),
+            Expr::Markup(tag) => {
+                // `{b}` could also be meant to show the variable `b`
+                if let Tag::Bold | Tag::Italic | Tag::Wait(None) | Tag::NoWait = tag {
+                    let name = Symbol::from(tag.name());
+                    if state.var(name).is_some() {
+                        state.errors.push(Error::AmbiguousMarkup(name, part.span));
+                    }
+                }
+                tag.to_string()
+            }
+            _ => {
+                flush(state, &mut source, &mut count);
+                compile_expr(state, part);
+                state.emit(Op::Escape, part.span);
+                count += 1;
+                continue;
+            }
+        };
+        source += &piece;
+        all_source += &piece;
+    }
+    flush(state, &mut source, &mut count);
+    if count != 1 {
+        state.emit(Op::Format(count), text.span);
+    }
+    if let Err(err) = markup::parse(&all_source) {
+        state.errors.push(Error::Markup(err, text.span));
+    }
+}
+
+/// Compiles the index expressions of an assignable expression, returning the place they index
+fn compile_place(state: &mut CompileState, expr: &Spanned<Expr>) -> Option<Place> {
+    match &expr.body {
+        Expr::Ident(name) => match state.var(*name) {
+            Some(var) => Some(Place {
+                var,
+                steps: Vec::new(),
+            }),
+            None => {
+                state.errors.push(Error::UndefinedName(*name, expr.span));
+                None
+            }
+        },
+        Expr::Index(base, idx) => {
+            // The outermost index is evaluated first
+            compile_expr(state, idx);
+            let mut place = compile_place(state, base)?;
+            place.steps.push(Step::Index(expr.span));
+            Some(place)
+        }
+        Expr::Field(base, field) => {
+            let mut place = compile_place(state, base)?;
+            place.steps.push(Step::Field(field.body, field.span));
+            Some(place)
+        }
+        _ => {
+            state.errors.push(Error::InvalidAssignTarget(expr.span));
+            None
+        }
+    }
+}
+
+/// Compiles an assignment, which leaves nothing on the stack
+fn compile_assign(
+    state: &mut CompileState,
+    op: BinaryOp,
+    lhs: &Spanned<Expr>,
+    rhs: &Spanned<Expr>,
+    span: Span,
+) {
+    compile_expr(state, rhs);
+    let Some(place) = compile_place(state, lhs) else {
+        return;
+    };
+    match (op, &place.steps[..], place.var) {
+        (BinaryOp::Assign, [], Var::Local(slot)) => {
+            state.emit(Op::StoreLocal(slot), lhs.span);
+        }
+        (BinaryOp::Assign, [], Var::Global(idx)) => {
+            state.emit(Op::StoreGlobal(idx), lhs.span);
+        }
+        (op, _, _) => {
+            state.program.places.push(place);
+            let idx = state.program.places.len() as u32 - 1;
+            match op {
+                BinaryOp::Assign => state.emit(Op::Assign(idx), span),
+                op => state.emit(Op::Update(idx, op), span),
+            };
+        }
+    }
+}
+
+/// Compiles a test of the value in `slot` against `pattern`, making its bindings. Returns the
+/// jumps taken when it does not match.
+fn compile_pattern(state: &mut CompileState, pattern: &Spanned<Pattern>, slot: u32) -> Vec<usize> {
+    let span = pattern.span;
+    match &pattern.body {
+        Pattern::Wildcard => Vec::new(),
+        Pattern::Literal(lit) => {
+            state.emit(Op::LoadLocal(slot), span);
+            let idx = state.konst(lit.clone().into());
+            state.emit(Op::Const(idx), span);
+            state.emit(Op::Matches, span);
+            vec![state.emit(Op::JumpIfFalse(0), span)]
+        }
+        Pattern::Binding(name) => {
+            state.emit(Op::LoadLocal(slot), span);
+            let binding = state.declare(*name);
+            state.emit(Op::StoreLocal(binding), span);
+            Vec::new()
+        }
+        Pattern::Or(alts) => {
+            let mut matched = Vec::new();
+            let mut fails = Vec::new();
+            for (i, alt) in alts.iter().enumerate() {
+                // When an alternative does not match, the next one is tried
+                for fail in fails.drain(..) {
+                    state.patch(fail);
+                }
+                fails = compile_pattern(state, alt, slot);
+                if i + 1 != alts.len() {
+                    matched.push(state.emit(Op::Jump(0), span));
+                }
+            }
+            for jump in matched {
+                state.patch(jump);
+            }
+            fails
+        }
+    }
+}
+
+fn compile_block(state: &mut CompileState, block: &Block) {
+    state.scopes.push(FxHashMap::default());
+    for stmt in block.stmts.iter() {
+        compile_stmt(state, stmt);
+    }
+    state.scopes.pop();
+}
+
+fn compile_scene_target(
+    state: &mut CompileState,
+    target: &Spanned<Vec<Spanned<Symbol>>>,
+) -> Option<u32> {
+    let (name, span) = match &target.body[..] {
+        [name] => (name.body, name.span),
+        segments => (join_path(segments), target.span),
+    };
+    let scene = state.scenes.get(&name).copied();
+    if scene.is_none() {
+        state.errors.push(Error::UndefinedScene(name, span));
+    }
+    scene
+}
+
+fn compile_stmt(state: &mut CompileState, stmt: &Spanned<Stmt>) {
+    let span = stmt.span;
+    match &stmt.body {
+        Stmt::Empty => {}
+        Stmt::Expr(Spanned {
+            body: Expr::Binary(op, lhs, rhs),
+            span,
+        }) if op.is_assignment() => compile_assign(state, op.body, lhs, rhs, *span),
+        Stmt::Expr(expr) => {
+            compile_expr(state, expr);
+            state.emit(Op::Pop, span);
+        }
+        Stmt::Let(name, init) => {
+            match init {
+                Some(init) => compile_expr(state, init),
+                None => {
+                    let idx = state.konst(Value::Unit);
+                    state.emit(Op::Const(idx), span);
+                }
+            }
+            let slot = state.declare(name.body);
+            state.emit(Op::StoreLocal(slot), name.span);
+        }
+        Stmt::Dialogue(speaker, text) => {
+            let speaker = speaker.as_ref().map(|speaker| {
+                if let Some(var) = state.var(speaker.body) {
+                    compile_load(state, var, speaker.span);
+                } else if state.characters.contains_key(&speaker.body) {
+                    let idx = state.konst(Value::Character(speaker.body));
+                    state.emit(Op::Const(idx), speaker.span);
+                } else {
+                    state
+                        .errors
+                        .push(Error::UndefinedName(speaker.body, speaker.span));
+                }
+                state.konst(Value::Symbol(speaker.body))
+            });
+            compile_text(state, text);
+            state.emit(Op::Say(speaker), text.span);
+        }
+        Stmt::Block(block) => compile_block(state, block),
+        Stmt::If(cond, then, else_branch) => {
+            compile_expr(state, cond);
+            let skip_then = state.emit(Op::JumpIfFalse(0), cond.span);
+            compile_block(state, then);
+            match else_branch {
+                Some(else_branch) => {
+                    let skip_else = state.emit(Op::Jump(0), span);
+                    state.patch(skip_then);
+                    compile_stmt(state, else_branch);
+                    state.patch(skip_else);
+                }
+                None => state.patch(skip_then),
+            }
+        }
+        Stmt::Match(scrutinee, arms) => {
+            compile_expr(state, scrutinee);
+            let slot = state.temp();
+            state.emit(Op::StoreLocal(slot), scrutinee.span);
+            let mut ends = Vec::new();
+            for arm in arms {
+                state.scopes.push(FxHashMap::default());
+                let mut fails = compile_pattern(state, &arm.pattern, slot);
+                if let Some(guard) = &arm.guard {
+                    compile_expr(state, guard);
+                    fails.push(state.emit(Op::JumpIfFalse(0), guard.span));
+                }
+                compile_block(state, &arm.body);
+                ends.push(state.emit(Op::Jump(0), arm.body.span));
+                for fail in fails {
+                    state.patch(fail);
+                }
+                state.scopes.pop();
+            }
+            for end in ends {
+                state.patch(end);
+            }
+        }
+        Stmt::Choice(arms) => {
+            let mut options = Vec::new();
+            for arm in arms {
+                let skip = arm.guard.as_ref().map(|guard| {
+                    compile_expr(state, guard);
+                    state.emit(Op::JumpIfFalse(0), guard.span)
+                });
+                compile_text(state, &arm.text);
+                options.push(state.emit(Op::AddOption(0), arm.text.span));
+                if let Some(skip) = skip {
+                    state.patch(skip);
+                }
+            }
+            state.emit(Op::Choose, span);
+            let mut ends = vec![state.emit(Op::Jump(0), span)];
+            for (arm, option) in arms.iter().zip(options) {
+                state.patch(option);
+                compile_block(state, &arm.body);
+                ends.push(state.emit(Op::Jump(0), arm.body.span));
+            }
+            for end in ends {
+                state.patch(end);
+            }
+        }
+        Stmt::Loop(body) => {
+            let start = state.here();
+            compile_loop(state, start, body);
+        }
+        Stmt::While(cond, body) => {
+            let start = state.here();
+            compile_expr(state, cond);
+            let exit = state.emit(Op::JumpIfFalse(0), cond.span);
+            compile_loop(state, start, body);
+            state.patch(exit);
+        }
+        Stmt::Break => match state.loops.is_empty() {
+            true => state.errors.push(Error::NotInLoop(span)),
+            false => {
+                let jump = state.emit(Op::Jump(0), span);
+                state.loops.last_mut().unwrap().breaks.push(jump);
+            }
+        },
+        Stmt::Continue => match state.loops.last() {
+            Some(lp) => {
+                let start = lp.start;
+                state.emit(Op::Jump(start), span);
+            }
+            None => state.errors.push(Error::NotInLoop(span)),
+        },
+        Stmt::Return => {
+            state.emit(Op::Return, span);
+        }
+        Stmt::Jump(target) => {
+            if let Some(scene) = compile_scene_target(state, target) {
+                state.emit(Op::Goto(scene), span);
+            }
+        }
+        Stmt::Call(target) => {
+            if let Some(scene) = compile_scene_target(state, target) {
+                state.emit(Op::Call(scene), span);
+            }
+        }
+        Stmt::Show(layer, image) => {
+            compile_expr(state, image);
+            if let Some(layer) = compile_layer(state, layer) {
+                state.emit(Op::Show(layer), span);
+            }
+        }
+        Stmt::Hide(layer) => {
+            if let Some(layer) = compile_layer(state, layer) {
+                state.emit(Op::Hide(layer), span);
+            }
+        }
+    }
+}
+
+/// The index of the layer `name` in [`LAYERS`]
+fn compile_layer(state: &mut CompileState, name: &Spanned<Symbol>) -> Option<u32> {
+    let layer = LAYERS.iter().position(|layer| *layer == name.body.as_str());
+    if layer.is_none() {
+        state.errors.push(Error::NoSuchLayer(name.body, name.span));
+    }
+    layer.map(|layer| layer as u32)
+}
+
+/// Compiles the body of a loop that starts at `start`, jumping back there at its end
+fn compile_loop(state: &mut CompileState, start: u32, body: &Spanned<Block>) {
+    state.loops.push(Loop {
+        start,
+        breaks: Vec::new(),
+    });
+    compile_block(state, body);
+    state.emit(Op::Jump(start), body.span);
+    for jump in state.loops.pop().unwrap().breaks {
+        state.patch(jump);
+    }
+}
+
+/// Compiles the code that runs `init` in a scope of its own, ending with `finish`
+fn compile_init(
+    state: &mut CompileState,
+    name: Spanned<Symbol>,
+    init: Option<&Spanned<Expr>>,
+    finish: Op,
+) {
+    let entry = state.here();
+    state.scopes.push(FxHashMap::default());
+    state.locals = 0;
+    match init {
+        Some(init) => compile_expr(state, init),
+        None => {
+            let idx = state.konst(Value::Unit);
+            state.emit(Op::Const(idx), name.span);
+        }
+    }
+    state.emit(finish, name.span);
+    state.emit(Op::Return, name.span);
+    state.scopes.pop();
+    state.program.inits.push(SceneInfo {
+        name: name.body,
+        entry,
+        locals: state.locals,
+    });
+}
+
+/// Compiles every item to a program, reporting all of the errors found in doing so
+pub fn compile(items: &[Spanned<Item>]) -> Result<Program, Vec<Error>> {
+    let mut state = CompileState::default();
+
+    let mut scene_spans = FxHashMap::<Symbol, Span>::default();
+    for item in items {
+        if let Item::Scene(scene) = &item.body {
+            let name = scene.name;
+            if let Some(prev) = scene_spans.get(&name.body) {
+                state.errors.push(Error::DuplicateDefinition(
+                    name.body,
+                    name.span,
+                    Box::new(*prev),
+                ));
+                continue;
+            }
+            scene_spans.insert(name.body, name.span);
+            state
+                .scenes
+                .insert(name.body, state.program.scenes.len() as u32);
+            state.program.scenes.push(SceneInfo {
+                name: name.body,
+                entry: 0,
+                locals: 0,
+            });
+        }
+    }
+
+    // Items are defined in order, so their initializers only see the items before them
+    let mut character_spans = FxHashMap::<Symbol, Span>::default();
+    let mut global_spans = FxHashMap::<Symbol, Span>::default();
+    for item in items {
+        match &item.body {
+            Item::Character(name, display) => {
+                if let Some(prev) = character_spans.get(&name.body) {
+                    state.errors.push(Error::DuplicateDefinition(
+                        name.body,
+                        name.span,
+                        Box::new(*prev),
+                    ));
+                    continue;
+                }
+                character_spans.insert(name.body, name.span);
+                let idx = state.program.characters.len() as u32;
+                state.program.characters.push(name.body);
+                compile_init(&mut state, *name, Some(display), Op::DefineCharacter(idx));
+                state.characters.insert(name.body, idx);
+            }
+            Item::Let(name, init) => {
+                if let Some(prev) = global_spans.get(&name.body) {
+                    state.errors.push(Error::DuplicateDefinition(
+                        name.body,
+                        name.span,
+                        Box::new(*prev),
+                    ));
+                    continue;
+                }
+                global_spans.insert(name.body, name.span);
+                let idx = state.program.globals.len() as u32;
+                state.program.globals.push(name.body);
+                compile_init(&mut state, *name, init.as_ref(), Op::StoreGlobal(idx));
+                state.globals.insert(name.body, idx);
+            }
+            // Resolved by the module loader, which qualifies the names of imported scenes
+            Item::Scene(_) | Item::Import(_) | Item::Use(_) => {}
+        }
+    }
+
+    let mut compiled = FxHashSet::default();
+    for item in items {
+        let Item::Scene(scene) = &item.body else {
+            continue;
+        };
+        // Only the first of duplicate scenes is compiled
+        if !compiled.insert(scene.name.body) {
+            continue;
+        }
+        let idx = state.scenes[&scene.name.body];
+        let entry = state.here();
+        state.locals = 0;
+        compile_block(&mut state, &scene.body);
+        state.emit(Op::Return, scene.body.span);
+        state.program.scenes[idx as usize].entry = entry;
+        state.program.scenes[idx as usize].locals = state.locals;
+    }
+
+    if state.errors.is_empty() {
+        Ok(state.program)
+    } else {
+        Err(state.errors)
+    }
+}
@@ -0,0 +1,299 @@
+use core::fmt::{self, Write};
+
+use super::{
+    span::{SourceMap, Span},
+    symbol::Symbol,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Warning => "warning",
+            Self::Error => "error",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    /// Primary labels are underlined with `^`, secondary ones with `-`
+    pub primary: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(level: Level, message: impl Into<String>) -> Self {
+        Self {
+            level,
+            message: message.into(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::new(Level::Error, message)
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self::new(Level::Warning, message)
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+            primary: true,
+        });
+        self
+    }
+
+    pub fn with_secondary(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+            primary: false,
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Renders the diagnostic in the style of rustc, quoting lines from `sources`
+    pub fn render(&self, sources: &SourceMap) -> String {
+        let mut out = String::new();
+        self.write(&mut out, sources).unwrap();
+        out
+    }
+
+    fn write(&self, out: &mut String, sources: &SourceMap) -> fmt::Result {
+        writeln!(out, "{}: {}", self.level, self.message)?;
+
+        let mut labels = self.labels.iter().collect::<Vec<_>>();
+        labels.sort_by_key(|label| (!label.primary, label.span.start.row, label.span.start.col));
+
+        let width = labels
+            .iter()
+            .map(|label| label.span.start.row.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let gutter = " ".repeat(width);
+
+        if let Some(primary) = labels.first() {
+            writeln!(out, "{}--> {}", gutter, primary.span.start)?;
+        }
+
+        // Labels on the same line are drawn together, in source order
+        let mut lines = Vec::<(Symbol, usize, Vec<&Label>)>::new();
+        for label in &labels {
+            let key = (label.span.start.file, label.span.start.row);
+            match lines.iter_mut().find(|(file, row, _)| (*file, *row) == key) {
+                Some((_, _, on_line)) => on_line.push(label),
+                None => lines.push((key.0, key.1, vec![label])),
+            }
+        }
+
+        let mut last_file = labels.first().map(|label| label.span.start.file);
+        for (file, row, mut on_line) in lines {
+            if Some(file) != last_file {
+                writeln!(out, "{}::: {}", gutter, on_line[0].span.start)?;
+                last_file = Some(file);
+            }
+            writeln!(out, "{} |", gutter)?;
+            let Some(text) = sources.line(file, row) else {
+                for label in &on_line {
+                    writeln!(out, "{} = {}", gutter, label.message)?;
+                }
+                continue;
+            };
+            writeln!(out, "{:>width$} | {}", row, text, width = width)?;
+            on_line.sort_by_key(|label| label.span.start.col);
+            for label in on_line {
+                // A column of 0 is taken to be the first, as in a position made without source
+                let start = label.span.start.col.saturating_sub(1);
+                let len = match sources.slice(label.span) {
+                    // Empty spans point at a single character
+                    _ if label.span.is_empty() => 1,
+                    // Spans running onto later lines are underlined to the end of this one
+                    Some(covered) => covered
+                        .lines()
+                        .next()
+                        .map_or(0, |line| line.chars().count()),
+                    None if label.span.end.row == row => {
+                        label.span.end.col.saturating_sub(1).saturating_sub(start)
+                    }
+                    None => text.chars().count().saturating_sub(start),
+                }
+                .max(1);
+                // Copy tabs from the source line so the underline stays aligned
+                let pad = text
+                    .chars()
+                    .take(start)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect::<String>();
+                let mark = if label.primary { "^" } else { "-" };
+                write!(out, "{} | {}{}", gutter, pad, mark.repeat(len))?;
+                if !label.message.is_empty() {
+                    write!(out, " {}", label.message)?;
+                }
+                writeln!(out)?;
+            }
+        }
+
+        if !self.labels.is_empty() && !self.notes.is_empty() {
+            writeln!(out, "{} |", gutter)?;
+        }
+        for note in &self.notes {
+            writeln!(out, "{} = note: {}", gutter, note)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects diagnostics so that every problem can be reported at once
+#[derive(Default)]
+pub struct Diagnostics {
+    list: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, diag: impl Into<Diagnostic>) {
+        self.list.push(diag.into());
+    }
+
+    pub fn extend<D: Into<Diagnostic>>(&mut self, diags: impl IntoIterator<Item = D>) {
+        self.list.extend(diags.into_iter().map(Into::into));
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.list
+            .iter()
+            .filter(|diag| diag.level == Level::Error)
+            .count()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.error_count() != 0
+    }
+
+    /// Renders every diagnostic, followed by a summary line if any were errors
+    pub fn render(&self, sources: &SourceMap) -> String {
+        let mut out = String::new();
+        for diag in &self.list {
+            out += &diag.render(sources);
+            out.push('\n');
+        }
+        match self.error_count() {
+            0 => {}
+            1 => out += "error: aborting due to previous error\n",
+            n => writeln!(out, "error: aborting due to {} previous errors", n).unwrap(),
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::span::Pos;
+
+    fn sources() -> SourceMap {
+        let mut sources = SourceMap::new();
+        sources.add("test.vns", "let x = \"héllo\" + y;\n\tfoo(bar)\n");
+        sources
+    }
+
+    fn span(start: (usize, usize, usize), end: (usize, usize, usize)) -> Span {
+        Span::new_simple(
+            Pos::new(start.0, start.1, start.2, "test.vns"),
+            Pos::new(end.0, end.1, end.2, "test.vns"),
+        )
+    }
+
+    #[test]
+    fn labels_align_after_multi_byte_characters_and_tabs() {
+        let diag = Diagnostic::error("mismatched types")
+            .with_secondary(span((2, 6, 27), (2, 9, 30)), "defined here")
+            .with_label(span((1, 19, 19), (1, 20, 20)), "not a string")
+            .with_note("strings are joined with `+`");
+        assert_eq!(
+            diag.render(&sources()),
+            concat!(
+                "error: mismatched types\n",
+                " --> test.vns:1:19\n",
+                "  |\n",
+                "1 | let x = \"héllo\" + y;\n",
+                "  |                   ^ not a string\n",
+                "  |\n",
+                "2 | \tfoo(bar)\n",
+                "  | \t    --- defined here\n",
+                "  |\n",
+                "  = note: strings are joined with `+`\n",
+            )
+        );
+    }
+
+    #[test]
+    fn labels_on_one_line_are_drawn_in_order() {
+        let diag = Diagnostic::warning("unused")
+            .with_label(span((1, 15, 15), (1, 20, 20)), "")
+            .with_secondary(span((1, 9, 8), (1, 16, 16)), "a string");
+        assert_eq!(
+            diag.render(&sources()),
+            concat!(
+                "warning: unused\n",
+                " --> test.vns:1:15\n",
+                "  |\n",
+                "1 | let x = \"héllo\" + y;\n",
+                "  |         ------- a string\n",
+                "  |               ^^^^^\n",
+            )
+        );
+    }
+
+    #[test]
+    fn column_zero_points_at_the_first_character() {
+        let diag = Diagnostic::error("bad start").with_label(span((2, 0, 22), (2, 0, 22)), "here");
+        assert_eq!(
+            diag.render(&sources()),
+            concat!(
+                "error: bad start\n",
+                " --> test.vns:2:0\n",
+                "  |\n",
+                "2 | \tfoo(bar)\n",
+                "  | ^ here\n",
+            )
+        );
+    }
+
+    #[test]
+    fn labels_in_unknown_files_are_listed() {
+        let diag = Diagnostic::error("lost").with_label(span((3, 1, 0), (3, 2, 1)), "somewhere");
+        assert_eq!(
+            diag.render(&SourceMap::new()),
+            "error: lost\n --> test.vns:3:1\n  |\n  = somewhere\n"
+        );
+    }
+}
@@ -0,0 +1,355 @@
+use core::{fmt, time::Duration};
+use std::collections::BTreeMap;
+
+use fxhash::FxHashMap;
+
+use super::{
+    symbol::{Snapshot, Symbol},
+    value::Value,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The data ended part way through a value
+    UnexpectedEnd,
+    /// A value of the named kind had a tag that is not one of its variants
+    InvalidTag(&'static str, u8),
+    /// A value of the named kind was out of range
+    InvalidValue(&'static str),
+    InvalidUtf8,
+    /// The data does not belong to the program it is being read with
+    WrongProgram,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEnd => f.write_str("unexpected end of data"),
+            Self::InvalidTag(kind, tag) => write!(f, "invalid tag {} for {}", tag, kind),
+            Self::InvalidValue(kind) => write!(f, "invalid {}", kind),
+            Self::InvalidUtf8 => f.write_str("invalid UTF-8 in string"),
+            Self::WrongProgram => f.write_str("data was saved by a different program"),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// How deeply lists and maps can be nested in a value that is read back, so that corrupt data
+/// cannot recurse until the stack overflows
+const MAX_DEPTH: u32 = 512;
+
+/// Symbols written by an [`Encoder`], each given the index of its first use
+#[derive(Default)]
+struct SymbolTable {
+    list: Vec<Symbol>,
+    indices: FxHashMap<Symbol, u32>,
+}
+
+/// Writes values to a byte buffer. Integers are little endian.
+#[derive(Default)]
+pub struct Encoder {
+    bytes: Vec<u8>,
+    /// If set, symbols are written as indices into the table rather than as their text
+    symbols: Option<SymbolTable>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An encoder that writes each symbol once, to a table returned by [`Encoder::into_parts`]
+    pub fn with_symbol_table() -> Self {
+        Self {
+            bytes: Vec::new(),
+            symbols: Some(SymbolTable::default()),
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// The bytes written, and the symbols they refer to in order of their indices
+    pub fn into_parts(self) -> (Vec<u8>, Snapshot) {
+        let symbols = self.symbols.map_or_else(Vec::new, |table| table.list);
+        (self.bytes, Snapshot::take(&symbols))
+    }
+
+    /// Writes bytes as they are, without a length
+    pub fn raw(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.bytes.push(v);
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    /// Writes a length or count
+    pub fn len(&mut self, len: usize) {
+        self.u32(len.try_into().expect("too many elements to encode"));
+    }
+
+    pub fn str(&mut self, s: &str) {
+        self.len(s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    pub fn symbol(&mut self, sym: Symbol) {
+        let Some(table) = &mut self.symbols else {
+            return self.str(&sym);
+        };
+        let idx = *table.indices.entry(sym).or_insert_with(|| {
+            table.list.push(sym);
+            table.list.len() as u32 - 1
+        });
+        self.u32(idx);
+    }
+
+    pub fn snapshot(&mut self, snapshot: &Snapshot) {
+        self.len(snapshot.strings.len());
+        for text in &snapshot.strings {
+            self.str(text);
+        }
+    }
+
+    pub fn value(&mut self, val: &Value) {
+        match val {
+            Value::Unit => self.u8(0),
+            Value::Int(i) => {
+                self.u8(1);
+                self.u64(*i as u64);
+            }
+            Value::Float(f) => {
+                self.u8(2);
+                self.u64(f.to_bits());
+            }
+            Value::Duration(d) => {
+                self.u8(3);
+                self.u64(d.as_secs());
+                self.u32(d.subsec_nanos());
+            }
+            Value::Bool(b) => {
+                self.u8(4);
+                self.bool(*b);
+            }
+            Value::String(s) => {
+                self.u8(5);
+                self.str(s);
+            }
+            Value::Symbol(sym) => {
+                self.u8(6);
+                self.symbol(*sym);
+            }
+            Value::List(l) => {
+                self.u8(7);
+                self.len(l.len());
+                for elem in l {
+                    self.value(elem);
+                }
+            }
+            Value::Map(m) => {
+                self.u8(8);
+                self.len(m.len());
+                for (key, val) in m {
+                    self.str(key);
+                    self.value(val);
+                }
+            }
+            Value::Character(id) => {
+                self.u8(9);
+                self.symbol(*id);
+            }
+            Value::Image(path) => {
+                self.u8(10);
+                self.symbol(*path);
+            }
+        }
+    }
+}
+
+/// Reads values written by an [`Encoder`]
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    /// The table symbols are read from, for data written with [`Encoder::with_symbol_table`]
+    symbols: Option<&'a [Symbol]>,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            symbols: None,
+        }
+    }
+
+    /// Reads the rest of the data as written with [`Encoder::with_symbol_table`], looking up
+    /// symbols in `symbols`
+    pub fn with_symbols(self, symbols: &'a [Symbol]) -> Self {
+        Self {
+            bytes: self.bytes,
+            symbols: Some(symbols),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Reads `n` bytes as they are
+    pub fn raw(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < n {
+            return Err(Error::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.raw(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.raw(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.raw(8)?.try_into().unwrap()))
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(Error::InvalidTag("bool", tag)),
+        }
+    }
+
+    pub fn len(&mut self) -> Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    pub fn str(&mut self) -> Result<&'a str> {
+        let len = self.len()?;
+        core::str::from_utf8(self.raw(len)?).map_err(|_| Error::InvalidUtf8)
+    }
+
+    pub fn symbol(&mut self) -> Result<Symbol> {
+        match self.symbols {
+            Some(symbols) => {
+                let idx = self.u32()? as usize;
+                symbols
+                    .get(idx)
+                    .copied()
+                    .ok_or(Error::InvalidValue("symbol"))
+            }
+            None => self.str().map(Symbol::from),
+        }
+    }
+
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        let strings = (0..self.len()?)
+            .map(|_| Ok(self.str()?.to_string()))
+            .collect::<Result<_>>()?;
+        Ok(Snapshot { strings })
+    }
+
+    pub fn value(&mut self) -> Result<Value> {
+        self.value_in(MAX_DEPTH)
+    }
+
+    /// Reads a value, which can have lists and maps nested `depth` deep
+    fn value_in(&mut self, depth: u32) -> Result<Value> {
+        Ok(match self.u8()? {
+            0 => Value::Unit,
+            1 => Value::Int(self.u64()? as i64),
+            2 => Value::Float(f64::from_bits(self.u64()?)),
+            3 => {
+                let secs = self.u64()?;
+                let nanos = self.u32()?;
+                if nanos >= 1_000_000_000 {
+                    return Err(Error::InvalidValue("duration"));
+                }
+                Value::Duration(Duration::new(secs, nanos))
+            }
+            4 => Value::Bool(self.bool()?),
+            5 => Value::String(self.str()?.to_string()),
+            6 => Value::Symbol(self.symbol()?),
+            7 | 8 if depth == 0 => return Err(Error::InvalidValue("value")),
+            7 => {
+                let len = self.len()?;
+                // The length is not trusted to reserve space with
+                let mut list = Vec::new();
+                for _ in 0..len {
+                    list.push(self.value_in(depth - 1)?);
+                }
+                Value::List(list)
+            }
+            8 => {
+                let len = self.len()?;
+                let mut map = BTreeMap::new();
+                for _ in 0..len {
+                    let key = self.str()?.into();
+                    map.insert(key, self.value_in(depth - 1)?);
+                }
+                Value::Map(map)
+            }
+            9 => Value::Character(self.symbol()?),
+            10 => Value::Image(self.symbol()?),
+            tag => return Err(Error::InvalidTag("value", tag)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(val: &Value) -> Result<Value> {
+        let mut enc = Encoder::new();
+        enc.value(val);
+        let bytes = enc.finish();
+        let mut dec = Decoder::new(&bytes);
+        let read = dec.value()?;
+        assert!(dec.is_empty());
+        Ok(read)
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let mut val = Value::Unit;
+        for _ in 0..MAX_DEPTH {
+            val = Value::List(vec![val]);
+        }
+        assert_eq!(round_trip(&val), Ok(val.clone()));
+        let deeper = Value::Map(BTreeMap::from([("a".into(), val)]));
+        assert_eq!(round_trip(&deeper), Err(Error::InvalidValue("value")));
+
+        // A list of one list of one list... without end
+        let bytes = [7, 1, 0, 0, 0].repeat(100_000);
+        assert_eq!(
+            Decoder::new(&bytes).value(),
+            Err(Error::InvalidValue("value"))
+        );
+    }
+
+    #[test]
+    fn lengths_past_the_end_are_refused() {
+        let bytes = [7, 0xFF, 0xFF, 0xFF, 0xFF, 0];
+        assert_eq!(Decoder::new(&bytes).value(), Err(Error::UnexpectedEnd));
+    }
+}
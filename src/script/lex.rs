@@ -1,6 +1,7 @@
 use core::fmt;
 
 use super::{
+    diag::Diagnostic,
     span::{Pos, Span, Speekable, Speekerator},
     symbol::Symbol,
 };
@@ -15,7 +16,6 @@ pub enum GroupType {
 }
 
 impl GroupType {
-    #[allow(dead_code)]
     pub fn start_char(&self) -> char {
         match self {
             Self::Parens => '(',
@@ -42,7 +42,7 @@ impl GroupType {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum IdentifierType {
     Default,
     Keyword,
@@ -50,7 +50,7 @@ pub enum IdentifierType {
 }
 
 #[allow(dead_code)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum StringType {
     Default,
     Raw(u8), // number of #s
@@ -58,7 +58,7 @@ pub enum StringType {
     RawByte(u8), // see above
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TokenType {
     Character,
     CommentMulti,
@@ -74,11 +74,13 @@ pub enum TokenType {
 pub struct Group {
     pub ty: GroupType,
     pub body: Vec<Lexeme>,
+    /// The closing delimiter, which is empty where the group was never closed
+    pub close: Span,
 }
 
 impl Group {
-    pub fn new(ty: GroupType, body: Vec<Lexeme>) -> Self {
-        Self { ty, body }
+    pub fn new(ty: GroupType, body: Vec<Lexeme>, close: Span) -> Self {
+        Self { ty, body, close }
     }
 
     pub fn with_span(self, span: Span) -> Lexeme {
@@ -89,10 +91,82 @@ impl Group {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NumberValue {
+    Int(u64),
+    Float(f64),
+}
+
+/// A unit suffix on a number literal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeUnit {
+    Seconds,
+    Milliseconds,
+}
+
+impl TimeUnit {
+    pub fn from_suffix(suffix: &str) -> Option<Self> {
+        match suffix {
+            "s" => Some(Self::Seconds),
+            "ms" => Some(Self::Milliseconds),
+            _ => None,
+        }
+    }
+
+    /// The length of one of this unit, in seconds
+    pub fn seconds(self) -> f64 {
+        match self {
+            Self::Seconds => 1.0,
+            Self::Milliseconds => 0.001,
+        }
+    }
+}
+
+/// The value of a number literal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Number {
+    pub value: NumberValue,
+    pub unit: Option<TimeUnit>,
+}
+
+impl Number {
+    /// Parses the text of a number literal, such as `0xFF`, `1_000`, `1e-3`, or `2.5s`.
+    /// `None` if it is malformed or out of range.
+    pub fn parse(text: &str) -> Option<Self> {
+        let (radix, rest) = match text.get(..2) {
+            Some("0x") => (16, &text[2..]),
+            Some("0o") => (8, &text[2..]),
+            Some("0b") => (2, &text[2..]),
+            _ => (10, text),
+        };
+        let is_float_char = |c: char| radix == 10 && matches!(c, '.' | 'e' | 'E' | '+' | '-');
+        let split = rest
+            .find(|c: char| !(c.is_digit(radix) || c == '_' || is_float_char(c)))
+            .unwrap_or(rest.len());
+        let (digits, suffix) = rest.split_at(split);
+        let unit = match suffix {
+            "" => None,
+            suffix => Some(TimeUnit::from_suffix(suffix)?),
+        };
+        let digits = digits.replace('_', "");
+        if !digits.starts_with(|c: char| c.is_digit(radix)) {
+            return None;
+        }
+        let value = if digits.contains(is_float_char) {
+            NumberValue::Float(digits.parse().ok()?)
+        } else {
+            NumberValue::Int(u64::from_str_radix(&digits, radix).ok()?)
+        };
+        Some(Self { value, unit })
+    }
+}
+
 #[derive(Clone)]
 pub struct Token {
     pub ty: TokenType,
     pub body: Symbol,
+    /// The value of a [`TokenType::Number`]
+    pub value: Option<Number>,
 }
 
 impl Token {
@@ -100,6 +174,15 @@ impl Token {
         Self {
             ty,
             body: body.into(),
+            value: None,
+        }
+    }
+
+    pub fn number(body: impl Into<Symbol>, value: Number) -> Self {
+        Self {
+            ty: TokenType::Number,
+            body: body.into(),
+            value: Some(value),
         }
     }
 
@@ -125,10 +208,10 @@ pub enum LexemeBody {
 impl fmt::Debug for LexemeBody {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Group(Group { ty, body }) => {
+            Self::Group(Group { ty, body, .. }) => {
                 f.debug_tuple("Group").field(ty).field(body).finish()
             }
-            Self::Token(Token { ty, body }) => write!(f, "Token({:?}, {:?})", ty, body),
+            Self::Token(Token { ty, body, .. }) => write!(f, "Token({:?}, {:?})", ty, body),
         }
     }
 }
@@ -146,6 +229,23 @@ pub enum LexemeClass {
     String,
 }
 
+impl fmt::Display for LexemeClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Character => f.write_str("character literal"),
+            Self::Eof => f.write_str("end of file"),
+            Self::Group(None) => f.write_str("group"),
+            Self::Group(Some(ty)) => write!(f, "`{}`", ty.start_char()),
+            Self::Keyword(kw) => write!(f, "`{}`", kw),
+            Self::Identifier => f.write_str("identifier"),
+            Self::Lifetime => f.write_str("symbol literal"),
+            Self::Number => f.write_str("number"),
+            Self::Punctuation(p) => write!(f, "`{}`", p),
+            Self::String => f.write_str("string"),
+        }
+    }
+}
+
 impl LexemeClass {
     pub fn of(lexeme: Option<&Lexeme>) -> Self {
         match lexeme {
@@ -169,7 +269,6 @@ impl LexemeClass {
                 TokenType::String(_) => Self::String,
                 _ => unreachable!(), // Comments should be removed by now
             },
-            _ => todo!(),
         }
     }
 
@@ -188,432 +287,605 @@ pub struct Lexeme {
 pub enum Error {
     UnexpectedEof(Pos),
     UnrecognizedChar(char, Pos),
+    /// A string literal starting at the position was not closed before the end of the line
+    UnterminatedString(Pos),
+    /// A raw string literal starting at the position was not closed before the end of the file
+    UnterminatedRawString(Pos),
+    UnterminatedChar(Pos),
+    UnterminatedBlockComment(Pos),
+    /// A closing delimiter at the first position that does not match the group opened at the
+    /// second position
+    MismatchedDelimiter(char, Pos, GroupType, Pos),
+    /// The group opened at the first position was still open at the second
+    UnclosedDelimiter(GroupType, Pos, Pos),
+    /// A closing delimiter outside of any group
+    UnmatchedDelimiter(char, Pos),
+    InvalidEscape(char, Pos),
+    InvalidNumber(Span),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEof(_) => f.write_str("unexpected end of file"),
+            Self::UnrecognizedChar(c, _) => write!(f, "unrecognized character {:?}", c),
+            Self::UnterminatedString(_) => f.write_str("unterminated string literal"),
+            Self::UnterminatedRawString(_) => f.write_str("unterminated raw string literal"),
+            Self::UnterminatedChar(_) => f.write_str("unterminated character literal"),
+            Self::UnterminatedBlockComment(_) => f.write_str("unterminated block comment"),
+            Self::MismatchedDelimiter(c, _, ty, _) => write!(
+                f,
+                "mismatched closing delimiter: `{}` does not close `{}`",
+                c,
+                ty.start_char()
+            ),
+            Self::UnclosedDelimiter(ty, _, _) => {
+                write!(f, "unclosed delimiter `{}`", ty.start_char())
+            }
+            Self::UnmatchedDelimiter(c, _) => write!(f, "unexpected closing delimiter `{}`", c),
+            Self::InvalidEscape(c, _) => write!(f, "unknown character escape `\\{}`", c),
+            Self::InvalidNumber(_) => f.write_str("invalid number literal"),
+        }
+    }
+}
+
+impl From<Error> for Diagnostic {
+    fn from(err: Error) -> Self {
+        let diag = Diagnostic::error(err.to_string());
+        let at = |pos| Span::new_simple(pos, pos);
+        match err {
+            Error::UnexpectedEof(pos) => diag.with_label(at(pos), ""),
+            Error::UnrecognizedChar(_, pos) => diag.with_label(at(pos), "not valid here"),
+            Error::UnterminatedString(pos) | Error::UnterminatedChar(pos) => diag
+                .with_label(at(pos), "literal starts here")
+                .with_note("literals cannot span multiple lines"),
+            Error::UnterminatedRawString(pos) => diag.with_label(at(pos), "literal starts here"),
+            Error::UnterminatedBlockComment(pos) => {
+                diag.with_label(at(pos), "comment starts here")
+            }
+            Error::MismatchedDelimiter(c, pos, ty, open) => diag
+                .with_label(at(pos), "mismatched closing delimiter")
+                .with_secondary(at(open), format!("expected `{}` to close this", ty.end_char()))
+                .with_note(format!("`{}` has no matching opening delimiter", c)),
+            Error::UnclosedDelimiter(ty, open, end) => diag
+                .with_label(at(open), "unclosed delimiter")
+                .with_secondary(at(end), format!("expected `{}` before here", ty.end_char())),
+            Error::UnmatchedDelimiter(_, pos) => diag.with_label(at(pos), "unexpected delimiter"),
+            Error::InvalidEscape(_, pos) => diag
+                .with_label(at(pos), "unknown escape")
+                .with_note("valid escapes are `\\n`, `\\r`, `\\t`, `\\0`, `\\\\`, `\\\"`, `\\'`, and `\\u{...}`"),
+            Error::InvalidNumber(span) => diag.with_label(span, ""),
+        }
+    }
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
 
-fn do_str(file: &mut Speekable<impl Iterator<Item = char>>) -> Result<(String, Pos)> {
-    let mut str = String::from('"');
-    let end = loop {
-        if let Some((pos, c)) = file.snext() {
+/// State shared by the whole of a lexing run
+#[derive(Default)]
+pub struct LexState {
+    /// Errors that were recovered from
+    errors: Vec<Error>,
+    /// The groups currently open, innermost last
+    open: Vec<(GroupType, Pos)>,
+}
+
+/// Checks the escape sequence following a `\`, which has already been consumed
+fn do_escape(
+    file: &mut Speekable<impl Iterator<Item = char>>,
+    str: &mut String,
+    state: &mut LexState,
+) {
+    match file.speek() {
+        Some(&(_, c @ ('n' | 'r' | 't' | '\\' | '0' | '"' | '\''))) => {
+            file.next();
             str.push(c);
-            match c {
-                '"' => break pos,
-                '\\' => match file.next() {
-                    Some(c @ ('n' | 'r' | 't' | '\\' | '0' | '"')) => str.push(c),
-                    x => todo!("{} {:?}", str, x),
-                },
-                '\n' => todo!(), // error
-                _ => {}
+        }
+        Some(&(pos, 'u')) => {
+            file.next();
+            str.push('u');
+            if file.peek() != Some(&'{') {
+                state.errors.push(Error::InvalidEscape('u', pos));
+                return;
             }
-        } else {
-            Err(Error::UnexpectedEof(file.last_pos()))?
+            let mut digits = String::new();
+            str.push('{');
+            file.next();
+            while let Some(&(_, c)) = file.speek() {
+                if !c.is_ascii_hexdigit() {
+                    break;
+                }
+                digits.push(c);
+                file.next();
+            }
+            str.push_str(&digits);
+            let valid = digits.len() <= 6
+                && u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .is_some();
+            if file.peek() == Some(&'}') && valid {
+                file.next();
+                str.push('}');
+            } else {
+                state.errors.push(Error::InvalidEscape('u', pos));
+            }
+        }
+        // Leave a line break for the caller to report as an unterminated literal
+        Some(&(_, '\n')) | None => {}
+        Some(&(pos, c)) => {
+            file.next();
+            str.push(c);
+            state.errors.push(Error::InvalidEscape(c, pos));
+        }
+    }
+}
+
+/// Decodes the escape sequence at the start of `rest`, which follows a `\`. Returns the
+/// character and how many characters of `rest` it takes up, or `None` if it is not valid.
+pub fn decode_escape(rest: &[char]) -> Option<(char, usize)> {
+    Some(match rest.first()? {
+        'n' => ('\n', 1),
+        'r' => ('\r', 1),
+        't' => ('\t', 1),
+        '0' => ('\0', 1),
+        c @ ('\\' | '"' | '\'') => (*c, 1),
+        'u' => {
+            if rest.get(1) != Some(&'{') {
+                return None;
+            }
+            let len = rest[2..].iter().position(|&c| c == '}')?;
+            let digits = rest[2..2 + len].iter().collect::<String>();
+            if digits.is_empty() || digits.len() > 6 {
+                return None;
+            }
+            let c = char::from_u32(u32::from_str_radix(&digits, 16).ok()?)?;
+            (c, len + 3)
+        }
+        _ => return None,
+    })
+}
+
+/// Lexes the rest of a string after the opening quote, returning it with the position just
+/// past its end. An unterminated string ends at the end of its line.
+fn do_str(
+    file: &mut Speekable<impl Iterator<Item = char>>,
+    start: Pos,
+    state: &mut LexState,
+) -> (String, Pos) {
+    let mut str = String::from('"');
+    loop {
+        match file.snext() {
+            Some((_, '"')) => {
+                str.push('"');
+                break (str, file.pos());
+            }
+            Some((_, '\\')) => {
+                str.push('\\');
+                do_escape(file, &mut str, state);
+            }
+            Some((pos, '\n')) => {
+                state.errors.push(Error::UnterminatedString(start));
+                break (str, pos);
+            }
+            None => {
+                state.errors.push(Error::UnterminatedString(start));
+                break (str, file.pos());
+            }
+            Some((_, c)) => str.push(c),
+        }
+    }
+}
+
+/// Every keyword of the script language. These are lexed as [`IdentifierType::Keyword`] unless
+/// written as raw identifiers, like `r#scene`.
+pub use super::symbol::kw::KEYWORDS;
+
+pub fn is_keyword(id: &str) -> bool {
+    KEYWORDS.contains(&id)
+}
+
+/// Every punctuation token. Every prefix of an entry is also an entry, which lets [`do_punct`]
+/// munch one character at a time.
+pub const PUNCTUATION: &[&str] = &[
+    "+", "+=", "-", "-=", "->", "*", "*=", "/", "/=", "%", "%=", "^", "^=", "!", "!=", "&", "&&",
+    "&=", "|", "||", "|=", "<", "<<", "<<=", "<=", ">", ">>", ">>=", ">=", "=", "==", "=>", ".",
+    "..", "...", "..=", ":", "::", ";", ",", "#", "@", "~",
+];
+
+fn is_punct_start(c: char) -> bool {
+    PUNCTUATION.iter().any(|punct| punct.starts_with(c))
+}
+
+/// Lexes the longest punctuation token starting with `c`, which has just been taken
+fn do_punct(file: &mut Speekable<impl Iterator<Item = char>>, c: char) -> String {
+    let mut punct = String::from(c);
+    while let Some(&c) = file.peek() {
+        punct.push(c);
+        if !PUNCTUATION.contains(&&*punct) {
+            punct.pop();
+            break;
+        }
+        file.next();
+    }
+    punct
+}
+
+/// Lexes the rest of a raw string after the opening quote, which was preceded by `hashes` `#`s.
+/// Raw strings may span lines, and `\r\n` in them is read as `\n`.
+fn do_raw_str(
+    file: &mut Speekable<impl Iterator<Item = char>>,
+    start: Pos,
+    hashes: usize,
+    state: &mut LexState,
+) -> String {
+    let mut str = String::from('"');
+    loop {
+        match file.snext() {
+            Some((_, '"')) => {
+                str.push('"');
+                let mut seen = 0;
+                while seen < hashes && file.peek() == Some(&'#') {
+                    file.next();
+                    str.push('#');
+                    seen += 1;
+                }
+                if seen == hashes {
+                    break str;
+                }
+            }
+            Some((_, '\r')) if file.peek() == Some(&'\n') => {}
+            Some((_, c)) => str.push(c),
+            None => {
+                state.errors.push(Error::UnterminatedRawString(start));
+                break str;
+            }
+        }
+    }
+}
+
+/// Moves characters matching `pred` from `file` to the end of `text`
+fn take_while(
+    file: &mut Speekable<impl Iterator<Item = char>>,
+    text: &mut String,
+    mut pred: impl FnMut(char) -> bool,
+) {
+    while let Some(&c) = file.peek() {
+        if !pred(c) {
+            break;
         }
+        file.next();
+        text.push(c);
+    }
+}
+
+/// Lexes a number literal starting with the digit `first`, which is at `start`
+fn do_number(
+    file: &mut Speekable<impl Iterator<Item = char>>,
+    first: char,
+    start: Pos,
+    state: &mut LexState,
+) -> Lexeme {
+    let mut text = String::from(first);
+    let radix = match (first, file.peek()) {
+        ('0', Some('x')) => 16,
+        ('0', Some('o')) => 8,
+        ('0', Some('b')) => 2,
+        _ => 10,
     };
-    Ok((str, end))
+    if radix != 10 {
+        // The prefix letter
+        text.extend(file.next());
+    }
+    take_while(file, &mut text, |c| c.is_digit(radix) || c == '_');
+    if radix == 10 {
+        // A `.` not followed by a digit is not part of the number, as in `0..5`
+        if file.peek() == Some(&'.') && file.peek_nth(1).is_some_and(char::is_ascii_digit) {
+            take_while(file, &mut text, |c| c == '.');
+            take_while(file, &mut text, |c| c.is_ascii_digit() || c == '_');
+        }
+        let exponent = match (file.peek_nth(1).copied(), file.peek_nth(2).copied()) {
+            (Some(c), _) if c.is_ascii_digit() => 2,
+            (Some('+' | '-'), Some(c)) if c.is_ascii_digit() => 3,
+            _ => 0,
+        };
+        if matches!(file.peek(), Some('e' | 'E')) && exponent != 0 {
+            for _ in 1..exponent {
+                text.extend(file.next());
+            }
+            take_while(file, &mut text, |c| c.is_ascii_digit() || c == '_');
+        }
+    }
+    // Unit suffix
+    take_while(file, &mut text, |c| c.is_xid_continue());
+
+    let span = Span::new_simple(start, file.pos());
+    let value = Number::parse(&text).unwrap_or_else(|| {
+        state.errors.push(Error::InvalidNumber(span));
+        Number {
+            value: NumberValue::Int(0),
+            unit: None,
+        }
+    });
+    Token::number(text, value).with_span(span)
 }
 
-fn do_lexeme(file: &mut Speekable<impl Iterator<Item = char>>) -> Result<Lexeme> {
+/// Lexes the next lexeme, recording any errors it can recover from in `state`. A closing
+/// delimiter is returned as [`Error::UnrecognizedChar`] and the end of input as
+/// [`Error::UnexpectedEof`], for [`do_group`] to handle.
+fn do_lexeme(
+    file: &mut Speekable<impl Iterator<Item = char>>,
+    state: &mut LexState,
+) -> Result<Lexeme> {
     loop {
         match file.snext() {
             Some((start, c)) => match c {
-                ' ' | '\n' => {}
+                ' ' | '\t' | '\r' | '\n' => {}
                 '(' | '[' | '{' => {
                     let ty = GroupType::from_start_char(c);
-                    let (body, end) = do_group(file, Some(ty.end_char()))?;
-                    break Ok(Group { ty, body }.with_span(Span::new_simple(start, end)));
+                    state.open.push((ty, start));
+                    let (body, end) = do_group(file, state);
+                    state.open.pop();
+                    let close = Span::new_simple(end, file.pos());
+                    break Ok(
+                        Group::new(ty, body, close).with_span(Span::new_simple(start, file.pos()))
+                    );
                 }
                 '"' => {
-                    let (str, end) = do_str(file)?;
+                    let (str, end) = do_str(file, start, state);
                     break Ok(Token::new(TokenType::String(StringType::Default), str)
                         .with_span(Span::new_simple(start, end)));
                 }
-                '0'..='9' => {
-                    let mut id = String::from(c);
-                    let mut end = start;
-                    while let Some(&(pos, c)) = file.speek() {
-                        if !c.is_xid_continue() {
-                            break;
-                        } else {
-                            id.push(c);
-                            end = pos;
-                            file.next();
-                        }
-                    }
-                    break Ok(
-                        Token::new(TokenType::Number, id).with_span(Span::new_simple(start, end))
-                    );
-                }
+                '0'..='9' => break Ok(do_number(file, c, start, state)),
                 x if x.is_xid_start() || x == '_' => {
                     let mut id = String::from(x);
-                    let mut end = start;
                     let mut ty = TokenType::Identifier(IdentifierType::Default);
-                    while let Some(&(pos, c)) = file.speek() {
+                    while let Some(&c) = file.peek() {
                         if !c.is_xid_continue() {
                             break;
                         } else {
                             id.push(c);
-                            end = pos;
                             file.next();
                         }
                     }
-                    if id == "r" || id == "rb" {
-                        match file.peek() {
-                            Some('#') => {
-                                id.push('#');
-                                file.next();
-                                while let Some(&(pos, c)) = file.speek() {
-                                    if !c.is_xid_continue() {
-                                        break;
-                                    } else {
-                                        id.push(c);
-                                        end = pos;
-                                        file.next();
-                                    }
+                    if (id == "r" || id == "rb") && matches!(file.peek(), Some('#' | '"')) {
+                        let mut hashes = 0;
+                        let mut last_hash = start;
+                        while let Some(&(pos, '#')) = file.speek() {
+                            file.next();
+                            id.push('#');
+                            last_hash = pos;
+                            hashes += 1;
+                        }
+                        if let Some(&(quote, '"')) = file.speek() {
+                            file.next();
+                            let str = do_raw_str(file, quote, hashes, state);
+                            let hashes = u8::try_from(hashes).unwrap_or(u8::MAX);
+                            let ty = if id.starts_with("rb") {
+                                StringType::RawByte(hashes)
+                            } else {
+                                StringType::Raw(hashes)
+                            };
+                            break Ok(Token::new(TokenType::String(ty), id + &str)
+                                .with_span(Span::new_simple(start, file.pos())));
+                        } else if hashes == 1 {
+                            while let Some(&c) = file.peek() {
+                                if !c.is_xid_continue() {
+                                    break;
+                                } else {
+                                    id.push(c);
+                                    file.next();
                                 }
-                                ty = TokenType::Identifier(IdentifierType::Raw);
                             }
-                            Some('"') => todo!(),
-                            _ => {}
+                            ty = TokenType::Identifier(IdentifierType::Raw);
+                        } else {
+                            state.errors.push(Error::UnrecognizedChar('#', last_hash));
                         }
-                    } else if id == "b" {
-                        if file.peek() == Some(&'"') {
-                            file.next();
-                            let (str, end) = do_str(file)?;
+                    } else if id == "b" && file.peek() == Some(&'"') {
+                        if let Some((quote, _)) = file.snext() {
+                            let (str, end) = do_str(file, quote, state);
                             break Ok(Token::new(TokenType::String(StringType::Byte), id + &str)
                                 .with_span(Span::new_simple(start, end)));
                         }
                     }
-                    break Ok(Token::new(ty, id).with_span(Span::new_simple(start, end)));
+                    if ty == TokenType::Identifier(IdentifierType::Default) && is_keyword(&id) {
+                        ty = TokenType::Identifier(IdentifierType::Keyword);
+                    }
+                    break Ok(Token::new(ty, id).with_span(Span::new_simple(start, file.pos())));
                 }
-                '/' => {
-                    let (tok, end, ty) = match file.speek() {
-                        Some(&(pos, '/')) => {
+                '/' if matches!(file.peek(), Some('/' | '*')) => {
+                    let (tok, ty) = match file.peek() {
+                        Some('/') => {
                             file.next();
                             let mut tok = String::from("//");
-                            let mut end = pos;
-                            while let Some((pos, c)) = file.snext() {
+                            while let Some(&c) = file.peek() {
                                 if c == '\n' {
                                     break;
                                 }
+                                file.next();
+                                if c == '\r' && file.peek() == Some(&'\n') {
+                                    continue;
+                                }
                                 tok.push(c);
-                                end = pos;
                             }
-                            (tok, end, TokenType::CommentSingle)
+                            (tok, TokenType::CommentSingle)
                         }
-                        Some(&(pos, '*')) => {
+                        Some('*') => {
                             file.next();
                             let mut tok = String::from("/*");
-                            let mut end = pos;
                             let mut star = false;
-                            while let Some((pos, c)) = file.snext() {
+                            let mut closed = false;
+                            for c in file.by_ref() {
                                 tok.push(c);
-                                end = pos;
                                 if c == '/' && star {
+                                    closed = true;
                                     break;
                                 }
                                 star = c == '*';
                             }
-                            (tok, end, TokenType::CommentMulti)
-                        }
-                        Some(&(end, '=')) => {
-                            file.next();
-                            ("/=".into(), end, TokenType::Punctuation)
+                            if !closed {
+                                state.errors.push(Error::UnterminatedBlockComment(start));
+                            }
+                            (tok, TokenType::CommentMulti)
                         }
-                        _ => ("/".into(), start, TokenType::Punctuation),
+                        _ => unreachable!(),
                     };
-                    break Ok(Token::new(ty, tok).with_span(Span::new_simple(start, end)));
+                    break Ok(Token::new(ty, tok).with_span(Span::new_simple(start, file.pos())));
                 }
                 '\'' => match file.snext() {
-                    Some((pos, '\\')) => {
+                    Some((_, '\\')) => {
                         let mut token = String::from("'\\");
-                        let mut end = pos;
-                        loop {
+                        do_escape(file, &mut token, state);
+                        let end = loop {
                             match file.snext() {
-                                Some((pos, '\'')) => {
+                                Some((_, '\'')) => {
                                     token.push('\'');
-                                    end = pos;
-                                    break;
+                                    break file.pos();
                                 }
-                                Some((pos, '\n')) => Err(Error::UnrecognizedChar('\n', pos))?,
-                                Some((pos, x)) => {
-                                    token.push(x);
-                                    end = pos;
+                                Some((pos, '\n')) => {
+                                    state.errors.push(Error::UnterminatedChar(start));
+                                    break pos;
+                                }
+                                None => {
+                                    state.errors.push(Error::UnterminatedChar(start));
+                                    break file.pos();
                                 }
-                                None => Err(Error::UnexpectedEof(end))?,
+                                Some((_, x)) => token.push(x),
                             }
-                        }
+                        };
                         break Ok(Token::new(TokenType::Character, token)
                             .with_span(Span::new_simple(start, end)));
                     }
-                    Some((pos, x)) => {
+                    Some((_, x)) => {
                         let mut token = String::from("'");
                         token.push(x);
-                        match file.speek() {
-                            Some(&(end, '\'')) => {
+                        match file.peek() {
+                            Some('\'') => {
                                 file.next();
                                 token.push('\'');
                                 break Ok(Token::new(TokenType::Character, token)
-                                    .with_span(Span::new_simple(start, end)));
+                                    .with_span(Span::new_simple(start, file.pos())));
                             }
-                            Some((_, x)) if !x.is_xid_continue() => {
+                            Some(x) if !x.is_xid_continue() => {
                                 break Ok(Token::new(TokenType::Lifetime, token)
-                                    .with_span(Span::new_simple(start, pos)));
+                                    .with_span(Span::new_simple(start, file.pos())));
                             }
                             None => {
                                 break Ok(Token::new(TokenType::Lifetime, token)
-                                    .with_span(Span::new_simple(start, pos)));
+                                    .with_span(Span::new_simple(start, file.pos())));
                             }
-                            Some(&(pos, x)) => {
-                                file.next();
-                                token.push(x);
-                                let mut end = pos;
-                                while let Some(&(pos, x)) = file.speek() {
+                            Some(_) => {
+                                while let Some(&x) = file.peek() {
                                     if !x.is_xid_continue() {
                                         break;
                                     }
                                     file.next();
-                                    end = pos;
                                     token.push(x);
                                 }
                                 break Ok(Token::new(TokenType::Lifetime, token)
-                                    .with_span(Span::new_simple(start, end)));
+                                    .with_span(Span::new_simple(start, file.pos())));
                             }
                         }
                     }
-                    None => Err(Error::UnexpectedEof(start))?,
+                    None => {
+                        state.errors.push(Error::UnterminatedChar(start));
+                        break Ok(Token::new(TokenType::Character, "'")
+                            .with_span(Span::new_simple(start, file.pos())));
+                    }
                 },
-                '.' => {
-                    let (punct, end) = match file.speek() {
-                        Some(&(end, '.')) => {
-                            file.next();
-                            match file.speek() {
-                                Some(&(end, '.')) => {
-                                    file.next();
-                                    ("...", end)
-                                }
-                                Some(&(end, '=')) => {
-                                    file.next();
-                                    ("..=", end)
-                                }
-                                _ => ("..", end),
-                            }
-                        }
-                        _ => (".", start),
-                    };
-                    break Ok(Token::punct(punct).with_span(Span::new_simple(start, end)));
-                }
-                '<' => {
-                    let (punct, end) = match file.speek() {
-                        Some(&(end, '<')) => {
-                            file.next();
-                            match file.speek() {
-                                Some(&(end, '=')) => {
-                                    file.next();
-                                    ("<<=", end)
-                                }
-                                _ => ("<<", end),
-                            }
-                        }
-                        Some(&(end, '=')) => {
-                            file.next();
-                            ("<=", end)
-                        }
-                        _ => ("<", start),
-                    };
-                    break Ok(Token::punct(punct).with_span(Span::new_simple(start, end)));
-                }
-                '>' => {
-                    let (punct, end) = match file.speek() {
-                        Some(&(end, '>')) => {
-                            file.next();
-                            match file.speek() {
-                                Some(&(end, '=')) => {
-                                    file.next();
-                                    (">>=", end)
-                                }
-                                _ => (">>", end),
-                            }
-                        }
-                        Some(&(end, '=')) => {
-                            file.next();
-                            (">=", end)
-                        }
-                        _ => (">", start),
-                    };
-                    break Ok(Token::punct(punct).with_span(Span::new_simple(start, end)));
-                }
-                '|' => {
-                    let (punct, end) = match file.speek() {
-                        Some(&(end, '|')) => ("||", end),
-                        Some(&(end, '=')) => {
-                            file.next();
-                            ("|=", end)
-                        }
-                        _ => ("|", start),
-                    };
-                    break Ok(Token::punct(punct).with_span(Span::new_simple(start, end)));
-                }
-                ':' => {
-                    let (punct, end) = match file.speek() {
-                        Some(&(end, ':')) => {
-                            file.next();
-                            ("::", end)
-                        }
-                        _ => (":", start),
-                    };
-                    break Ok(Token::punct(punct).with_span(Span::new_simple(start, end)));
-                }
-                '=' => {
-                    let (punct, end) = match file.speek() {
-                        Some(&(end, '=')) => {
-                            file.next();
-                            ("==", end)
-                        }
-                        Some(&(end, '>')) => {
-                            file.next();
-                            ("=>", end)
-                        }
-                        _ => ("=", start),
-                    };
-                    break Ok(Token::punct(punct).with_span(Span::new_simple(start, end)));
-                }
-                '&' => {
-                    let (punct, end) = match file.speek() {
-                        Some(&(end, '=')) => {
-                            file.next();
-                            ("&=", end)
-                        }
-                        Some(&(end, '&')) => {
-                            file.next();
-                            ("&&", end)
-                        }
-                        _ => ("&", start),
-                    };
-                    break Ok(Token::punct(punct).with_span(Span::new_simple(start, end)));
+                c if is_punct_start(c) => {
+                    let punct = do_punct(file, c);
+                    break Ok(Token::punct(punct).with_span(Span::new_simple(start, file.pos())));
                 }
-                '+' | '*' | '!' => {
-                    let (punct, end) = match file.speek() {
-                        Some(&(end, '=')) => {
-                            file.next();
-                            ("=", end)
-                        }
-                        _ => ("", start),
-                    };
-                    break Ok(Token::punct(String::from(c) + punct)
-                        .with_span(Span::new_simple(start, end)));
-                }
-                '-' => {
-                    let (punct, end) = match file.speek() {
-                        Some(&(end, '>')) => {
-                            file.next();
-                            ("->", end)
-                        }
-                        Some(&(end, '=')) => {
-                            file.next();
-                            ("-=", end)
-                        }
-                        _ => ("-", start),
-                    };
-                    break Ok(Token::punct(punct).with_span(Span::new_simple(start, end)));
-                }
-                ';' | '#' | ',' | '@' => {
-                    break Ok(
-                        Token::punct(String::from(c)).with_span(Span::new_simple(start, start))
-                    );
-                }
-                x => Err(Error::UnrecognizedChar(x, start))?,
+                ')' | ']' | '}' => Err(Error::UnrecognizedChar(c, start))?,
+                x => state.errors.push(Error::UnrecognizedChar(x, start)),
             },
             None => Err(Error::UnexpectedEof(file.last_pos()))?,
         }
     }
 }
 
-#[allow(dead_code)]
-/// Assumes that the token is legal. If it is not, the result is unspecified.
-pub fn do_lexeme_str(token: &str, span: Span) -> Result<Lexeme> {
-    let mut iter = token.chars();
-    let ty = match iter.next() {
-        Some('"') => TokenType::String(StringType::Default),
-        Some('0'..='9') => TokenType::Number,
-        Some('\'') => {
-            // Lifetime or char
-            match iter.next() {
-                Some(x) if x.is_xid_start() || x == '_' => match iter.next() {
-                    Some('\'') => TokenType::Character,
-                    _ => TokenType::Lifetime,
-                },
-                _ => TokenType::Character,
-            }
-        }
-        Some(x) if x.is_xid_start() || x.is_xid_continue() => {
-            let hash_pos = token.find('#');
-            let quote_pos = token.find('"');
-            match (hash_pos, quote_pos) {
-                (None, None) => TokenType::Identifier(IdentifierType::Default),
-                (None, Some(_)) => {
-                    if x == 'b' {
-                        TokenType::String(StringType::Byte)
-                    } else if x == 'r' && iter.next() == Some('b') {
-                        TokenType::String(StringType::RawByte(0))
-                    } else {
-                        // Could have an if, but again, we are assuming a legal token
-                        TokenType::String(StringType::Raw(0))
-                    }
-                }
-                (Some(_), None) => TokenType::Identifier(IdentifierType::Raw),
-                (Some(a), Some(b)) if a > b => {
-                    if x == 'b' {
-                        TokenType::String(StringType::Byte)
-                    } else if x == 'r' && iter.next() == Some('b') {
-                        TokenType::String(StringType::RawByte(0))
-                    } else {
-                        // Could have an if, but again, we are assuming a legal token
-                        TokenType::String(StringType::Raw(0))
-                    }
-                }
-                (Some(_), Some(_)) => todo!(),
-            }
-        }
-        Some(x) => Err(Error::UnrecognizedChar(x, Pos::default()))?, // invalid pos b/c we have no idea
-        None => Err(Error::UnexpectedEof(Pos::default()))?,
-    };
-    Ok(Token::new(ty, token).with_span(span))
-}
-
+/// Lexes lexemes up to the end of the innermost group in `state`, or the end of input at the
+/// top level, and returns them with the position of the closing delimiter, or of the end of
+/// input if there was none.
+///
+/// A `;` or `}` cannot be inside parentheses or brackets, so a group of those that reaches one
+/// was never closed, and ends there rather than taking in the rest of the file.
 pub fn do_group(
     file: &mut Speekable<impl Iterator<Item = char>>,
-    end_char: Option<char>,
-) -> Result<(Vec<Lexeme>, Pos)> {
+    state: &mut LexState,
+) -> (Vec<Lexeme>, Pos) {
     let mut result = Vec::new();
+    let open = state.open.last().copied();
     let end = loop {
-        let lexeme = do_lexeme(file);
-        if let Ok(lexeme) = lexeme {
-            result.push(lexeme);
-        } else if let Some(c) = end_char {
-            if let Err(Error::UnrecognizedChar(x, end)) = lexeme {
-                if x == c {
-                    break end;
-                } else {
-                    lexeme?;
+        match do_lexeme(file, state) {
+            Ok(lexeme) => match (open, &lexeme.body) {
+                (Some((ty, start)), LexemeBody::Token(token))
+                    if ty != GroupType::Braces
+                        && token.ty == TokenType::Punctuation
+                        && token.body == *";" =>
+                {
+                    let pos = lexeme.span.start;
+                    state.errors.push(Error::UnclosedDelimiter(ty, start, pos));
+                    file.unread(pos, ';');
+                    break pos;
                 }
-            } else {
-                lexeme?;
+                _ => result.push(lexeme),
+            },
+            Err(Error::UnrecognizedChar(c, pos)) => match open {
+                Some((ty, _)) if ty.end_char() == c => break pos,
+                // Closes an enclosing group, or ends the statement this one is in, so this one
+                // was never closed
+                Some((ty, start))
+                    if c == '}' || state.open.iter().any(|(ty, _)| ty.end_char() == c) =>
+                {
+                    state.errors.push(Error::UnclosedDelimiter(ty, start, pos));
+                    file.unread(pos, c);
+                    break pos;
+                }
+                Some((ty, start)) => state
+                    .errors
+                    .push(Error::MismatchedDelimiter(c, pos, ty, start)),
+                None => state.errors.push(Error::UnmatchedDelimiter(c, pos)),
+            },
+            Err(Error::UnexpectedEof(pos)) => {
+                if let Some((ty, start)) = open {
+                    state.errors.push(Error::UnclosedDelimiter(ty, start, pos));
+                }
+                break pos;
             }
-        } else if let Err(Error::UnexpectedEof(end)) = lexeme {
-            break end;
-        } else {
-            lexeme?;
+            Err(e) => state.errors.push(e),
         }
     };
-    Ok((result, end))
+    (result, end)
 }
 
+/// Lexes a whole file, reporting every error found in it
 pub fn lex(
     file: &mut impl Iterator<Item = char>,
     file_name: impl Into<Symbol>,
-) -> Result<Vec<Lexeme>> {
-    do_group(&mut file.speekable(file_name), None).map(|x| x.0)
+) -> core::result::Result<Vec<Lexeme>, Vec<Error>> {
+    lex_all(&mut file.speekable(file_name))
+}
+
+/// Lexes text that starts at `start` within a file, such as an expression embedded in a string
+pub fn lex_at(
+    file: &mut impl Iterator<Item = char>,
+    start: Pos,
+) -> core::result::Result<Vec<Lexeme>, Vec<Error>> {
+    lex_all(&mut file.speekable_at(start))
+}
+
+fn lex_all(
+    file: &mut Speekable<impl Iterator<Item = char>>,
+) -> core::result::Result<Vec<Lexeme>, Vec<Error>> {
+    let mut state = LexState::default();
+    let (lexemes, _) = do_group(file, &mut state);
+    if state.errors.is_empty() {
+        Ok(lexemes)
+    } else {
+        Err(state.errors)
+    }
 }
 
 pub fn filter_comments(tree: &mut Vec<Lexeme>) {
@@ -629,3 +901,455 @@ pub fn filter_comments(tree: &mut Vec<Lexeme>) {
         _ => true,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::span::SourceMap;
+
+    fn tokens(src: &str) -> Vec<(TokenType, String)> {
+        let lexemes = lex(&mut src.chars(), "test").unwrap_or_else(|errs| panic!("{:?}", errs));
+        lexemes
+            .into_iter()
+            .map(|lexeme| match lexeme.body {
+                LexemeBody::Token(token) => (token.ty, token.body.to_string()),
+                LexemeBody::Group(_) => panic!("unexpected group"),
+            })
+            .collect()
+    }
+
+    fn puncts(src: &str) -> Vec<String> {
+        tokens(src)
+            .into_iter()
+            .map(|(ty, body)| {
+                assert_eq!(ty, TokenType::Punctuation, "{:?}", body);
+                body
+            })
+            .collect()
+    }
+
+    #[test]
+    fn raw_string() {
+        assert_eq!(
+            tokens(r#"r"a \n b""#),
+            [(
+                TokenType::String(StringType::Raw(0)),
+                r#"r"a \n b""#.to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn raw_string_with_hashes() {
+        assert_eq!(
+            tokens(r###"r##"say "#hi"#"## x"###),
+            [
+                (
+                    TokenType::String(StringType::Raw(2)),
+                    r###"r##"say "#hi"#"##"###.to_string()
+                ),
+                (
+                    TokenType::Identifier(IdentifierType::Default),
+                    "x".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_byte_string() {
+        assert_eq!(
+            tokens(r##"rb#"\"#"##),
+            [(
+                TokenType::String(StringType::RawByte(1)),
+                r##"rb#"\"#"##.to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn raw_string_spans_lines() {
+        let lexemes = lex(&mut "r\"a\r\nb\" c".chars(), "test").unwrap();
+        match &lexemes[0].body {
+            LexemeBody::Token(token) => assert_eq!(&*token.body, "r\"a\nb\""),
+            LexemeBody::Group(_) => panic!("unexpected group"),
+        }
+        assert_eq!(lexemes[1].span.start.row, 2);
+        assert_eq!(lexemes[1].span.start.col, 4);
+    }
+
+    #[test]
+    fn unterminated_raw_string() {
+        let errs = lex(&mut "r#\"abc\"".chars(), "test").unwrap_err();
+        assert!(matches!(errs[..], [Error::UnterminatedRawString(_)]));
+    }
+
+    #[test]
+    fn raw_identifier() {
+        assert_eq!(
+            tokens("r#scene"),
+            [(
+                TokenType::Identifier(IdentifierType::Raw),
+                "r#scene".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn keywords() {
+        assert_eq!(
+            tokens("scene r#scene scenes"),
+            [
+                (
+                    TokenType::Identifier(IdentifierType::Keyword),
+                    "scene".to_string()
+                ),
+                (
+                    TokenType::Identifier(IdentifierType::Raw),
+                    "r#scene".to_string()
+                ),
+                (
+                    TokenType::Identifier(IdentifierType::Default),
+                    "scenes".to_string()
+                ),
+            ]
+        );
+        assert!(KEYWORDS.iter().all(|kw| is_keyword(kw)));
+    }
+
+    #[test]
+    fn escaped_chars() {
+        assert_eq!(
+            tokens(r"'\'' '\\' '\u{e9}'"),
+            [
+                (TokenType::Character, r"'\''".to_string()),
+                (TokenType::Character, r"'\\'".to_string()),
+                (TokenType::Character, r"'\u{e9}'".to_string()),
+            ]
+        );
+        assert_eq!(
+            errors(r"'\q'"),
+            [(r"unknown character escape `\q`".to_string(), 1, 3)]
+        );
+    }
+
+    #[test]
+    fn tabs() {
+        let lexemes = lex(&mut "\tlet\tx".chars(), "test").unwrap();
+        assert_eq!(lexemes.len(), 2);
+        assert_eq!(lexemes[1].span.start.col, 6);
+    }
+
+    #[test]
+    fn crlf() {
+        let mut lexemes = lex(&mut "a // note\r\nb\r\n".chars(), "test").unwrap();
+        match &lexemes[1].body {
+            LexemeBody::Token(token) => assert_eq!(&*token.body, "// note"),
+            LexemeBody::Group(_) => panic!("unexpected group"),
+        }
+        filter_comments(&mut lexemes);
+        assert_eq!(lexemes.len(), 2);
+        assert_eq!(lexemes[1].span.start.row, 2);
+        assert_eq!(lexemes[1].span.start.col, 1);
+    }
+
+    #[test]
+    fn crlf_string() {
+        assert_eq!(
+            tokens("\"a\"\r\n\"b\"\r\n"),
+            [
+                (TokenType::String(StringType::Default), "\"a\"".to_string()),
+                (TokenType::String(StringType::Default), "\"b\"".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn remainder_and_xor() {
+        assert_eq!(puncts("% %= ^ ^= ~"), ["%", "%=", "^", "^=", "~"]);
+        assert_eq!(
+            tokens("a%b")
+                .into_iter()
+                .map(|(_, body)| body)
+                .collect::<Vec<_>>(),
+            ["a", "%", "b"]
+        );
+    }
+
+    /// Lexes `op` between two identifiers, and checks that it comes out whole with its own span
+    fn check_punct(op: &str) {
+        let src = format!("a {} b", op);
+        let lexemes = lex(&mut src.chars(), "test").unwrap();
+        assert_eq!(lexemes.len(), 3, "{:?}", src);
+        match &lexemes[1].body {
+            LexemeBody::Token(token) => {
+                assert_eq!(token.ty, TokenType::Punctuation);
+                assert_eq!(&*token.body, op);
+            }
+            LexemeBody::Group(_) => panic!("unexpected group"),
+        }
+        let span = lexemes[1].span;
+        assert_eq!((span.start.row, span.start.col), (1, 3));
+        assert_eq!((span.end.row, span.end.col), (1, 3 + op.len()));
+    }
+
+    macro_rules! punct_tests {
+        ($($name:ident: $op:literal,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    check_punct($op);
+                }
+            )*
+
+            #[test]
+            fn every_punct_tested() {
+                let mut tested = [$($op),*];
+                let mut all = PUNCTUATION.to_vec();
+                tested.sort_unstable();
+                all.sort_unstable();
+                assert_eq!(tested[..], all[..]);
+            }
+        };
+    }
+
+    punct_tests! {
+        add: "+",
+        add_assign: "+=",
+        sub: "-",
+        sub_assign: "-=",
+        arrow: "->",
+        mul: "*",
+        mul_assign: "*=",
+        div: "/",
+        div_assign: "/=",
+        rem: "%",
+        rem_assign: "%=",
+        bit_xor: "^",
+        bit_xor_assign: "^=",
+        not: "!",
+        cmp_ne: "!=",
+        bit_and: "&",
+        logic_and: "&&",
+        bit_and_assign: "&=",
+        bit_or: "|",
+        logic_or: "||",
+        bit_or_assign: "|=",
+        cmp_lt: "<",
+        left_shift: "<<",
+        left_shift_assign: "<<=",
+        cmp_le: "<=",
+        cmp_gt: ">",
+        right_shift: ">>",
+        right_shift_assign: ">>=",
+        cmp_ge: ">=",
+        assign: "=",
+        cmp_eq: "==",
+        fat_arrow: "=>",
+        dot: ".",
+        dot_dot: "..",
+        ellipsis: "...",
+        dot_dot_eq: "..=",
+        colon: ":",
+        path_sep: "::",
+        semi: ";",
+        comma: ",",
+        pound: "#",
+        at: "@",
+        bit_not: "~",
+    }
+
+    #[test]
+    fn maximal_munch() {
+        assert_eq!(puncts("<<=="), ["<<=", "="]);
+        assert_eq!(puncts("|||"), ["||", "|"]);
+        assert_eq!(puncts("!!="), ["!", "!="]);
+        assert_eq!(puncts("...."), ["...", "."]);
+        assert_eq!(puncts("->>"), ["->", ">"]);
+    }
+
+    fn number(src: &str) -> Number {
+        let lexemes = lex(&mut src.chars(), "test").unwrap_or_else(|errs| panic!("{:?}", errs));
+        assert_eq!(lexemes.len(), 1, "{:?}", src);
+        match &lexemes[0].body {
+            LexemeBody::Token(token) => {
+                assert_eq!(&*token.body, src);
+                token.value.unwrap()
+            }
+            LexemeBody::Group(_) => panic!("unexpected group"),
+        }
+    }
+
+    fn int(value: u64) -> Number {
+        Number {
+            value: NumberValue::Int(value),
+            unit: None,
+        }
+    }
+
+    fn float(value: f64) -> Number {
+        Number {
+            value: NumberValue::Float(value),
+            unit: None,
+        }
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(number("0"), int(0));
+        assert_eq!(number("1_000"), int(1000));
+        assert_eq!(number("0xFF"), int(255));
+        assert_eq!(number("0o17"), int(15));
+        assert_eq!(number("0b1010_1010"), int(170));
+        assert_eq!(number("18446744073709551615"), int(u64::MAX));
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(number("0.5"), float(0.5));
+        assert_eq!(number("1e3"), float(1000.0));
+        assert_eq!(number("2.5E-1"), float(0.25));
+        assert_eq!(number("1_0.0_1"), float(10.01));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(
+            number("2.5s"),
+            Number {
+                value: NumberValue::Float(2.5),
+                unit: Some(TimeUnit::Seconds)
+            }
+        );
+        assert_eq!(
+            number("300ms"),
+            Number {
+                value: NumberValue::Int(300),
+                unit: Some(TimeUnit::Milliseconds)
+            }
+        );
+    }
+
+    #[test]
+    fn number_then_dot() {
+        assert_eq!(
+            tokens("0..5")
+                .into_iter()
+                .map(|(_, body)| body)
+                .collect::<Vec<_>>(),
+            ["0", "..", "5"]
+        );
+        assert_eq!(tokens("1.max").len(), 3);
+    }
+
+    #[test]
+    fn invalid_numbers() {
+        for src in ["12ab", "0x", "0b102", "1e", "18446744073709551616", "5min"] {
+            let errs = lex(&mut src.chars(), "test").unwrap_err();
+            assert!(matches!(errs[..], [Error::InvalidNumber(_)]), "{}", src);
+        }
+    }
+
+    #[test]
+    fn spans_cover_their_text() {
+        let src = "let é = \"ünï\" // done\n{ 0x1F }";
+        let lexemes = lex(&mut src.chars(), "test").unwrap();
+        let mut sources = SourceMap::new();
+        sources.add("test", src);
+        let texts = lexemes
+            .iter()
+            .map(|lexeme| sources.slice(lexeme.span).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["let", "é", "=", "\"ünï\"", "// done", "{ 0x1F }"]);
+        let string = lexemes[3].span;
+        assert_eq!((string.end.row, string.end.col), (1, 14));
+        assert!(!string.is_empty() && Span::at(string.end).is_empty());
+        assert_eq!(lexemes[0].span.join(string).range(), 0..string.end.idx);
+    }
+
+    /// The kind of each error, with the line and column it was reported at
+    fn errors(src: &str) -> Vec<(String, usize, usize)> {
+        let errs = lex(&mut src.chars(), "test").unwrap_err();
+        errs.iter()
+            .map(|err| {
+                let pos = match *err {
+                    Error::UnexpectedEof(pos)
+                    | Error::UnrecognizedChar(_, pos)
+                    | Error::UnterminatedString(pos)
+                    | Error::UnterminatedRawString(pos)
+                    | Error::UnterminatedChar(pos)
+                    | Error::UnterminatedBlockComment(pos)
+                    | Error::MismatchedDelimiter(_, pos, _, _)
+                    | Error::UnclosedDelimiter(_, pos, _)
+                    | Error::UnmatchedDelimiter(_, pos)
+                    | Error::InvalidEscape(_, pos) => pos,
+                    Error::InvalidNumber(span) => span.start,
+                };
+                (err.to_string(), pos.row, pos.col)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recovers_to_report_every_error() {
+        let src = concat!(
+            "let a = \"unterminated\n",
+            "let b = 12ab + $;\n",
+            "f(x]\n",
+            "}\n",
+            "{ (\n",
+        );
+        let expected = [
+            ("unterminated string literal", 1, 9),
+            ("invalid number literal", 2, 9),
+            ("unrecognized character '$'", 2, 16),
+            ("mismatched closing delimiter: `]` does not close `(`", 3, 4),
+            // The `}` ends the `(`, and is then outside of any group
+            ("unclosed delimiter `(`", 3, 2),
+            ("unexpected closing delimiter `}`", 4, 1),
+            ("unclosed delimiter `(`", 5, 3),
+            ("unclosed delimiter `{`", 5, 1),
+        ];
+        let expected = expected
+            .iter()
+            .map(|&(msg, row, col)| (msg.to_string(), row, col))
+            .collect::<Vec<_>>();
+        assert_eq!(errors(src), expected);
+    }
+
+    #[test]
+    fn unclosed_groups_end_at_the_statement() {
+        let src = concat!(
+            "scene a {\n",
+            "    let b = f(1, [2;\n",
+            "    let c = 12ab;\n",
+            "    say(c }\n",
+            "let d = $;\n",
+        );
+        let expected = [
+            ("unclosed delimiter `[`", 2, 18),
+            ("unclosed delimiter `(`", 2, 14),
+            ("invalid number literal", 3, 13),
+            ("unclosed delimiter `(`", 4, 8),
+            ("unrecognized character '$'", 5, 9),
+        ];
+        let expected = expected
+            .iter()
+            .map(|&(msg, row, col)| (msg.to_string(), row, col))
+            .collect::<Vec<_>>();
+        assert_eq!(errors(src), expected);
+    }
+
+    #[test]
+    fn invalid_escapes() {
+        assert_eq!(
+            errors(r#""\q \u{110000} \u12 \u{} \n""#),
+            [
+                ("unknown character escape `\\q`".to_string(), 1, 3),
+                ("unknown character escape `\\u`".to_string(), 1, 6),
+                ("unknown character escape `\\u`".to_string(), 1, 17),
+                ("unknown character escape `\\u`".to_string(), 1, 22),
+            ]
+        );
+    }
+}
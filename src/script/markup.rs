@@ -0,0 +1,436 @@
+use core::{fmt, time::Duration};
+
+use crate::graphics::Colour;
+
+/// Every markup tag that can appear in dialogue, as in `{b}bold{/b}`
+pub const TAGS: &[&str] = &["b", "i", "color", "speed", "w", "nw"];
+
+/// How a run of dialogue text is shown
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    /// `None` uses the colour of the text box
+    pub colour: Option<Colour>,
+    /// Multiplies the speed the text is revealed at
+    pub speed: f32,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self {
+            bold: false,
+            italic: false,
+            colour: None,
+            speed: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Run {
+    pub text: String,
+    pub style: Style,
+}
+
+/// A piece of a line of dialogue, in the order it is shown
+#[derive(Clone, Debug, PartialEq)]
+pub enum Piece {
+    Text(Run),
+    /// `{w}` or `{w=1.5}`. Pauses revealing the text, until the player clicks if there is no
+    /// duration.
+    Wait(Option<Duration>),
+    /// `{nw}`. Goes on once the line is shown, without waiting for the player.
+    NoWait,
+}
+
+/// A single tag, with its value checked
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tag {
+    Bold,
+    Italic,
+    Colour(Colour),
+    Speed(f32),
+    /// A closing tag, like `{/b}`
+    Close(&'static str),
+    Wait(Option<Duration>),
+    NoWait,
+}
+
+impl Tag {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Bold => "b",
+            Self::Italic => "i",
+            Self::Colour(_) => "color",
+            Self::Speed(_) => "speed",
+            Self::Close(name) => name,
+            Self::Wait(_) => "w",
+            Self::NoWait => "nw",
+        }
+    }
+}
+
+/// Writes the tag as it is written in a string, which [`parse_tag`] reads back
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Colour(Colour { r, g, b, a }) => {
+                write!(f, "{{color=#{:02x}{:02x}{:02x}{:02x}}}", r, g, b, a)
+            }
+            Self::Speed(speed) => write!(f, "{{speed={}}}", speed),
+            Self::Close(name) => write!(f, "{{/{}}}", name),
+            Self::Wait(Some(duration)) => write!(f, "{{w={}}}", duration.as_secs_f64()),
+            tag => write!(f, "{{{}}}", tag.name()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// A `{` with no `}` after it
+    UnclosedTag,
+    UnknownTag(String),
+    /// The tag needs a value, like `{color=#fff}`
+    MissingValue(&'static str),
+    /// The tag takes no value, like `{b}`
+    UnexpectedValue(&'static str),
+    InvalidValue(&'static str, String),
+    /// A closing tag for a tag that does not wrap text, such as `{/w}`
+    NotClosable(&'static str),
+    /// A closing tag that does not match the innermost open tag, which is given if there is one
+    MismatchedClose(&'static str, Option<&'static str>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnclosedTag => f.write_str("unclosed markup tag"),
+            Self::UnknownTag(tag) => write!(f, "unknown markup tag `{{{}}}`", tag),
+            Self::MissingValue(tag) => write!(f, "markup tag `{}` needs a value", tag),
+            Self::UnexpectedValue(tag) => write!(f, "markup tag `{}` does not take a value", tag),
+            Self::InvalidValue(tag, value) => {
+                write!(f, "invalid value `{}` for markup tag `{}`", value, tag)
+            }
+            Self::NotClosable(tag) => write!(f, "markup tag `{}` cannot be closed", tag),
+            Self::MismatchedClose(tag, Some(open)) => {
+                write!(f, "`{{/{}}}` does not close the open `{{{}}}`", tag, open)
+            }
+            Self::MismatchedClose(tag, None) => write!(f, "`{{/{}}}` closes no open tag", tag),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Whether the text between a pair of braces names a markup tag, rather than being an
+/// embedded expression. Its value may still be invalid.
+pub fn is_tag(content: &str) -> bool {
+    let body = content.strip_prefix('/').unwrap_or(content);
+    let name = body.split_once('=').map_or(body, |(name, _)| name);
+    TAGS.contains(&name)
+}
+
+/// Parses the text between the braces of a tag
+pub fn parse_tag(content: &str) -> Result<Tag> {
+    let (close, body) = match content.strip_prefix('/') {
+        Some(body) => (true, body),
+        None => (false, content),
+    };
+    let (name, value) = match body.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (body, None),
+    };
+    let name = *TAGS
+        .iter()
+        .find(|tag| **tag == name)
+        .ok_or_else(|| Error::UnknownTag(content.to_string()))?;
+    let invalid = |value: &str| Error::InvalidValue(name, value.to_string());
+
+    if close {
+        return match (name, value) {
+            (_, Some(_)) => Err(Error::UnexpectedValue(name)),
+            ("w" | "nw", None) => Err(Error::NotClosable(name)),
+            (_, None) => Ok(Tag::Close(name)),
+        };
+    }
+    match (name, value) {
+        ("b", None) => Ok(Tag::Bold),
+        ("i", None) => Ok(Tag::Italic),
+        ("nw", None) => Ok(Tag::NoWait),
+        ("w", None) => Ok(Tag::Wait(None)),
+        ("color", Some(value)) => Colour::from_hex(value)
+            .map(Tag::Colour)
+            .ok_or_else(|| invalid(value)),
+        ("speed", Some(value)) => match value.parse::<f32>() {
+            Ok(speed) if speed.is_finite() && speed > 0.0 => Ok(Tag::Speed(speed)),
+            _ => Err(invalid(value)),
+        },
+        ("w", Some(value)) => value
+            .parse()
+            .ok()
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .map(|duration| Tag::Wait(Some(duration)))
+            .ok_or_else(|| invalid(value)),
+        ("color" | "speed", None) => Err(Error::MissingValue(name)),
+        (_, Some(_)) => Err(Error::UnexpectedValue(name)),
+        (_, None) => unreachable!(),
+    }
+}
+
+/// Doubles the braces in `text`, so that [`parse`] reads it back as plain text
+pub fn escape(text: &str) -> String {
+    text.replace('{', "{{").replace('}', "}}")
+}
+
+/// Adds `text` to the end of `pieces` in `style`, joining it to the last run if that has the
+/// same style
+fn push_text(pieces: &mut Vec<Piece>, text: &mut String, style: Style) {
+    if text.is_empty() {
+        return;
+    }
+    match pieces.last_mut() {
+        Some(Piece::Text(run)) if run.style == style => run.text.push_str(text),
+        _ => pieces.push(Piece::Text(Run {
+            text: text.clone(),
+            style,
+        })),
+    }
+    text.clear();
+}
+
+/// Splits a line of dialogue into styled runs and control events. `{{` and `}}` stand for
+/// literal braces, and tags left open are closed at the end of the line.
+pub fn parse(line: &str) -> Result<Vec<Piece>> {
+    let mut pieces = Vec::new();
+    // The open tags, innermost last, with the style from before each
+    let mut open = Vec::<(&'static str, Style)>::new();
+    let mut style = Style::default();
+    let mut text = String::new();
+    let mut rest = line;
+    while let Some(i) = rest.find(['{', '}']) {
+        text.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(after) = rest.strip_prefix("{{") {
+            text.push('{');
+            rest = after;
+            continue;
+        } else if let Some(after) = rest.strip_prefix("}}").or_else(|| rest.strip_prefix('}')) {
+            text.push('}');
+            rest = after;
+            continue;
+        }
+        let end = rest.find('}').ok_or(Error::UnclosedTag)?;
+        let tag = parse_tag(&rest[1..end])?;
+        rest = &rest[end + 1..];
+
+        push_text(&mut pieces, &mut text, style);
+        let prev = style;
+        match tag {
+            Tag::Bold => style.bold = true,
+            Tag::Italic => style.italic = true,
+            Tag::Colour(colour) => style.colour = Some(colour),
+            Tag::Speed(speed) => style.speed = speed,
+            Tag::Close(name) => match open.pop() {
+                Some((tag, prev)) if tag == name => style = prev,
+                Some((tag, _)) => return Err(Error::MismatchedClose(name, Some(tag))),
+                None => return Err(Error::MismatchedClose(name, None)),
+            },
+            Tag::Wait(duration) => pieces.push(Piece::Wait(duration)),
+            Tag::NoWait => pieces.push(Piece::NoWait),
+        }
+        if let Tag::Bold | Tag::Italic | Tag::Colour(_) | Tag::Speed(_) = tag {
+            open.push((tag.name(), prev));
+        }
+    }
+    text.push_str(rest);
+    push_text(&mut pieces, &mut text, style);
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(text: &str, style: Style) -> Piece {
+        Piece::Text(Run {
+            text: text.to_string(),
+            style,
+        })
+    }
+
+    fn bold() -> Style {
+        Style {
+            bold: true,
+            ..Style::default()
+        }
+    }
+
+    const YELLOW: Colour = Colour {
+        r: 0xFF,
+        g: 0xFF,
+        b: 0x00,
+        a: 0xFF,
+    };
+
+    #[test]
+    fn plain() {
+        assert_eq!(
+            parse("Hello, world").unwrap(),
+            [run("Hello, world", Style::default())]
+        );
+        assert_eq!(parse("").unwrap(), []);
+    }
+
+    #[test]
+    fn bold_run() {
+        assert_eq!(
+            parse("a {b}big{/b} deal").unwrap(),
+            [
+                run("a ", Style::default()),
+                run("big", bold()),
+                run(" deal", Style::default()),
+            ]
+        );
+    }
+
+    #[test]
+    fn nesting() {
+        let yellow_bold = Style {
+            colour: Some(YELLOW),
+            ..bold()
+        };
+        let slow_yellow_bold = Style {
+            speed: 0.5,
+            ..yellow_bold
+        };
+        assert_eq!(
+            parse("{b}x{color=#ff0}y{speed=0.5}z{/speed}y{/color}x{/b}").unwrap(),
+            [
+                run("x", bold()),
+                run("y", yellow_bold),
+                run("z", slow_yellow_bold),
+                run("y", yellow_bold),
+                run("x", bold()),
+            ]
+        );
+    }
+
+    #[test]
+    fn same_style_runs_join() {
+        assert_eq!(
+            parse("a{b}{/b}b{i}{/i}c").unwrap(),
+            [run("abc", Style::default())]
+        );
+    }
+
+    #[test]
+    fn unclosed_tags_end_with_line() {
+        assert_eq!(parse("{i}{b}x").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn events() {
+        assert_eq!(
+            parse("Wait{w}... for it{w=1.5}!{nw}").unwrap(),
+            [
+                run("Wait", Style::default()),
+                Piece::Wait(None),
+                run("... for it", Style::default()),
+                Piece::Wait(Some(Duration::from_millis(1500))),
+                run("!", Style::default()),
+                Piece::NoWait,
+            ]
+        );
+    }
+
+    #[test]
+    fn escaped_braces() {
+        assert_eq!(
+            parse("{{b}} and }").unwrap(),
+            [run("{b} and }", Style::default())]
+        );
+    }
+
+    #[test]
+    fn escaped_text_is_plain() {
+        let text = "{b}}{{";
+        assert_eq!(parse(&escape(text)).unwrap(), [run(text, Style::default())]);
+    }
+
+    #[test]
+    fn tags_are_written_as_parsed() {
+        for src in [
+            "b",
+            "/i",
+            "color=#12ab3456",
+            "speed=0.5",
+            "w",
+            "w=1.25",
+            "nw",
+        ] {
+            let tag = parse_tag(src).unwrap();
+            assert_eq!(tag.to_string(), format!("{{{}}}", src));
+        }
+        assert_eq!(
+            parse_tag("color=#ff0").unwrap().to_string(),
+            "{color=#ffff00ff}"
+        );
+    }
+
+    #[test]
+    fn colours() {
+        let rgba = |r, g, b, a| Some(Tag::Colour(Colour { r, g, b, a }));
+        assert_eq!(parse_tag("color=#ff0").ok(), rgba(0xFF, 0xFF, 0, 0xFF));
+        assert_eq!(parse_tag("color=#ff08").ok(), rgba(0xFF, 0xFF, 0, 0x88));
+        assert_eq!(
+            parse_tag("color=#12ab34").ok(),
+            rgba(0x12, 0xAB, 0x34, 0xFF)
+        );
+        assert_eq!(
+            parse_tag("color=#12ab3456").ok(),
+            rgba(0x12, 0xAB, 0x34, 0x56)
+        );
+    }
+
+    #[test]
+    fn mismatched_close() {
+        assert_eq!(
+            parse("{b}{i}x{/b}{/i}"),
+            Err(Error::MismatchedClose("b", Some("i")))
+        );
+        assert_eq!(parse("x{/b}"), Err(Error::MismatchedClose("b", None)));
+    }
+
+    #[test]
+    fn malformed_tags() {
+        assert_eq!(parse("{b"), Err(Error::UnclosedTag));
+        assert_eq!(parse("{bold}"), Err(Error::UnknownTag("bold".to_string())));
+        assert_eq!(parse("{color}"), Err(Error::MissingValue("color")));
+        assert_eq!(parse("{b=1}"), Err(Error::UnexpectedValue("b")));
+        assert_eq!(parse("{/b=1}"), Err(Error::UnexpectedValue("b")));
+        assert_eq!(parse("{/w}"), Err(Error::NotClosable("w")));
+        for (tag, value) in [
+            ("color", "ff0"),
+            ("color", "#ff"),
+            ("color", "#ggg"),
+            ("speed", "0"),
+            ("speed", "fast"),
+            ("w", "-1"),
+        ] {
+            assert_eq!(
+                parse(&format!("{{{}={}}}", tag, value)),
+                Err(Error::InvalidValue(tag, value.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn tags_are_recognized() {
+        assert!(is_tag("b"));
+        assert!(is_tag("/color"));
+        assert!(is_tag("w=oops"));
+        assert!(!is_tag("gold"));
+        assert!(!is_tag("gold * 2"));
+    }
+}
@@ -0,0 +1,706 @@
+use core::fmt;
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+    rc::Rc,
+    time::SystemTime,
+};
+
+use fxhash::{FxHashMap, FxHashSet};
+
+use super::{
+    diag::Diagnostic,
+    lex::{self, Lexeme},
+    parse::{self, Block, Item, Spanned, Stmt},
+    span::{SourceMap, Span},
+    symbol::Symbol,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The named file could not be read. The span is the import, unless it is the entry file.
+    Read(Symbol, io::Error, Option<Span>),
+    /// The import at the span names a file outside of the game root
+    OutsideRoot(Symbol, Span),
+    /// The import at the span names a file that is still being loaded. The files are the cycle,
+    /// starting and ending with the imported file.
+    ImportCycle(Vec<Symbol>, Span),
+    /// The second span is the previous definition
+    DuplicateName(Symbol, Span, Box<Span>),
+    UnresolvedModule(Symbol, Span),
+    UnresolvedScene(Symbol, Span),
+    /// A `use` of a path with only one segment
+    InvalidUse(Span),
+    Lex(lex::Error),
+    Parse(parse::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(name, err, _) => write!(f, "could not read `{}`: {}", name, err),
+            Self::OutsideRoot(name, _) => write!(f, "`{}` is outside of the game root", name),
+            Self::ImportCycle(files, _) => write!(f, "`{}` imports itself", files[0]),
+            Self::DuplicateName(name, _, _) => {
+                write!(f, "`{}` is defined multiple times", name)
+            }
+            Self::UnresolvedModule(name, _) => write!(f, "cannot find module `{}`", name),
+            Self::UnresolvedScene(name, _) => write!(f, "cannot find scene `{}`", name),
+            Self::InvalidUse(_) => f.write_str("`use` requires the module of the scene"),
+            Self::Lex(err) => err.fmt(f),
+            Self::Parse(err) => err.fmt(f),
+        }
+    }
+}
+
+impl From<Error> for Diagnostic {
+    fn from(err: Error) -> Self {
+        let diag = Diagnostic::error(err.to_string());
+        match err {
+            Error::Read(_, _, Some(span)) => diag.with_label(span, "imported here"),
+            Error::Read(_, _, None) => diag,
+            Error::OutsideRoot(_, span) => diag
+                .with_label(span, "")
+                .with_note("imports are relative to the directory of the entry script"),
+            Error::ImportCycle(files, span) => diag.with_label(span, "").with_note(format!(
+                "the cycle is {}",
+                files
+                    .iter()
+                    .map(|file| format!("`{}`", file))
+                    .collect::<Vec<_>>()
+                    .join(" -> ")
+            )),
+            Error::DuplicateName(_, span, prev) => diag
+                .with_label(span, "")
+                .with_secondary(*prev, "previously defined here"),
+            Error::UnresolvedModule(_, span) => diag.with_label(span, "").with_note(
+                "a module is named after its path from the game root, so `chapters/one.vns` is \
+                 `chapters::one`, and must be imported by the file using it",
+            ),
+            Error::UnresolvedScene(_, span) => diag.with_label(span, ""),
+            Error::InvalidUse(span) => diag
+                .with_label(span, "")
+                .with_note("scenes in the same file can be used without `use`"),
+            Error::Lex(err) => err.into(),
+            Error::Parse(err) => err.into(),
+        }
+    }
+}
+
+impl From<lex::Error> for Error {
+    fn from(err: lex::Error) -> Self {
+        Self::Lex(err)
+    }
+}
+
+impl From<parse::Error> for Error {
+    fn from(err: parse::Error) -> Self {
+        Self::Parse(err)
+    }
+}
+
+/// A lexed file, kept so that it is only lexed again once it changes
+struct CachedFile {
+    /// The file name used in the spans of `lexemes`
+    name: Symbol,
+    modified: Option<SystemTime>,
+    text: Rc<str>,
+    lexemes: Vec<Lexeme>,
+}
+
+/// A file loaded by a [`Loader::load`]
+struct Module {
+    name: Symbol,
+    /// Prefixed to the names of the scenes in the file. Empty for the entry file.
+    prefix: String,
+    /// The scenes defined in the file, or `None` if it could not be loaded. Names in a file that
+    /// failed to load are not checked, as the errors would only repeat the failure.
+    scenes: Option<FxHashSet<Symbol>>,
+}
+
+impl Module {
+    fn qualify(&self, name: Symbol) -> Symbol {
+        if self.prefix.is_empty() {
+            name
+        } else {
+            format!("{}::{}", self.prefix, name).into()
+        }
+    }
+}
+
+/// State shared by the whole of a [`Loader::load`]
+#[derive(Default)]
+struct LoadState {
+    modules: Vec<Module>,
+    by_path: FxHashMap<PathBuf, usize>,
+    /// The files currently being loaded, outermost first
+    stack: Vec<PathBuf>,
+    items: Vec<Spanned<Item>>,
+    errors: Vec<Error>,
+}
+
+/// The names scenes can be referred to by in one file
+struct Scope<'a> {
+    modules: &'a [Module],
+    this: usize,
+    /// Imported modules, by the name they are bound to
+    imports: FxHashMap<Symbol, (usize, Span)>,
+    /// Scenes brought in by `use`, by their unqualified name, with their qualified name
+    uses: FxHashMap<Symbol, (Symbol, Span)>,
+}
+
+impl Scope<'_> {
+    /// The qualified name of the scene `path` refers to
+    fn resolve(&self, path: &Spanned<Vec<Spanned<Symbol>>>) -> Result<Symbol, Error> {
+        let (name, module_path) = path.body.split_last().unwrap();
+        let module = if module_path.is_empty() {
+            if let Some((qualified, _)) = self.uses.get(&name.body) {
+                return Ok(*qualified);
+            }
+            &self.modules[self.this]
+        } else {
+            let module_name = join_path(module_path);
+            let span = module_path[0].span.join(module_path.last().unwrap().span);
+            match self.imports.get(&module_name) {
+                Some(&(idx, _)) => &self.modules[idx],
+                None => return Err(Error::UnresolvedModule(module_name, span)),
+            }
+        };
+        match &module.scenes {
+            Some(scenes) if !scenes.contains(&name.body) => {
+                Err(Error::UnresolvedScene(join_path(&path.body), path.span))
+            }
+            _ => Ok(module.qualify(name.body)),
+        }
+    }
+}
+
+/// The name of the module of the file at `relative`, a path from the game root. The module of
+/// `chapters/one.vns` is `chapters::one`.
+fn module_path(relative: &Path) -> String {
+    relative
+        .with_extension("")
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(seg) => Some(seg.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("::")
+}
+
+fn join_path(segments: &[Spanned<Symbol>]) -> Symbol {
+    segments
+        .iter()
+        .map(|seg| seg.as_str())
+        .collect::<Vec<_>>()
+        .join("::")
+        .into()
+}
+
+/// Loads a script and the files it imports. Lexed files are cached, so loading again only
+/// lexes the files that have changed since.
+pub struct Loader {
+    /// Imports are relative to this directory
+    root: PathBuf,
+    cache: FxHashMap<PathBuf, CachedFile>,
+}
+
+impl Loader {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            cache: FxHashMap::default(),
+        }
+    }
+
+    /// Loads the script at `entry` and every file it imports, adding their text to `sources`.
+    ///
+    /// The scenes of imported files are named with their path from the root, so the scene
+    /// `intro` of `chapters/one.vns` is `chapters::one::intro`, while those of `entry` keep
+    /// their own names. Every `jump` and `call` refers to its scene by that name. A file that
+    /// imports `chapters/one.vns` names the scene the same way, as `chapters::one::intro`.
+    pub fn load(
+        &mut self,
+        entry: &Path,
+        sources: &mut SourceMap,
+    ) -> core::result::Result<Vec<Spanned<Item>>, Vec<Error>> {
+        let mut state = LoadState::default();
+        self.load_file(&mut state, sources, entry, String::new(), None);
+        if state.errors.is_empty() {
+            Ok(state.items)
+        } else {
+            Err(state.errors)
+        }
+    }
+
+    /// The path of the root that files are compared against, to find their module path
+    fn canonical_root(&self) -> PathBuf {
+        let root = if self.root.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            &self.root
+        };
+        root.canonicalize().unwrap_or_else(|_| root.to_path_buf())
+    }
+
+    /// Loads an imported file, given the path written in its import
+    fn import(
+        &mut self,
+        state: &mut LoadState,
+        sources: &mut SourceMap,
+        path: &Spanned<String>,
+    ) -> usize {
+        let full = self.root.join(&path.body);
+        let name = Symbol::from(&*full.to_string_lossy());
+        let failed = |state: &mut LoadState, err| {
+            state.errors.push(err);
+            state.modules.push(Module {
+                name,
+                prefix: module_path(Path::new(&path.body)),
+                scenes: None,
+            });
+            state.modules.len() - 1
+        };
+        let canonical = match full.canonicalize() {
+            Ok(canonical) => canonical,
+            Err(err) => return failed(state, Error::Read(name, err, Some(path.span))),
+        };
+        let Ok(relative) = canonical.strip_prefix(self.canonical_root()) else {
+            return failed(state, Error::OutsideRoot(name, path.span));
+        };
+        let prefix = module_path(relative);
+        self.load_file(state, sources, &full, prefix, Some(path.span))
+    }
+
+    /// Loads the file at `path` unless it was already, returning its index in
+    /// `state.modules`
+    fn load_file(
+        &mut self,
+        state: &mut LoadState,
+        sources: &mut SourceMap,
+        path: &Path,
+        prefix: String,
+        import: Option<Span>,
+    ) -> usize {
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        if let Some(&idx) = state.by_path.get(&canonical) {
+            if let Some(start) = state.stack.iter().position(|file| *file == canonical) {
+                let mut files = state.stack[start..]
+                    .iter()
+                    .map(|file| state.modules[state.by_path[file]].name)
+                    .collect::<Vec<_>>();
+                files.push(state.modules[idx].name);
+                state
+                    .errors
+                    .push(Error::ImportCycle(files, import.unwrap()));
+            }
+            return idx;
+        }
+
+        let name = Symbol::from(&*path.to_string_lossy());
+        let items = match self.lex_file(&canonical, name, sources) {
+            Ok(lexemes) => parse::do_file(&mut parse::TokenStream::new(lexemes))
+                .map_err(|errs| errs.into_iter().map(Error::from).collect()),
+            Err(errs) => Err(errs),
+        };
+        let items = match items {
+            Ok(items) => items,
+            Err(errs) => {
+                let errs = errs.into_iter().map(|err| match err {
+                    Error::Read(name, err, _) => Error::Read(name, err, import),
+                    err => err,
+                });
+                state.errors.extend(errs);
+                state.modules.push(Module {
+                    name,
+                    prefix,
+                    scenes: None,
+                });
+                state.by_path.insert(canonical, state.modules.len() - 1);
+                return state.modules.len() - 1;
+            }
+        };
+
+        let this = state.modules.len();
+        state.modules.push(Module {
+            name,
+            prefix,
+            scenes: Some(
+                items
+                    .iter()
+                    .filter_map(|item| match &item.body {
+                        Item::Scene(scene) => Some(scene.name.body),
+                        _ => None,
+                    })
+                    .collect(),
+            ),
+        });
+        state.by_path.insert(canonical.clone(), this);
+
+        // Imported files are loaded first, so their globals are defined before this file's
+        state.stack.push(canonical);
+        let mut imports = FxHashMap::<Symbol, (usize, Span)>::default();
+        for item in &items {
+            if let Item::Import(path) = &item.body {
+                let idx = self.import(state, sources, path);
+                // Bound by the prefix of its scenes, so both name them the same way
+                let binding = Symbol::from(&*state.modules[idx].prefix);
+                if let Some((_, prev)) = imports.get(&binding) {
+                    state
+                        .errors
+                        .push(Error::DuplicateName(binding, path.span, Box::new(*prev)));
+                } else {
+                    imports.insert(binding, (idx, path.span));
+                }
+            }
+        }
+        state.stack.pop();
+
+        let mut scope = Scope {
+            modules: &state.modules,
+            this,
+            imports,
+            uses: FxHashMap::default(),
+        };
+        let scene_spans = items
+            .iter()
+            .filter_map(|item| match &item.body {
+                Item::Scene(scene) => Some((scene.name.body, scene.name.span)),
+                _ => None,
+            })
+            .collect::<FxHashMap<_, _>>();
+        for item in &items {
+            let Item::Use(path) = &item.body else {
+                continue;
+            };
+            if path.body.len() < 2 {
+                state.errors.push(Error::InvalidUse(path.span));
+                continue;
+            }
+            let name = path.body.last().unwrap();
+            let prev = scene_spans
+                .get(&name.body)
+                .or_else(|| scope.uses.get(&name.body).map(|(_, span)| span));
+            if let Some(prev) = prev {
+                state
+                    .errors
+                    .push(Error::DuplicateName(name.body, name.span, Box::new(*prev)));
+                continue;
+            }
+            match scope.resolve(path) {
+                Ok(qualified) => {
+                    scope.uses.insert(name.body, (qualified, name.span));
+                }
+                Err(err) => state.errors.push(err),
+            }
+        }
+
+        for mut item in items {
+            if let Item::Scene(scene) = &mut item.body {
+                scene.name.body = scope.modules[this].qualify(scene.name.body);
+                resolve_block(&scope, &mut scene.body, &mut state.errors);
+            }
+            state.items.push(item);
+        }
+        this
+    }
+
+    /// Lexes the file at `path`, or takes its lexemes from the cache if it has not changed
+    fn lex_file(
+        &mut self,
+        path: &Path,
+        name: Symbol,
+        sources: &mut SourceMap,
+    ) -> core::result::Result<Vec<Lexeme>, Vec<Error>> {
+        let modified = path.metadata().and_then(|meta| meta.modified()).ok();
+        if let Some(cached) = self.cache.get(path) {
+            if modified.is_some() && cached.modified == modified {
+                sources.add(cached.name, cached.text.clone());
+                return Ok(cached.lexemes.clone());
+            }
+        }
+
+        let text =
+            std::fs::read_to_string(path).map_err(|err| vec![Error::Read(name, err, None)])?;
+        let text = Rc::<str>::from(text);
+        sources.add(name, text.clone());
+        let mut lexemes = lex::lex(&mut text.chars(), name)
+            .map_err(|errs| errs.into_iter().map(Error::from).collect::<Vec<_>>())?;
+        lex::filter_comments(&mut lexemes);
+        self.cache.insert(
+            path.to_path_buf(),
+            CachedFile {
+                name,
+                modified,
+                text,
+                lexemes: lexemes.clone(),
+            },
+        );
+        Ok(lexemes)
+    }
+}
+
+fn resolve_block(scope: &Scope, block: &mut Spanned<Block>, errors: &mut Vec<Error>) {
+    let mut stmts = block.body.stmts.to_vec();
+    for stmt in &mut stmts {
+        resolve_stmt(scope, stmt, errors);
+    }
+    block.body.stmts = stmts.into();
+}
+
+/// Replaces the targets of every `jump` and `call` in `stmt` with the qualified names of their
+/// scenes
+fn resolve_stmt(scope: &Scope, stmt: &mut Spanned<Stmt>, errors: &mut Vec<Error>) {
+    match &mut stmt.body {
+        Stmt::Block(block) | Stmt::Loop(block) | Stmt::While(_, block) => {
+            resolve_block(scope, block, errors)
+        }
+        Stmt::If(_, then, else_branch) => {
+            resolve_block(scope, then, errors);
+            if let Some(else_branch) = else_branch {
+                resolve_stmt(scope, else_branch, errors);
+            }
+        }
+        Stmt::Match(_, arms) => {
+            for arm in arms {
+                resolve_block(scope, &mut arm.body, errors);
+            }
+        }
+        Stmt::Choice(arms) => {
+            for arm in arms {
+                resolve_block(scope, &mut arm.body, errors);
+            }
+        }
+        Stmt::Jump(target) | Stmt::Call(target) => match scope.resolve(target) {
+            Ok(name) => target.body = vec![Spanned::new(name, target.span)],
+            Err(err) => errors.push(err),
+        },
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, time::Duration};
+
+    use super::*;
+
+    /// A directory of script files, removed when dropped
+    struct Game {
+        root: PathBuf,
+    }
+
+    impl Game {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let root = std::env::temp_dir().join(format!(
+                "vn-engine-module-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&root);
+            let game = Self { root };
+            for (path, text) in files {
+                game.write(path, text);
+            }
+            game
+        }
+
+        fn write(&self, path: &str, text: &str) {
+            let path = self.root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+
+        fn load(&self, loader: &mut Loader) -> Result<Vec<Spanned<Item>>, Vec<Error>> {
+            loader.load(&self.root.join("main.vns"), &mut SourceMap::new())
+        }
+    }
+
+    impl Drop for Game {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    /// The name of every scene, with the scenes each one jumps to or calls
+    fn scenes(items: &[Spanned<Item>]) -> Vec<(String, Vec<String>)> {
+        fn targets(block: &Block, out: &mut Vec<String>) {
+            for stmt in block.stmts.iter() {
+                match &stmt.body {
+                    Stmt::Jump(target) | Stmt::Call(target) => {
+                        out.push(join_path(&target.body).to_string())
+                    }
+                    Stmt::Block(block) => targets(block, out),
+                    _ => {}
+                }
+            }
+        }
+        items
+            .iter()
+            .filter_map(|item| match &item.body {
+                Item::Scene(scene) => {
+                    let mut out = Vec::new();
+                    targets(&scene.body, &mut out);
+                    Some((scene.name.to_string(), out))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn owned(scenes: &[(&str, &[&str])]) -> Vec<(String, Vec<String>)> {
+        scenes
+            .iter()
+            .map(|(name, targets)| {
+                let targets = targets.iter().map(|target| target.to_string()).collect();
+                (name.to_string(), targets)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn imported_scenes_are_named_by_their_path() {
+        let game = Game::new(
+            "paths",
+            &[
+                (
+                    "main.vns",
+                    r#"
+                        import "chapters/one.vns";
+                        use chapters::one::intro;
+                        scene main { call intro; jump chapters::one::outro }
+                    "#,
+                ),
+                (
+                    "chapters/one.vns",
+                    r#"
+                        import "chapters/two.vns";
+                        scene intro { jump outro }
+                        scene outro { jump chapters::two::end }
+                    "#,
+                ),
+                ("chapters/two.vns", "scene end {}"),
+            ],
+        );
+        let items = game.load(&mut Loader::new(&game.root)).unwrap();
+        assert_eq!(
+            scenes(&items),
+            owned(&[
+                ("chapters::two::end", &[]),
+                ("chapters::one::intro", &["chapters::one::outro"]),
+                ("chapters::one::outro", &["chapters::two::end"]),
+                ("main", &["chapters::one::intro", "chapters::one::outro"]),
+            ])
+        );
+    }
+
+    #[test]
+    fn modules_are_not_named_by_their_file_alone() {
+        let game = Game::new(
+            "stem",
+            &[
+                (
+                    "main.vns",
+                    "import \"chapters/one.vns\"; scene main { jump one::intro }",
+                ),
+                ("chapters/one.vns", "scene intro {}"),
+            ],
+        );
+        let errs = game.load(&mut Loader::new(&game.root)).unwrap_err();
+        assert!(
+            matches!(&errs[..], [Error::UnresolvedModule(name, _)] if name.as_str() == "one"),
+            "{:?}",
+            errs
+        );
+    }
+
+    #[test]
+    fn unknown_scenes_are_reported() {
+        let game = Game::new(
+            "unknown",
+            &[
+                (
+                    "main.vns",
+                    "import \"one.vns\"; scene main { jump one::nowhere; call missing }",
+                ),
+                ("one.vns", "scene intro {}"),
+            ],
+        );
+        let errs = game.load(&mut Loader::new(&game.root)).unwrap_err();
+        let names = errs
+            .iter()
+            .map(|err| match err {
+                Error::UnresolvedScene(name, _) => name.to_string(),
+                err => panic!("{:?}", err),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["one::nowhere", "missing"]);
+    }
+
+    #[test]
+    fn import_cycles_are_reported() {
+        let game = Game::new(
+            "cycle",
+            &[
+                ("main.vns", "import \"a.vns\"; scene main {}"),
+                ("a.vns", "import \"b.vns\";"),
+                ("b.vns", "import \"a.vns\";"),
+            ],
+        );
+        let errs = game.load(&mut Loader::new(&game.root)).unwrap_err();
+        let [Error::ImportCycle(files, _)] = &errs[..] else {
+            panic!("{:?}", errs)
+        };
+        let files = files
+            .iter()
+            .map(|file| Path::new(file.as_str()).file_name().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(files, ["a.vns", "b.vns", "a.vns"]);
+    }
+
+    #[test]
+    fn imports_outside_the_root_are_refused() {
+        let game = Game::new(
+            "outside",
+            &[
+                ("game/main.vns", "import \"../other.vns\"; scene main {}"),
+                ("other.vns", "scene other {}"),
+            ],
+        );
+        let mut loader = Loader::new(game.root.join("game"));
+        let errs = loader
+            .load(&game.root.join("game/main.vns"), &mut SourceMap::new())
+            .unwrap_err();
+        assert!(matches!(&errs[..], [Error::OutsideRoot(..)]), "{:?}", errs);
+    }
+
+    #[test]
+    fn unchanged_files_are_not_lexed_again() {
+        let game = Game::new(
+            "cache",
+            &[
+                ("main.vns", "import \"one.vns\"; scene main {}"),
+                ("one.vns", "scene a {}"),
+            ],
+        );
+        let mut loader = Loader::new(&game.root);
+        let file = fs::File::options()
+            .write(true)
+            .open(game.root.join("one.vns"))
+            .unwrap();
+        let modified = file.metadata().unwrap().modified().unwrap();
+        game.load(&mut loader).unwrap();
+
+        // The text changes but the time it was modified does not, so the cached lexemes are used
+        game.write("one.vns", "scene b {}");
+        file.set_modified(modified).unwrap();
+        let names = scenes(&game.load(&mut loader).unwrap());
+        assert_eq!(names[0].0, "one::a");
+
+        file.set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        let names = scenes(&game.load(&mut loader).unwrap());
+        assert_eq!(names[0].0, "one::b");
+    }
+}
@@ -1,9 +1,26 @@
+use core::{fmt, ops::Deref, time::Duration};
+use std::rc::Rc;
+
+use peekmore::{PeekMore, PeekMoreIterator};
+
+use super::{
+    diag::Diagnostic,
+    lex::{
+        self, Group, GroupType, IdentifierType, Lexeme, LexemeBody, LexemeClass, Number,
+        NumberValue, StringType, Token, TokenType,
+    },
+    markup,
+    span::{Pos, Span},
+    symbol::{kw, Symbol},
+};
+
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub enum BinaryOp {
     Add,
     Sub,
     Mul,
     Div,
+    Rem,
     BitAnd,
     BitOr,
     BitXor,
@@ -16,6 +33,7 @@ pub enum BinaryOp {
     SubAssign,
     MulAssign,
     DivAssign,
+    RemAssign,
     BitAndAssign,
     BitOrAssign,
     BitXorAssign,
@@ -29,11 +47,1735 @@ pub enum BinaryOp {
     CmpGe,
 }
 
+impl BinaryOp {
+    pub fn from_punct(punct: &str) -> Option<Self> {
+        Some(match punct {
+            "+" => Self::Add,
+            "-" => Self::Sub,
+            "*" => Self::Mul,
+            "/" => Self::Div,
+            "%" => Self::Rem,
+            "&" => Self::BitAnd,
+            "|" => Self::BitOr,
+            "^" => Self::BitXor,
+            "&&" => Self::LogicAnd,
+            "||" => Self::LogicOr,
+            "<<" => Self::LeftShift,
+            ">>" => Self::RightShift,
+            "=" => Self::Assign,
+            "+=" => Self::AddAssign,
+            "-=" => Self::SubAssign,
+            "*=" => Self::MulAssign,
+            "/=" => Self::DivAssign,
+            "%=" => Self::RemAssign,
+            "&=" => Self::BitAndAssign,
+            "|=" => Self::BitOrAssign,
+            "^=" => Self::BitXorAssign,
+            "<<=" => Self::LeftShiftAssign,
+            ">>=" => Self::RightShiftAssign,
+            "==" => Self::CmpEq,
+            "!=" => Self::CmpNe,
+            "<" => Self::CmpLt,
+            ">" => Self::CmpGt,
+            "<=" => Self::CmpLe,
+            ">=" => Self::CmpGe,
+            _ => return None,
+        })
+    }
+
+    /// Binding strength of the operator. Higher binds tighter.
+    pub fn precedence(self) -> u8 {
+        match self {
+            Self::Assign
+            | Self::AddAssign
+            | Self::SubAssign
+            | Self::MulAssign
+            | Self::DivAssign
+            | Self::RemAssign
+            | Self::BitAndAssign
+            | Self::BitOrAssign
+            | Self::BitXorAssign
+            | Self::LeftShiftAssign
+            | Self::RightShiftAssign => 1,
+            Self::LogicOr => 2,
+            Self::LogicAnd => 3,
+            Self::CmpEq | Self::CmpNe | Self::CmpLt | Self::CmpGt | Self::CmpLe | Self::CmpGe => 4,
+            Self::BitOr => 5,
+            Self::BitXor => 6,
+            Self::BitAnd => 7,
+            Self::LeftShift | Self::RightShift => 8,
+            Self::Add | Self::Sub => 9,
+            Self::Mul | Self::Div | Self::Rem => 10,
+        }
+    }
+
+    pub fn is_assignment(self) -> bool {
+        self.precedence() == 1
+    }
+
+    /// For a compound assignment (`+=` etc.), the operator applied before storing.
+    pub fn compound_op(self) -> Option<Self> {
+        Some(match self {
+            Self::AddAssign => Self::Add,
+            Self::SubAssign => Self::Sub,
+            Self::MulAssign => Self::Mul,
+            Self::DivAssign => Self::Div,
+            Self::RemAssign => Self::Rem,
+            Self::BitAndAssign => Self::BitAnd,
+            Self::BitOrAssign => Self::BitOr,
+            Self::BitXorAssign => Self::BitXor,
+            Self::LeftShiftAssign => Self::LeftShift,
+            Self::RightShiftAssign => Self::RightShift,
+            _ => return None,
+        })
+    }
+}
+
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub enum UnaryOp {
     Neg,
     Not,
+    BitNot,
+}
+
+impl UnaryOp {
+    pub fn from_punct(punct: &str) -> Option<Self> {
+        match punct {
+            "-" => Some(Self::Neg),
+            "!" => Some(Self::Not),
+            "~" => Some(Self::BitNot),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Spanned<T> {
+    pub body: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(body: T, span: Span) -> Self {
+        Self { body, span }
+    }
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.body
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    Int(i64),
+    Float(f64),
+    /// A number with a time unit, such as `2.5s` or `300ms`
+    Duration(Duration),
+    Bool(bool),
+    Char(char),
+    String(String),
+    Symbol(Symbol),
+}
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Literal(Literal),
+    Ident(Symbol),
+    Path(Vec<Spanned<Symbol>>),
+    List(Vec<Spanned<Expr>>),
+    Call(Box<Spanned<Expr>>, Vec<Spanned<Expr>>),
+    Field(Box<Spanned<Expr>>, Spanned<Symbol>),
+    Index(Box<Spanned<Expr>>, Box<Spanned<Expr>>),
+    Unary(Spanned<UnaryOp>, Box<Spanned<Expr>>),
+    Binary(Spanned<BinaryOp>, Box<Spanned<Expr>>, Box<Spanned<Expr>>),
+    /// A string with embedded expressions, such as `"You have {gold} coins"`. The pieces are
+    /// displayed and joined in order.
+    Format(Vec<Spanned<Expr>>),
+    /// A markup tag written in a string, which is only ever a piece of an [`Expr::Format`]
+    Markup(markup::Tag),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    UnexpectedToken(Box<Lexeme>, Vec<LexemeClass>),
+    UnexpectedEof(Pos, Vec<LexemeClass>),
+    InvalidLiteral(Box<Lexeme>),
+    /// An error lexing an expression embedded in a string
+    Lex(lex::Error),
+    /// A `{` in a string without a matching `}`
+    UnclosedInterpolation(Span),
+    /// A `}` in a string that does not close an embedded expression
+    UnmatchedBrace(Span),
+    EmptyInterpolation(Span),
+    Markup(markup::Error, Span),
+}
+
+/// Writes `classes` as an English list, eg. "`a`, `b`, or `c`"
+fn write_expected(f: &mut fmt::Formatter<'_>, classes: &[LexemeClass]) -> fmt::Result {
+    for (i, class) in classes.iter().enumerate() {
+        match i {
+            0 => {}
+            i if i + 1 == classes.len() && i == 1 => f.write_str(" or ")?,
+            i if i + 1 == classes.len() => f.write_str(", or ")?,
+            _ => f.write_str(", ")?,
+        }
+        write!(f, "{}", class)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedToken(lexeme, expected) => {
+                f.write_str("expected ")?;
+                write_expected(f, expected)?;
+                match LexemeClass::of(Some(lexeme)) {
+                    LexemeClass::Keyword(kw) => write!(f, ", found keyword `{}`", kw),
+                    found => write!(f, ", found {}", found),
+                }
+            }
+            Self::UnexpectedEof(_, expected) => {
+                f.write_str("expected ")?;
+                write_expected(f, expected)?;
+                f.write_str(", found end of input")
+            }
+            Self::InvalidLiteral(_) => f.write_str("invalid literal"),
+            Self::Lex(err) => err.fmt(f),
+            Self::UnclosedInterpolation(_) => f.write_str("unclosed `{` in string"),
+            Self::UnmatchedBrace(_) => f.write_str("unmatched `}` in string"),
+            Self::EmptyInterpolation(_) => f.write_str("empty expression in string"),
+            Self::Markup(err, _) => err.fmt(f),
+        }
+    }
+}
+
+impl From<Error> for Diagnostic {
+    fn from(err: Error) -> Self {
+        let diag = Diagnostic::error(err.to_string());
+        match err {
+            Error::UnexpectedToken(lexeme, expected) => {
+                let found = LexemeClass::of(Some(&lexeme));
+                let diag = diag.with_label(lexeme.span, format!("unexpected {}", found));
+                match found {
+                    LexemeClass::Keyword(kw) if expected.contains(&LexemeClass::Identifier) => diag
+                        .with_note(format!(
+                            "keywords can be used as names when written as raw identifiers, like `r#{}`",
+                            kw
+                        )),
+                    _ => diag,
+                }
+            }
+            Error::UnexpectedEof(pos, _) => {
+                diag.with_label(Span::at(pos), "the enclosing group or file ends here")
+            }
+            Error::InvalidLiteral(lexeme) => diag.with_label(lexeme.span, ""),
+            Error::Lex(err) => err.into(),
+            Error::UnclosedInterpolation(span) => diag
+                .with_label(span, "embedded expression starts here")
+                .with_note("use `{{` to write a literal `{`"),
+            Error::UnmatchedBrace(span) => diag
+                .with_label(span, "")
+                .with_note("use `}}` to write a literal `}`"),
+            Error::EmptyInterpolation(span) => diag.with_label(span, "expected an expression"),
+            Error::Markup(_, span) => diag.with_label(span, ""),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// A stream of lexemes at one level of the token tree, as produced by [`super::lex::lex`]
+/// (after [`super::lex::filter_comments`]) or taken from the body of a [`Group`].
+pub struct TokenStream {
+    inner: PeekMoreIterator<std::vec::IntoIter<Lexeme>>,
+    end: Pos,
+}
+
+impl TokenStream {
+    pub fn new(lexemes: Vec<Lexeme>) -> Self {
+        let end = lexemes.last().map(|l| l.span.end).unwrap_or_default();
+        Self::with_end(lexemes, end)
+    }
+
+    /// A stream of `lexemes` that reports `end` as the position of the end of input
+    pub fn with_end(lexemes: Vec<Lexeme>, end: Pos) -> Self {
+        Self {
+            inner: lexemes.into_iter().peekmore(),
+            end,
+        }
+    }
+
+    /// The body of `group`. The end of input is reported at the closing delimiter.
+    pub fn from_group(group: Group) -> Self {
+        Self {
+            end: group.close.start,
+            inner: group.body.into_iter().peekmore(),
+        }
+    }
+
+    pub fn peek(&mut self) -> Option<&Lexeme> {
+        self.inner.peek_nth(0)
+    }
+
+    pub fn peek_nth(&mut self, n: usize) -> Option<&Lexeme> {
+        self.inner.peek_nth(n)
+    }
+
+    pub fn peek_class(&mut self) -> LexemeClass {
+        LexemeClass::of(self.peek())
+    }
+
+    pub fn end(&self) -> Pos {
+        self.end
+    }
+
+    pub fn is_empty(&mut self) -> bool {
+        self.peek().is_none()
+    }
+
+    /// Errors unless every lexeme in the stream has been consumed
+    pub fn finish(mut self) -> Result<()> {
+        match self.inner.next() {
+            Some(lexeme) => Err(Error::UnexpectedToken(
+                Box::new(lexeme),
+                vec![LexemeClass::Eof],
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Iterator for TokenStream {
+    type Item = Lexeme;
+
+    fn next(&mut self) -> Option<Lexeme> {
+        self.inner.next()
+    }
+}
+
+pub fn do_lexeme_class(tokens: &mut TokenStream, class: LexemeClass) -> Result<Lexeme> {
+    do_lexeme_classes(tokens, &[class]).map(|(lexeme, _)| lexeme)
+}
+
+pub fn do_lexeme_classes(
+    tokens: &mut TokenStream,
+    classes: &[LexemeClass],
+) -> Result<(Lexeme, LexemeClass)> {
+    let class = tokens.peek_class();
+    for expected in classes {
+        if class.is(expected) {
+            return match tokens.next() {
+                Some(lexeme) => Ok((lexeme, class)),
+                None => Err(Error::UnexpectedEof(tokens.end(), classes.to_vec())),
+            };
+        }
+    }
+    match tokens.next() {
+        Some(lexeme) => Err(Error::UnexpectedToken(Box::new(lexeme), classes.to_vec())),
+        None => Err(Error::UnexpectedEof(tokens.end(), classes.to_vec())),
+    }
+}
+
+/// Consumes the punctuation `punct` if it is next in the stream
+pub fn eat_punct(tokens: &mut TokenStream, punct: &str) -> Option<Lexeme> {
+    match tokens.peek_class() {
+        LexemeClass::Punctuation(p) if p == *punct => tokens.next(),
+        _ => None,
+    }
+}
+
+pub fn do_ident(tokens: &mut TokenStream) -> Result<Spanned<Symbol>> {
+    let lexeme = do_lexeme_class(tokens, LexemeClass::Identifier)?;
+    match lexeme.body {
+        LexemeBody::Token(Token {
+            ty: TokenType::Identifier(ty),
+            body,
+            ..
+        }) => Ok(Spanned::new(ident_name(ty, body), lexeme.span)),
+        _ => unreachable!(),
+    }
+}
+
+fn ident_name(ty: IdentifierType, body: Symbol) -> Symbol {
+    match ty {
+        IdentifierType::Raw => body.strip_prefix("r#").unwrap_or(&body).into(),
+        _ => body,
+    }
+}
+
+/// Consumes `true` or `false` if it is next in the stream
+fn eat_bool(tokens: &mut TokenStream) -> Option<Spanned<Literal>> {
+    [(kw::TRUE, true), (kw::FALSE, false)]
+        .into_iter()
+        .find_map(|(kw, val)| {
+            Some(Spanned::new(
+                Literal::Bool(val),
+                eat_keyword(tokens, kw)?.span,
+            ))
+        })
+}
+
+/// The text between the quotes of a string token
+fn string_contents(body: &str) -> &str {
+    let start = body.find('"').map_or(0, |i| i + 1);
+    let end = body
+        .rfind('"')
+        .filter(|&end| end >= start)
+        .unwrap_or(body.len());
+    &body[start..end]
+}
+
+/// Decodes the escape sequences in the contents of a string
+fn unescape(text: &[char]) -> String {
+    let mut result = String::new();
+    let mut i = 0;
+    while i < text.len() {
+        match text[i] {
+            '\\' => match lex::decode_escape(&text[i + 1..]) {
+                Some((c, len)) => {
+                    result.push(c);
+                    i += len + 1;
+                }
+                // Already reported by the lexer
+                None => {
+                    result.push('\\');
+                    i += 1;
+                }
+            },
+            c => {
+                result.push(c);
+                i += 1;
+            }
+        }
+    }
+    result
+}
+
+/// Splits a string token with the text `body` into its text, the expressions embedded in it
+/// with `{...}`, and its markup tags. Escapes in the text are decoded, as are the `{{` and `}}`
+/// that stand for literal braces.
+fn do_interpolated(body: &str, span: Span) -> Result<Spanned<Expr>> {
+    let chars = body.chars().collect::<Vec<_>>();
+    // The byte offset of each character, and of the end of the body
+    let offsets = body
+        .char_indices()
+        .map(|(idx, _)| idx)
+        .chain([body.len()])
+        .collect::<Vec<_>>();
+    // String tokens are on a single line, so each character is one column on from the last
+    let pos = |i: usize| Pos {
+        col: span.start.col + i,
+        idx: span.start.idx + offsets[i],
+        ..span.start
+    };
+    let end = match chars.last() {
+        Some('"') if chars.len() > 1 => chars.len() - 1,
+        _ => chars.len(),
+    };
+
+    let mut parts = Vec::new();
+    let mut text = Vec::new();
+    let mut text_start = 1;
+    let flush = |text: &mut Vec<char>, parts: &mut Vec<Spanned<Expr>>, start, end| {
+        if !text.is_empty() {
+            let lit = Literal::String(unescape(text));
+            parts.push(Spanned::new(
+                Expr::Literal(lit),
+                Span::new_simple(pos(start), pos(end)),
+            ));
+            text.clear();
+        }
+    };
+    let mut i = 1;
+    while i < end {
+        match chars[i] {
+            '\\' => {
+                // Keep the escape whole, so that `\{` is not taken as an interpolation
+                let len = lex::decode_escape(&chars[i + 1..end]).map_or(1, |(_, len)| len + 1);
+                text.extend_from_slice(&chars[i..i + len]);
+                i += len;
+            }
+            c @ ('{' | '}') if chars.get(i + 1) == Some(&c) && i + 1 < end => {
+                text.push(c);
+                i += 2;
+            }
+            '}' => return Err(Error::UnmatchedBrace(Span::new_simple(pos(i), pos(i + 1)))),
+            '{' => {
+                let mut depth = 0;
+                let close = (i + 1..end).find(|&j| {
+                    match chars[j] {
+                        '{' => depth += 1,
+                        '}' if depth == 0 => return true,
+                        '}' => depth -= 1,
+                        _ => {}
+                    }
+                    false
+                });
+                let Some(close) = close else {
+                    return Err(Error::UnclosedInterpolation(Span::new_simple(
+                        pos(i),
+                        pos(i + 1),
+                    )));
+                };
+                let source = &chars[i + 1..close];
+                let content = source.iter().collect::<String>();
+                flush(&mut text, &mut parts, text_start, i);
+                if markup::is_tag(&content) {
+                    let span = Span::new_simple(pos(i), pos(close + 1));
+                    let tag =
+                        markup::parse_tag(&content).map_err(|err| Error::Markup(err, span))?;
+                    parts.push(Spanned::new(Expr::Markup(tag), span));
+                    i = close + 1;
+                    text_start = i;
+                    continue;
+                }
+                if source.iter().all(|c| c.is_whitespace()) {
+                    return Err(Error::EmptyInterpolation(Span::new_simple(
+                        pos(i),
+                        pos(close + 1),
+                    )));
+                }
+                let mut lexemes = lex::lex_at(&mut source.iter().copied(), pos(i + 1))
+                    .map_err(|mut errs| Error::Lex(errs.remove(0)))?;
+                lex::filter_comments(&mut lexemes);
+                let mut inner = TokenStream::with_end(lexemes, pos(close));
+                parts.push(do_expr(&mut inner)?);
+                inner.finish().map_err(|err| match err {
+                    Error::UnexpectedToken(lexeme, _) => {
+                        Error::UnexpectedToken(lexeme, vec![LexemeClass::Punctuation("}".into())])
+                    }
+                    err => err,
+                })?;
+                i = close + 1;
+                text_start = i;
+            }
+            c => {
+                text.push(c);
+                i += 1;
+            }
+        }
+    }
+    flush(&mut text, &mut parts, text_start, end);
+
+    let expr = match &parts[..] {
+        [] => Expr::Literal(Literal::String(String::new())),
+        [Spanned {
+            body: Expr::Literal(Literal::String(_)),
+            ..
+        }] => parts.pop().unwrap().body,
+        _ => Expr::Format(parts),
+    };
+    Ok(Spanned::new(expr, span))
+}
+
+fn do_literal(lexeme: Lexeme) -> Result<Spanned<Expr>> {
+    let span = lexeme.span;
+    let lit = match &lexeme.body {
+        LexemeBody::Token(Token { ty, body, value }) => match ty {
+            TokenType::Number => match *value {
+                Some(Number {
+                    value,
+                    unit: Some(unit),
+                }) => {
+                    let secs = match value {
+                        NumberValue::Int(i) => i as f64,
+                        NumberValue::Float(f) => f,
+                    } * unit.seconds();
+                    match Duration::try_from_secs_f64(secs) {
+                        Ok(duration) => Literal::Duration(duration),
+                        Err(_) => return Err(Error::InvalidLiteral(Box::new(lexeme))),
+                    }
+                }
+                Some(Number {
+                    value: NumberValue::Int(i),
+                    unit: None,
+                }) => match i64::try_from(i) {
+                    Ok(i) => Literal::Int(i),
+                    Err(_) => return Err(Error::InvalidLiteral(Box::new(lexeme))),
+                },
+                Some(Number {
+                    value: NumberValue::Float(f),
+                    unit: None,
+                }) => Literal::Float(f),
+                None => return Err(Error::InvalidLiteral(Box::new(lexeme))),
+            },
+            TokenType::String(StringType::Default) => return do_interpolated(body, span),
+            TokenType::String(StringType::Byte) => {
+                let contents = string_contents(body).chars().collect::<Vec<_>>();
+                Literal::String(unescape(&contents))
+            }
+            TokenType::String(StringType::Raw(_) | StringType::RawByte(_)) => {
+                Literal::String(string_contents(body).to_string())
+            }
+            TokenType::Character => {
+                let inner = body.strip_prefix('\'').unwrap_or(body);
+                let inner = inner.strip_suffix('\'').unwrap_or(inner);
+                match &inner.chars().collect::<Vec<_>>()[..] {
+                    [c] => Literal::Char(*c),
+                    ['\\', rest @ ..] => match lex::decode_escape(rest) {
+                        Some((c, len)) if len == rest.len() => Literal::Char(c),
+                        _ => return Err(Error::InvalidLiteral(Box::new(lexeme))),
+                    },
+                    _ => return Err(Error::InvalidLiteral(Box::new(lexeme))),
+                }
+            }
+            TokenType::Lifetime => Literal::Symbol(body.trim_start_matches('\'').into()),
+            _ => unreachable!(),
+        },
+        LexemeBody::Group(_) => unreachable!(),
+    };
+    Ok(Spanned::new(Expr::Literal(lit), span))
+}
+
+/// Parses a comma separated list of expressions filling the whole of `tokens`.
+/// A trailing comma is permitted.
+pub fn do_expr_list(tokens: &mut TokenStream) -> Result<Vec<Spanned<Expr>>> {
+    let mut exprs = Vec::new();
+    while !tokens.is_empty() {
+        exprs.push(do_expr(tokens)?);
+        if tokens.is_empty() {
+            break;
+        }
+        do_lexeme_class(tokens, LexemeClass::Punctuation(",".into()))?;
+    }
+    Ok(exprs)
+}
+
+fn do_primary(tokens: &mut TokenStream) -> Result<Spanned<Expr>> {
+    if let Some(lit) = eat_bool(tokens) {
+        return Ok(Spanned::new(Expr::Literal(lit.body), lit.span));
+    }
+    let (lexeme, class) = do_lexeme_classes(
+        tokens,
+        &[
+            LexemeClass::Identifier,
+            LexemeClass::Number,
+            LexemeClass::String,
+            LexemeClass::Character,
+            LexemeClass::Lifetime,
+            LexemeClass::Group(Some(GroupType::Parens)),
+            LexemeClass::Group(Some(GroupType::Brackets)),
+        ],
+    )?;
+    let span = lexeme.span;
+    match (class, lexeme.body) {
+        (
+            LexemeClass::Identifier,
+            LexemeBody::Token(Token {
+                ty: TokenType::Identifier(ty),
+                body,
+                ..
+            }),
+        ) => {
+            let first = Spanned::new(ident_name(ty, body), span);
+            let mut segments = vec![first];
+            while eat_punct(tokens, "::").is_some() {
+                segments.push(do_ident(tokens)?);
+            }
+            if segments.len() == 1 {
+                Ok(Spanned::new(Expr::Ident(segments[0].body), span))
+            } else {
+                let end = segments.last().unwrap().span.end;
+                Ok(Spanned::new(
+                    Expr::Path(segments),
+                    Span::new_simple(span.start, end),
+                ))
+            }
+        }
+        (LexemeClass::Group(Some(GroupType::Parens)), LexemeBody::Group(group)) => {
+            let mut inner = TokenStream::from_group(group);
+            let expr = do_expr(&mut inner)?;
+            inner.finish()?;
+            Ok(Spanned::new(expr.body, span))
+        }
+        (LexemeClass::Group(Some(GroupType::Brackets)), LexemeBody::Group(group)) => {
+            let mut inner = TokenStream::from_group(group);
+            let elems = do_expr_list(&mut inner)?;
+            Ok(Spanned::new(Expr::List(elems), span))
+        }
+        (_, body) => do_literal(Lexeme { span, body }),
+    }
+}
+
+fn do_postfix(tokens: &mut TokenStream) -> Result<Spanned<Expr>> {
+    let mut expr = do_primary(tokens)?;
+    loop {
+        match tokens.peek_class() {
+            LexemeClass::Group(Some(GroupType::Parens)) => {
+                let lexeme = tokens.next().unwrap();
+                let span = expr.span.join(lexeme.span);
+                let LexemeBody::Group(group) = lexeme.body else {
+                    unreachable!()
+                };
+                let args = do_expr_list(&mut TokenStream::from_group(group))?;
+                expr = Spanned::new(Expr::Call(Box::new(expr), args), span);
+            }
+            LexemeClass::Group(Some(GroupType::Brackets)) => {
+                let lexeme = tokens.next().unwrap();
+                let span = expr.span.join(lexeme.span);
+                let LexemeBody::Group(group) = lexeme.body else {
+                    unreachable!()
+                };
+                let mut inner = TokenStream::from_group(group);
+                let idx = do_expr(&mut inner)?;
+                inner.finish()?;
+                expr = Spanned::new(Expr::Index(Box::new(expr), Box::new(idx)), span);
+            }
+            LexemeClass::Punctuation(p) if p == *"." => {
+                tokens.next();
+                let field = do_ident(tokens)?;
+                let span = expr.span.join(field.span);
+                expr = Spanned::new(Expr::Field(Box::new(expr), field), span);
+            }
+            _ => break Ok(expr),
+        }
+    }
+}
+
+fn do_unary(tokens: &mut TokenStream) -> Result<Spanned<Expr>> {
+    let op = match tokens.peek_class() {
+        LexemeClass::Punctuation(p) => UnaryOp::from_punct(&p),
+        _ => None,
+    };
+    if let Some(op) = op {
+        let lexeme = tokens.next().unwrap();
+        let operand = do_unary(tokens)?;
+        let span = lexeme.span.join(operand.span);
+        Ok(Spanned::new(
+            Expr::Unary(Spanned::new(op, lexeme.span), Box::new(operand)),
+            span,
+        ))
+    } else {
+        do_postfix(tokens)
+    }
+}
+
+fn do_binary(tokens: &mut TokenStream, min_prec: u8) -> Result<Spanned<Expr>> {
+    let mut lhs = do_unary(tokens)?;
+    loop {
+        let op = match tokens.peek_class() {
+            LexemeClass::Punctuation(p) => BinaryOp::from_punct(&p),
+            _ => None,
+        };
+        let op = match op {
+            Some(op) if op.precedence() >= min_prec => op,
+            _ => break Ok(lhs),
+        };
+        let op_span = tokens.next().unwrap().span;
+        // Assignments are right associative, everything else is left associative
+        let next_prec = if op.is_assignment() {
+            op.precedence()
+        } else {
+            op.precedence() + 1
+        };
+        let rhs = do_binary(tokens, next_prec)?;
+        let span = lhs.span.join(rhs.span);
+        lhs = Spanned::new(
+            Expr::Binary(Spanned::new(op, op_span), Box::new(lhs), Box::new(rhs)),
+            span,
+        );
+    }
+}
+
+pub fn do_expr(tokens: &mut TokenStream) -> Result<Spanned<Expr>> {
+    do_binary(tokens, 0)
+}
+
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    Wildcard,
+    Literal(Literal),
+    Binding(Symbol),
+    Or(Vec<Spanned<Pattern>>),
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
-pub enum Expr {}
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub stmts: Rc<[Spanned<Stmt>]>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MatchArm {
+    pub pattern: Spanned<Pattern>,
+    pub guard: Option<Spanned<Expr>>,
+    pub body: Spanned<Block>,
+}
+
+/// One option of a `choice` menu
+#[derive(Clone, Debug)]
+pub struct ChoiceArm {
+    pub text: Spanned<Expr>,
+    /// The option is only offered if the guard holds
+    pub guard: Option<Spanned<Expr>>,
+    pub body: Spanned<Block>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Stmt {
+    Empty,
+    Expr(Spanned<Expr>),
+    Let(Spanned<Symbol>, Option<Spanned<Expr>>),
+    /// A line of dialogue. Narration has no speaker.
+    Dialogue(Option<Spanned<Symbol>>, Spanned<Expr>),
+    Block(Spanned<Block>),
+    If(Spanned<Expr>, Spanned<Block>, Option<Box<Spanned<Stmt>>>),
+    Match(Spanned<Expr>, Vec<MatchArm>),
+    Choice(Vec<ChoiceArm>),
+    Loop(Spanned<Block>),
+    While(Spanned<Expr>, Spanned<Block>),
+    Break,
+    Continue,
+    Jump(Spanned<Vec<Spanned<Symbol>>>),
+    Call(Spanned<Vec<Spanned<Symbol>>>),
+    Return,
+    /// `show background image("park.png");`, puts an image on the named layer
+    Show(Spanned<Symbol>, Spanned<Expr>),
+    /// `hide background;`, clears the named layer
+    Hide(Spanned<Symbol>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Scene {
+    pub name: Spanned<Symbol>,
+    pub body: Spanned<Block>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Item {
+    Scene(Scene),
+    /// `character alice = "Alice";`, the expression gives the display name
+    Character(Spanned<Symbol>, Spanned<Expr>),
+    /// A global variable, shared by every scene
+    Let(Spanned<Symbol>, Option<Spanned<Expr>>),
+    /// `import "chapter1.vns";`, the path is relative to the game root
+    Import(Spanned<String>),
+    /// `use chapter1::intro;`, makes a scene of an imported file nameable without its module
+    Use(Spanned<Vec<Spanned<Symbol>>>),
+}
+
+/// Consumes the keyword `kw` if it is next in the stream
+pub fn eat_keyword(tokens: &mut TokenStream, kw: Symbol) -> Option<Lexeme> {
+    match tokens.peek_class() {
+        LexemeClass::Keyword(k) if k == kw => tokens.next(),
+        _ => None,
+    }
+}
+
+pub fn do_path(tokens: &mut TokenStream) -> Result<Spanned<Vec<Spanned<Symbol>>>> {
+    let first = do_ident(tokens)?;
+    let start = first.span.start;
+    let mut end = first.span.end;
+    let mut segments = vec![first];
+    while eat_punct(tokens, "::").is_some() {
+        let seg = do_ident(tokens)?;
+        end = seg.span.end;
+        segments.push(seg);
+    }
+    Ok(Spanned::new(segments, Span::new_simple(start, end)))
+}
+
+/// Parses a string literal, which may have embedded expressions
+fn do_string(tokens: &mut TokenStream) -> Result<Spanned<Expr>> {
+    let lexeme = do_lexeme_class(tokens, LexemeClass::String)?;
+    do_literal(lexeme)
+}
+
+pub fn do_block(tokens: &mut TokenStream) -> Result<Spanned<Block>> {
+    let lexeme = do_lexeme_class(tokens, LexemeClass::Group(Some(GroupType::Braces)))?;
+    let span = lexeme.span;
+    let LexemeBody::Group(group) = lexeme.body else {
+        unreachable!()
+    };
+    let mut inner = TokenStream::from_group(group);
+    let mut stmts = Vec::new();
+    while !inner.is_empty() {
+        stmts.push(do_stmt(&mut inner)?);
+    }
+    Ok(Spanned::new(
+        Block {
+            stmts: stmts.into(),
+        },
+        span,
+    ))
+}
+
+/// The body of a `match` or `choice` arm, either a block or a single statement
+fn do_arm_body(tokens: &mut TokenStream) -> Result<Spanned<Block>> {
+    if let LexemeClass::Group(Some(GroupType::Braces)) = tokens.peek_class() {
+        do_block(tokens)
+    } else {
+        let stmt = do_stmt(tokens)?;
+        let span = stmt.span;
+        Ok(Spanned::new(
+            Block {
+                stmts: Rc::new([stmt]),
+            },
+            span,
+        ))
+    }
+}
+
+fn do_pattern_single(tokens: &mut TokenStream) -> Result<Spanned<Pattern>> {
+    if let Some(minus) = eat_punct(tokens, "-") {
+        let lexeme = do_lexeme_class(tokens, LexemeClass::Number)?;
+        let span = minus.span.join(lexeme.span);
+        return match do_literal(lexeme.clone())?.body {
+            Expr::Literal(Literal::Int(v)) => {
+                Ok(Spanned::new(Pattern::Literal(Literal::Int(-v)), span))
+            }
+            Expr::Literal(Literal::Float(v)) => {
+                Ok(Spanned::new(Pattern::Literal(Literal::Float(-v)), span))
+            }
+            // Durations cannot be negative
+            _ => Err(Error::InvalidLiteral(Box::new(lexeme))),
+        };
+    }
+    if let Some(lit) = eat_bool(tokens) {
+        return Ok(Spanned::new(Pattern::Literal(lit.body), lit.span));
+    }
+    match tokens.peek_class() {
+        LexemeClass::Identifier => {
+            let id = do_ident(tokens)?;
+            let pat = match id.as_str() {
+                "_" => Pattern::Wildcard,
+                _ => Pattern::Binding(id.body),
+            };
+            Ok(Spanned::new(pat, id.span))
+        }
+        _ => {
+            let (lexeme, _) = do_lexeme_classes(
+                tokens,
+                &[
+                    LexemeClass::Number,
+                    LexemeClass::String,
+                    LexemeClass::Character,
+                    LexemeClass::Lifetime,
+                ],
+            )?;
+            let lit = do_literal(lexeme)?;
+            match lit.body {
+                Expr::Literal(l) => Ok(Spanned::new(Pattern::Literal(l), lit.span)),
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+pub fn do_pattern(tokens: &mut TokenStream) -> Result<Spanned<Pattern>> {
+    let first = do_pattern_single(tokens)?;
+    if let LexemeClass::Punctuation(p) = tokens.peek_class() {
+        if p == *"|" {
+            let start = first.span.start;
+            let mut end = first.span.end;
+            let mut alts = vec![first];
+            while eat_punct(tokens, "|").is_some() {
+                let alt = do_pattern_single(tokens)?;
+                end = alt.span.end;
+                alts.push(alt);
+            }
+            return Ok(Spanned::new(
+                Pattern::Or(alts),
+                Span::new_simple(start, end),
+            ));
+        }
+    }
+    Ok(first)
+}
+
+fn do_match_arms(tokens: &mut TokenStream) -> Result<Vec<MatchArm>> {
+    let mut arms = Vec::new();
+    while !tokens.is_empty() {
+        let pattern = do_pattern(tokens)?;
+        let guard = if eat_keyword(tokens, kw::IF).is_some() {
+            Some(do_expr(tokens)?)
+        } else {
+            None
+        };
+        do_lexeme_class(tokens, LexemeClass::Punctuation("=>".into()))?;
+        let body = do_arm_body(tokens)?;
+        eat_punct(tokens, ",");
+        arms.push(MatchArm {
+            pattern,
+            guard,
+            body,
+        });
+    }
+    Ok(arms)
+}
+
+fn do_choice_arms(tokens: &mut TokenStream) -> Result<Vec<ChoiceArm>> {
+    let mut arms = Vec::new();
+    while !tokens.is_empty() {
+        let text = do_string(tokens)?;
+        let guard = if eat_keyword(tokens, kw::IF).is_some() {
+            Some(do_expr(tokens)?)
+        } else {
+            None
+        };
+        do_lexeme_class(tokens, LexemeClass::Punctuation("=>".into()))?;
+        let body = do_arm_body(tokens)?;
+        eat_punct(tokens, ",");
+        arms.push(ChoiceArm { text, guard, body });
+    }
+    Ok(arms)
+}
+
+fn do_if(tokens: &mut TokenStream, start: Pos) -> Result<Spanned<Stmt>> {
+    let cond = do_expr(tokens)?;
+    let then = do_block(tokens)?;
+    let mut end = then.span.end;
+    let else_branch = if eat_keyword(tokens, kw::ELSE).is_some() {
+        let stmt = if let Some(kw) = eat_keyword(tokens, kw::IF) {
+            do_if(tokens, kw.span.start)?
+        } else {
+            let block = do_block(tokens)?;
+            let span = block.span;
+            Spanned::new(Stmt::Block(block), span)
+        };
+        end = stmt.span.end;
+        Some(Box::new(stmt))
+    } else {
+        None
+    };
+    Ok(Spanned::new(
+        Stmt::If(cond, then, else_branch),
+        Span::new_simple(start, end),
+    ))
+}
+
+fn do_let(tokens: &mut TokenStream) -> Result<(Spanned<Symbol>, Option<Spanned<Expr>>, Pos)> {
+    let name = do_ident(tokens)?;
+    let mut end = name.span.end;
+    let init = if eat_punct(tokens, "=").is_some() {
+        let expr = do_expr(tokens)?;
+        end = expr.span.end;
+        Some(expr)
+    } else {
+        None
+    };
+    if let Some(semi) = eat_punct(tokens, ";") {
+        end = semi.span.end;
+    }
+    Ok((name, init, end))
+}
+
+/// Consumes an optional trailing `;`, returning the end of the statement
+fn do_stmt_end(tokens: &mut TokenStream, end: Pos) -> Pos {
+    eat_punct(tokens, ";").map_or(end, |semi| semi.span.end)
+}
+
+pub fn do_stmt(tokens: &mut TokenStream) -> Result<Spanned<Stmt>> {
+    if let Some(semi) = eat_punct(tokens, ";") {
+        return Ok(Spanned::new(Stmt::Empty, semi.span));
+    }
+    if let LexemeClass::Group(Some(GroupType::Braces)) = tokens.peek_class() {
+        let block = do_block(tokens)?;
+        let span = block.span;
+        return Ok(Spanned::new(Stmt::Block(block), span));
+    }
+    if let Some(kw) = eat_keyword(tokens, kw::LET) {
+        let (name, init, end) = do_let(tokens)?;
+        return Ok(Spanned::new(
+            Stmt::Let(name, init),
+            Span::new_simple(kw.span.start, end),
+        ));
+    }
+    if let Some(kw) = eat_keyword(tokens, kw::IF) {
+        return do_if(tokens, kw.span.start);
+    }
+    if let Some(kw) = eat_keyword(tokens, kw::MATCH) {
+        let scrutinee = do_expr(tokens)?;
+        let lexeme = do_lexeme_class(tokens, LexemeClass::Group(Some(GroupType::Braces)))?;
+        let span = kw.span.join(lexeme.span);
+        let LexemeBody::Group(group) = lexeme.body else {
+            unreachable!()
+        };
+        let arms = do_match_arms(&mut TokenStream::from_group(group))?;
+        return Ok(Spanned::new(Stmt::Match(scrutinee, arms), span));
+    }
+    if let Some(kw) = eat_keyword(tokens, kw::CHOICE) {
+        let lexeme = do_lexeme_class(tokens, LexemeClass::Group(Some(GroupType::Braces)))?;
+        let span = kw.span.join(lexeme.span);
+        let LexemeBody::Group(group) = lexeme.body else {
+            unreachable!()
+        };
+        let arms = do_choice_arms(&mut TokenStream::from_group(group))?;
+        return Ok(Spanned::new(Stmt::Choice(arms), span));
+    }
+    if let Some(kw) = eat_keyword(tokens, kw::LOOP) {
+        let body = do_block(tokens)?;
+        let span = kw.span.join(body.span);
+        return Ok(Spanned::new(Stmt::Loop(body), span));
+    }
+    if let Some(kw) = eat_keyword(tokens, kw::WHILE) {
+        let cond = do_expr(tokens)?;
+        let body = do_block(tokens)?;
+        let span = kw.span.join(body.span);
+        return Ok(Spanned::new(Stmt::While(cond, body), span));
+    }
+    for (kw, stmt) in [
+        (kw::BREAK, Stmt::Break),
+        (kw::CONTINUE, Stmt::Continue),
+        (kw::RETURN, Stmt::Return),
+    ] {
+        if let Some(kw) = eat_keyword(tokens, kw) {
+            let end = do_stmt_end(tokens, kw.span.end);
+            return Ok(Spanned::new(stmt, Span::new_simple(kw.span.start, end)));
+        }
+    }
+    if let Some(kw) = eat_keyword(tokens, kw::JUMP) {
+        let target = do_path(tokens)?;
+        let end = do_stmt_end(tokens, target.span.end);
+        return Ok(Spanned::new(
+            Stmt::Jump(target),
+            Span::new_simple(kw.span.start, end),
+        ));
+    }
+    if let Some(kw) = eat_keyword(tokens, kw::CALL) {
+        let target = do_path(tokens)?;
+        let end = do_stmt_end(tokens, target.span.end);
+        return Ok(Spanned::new(
+            Stmt::Call(target),
+            Span::new_simple(kw.span.start, end),
+        ));
+    }
+    if let Some(kw) = eat_keyword(tokens, kw::SHOW) {
+        let layer = do_ident(tokens)?;
+        let image = do_expr(tokens)?;
+        let end = do_stmt_end(tokens, image.span.end);
+        return Ok(Spanned::new(
+            Stmt::Show(layer, image),
+            Span::new_simple(kw.span.start, end),
+        ));
+    }
+    if let Some(kw) = eat_keyword(tokens, kw::HIDE) {
+        let layer = do_ident(tokens)?;
+        let end = do_stmt_end(tokens, layer.span.end);
+        return Ok(Spanned::new(
+            Stmt::Hide(layer),
+            Span::new_simple(kw.span.start, end),
+        ));
+    }
+
+    // Dialogue is either a bare string (narration), or a speaker followed by a string
+    let is_dialogue = match tokens.peek_class() {
+        LexemeClass::String => true,
+        LexemeClass::Identifier => LexemeClass::of(tokens.peek_nth(1)) == LexemeClass::String,
+        _ => false,
+    };
+    if is_dialogue {
+        let speaker = match tokens.peek_class() {
+            LexemeClass::Identifier => Some(do_ident(tokens)?),
+            _ => None,
+        };
+        let text = do_string(tokens)?;
+        let start = speaker.as_ref().map_or(text.span.start, |s| s.span.start);
+        let end = do_stmt_end(tokens, text.span.end);
+        return Ok(Spanned::new(
+            Stmt::Dialogue(speaker, text),
+            Span::new_simple(start, end),
+        ));
+    }
+
+    let expr = do_expr(tokens)?;
+    let span = Span::new_simple(expr.span.start, do_stmt_end(tokens, expr.span.end));
+    Ok(Spanned::new(Stmt::Expr(expr), span))
+}
+
+/// The keywords that start an item
+const ITEM_KEYWORDS: &[Symbol] = &[kw::SCENE, kw::CHARACTER, kw::LET, kw::IMPORT, kw::USE];
+
+pub fn do_item(tokens: &mut TokenStream) -> Result<Spanned<Item>> {
+    if let Some(kw) = eat_keyword(tokens, kw::SCENE) {
+        let name = do_ident(tokens)?;
+        let body = do_block(tokens)?;
+        let span = kw.span.join(body.span);
+        Ok(Spanned::new(Item::Scene(Scene { name, body }), span))
+    } else if let Some(kw) = eat_keyword(tokens, kw::CHARACTER) {
+        let name = do_ident(tokens)?;
+        do_lexeme_class(tokens, LexemeClass::Punctuation("=".into()))?;
+        let display = do_expr(tokens)?;
+        let end = do_stmt_end(tokens, display.span.end);
+        Ok(Spanned::new(
+            Item::Character(name, display),
+            Span::new_simple(kw.span.start, end),
+        ))
+    } else if let Some(kw) = eat_keyword(tokens, kw::LET) {
+        let (name, init, end) = do_let(tokens)?;
+        Ok(Spanned::new(
+            Item::Let(name, init),
+            Span::new_simple(kw.span.start, end),
+        ))
+    } else if let Some(kw) = eat_keyword(tokens, kw::IMPORT) {
+        let lexeme = do_lexeme_class(tokens, LexemeClass::String)?;
+        let path = match do_literal(lexeme.clone())? {
+            Spanned {
+                body: Expr::Literal(Literal::String(path)),
+                span,
+            } => Spanned::new(path, span),
+            // Paths cannot have embedded expressions
+            _ => return Err(Error::InvalidLiteral(Box::new(lexeme))),
+        };
+        let end = do_stmt_end(tokens, path.span.end);
+        Ok(Spanned::new(
+            Item::Import(path),
+            Span::new_simple(kw.span.start, end),
+        ))
+    } else if let Some(kw) = eat_keyword(tokens, kw::USE) {
+        let path = do_path(tokens)?;
+        let end = do_stmt_end(tokens, path.span.end);
+        Ok(Spanned::new(
+            Item::Use(path),
+            Span::new_simple(kw.span.start, end),
+        ))
+    } else {
+        let expected = ITEM_KEYWORDS
+            .iter()
+            .map(|&kw| LexemeClass::Keyword(kw))
+            .collect();
+        match tokens.next() {
+            Some(lexeme) => Err(Error::UnexpectedToken(Box::new(lexeme), expected)),
+            None => Err(Error::UnexpectedEof(tokens.end(), expected)),
+        }
+    }
+}
+
+/// Parses every item in a file. After an error, parsing resumes at the next item so that
+/// every error in the file is reported.
+pub fn do_file(tokens: &mut TokenStream) -> core::result::Result<Vec<Spanned<Item>>, Vec<Error>> {
+    let mut items = Vec::new();
+    let mut errors = Vec::new();
+    while !tokens.is_empty() {
+        if eat_punct(tokens, ";").is_some() {
+            continue;
+        }
+        match do_item(tokens) {
+            Ok(item) => items.push(item),
+            Err(e) => {
+                errors.push(e);
+                while !tokens.is_empty() && !at_item_start(tokens) {
+                    tokens.next();
+                }
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(items)
+    } else {
+        Err(errors)
+    }
+}
+
+fn at_item_start(tokens: &mut TokenStream) -> bool {
+    match tokens.peek_class() {
+        LexemeClass::Keyword(kw) => ITEM_KEYWORDS.contains(&kw),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tokens(src: &str) -> TokenStream {
+        let mut lexemes =
+            lex::lex(&mut src.chars(), "test").unwrap_or_else(|errs| panic!("{:?}", errs));
+        lex::filter_comments(&mut lexemes);
+        TokenStream::new(lexemes)
+    }
+
+    fn expr(src: &str) -> Spanned<Expr> {
+        let mut tokens = tokens(src);
+        let expr = do_expr(&mut tokens).unwrap_or_else(|err| panic!("{}", err));
+        tokens.finish().unwrap_or_else(|err| panic!("{}", err));
+        expr
+    }
+
+    /// Writes an expression with its structure made explicit, eg. `(+ a (* b c))`
+    fn sexpr(expr: &Expr) -> String {
+        let list = |exprs: &[Spanned<Expr>]| {
+            exprs
+                .iter()
+                .map(|expr| sexpr(expr))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        match expr {
+            Expr::Literal(Literal::Int(i)) => i.to_string(),
+            Expr::Literal(Literal::String(s)) => format!("{:?}", s),
+            Expr::Literal(lit) => format!("{:?}", lit),
+            Expr::Ident(name) => name.to_string(),
+            Expr::Path(segments) => segments
+                .iter()
+                .map(|seg| seg.as_str())
+                .collect::<Vec<_>>()
+                .join("::"),
+            Expr::List(elems) => format!("[{}]", list(elems)),
+            Expr::Call(callee, args) if args.is_empty() => format!("(call {})", sexpr(callee)),
+            Expr::Call(callee, args) => format!("(call {} {})", sexpr(callee), list(args)),
+            Expr::Field(base, field) => format!("(. {} {})", sexpr(base), field.body),
+            Expr::Index(base, idx) => format!("([] {} {})", sexpr(base), sexpr(idx)),
+            Expr::Unary(op, operand) => {
+                let op = match op.body {
+                    UnaryOp::Neg => "-",
+                    UnaryOp::Not => "!",
+                    UnaryOp::BitNot => "~",
+                };
+                format!("({} {})", op, sexpr(operand))
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                format!("({} {} {})", punct(op.body), sexpr(lhs), sexpr(rhs))
+            }
+            Expr::Format(parts) => format!("(format {})", list(parts)),
+            Expr::Markup(tag) => tag.to_string(),
+        }
+    }
+
+    fn parse(src: &str) -> String {
+        sexpr(&expr(src))
+    }
+
+    /// The punctuation `op` is written with
+    fn punct(op: BinaryOp) -> &'static str {
+        LEVELS
+            .iter()
+            .flat_map(|ops| ops.iter())
+            .find(|punct| BinaryOp::from_punct(punct) == Some(op))
+            .unwrap()
+    }
+
+    /// Every binary operator, from the loosest binding to the tightest
+    const LEVELS: &[&[&str]] = &[
+        &[
+            "=", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "<<=", ">>=",
+        ],
+        &["||"],
+        &["&&"],
+        &["==", "!=", "<", ">", "<=", ">="],
+        &["|"],
+        &["^"],
+        &["&"],
+        &["<<", ">>"],
+        &["+", "-"],
+        &["*", "/", "%"],
+    ];
+
+    #[test]
+    fn levels_cover_every_operator() {
+        let mut seen = Vec::new();
+        for (level, ops) in LEVELS.iter().enumerate() {
+            for &written in *ops {
+                let op = BinaryOp::from_punct(written).unwrap();
+                assert_eq!(op.precedence() as usize, level + 1, "{:?}", op);
+                assert_eq!(punct(op), written);
+                seen.push(op);
+            }
+        }
+        assert_eq!(seen.len(), 29);
+    }
+
+    #[test]
+    fn tighter_operators_bind_first() {
+        for (i, loose_ops) in LEVELS.iter().enumerate() {
+            for tight_ops in &LEVELS[i + 1..] {
+                for loose in *loose_ops {
+                    for tight in *tight_ops {
+                        assert_eq!(
+                            parse(&format!("a {} b {} c", loose, tight)),
+                            format!("({} a ({} b c))", loose, tight)
+                        );
+                        assert_eq!(
+                            parse(&format!("a {} b {} c", tight, loose)),
+                            format!("({} ({} a b) c)", loose, tight)
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn operators_of_a_level_associate() {
+        for ops in LEVELS {
+            for first in *ops {
+                for second in *ops {
+                    let expected = if BinaryOp::from_punct(first).unwrap().is_assignment() {
+                        format!("({} a ({} b c))", first, second)
+                    } else {
+                        format!("({} ({} a b) c)", second, first)
+                    };
+                    assert_eq!(parse(&format!("a {} b {} c", first, second)), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parentheses_override_precedence() {
+        assert_eq!(parse("(a + b) * c"), "(* (+ a b) c)");
+        assert_eq!(parse("a - (b - c)"), "(- a (- b c))");
+        assert_eq!(parse("((a))"), "a");
+    }
+
+    #[test]
+    fn unary_operators() {
+        assert_eq!(parse("-a * b"), "(* (- a) b)");
+        assert_eq!(parse("!-~a"), "(! (- (~ a)))");
+        assert_eq!(parse("a - -b"), "(- a (- b))");
+        // Postfix operators bind tighter than prefix ones
+        assert_eq!(parse("-a.b"), "(- (. a b))");
+        assert_eq!(parse("!f(x)[0]"), "(! ([] (call f x) 0))");
+    }
+
+    #[test]
+    fn paths_calls_and_indexing() {
+        assert_eq!(parse("a::b::c"), "a::b::c");
+        assert_eq!(parse("a::b(1)"), "(call a::b 1)");
+        assert_eq!(parse("f()"), "(call f)");
+        assert_eq!(parse("f(1, 2 + 3,)"), "(call f 1 (+ 2 3))");
+        assert_eq!(parse("f(x)(y)"), "(call (call f x) y)");
+        assert_eq!(parse("a[1][b + 2]"), "([] ([] a 1) (+ b 2))");
+        assert_eq!(parse("a.b.c"), "(. (. a b) c)");
+        assert_eq!(parse("a.b[0].c(d)"), "(call (. ([] (. a b) 0) c) d)");
+        assert_eq!(parse("[1, [a], ]"), "[1 [a]]");
+        assert_eq!(parse("[]"), "[]");
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(parse("0x10 + 1_000"), "(+ 16 1000)");
+        assert_eq!(parse("true || false"), "(|| Bool(true) Bool(false))");
+        assert_eq!(parse("'a'"), "Char('a')");
+        assert_eq!(parse(r"'\''"), r"Char('\'')");
+        assert_eq!(parse(r"'\u{e9}'"), "Char('é')");
+        assert_eq!(parse("'sym"), r#"Symbol("sym")"#);
+        assert_eq!(parse("1.5"), "Float(1.5)");
+        assert_eq!(parse("500ms"), "Duration(500ms)");
+    }
+
+    #[test]
+    fn expression_spans() {
+        let src = "f(a, b)[0].c + -1";
+        let text = |span: Span| &src[span.range()];
+        let sum = expr(src);
+        assert_eq!(text(sum.span), src);
+        let Expr::Binary(op, lhs, rhs) = &sum.body else {
+            panic!("{:?}", sum)
+        };
+        assert_eq!(text(op.span), "+");
+        assert_eq!(text(lhs.span), "f(a, b)[0].c");
+        assert_eq!(text(rhs.span), "-1");
+        let Expr::Field(base, field) = &lhs.body else {
+            panic!("{:?}", lhs)
+        };
+        assert_eq!(text(field.span), "c");
+        assert_eq!(text(base.span), "f(a, b)[0]");
+        let Expr::Index(call, idx) = &base.body else {
+            panic!("{:?}", base)
+        };
+        assert_eq!(text(call.span), "f(a, b)");
+        assert_eq!(text(idx.span), "0");
+    }
+
+    #[test]
+    fn group_ends_at_closing_delimiter() {
+        // The closing delimiter is on a later line, after a multi-byte character
+        let src = "f(é +\n  )";
+        let mut tokens = tokens(src);
+        match do_expr(&mut tokens) {
+            Err(Error::UnexpectedEof(pos, _)) => {
+                assert_eq!((pos.row, pos.col, pos.idx), (2, 3, src.find(')').unwrap()));
+            }
+            res => panic!("{:?}", res.map(|expr| sexpr(&expr))),
+        }
+    }
+
+    fn stmts(src: &str) -> Vec<Spanned<Stmt>> {
+        let mut tokens = tokens(src);
+        let mut stmts = Vec::new();
+        while !tokens.is_empty() {
+            stmts.push(do_stmt(&mut tokens).unwrap_or_else(|err| panic!("{}", err)));
+        }
+        stmts
+    }
+
+    fn pattern_sexpr(pattern: &Pattern) -> String {
+        match pattern {
+            Pattern::Wildcard => "_".to_string(),
+            Pattern::Literal(Literal::Int(i)) => i.to_string(),
+            Pattern::Literal(Literal::String(s)) => format!("{:?}", s),
+            Pattern::Literal(lit) => format!("{:?}", lit),
+            Pattern::Binding(name) => name.to_string(),
+            Pattern::Or(alts) => alts
+                .iter()
+                .map(|alt| pattern_sexpr(alt))
+                .collect::<Vec<_>>()
+                .join(" | "),
+        }
+    }
+
+    /// Writes a statement in the style of [`sexpr`], with blocks in braces
+    fn stmt_sexpr(stmt: &Stmt) -> String {
+        let block = |block: &Block| {
+            let stmts = block
+                .stmts
+                .iter()
+                .map(|stmt| stmt_sexpr(stmt))
+                .collect::<Vec<_>>();
+            format!("{{{}}}", stmts.join(" "))
+        };
+        let guard = |guard: &Option<Spanned<Expr>>| match guard {
+            Some(guard) => format!(" if {}", sexpr(guard)),
+            None => String::new(),
+        };
+        let path = |path: &[Spanned<Symbol>]| {
+            path.iter()
+                .map(|seg| seg.as_str())
+                .collect::<Vec<_>>()
+                .join("::")
+        };
+        match stmt {
+            Stmt::Empty => ";".to_string(),
+            Stmt::Expr(expr) => sexpr(expr),
+            Stmt::Let(name, None) => format!("(let {})", name.body),
+            Stmt::Let(name, Some(init)) => format!("(let {} {})", name.body, sexpr(init)),
+            Stmt::Dialogue(None, text) => format!("(say {})", sexpr(text)),
+            Stmt::Dialogue(Some(speaker), text) => {
+                format!("(say {} {})", speaker.body, sexpr(text))
+            }
+            Stmt::Block(body) => block(body),
+            Stmt::If(cond, then, None) => format!("(if {} {})", sexpr(cond), block(then)),
+            Stmt::If(cond, then, Some(else_branch)) => format!(
+                "(if {} {} {})",
+                sexpr(cond),
+                block(then),
+                stmt_sexpr(else_branch)
+            ),
+            Stmt::Match(scrutinee, arms) => {
+                let arms = arms.iter().map(|arm| {
+                    format!(
+                        " ({}{} => {})",
+                        pattern_sexpr(&arm.pattern),
+                        guard(&arm.guard),
+                        block(&arm.body)
+                    )
+                });
+                format!("(match {}{})", sexpr(scrutinee), arms.collect::<String>())
+            }
+            Stmt::Choice(arms) => {
+                let arms = arms.iter().map(|arm| {
+                    format!(
+                        " ({}{} => {})",
+                        sexpr(&arm.text),
+                        guard(&arm.guard),
+                        block(&arm.body)
+                    )
+                });
+                format!("(choice{})", arms.collect::<String>())
+            }
+            Stmt::Loop(body) => format!("(loop {})", block(body)),
+            Stmt::While(cond, body) => format!("(while {} {})", sexpr(cond), block(body)),
+            Stmt::Break => "break".to_string(),
+            Stmt::Continue => "continue".to_string(),
+            Stmt::Jump(target) => format!("(jump {})", path(&target.body)),
+            Stmt::Call(target) => format!("(call {})", path(&target.body)),
+            Stmt::Return => "return".to_string(),
+            Stmt::Show(layer, image) => format!("(show {} {})", layer.body, sexpr(image)),
+            Stmt::Hide(layer) => format!("(hide {})", layer.body),
+        }
+    }
+
+    fn parse_stmts(src: &str) -> Vec<String> {
+        stmts(src).iter().map(|stmt| stmt_sexpr(stmt)).collect()
+    }
+
+    #[test]
+    fn let_and_expression_statements() {
+        assert_eq!(
+            parse_stmts("let a = 1 + 2; let b\n let c = a;; a += b"),
+            ["(let a (+ 1 2))", "(let b)", "(let c a)", ";", "(+= a b)"]
+        );
+    }
+
+    #[test]
+    fn dialogue() {
+        assert_eq!(
+            parse_stmts(r#""Narration." alice "Hi, {name}!"; "Unended""#),
+            [
+                r#"(say "Narration.")"#,
+                r#"(say alice (format "Hi, " name "!"))"#,
+                r#"(say "Unended")"#,
+            ]
+        );
+        // An identifier followed by anything but a string is an expression
+        assert_eq!(parse_stmts("alice; f(\"x\")"), ["alice", r#"(call f "x")"#]);
+    }
+
+    #[test]
+    fn if_else() {
+        assert_eq!(
+            parse_stmts("if a { b } if a { b } else { c } if a {} else if b {} else { c; d }"),
+            ["(if a {b})", "(if a {b} {c})", "(if a {} (if b {} {c d}))",]
+        );
+    }
+
+    #[test]
+    fn match_arms() {
+        assert_eq!(
+            parse_stmts(
+                r#"match x {
+                    0 | 1 => "small",
+                    -2 => { a; b }
+                    n if n > 10 => return,
+                    "s" => {},
+                    _ => jump end
+                }"#
+            ),
+            [concat!(
+                r#"(match x (0 | 1 => {(say "small")}) (-2 => {a b}) "#,
+                r#"(n if (> n 10) => {return}) ("s" => {}) (_ => {(jump end)}))"#
+            )]
+        );
+    }
+
+    #[test]
+    fn choice_arms() {
+        assert_eq!(
+            parse_stmts(
+                r#"choice {
+                    "Stay" => {},
+                    "Leave {place}" if can_leave => jump chapter::outside,
+                    "Wait" => loop { break }
+                }"#
+            ),
+            [concat!(
+                r#"(choice ("Stay" => {}) "#,
+                r#"((format "Leave " place) if can_leave => {(jump chapter::outside)}) "#,
+                r#"("Wait" => {(loop {break})}))"#
+            )]
+        );
+    }
+
+    #[test]
+    fn loops() {
+        assert_eq!(
+            parse_stmts("loop { continue; } while i < 3 { i += 1; break }"),
+            ["(loop {continue})", "(while (< i 3) {(+= i 1) break})"]
+        );
+    }
+
+    #[test]
+    fn jump_and_call() {
+        assert_eq!(
+            parse_stmts("jump a; call b::c::d\n call e return;"),
+            ["(jump a)", "(call b::c::d)", "(call e)", "return"]
+        );
+    }
+
+    #[test]
+    fn show_and_hide() {
+        assert_eq!(
+            parse_stmts(
+                r#"show background image("park.png"); show characters alice.sprite hide ui;"#
+            ),
+            [
+                r#"(show background (call image "park.png"))"#,
+                "(show characters (. alice sprite))",
+                "(hide ui)",
+            ]
+        );
+    }
+
+    #[test]
+    fn statement_spans() {
+        let src = "let a = 1;\nalice \"Hi\"\nif a {\n  jump b\n} else { c }\nshow ui x;";
+        let text = |span: Span| &src[span.range()];
+        let spans = stmts(src)
+            .iter()
+            .map(|stmt| text(stmt.span))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [
+                "let a = 1;",
+                "alice \"Hi\"",
+                "if a {\n  jump b\n} else { c }",
+                "show ui x;",
+            ]
+        );
+        let stmts = stmts(src);
+        let Stmt::If(cond, then, Some(else_branch)) = &stmts[2].body else {
+            panic!("{:?}", stmts[2])
+        };
+        assert_eq!(text(cond.span), "a");
+        assert_eq!(text(then.span), "{\n  jump b\n}");
+        assert_eq!(text(then.body.stmts[0].span), "jump b");
+        assert_eq!(text(else_branch.span), "{ c }");
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(
+            parse(r#""a\nb\t\"c\" \\ \u{e9}\u{1F600}\0""#),
+            format!("{:?}", "a\nb\t\"c\" \\ é😀\0")
+        );
+        // Escaped quotes and backslashes do not end the string or start an interpolation
+        assert_eq!(parse(r#""\\{x}""#), r#"(format "\\" x)"#);
+    }
+
+    #[test]
+    fn interpolation() {
+        assert_eq!(parse(r#""Hi {name}!""#), r#"(format "Hi " name "!")"#);
+        assert_eq!(
+            parse(r#""{f(1)[0]}{a + b}""#),
+            "(format ([] (call f 1) 0) (+ a b))"
+        );
+        assert_eq!(
+            parse(r#""{{x}} {b}bold{/b} {x}""#),
+            r#"(format "{x} " {b} "bold" {/b} " " x)"#
+        );
+    }
+
+    #[test]
+    fn doubled_braces_are_decoded() {
+        assert_eq!(parse(r#""{{""#), r#""{""#);
+        assert_eq!(parse(r#""}}{{b}}""#), r#""}{b}""#);
+        assert_eq!(parse(r#""{{{x}}}""#), r#"(format "{" x "}")"#);
+    }
+
+    #[test]
+    fn interpolation_ends_at_its_matching_brace() {
+        // The embedded expression is `{x}`, so the error is that a block is not an expression,
+        // rather than a stray `}` after the first one closed the interpolation
+        let mut tokens = tokens(r#""{ {x} }""#);
+        match do_expr(&mut tokens) {
+            Err(Error::UnexpectedToken(lexeme, _)) => {
+                assert_eq!((lexeme.span.start.col, lexeme.span.end.col), (4, 7));
+            }
+            res => panic!("{:?}", res.map(|expr| sexpr(&expr))),
+        }
+    }
+
+    #[test]
+    fn interpolation_errors() {
+        let error = |src: &str| do_expr(&mut tokens(src)).map(|expr| sexpr(&expr));
+        assert!(matches!(error(r#""a}b""#), Err(Error::UnmatchedBrace(_))));
+        assert!(matches!(
+            error(r#""{a""#),
+            Err(Error::UnclosedInterpolation(_))
+        ));
+        assert!(matches!(
+            error(r#""{ }""#),
+            Err(Error::EmptyInterpolation(_))
+        ));
+        assert!(matches!(
+            error(r#""{a b}""#),
+            Err(Error::UnexpectedToken(..))
+        ));
+    }
+}
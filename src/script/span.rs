@@ -1,11 +1,17 @@
-use core::{cmp::Ordering, fmt};
+use core::{cmp::Ordering, fmt, ops::Range};
+use std::{collections::VecDeque, rc::Rc};
+
+use fxhash::FxHashMap;
 
 use super::symbol::Symbol;
 
 #[derive(Clone, Copy, Default)]
 pub struct Pos {
+    /// 1-based line number
     pub row: usize,
+    /// 1-based column, counted in characters
     pub col: usize,
+    /// Byte offset into the file
     pub idx: usize,
     pub file: Symbol,
 }
@@ -19,34 +25,48 @@ impl Pos {
             file: file.into(),
         }
     }
+
+    /// The position just after `c`, if `c` is at this position
+    pub fn advance(self, c: char) -> Self {
+        match c {
+            '\n' => Pos::new(self.row + 1, 1, self.idx + 1, self.file),
+            _ => Pos::new(self.row, self.col + 1, self.idx + c.len_utf8(), self.file),
+        }
+    }
 }
 
 impl fmt::Debug for Pos {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}:{}", self.row, self.col)
+        write!(f, "{}:{}:{}", self.file, self.row, self.col)
+    }
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.row, self.col)
     }
 }
 
 impl PartialEq for Pos {
     fn eq(&self, other: &Self) -> bool {
-        self.file == other.file && self.row == other.row && self.col == other.col
+        self.file == other.file && self.idx == other.idx
     }
 }
 
+impl Eq for Pos {}
+
+/// Positions in different files are unordered
 impl PartialOrd for Pos {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         if self.file != other.file {
             return None;
         }
-        match self.row.cmp(&other.row) {
-            Ordering::Equal => {}
-            x => return Some(x),
-        }
-        Some(self.col.cmp(&other.col))
+        Some(self.idx.cmp(&other.idx))
     }
 }
 
-#[derive(Clone, Copy)]
+/// The text from `start` up to, but not including, `end`
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Span {
     pub start: Pos,
     pub end: Pos,
@@ -56,6 +76,36 @@ impl Span {
     pub fn new_simple(start: Pos, end: Pos) -> Self {
         Self { start, end }
     }
+
+    /// An empty span at `pos`
+    pub fn at(pos: Pos) -> Self {
+        Self::new_simple(pos, pos)
+    }
+
+    /// The smallest span covering both spans, which must be in the same file
+    pub fn join(self, other: Span) -> Self {
+        debug_assert_eq!(self.start.file, other.start.file);
+        let start = if other.start.idx < self.start.idx {
+            other.start
+        } else {
+            self.start
+        };
+        let end = if other.end.idx > self.end.idx {
+            other.end
+        } else {
+            self.end
+        };
+        Self { start, end }
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.start.idx >= self.end.idx
+    }
+
+    /// The bytes of the file this span covers
+    pub fn range(self) -> Range<usize> {
+        self.start.idx..self.end.idx
+    }
 }
 
 impl fmt::Debug for Span {
@@ -66,74 +116,147 @@ impl fmt::Debug for Span {
 
 pub trait Speekerator: Iterator<Item = char> + Sized {
     fn speekable(self, file_name: impl Into<Symbol>) -> Speekable<Self>;
+
+    /// Like [`Speekerator::speekable`], for text that starts at `start` rather than the
+    /// beginning of a file
+    fn speekable_at(self, start: Pos) -> Speekable<Self>;
 }
 
 impl<I: Iterator<Item = char>> Speekerator for I {
     fn speekable(self, file_name: impl Into<Symbol>) -> Speekable<Self> {
-        Speekable::new(self, file_name)
+        self.speekable_at(Pos::new(1, 1, 0, file_name))
+    }
+
+    fn speekable_at(self, start: Pos) -> Speekable<Self> {
+        Speekable::new(self, start)
     }
 }
 
 pub struct Speekable<I: Iterator<Item = char>> {
     inner: I,
-    peeked: Option<(Pos, char)>,
+    /// Characters read from `inner` but not yet taken, in order
+    peeked: VecDeque<(Pos, char)>,
     pos: Pos,
 }
 
 #[allow(dead_code)]
 impl<I: Iterator<Item = char>> Speekable<I> {
-    fn new(inner: I, file_name: impl Into<Symbol>) -> Self {
+    fn new(inner: I, start: Pos) -> Self {
         Self {
             inner,
-            peeked: None,
-            pos: Pos::new(1, 1, 0, file_name),
+            peeked: VecDeque::new(),
+            pos: start,
         }
     }
 
-    fn tick(&mut self) {
-        if self.peeked.is_none() {
-            let c = self.inner.next();
-            if let Some(c) = c {
-                let next_pos = match c {
-                    '\n' => Pos::new(self.pos.row + 1, 1, self.pos.idx + 1, self.pos.file),
-                    _ => Pos::new(
-                        self.pos.row,
-                        self.pos.col + 1,
-                        self.pos.idx + 1,
-                        self.pos.file,
-                    ),
-                };
-                self.peeked = Some((self.pos, c));
-                self.pos = next_pos;
+    /// Reads characters from `inner` until `n + 1` are buffered, or it runs out
+    fn fill(&mut self, n: usize) {
+        while self.peeked.len() <= n {
+            if let Some(c) = self.inner.next() {
+                self.peeked.push_back((self.pos, c));
+                self.pos = self.pos.advance(c);
+            } else {
+                break;
             }
         }
     }
 
     pub fn speek(&mut self) -> Option<&(Pos, char)> {
-        self.tick();
-        self.peeked.as_ref()
+        self.speek_nth(0)
+    }
+
+    /// Looks `n` characters past the next one without taking any
+    pub fn speek_nth(&mut self, n: usize) -> Option<&(Pos, char)> {
+        self.fill(n);
+        self.peeked.get(n)
     }
 
     pub fn peek(&mut self) -> Option<&char> {
-        self.tick();
-        self.peeked.as_ref().map(|(_, x)| x)
+        self.speek().map(|(_, x)| x)
+    }
+
+    pub fn peek_nth(&mut self, n: usize) -> Option<&char> {
+        self.speek_nth(n).map(|(_, x)| x)
     }
 
     pub fn snext(&mut self) -> Option<(Pos, char)> {
-        self.tick();
-        self.peeked.take()
+        self.fill(0);
+        self.peeked.pop_front()
+    }
+
+    /// Puts back a character just taken by [`Speekable::snext`], so it is returned again
+    pub fn unread(&mut self, pos: Pos, c: char) {
+        self.peeked.push_front((pos, c));
     }
 
     pub fn last_pos(&self) -> Pos {
         self.pos
     }
+
+    /// The position of the next character, or the end of the text if there is none
+    pub fn pos(&mut self) -> Pos {
+        self.fill(0);
+        self.peeked.front().map_or(self.pos, |&(pos, _)| pos)
+    }
 }
 
 impl<I: Iterator<Item = char>> Iterator for Speekable<I> {
     type Item = char;
 
     fn next(&mut self) -> Option<char> {
-        self.tick();
-        self.peeked.take().map(|(_, x)| x)
+        self.snext().map(|(_, x)| x)
+    }
+}
+
+struct SourceFile {
+    text: Rc<str>,
+    /// The byte offset of the start of each line
+    line_starts: Vec<usize>,
+}
+
+/// The text of every source file, by the name used in its [`Span`]s
+#[derive(Default)]
+pub struct SourceMap {
+    files: FxHashMap<Symbol, SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, name: impl Into<Symbol>, text: impl Into<Rc<str>>) {
+        let text = text.into();
+        let line_starts = core::iter::once(0)
+            .chain(text.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect();
+        self.files
+            .insert(name.into(), SourceFile { text, line_starts });
+    }
+
+    pub fn get(&self, name: Symbol) -> Option<&str> {
+        self.files.get(&name).map(|file| &*file.text)
+    }
+
+    /// Every file with its text, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (Symbol, &str)> {
+        self.files.iter().map(|(name, file)| (*name, &*file.text))
+    }
+
+    /// The text of line `row` (1-based) of `file`, without the line terminator
+    pub fn line(&self, file: Symbol, row: usize) -> Option<&str> {
+        let file = self.files.get(&file)?;
+        let start = *file.line_starts.get(row.checked_sub(1)?)?;
+        let end = file
+            .line_starts
+            .get(row)
+            .map_or(file.text.len(), |next| next - 1);
+        let line = &file.text[start..end];
+        Some(line.strip_suffix('\r').unwrap_or(line))
+    }
+
+    /// The text `span` covers, if its file is known and the span lies within it
+    pub fn slice(&self, span: Span) -> Option<&str> {
+        self.get(span.start.file)?.get(span.range())
     }
 }
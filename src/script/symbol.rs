@@ -1,8 +1,11 @@
-use core::borrow::Borrow;
+use core::cell::RefCell;
 use core::convert::AsRef;
+use core::marker::PhantomData;
 use core::num::NonZeroU64;
 use core::ops::Deref;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use core::{ptr, slice, str};
+use std::borrow::Cow;
 
 use fxhash::FxHashMap;
 use parking_lot::RwLock;
@@ -57,9 +60,10 @@ impl core::cmp::PartialEq<Symbol> for str {
     }
 }
 
+/// Symbols are equal exactly when their ids are, so they hash by id rather than by text
 impl core::hash::Hash for Symbol {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.as_str().hash(state)
+        self.0.hash(state)
     }
 }
 
@@ -75,18 +79,182 @@ impl core::fmt::Debug for Symbol {
     }
 }
 
-static COUNTER: AtomicUsize = AtomicUsize::new(2);
+macro_rules! predefined {
+    ($($name:ident = $text:literal,)*) => {
+        #[allow(clippy::upper_case_acronyms)]
+        #[repr(u64)]
+        enum Predefined {
+            Empty = 1,
+            $($name,)*
+        }
 
-lazy_static::lazy_static! {
-    static ref MAP: RwLock<(FxHashMap<&'static str, NonZeroU64>,FxHashMap<NonZeroU64,&'static str>)> = {
-        let mut map = (FxHashMap::default(),FxHashMap::default());
-        let val = unsafe{NonZeroU64::new_unchecked(1)};
-        map.0.insert("",val);
-        map.1.insert(val,"");
-        parking_lot::const_rwlock(map)
+        /// The text of every predefined symbol, in order of id
+        const PREDEFINED: &[&str] = &["", $($text,)*];
+
+        /// The keywords of the script language, which are interned before anything else so they
+        /// can be constants
+        pub mod kw {
+            use super::{Predefined, Symbol};
+
+            $(pub const $name: Symbol = Symbol::new(Predefined::$name as u64);)*
+
+            /// The text of every keyword
+            pub const KEYWORDS: &[&str] = &[$($text,)*];
+        }
     };
 }
 
+predefined! {
+    BREAK = "break",
+    CALL = "call",
+    CHARACTER = "character",
+    CHOICE = "choice",
+    CONTINUE = "continue",
+    ELSE = "else",
+    FALSE = "false",
+    HIDE = "hide",
+    IF = "if",
+    IMPORT = "import",
+    JUMP = "jump",
+    LET = "let",
+    LOOP = "loop",
+    MATCH = "match",
+    RETURN = "return",
+    SCENE = "scene",
+    SHOW = "show",
+    TRUE = "true",
+    USE = "use",
+    WHILE = "while",
+}
+
+/// The number of slots in the first chunk of the [`Arena`]. Each chunk after it has twice as
+/// many as the one before.
+const FIRST_CHUNK: usize = 64;
+
+/// Enough chunks for far more symbols than will ever be interned
+const CHUNKS: usize = 40;
+
+/// The text of an interned symbol, set when it is interned and cleared if its [`Scope`] ends
+struct Slot {
+    ptr: AtomicPtr<u8>,
+    len: AtomicUsize,
+}
+
+/// The text of every symbol other than the predefined ones, in order of id. Chunks are never
+/// moved or freed once allocated, so text can be looked up without taking a lock. Slots are
+/// only written with [`INTERNER`] locked for writing, and the text in a slot is only freed once
+/// it is cleared, as its [`Scope`] ends.
+struct Arena {
+    chunks: [AtomicPtr<Slot>; CHUNKS],
+}
+
+static ARENA: Arena = Arena {
+    chunks: [const { AtomicPtr::new(ptr::null_mut()) }; CHUNKS],
+};
+
+impl Arena {
+    /// The chunk holding slot `idx`, and the index of the slot within it
+    fn locate(idx: usize) -> Option<(usize, usize)> {
+        let chunk = (usize::BITS - 1 - (idx / FIRST_CHUNK + 1).leading_zeros()) as usize;
+        (chunk < CHUNKS).then(|| (chunk, idx - FIRST_CHUNK * ((1 << chunk) - 1)))
+    }
+
+    fn slot(&self, idx: usize) -> Option<&Slot> {
+        let (chunk, offset) = Self::locate(idx)?;
+        // Acquire pairs with the release in `set` that published the chunk, so its slots are
+        // seen initialised
+        let base = self.chunks[chunk].load(Ordering::Acquire);
+        // SAFETY: A chunk is never freed once published, and `locate` keeps `offset` within it
+        (!base.is_null()).then(|| unsafe { &*base.add(offset) })
+    }
+
+    fn get(&self, idx: usize) -> Option<&'static str> {
+        let slot = self.slot(idx)?;
+        // Acquire pairs with the release of `ptr` in `set`, so the text and the length stored
+        // before it are seen too. That is also why loading the length can be relaxed.
+        let ptr = slot.ptr.load(Ordering::Acquire);
+        if ptr.is_null() {
+            return None;
+        }
+        let len = slot.len.load(Ordering::Relaxed);
+        // SAFETY: `ptr` and `len` were stored from a leaked `str` by `set`, with `len` stored
+        // before `ptr` was released
+        Some(unsafe { str::from_utf8_unchecked(slice::from_raw_parts(ptr, len)) })
+    }
+
+    /// Stores the text of slot `idx`, allocating its chunk if need be
+    fn set(&self, idx: usize, text: &'static str) {
+        let (chunk, offset) = Self::locate(idx).expect("too many symbols");
+        // Writers are ordered by the lock on `INTERNER`, so this could be relaxed, but acquire
+        // keeps every load of a chunk the same
+        let mut base = self.chunks[chunk].load(Ordering::Acquire);
+        if base.is_null() {
+            let slots = (0..FIRST_CHUNK << chunk)
+                .map(|_| Slot {
+                    ptr: AtomicPtr::new(ptr::null_mut()),
+                    len: AtomicUsize::new(0),
+                })
+                .collect::<Box<[Slot]>>();
+            base = Box::leak(slots).as_mut_ptr();
+            // Release publishes the initialised slots to readers that load the chunk
+            self.chunks[chunk].store(base, Ordering::Release);
+        }
+        // SAFETY: As in `slot`
+        let slot = unsafe { &*base.add(offset) };
+        // The length is published by the release of `ptr` after it, so it can be relaxed
+        slot.len.store(text.len(), Ordering::Relaxed);
+        slot.ptr.store(text.as_ptr() as *mut u8, Ordering::Release);
+    }
+
+    /// Clears slot `idx`, returning the text it had
+    fn take(&self, idx: usize) -> Option<&'static str> {
+        let text = self.get(idx)?;
+        // Readers that load the null pointer find the symbol gone rather than its old text
+        self.slot(idx)?
+            .ptr
+            .store(ptr::null_mut(), Ordering::Release);
+        Some(text)
+    }
+}
+
+/// Finds the symbol for a string. Looking up the text of a symbol goes through [`ARENA`] alone.
+struct Interner {
+    /// The number of symbols interned, counting the predefined ones and those whose [`Scope`]
+    /// has ended, whose ids are never given out again
+    len: usize,
+    ids: FxHashMap<&'static str, Symbol>,
+}
+
+impl Interner {
+    fn new() -> Self {
+        Self {
+            len: PREDEFINED.len(),
+            ids: PREDEFINED
+                .iter()
+                .enumerate()
+                .map(|(idx, &text)| (text, Symbol::new(idx as u64 + 1)))
+                .collect(),
+        }
+    }
+
+    fn insert(&mut self, text: &'static str) -> Symbol {
+        ARENA.set(self.len - PREDEFINED.len(), text);
+        self.len += 1;
+        let sym = Symbol::new(self.len as u64);
+        self.ids.insert(text, sym);
+        sym
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref INTERNER: RwLock<Interner> = RwLock::new(Interner::new());
+}
+
+thread_local! {
+    /// The symbols first interned on this thread within each open [`Scope`], innermost last
+    static SCOPES: RefCell<Vec<Vec<Symbol>>> = const { RefCell::new(Vec::new()) };
+}
+
 impl From<&'_ str> for Symbol {
     fn from(x: &str) -> Self {
         Self::intern(x)
@@ -101,55 +269,150 @@ impl From<String> for Symbol {
 
 impl Symbol {
     pub const fn empty() -> Self {
-        Self(unsafe { NonZeroU64::new_unchecked(1) })
+        Self::new(Predefined::Empty as u64)
+    }
+
+    const fn new(id: u64) -> Self {
+        match NonZeroU64::new(id) {
+            Some(id) => Self(id),
+            None => panic!("symbol ids start at 1"),
+        }
+    }
+
+    fn intern_cow(st: Cow<str>) -> Self {
+        if let Some(sym) = INTERNER.read().ids.get(&*st).copied() {
+            return sym;
+        }
+        let mut interner = INTERNER.write();
+        // Another thread may have interned it between the locks
+        if let Some(sym) = interner.ids.get(&*st).copied() {
+            return sym;
+        }
+        let sym = interner.insert(Box::leak(st.into_owned().into_boxed_str()));
+        SCOPES.with(|scopes| {
+            if let Some(scope) = scopes.borrow_mut().last_mut() {
+                scope.push(sym);
+            }
+        });
+        sym
     }
 
     pub fn intern_by_val(st: String) -> Self {
-        let rdgrd = MAP.read();
-        if let Some(sym) = rdgrd.0.get(&*st).copied() {
-            Symbol(sym)
-        } else {
-            drop(rdgrd);
-            let val = COUNTER
-                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |val| {
-                    <usize>::checked_add(val, 1)
-                })
-                .expect("Overflowed number of symbols") as u64;
+        Self::intern_cow(Cow::Owned(st))
+    }
 
-            let leaked = Box::leak(st.into_boxed_str());
+    pub fn intern(st: &str) -> Self {
+        Self::intern_cow(Cow::Borrowed(st))
+    }
 
-            let mut guard = MAP.write();
+    pub fn as_str(&self) -> &str {
+        let idx = self.0.get() as usize - 1;
+        match idx.checked_sub(PREDEFINED.len()) {
+            None => PREDEFINED[idx],
+            // Symbols are only made by interning, which fills in their slot first
+            Some(idx) => ARENA
+                .get(idx)
+                .expect("symbol used after the scope it was interned in ended"),
+        }
+    }
+}
+
+/// While a scope is open, every symbol first interned on its thread belongs to it, and the
+/// memory of its text is freed when the scope is dropped. This keeps names that turn out not to
+/// be needed, such as those of a bundle that is out of date, from building up.
+///
+/// The ids of freed symbols are not given out again, so a symbol used after its scope has
+/// ended panics rather than naming some other text.
+pub struct Scope {
+    /// The number of scopes open on the thread when this one was, to check they end in order
+    depth: usize,
+    /// Scopes are ended on the thread that opened them
+    _not_send: PhantomData<*const ()>,
+}
 
-            let sym = unsafe { NonZeroU64::new_unchecked(val) };
-            guard.0.insert(leaked, sym);
-            guard.1.insert(sym, leaked);
-            drop(guard);
-            Symbol(sym)
+impl Scope {
+    /// Opens a scope, lasting until the value returned is dropped. Scopes nest, and must end in
+    /// the reverse of the order they were opened.
+    ///
+    /// # Safety
+    ///
+    /// Every symbol first interned on this thread while the scope is open must not be used by
+    /// any thread once it is dropped, and neither must any string borrowed from one.
+    pub unsafe fn enter() -> Self {
+        let depth = SCOPES.with(|scopes| {
+            let mut scopes = scopes.borrow_mut();
+            scopes.push(Vec::new());
+            scopes.len()
+        });
+        Self {
+            depth,
+            _not_send: PhantomData,
         }
     }
-    pub fn intern(st: &str) -> Self {
-        let rdgrd = MAP.read();
-        if let Some(sym) = rdgrd.0.get(st).copied() {
-            Symbol(sym)
-        } else {
-            drop(rdgrd);
-            let val = COUNTER
-                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |val| {
-                    <usize>::checked_add(val, 1)
-                })
-                .expect("Overflowed number of symbols") as u64;
-            let leaked = Box::leak(Box::<str>::from(st));
-            let mut guard = MAP.write();
-            let sym = unsafe { NonZeroU64::new_unchecked(val) };
-            guard.0.insert(leaked, sym);
-            guard.1.insert(sym, leaked);
-            drop(guard);
-            Symbol(sym)
+
+    /// Ends the scope without freeing its symbols, which then belong to the enclosing scope if
+    /// there is one
+    pub fn keep(self) {
+        let symbols = self.pop();
+        SCOPES.with(|scopes| {
+            if let Some(scope) = scopes.borrow_mut().last_mut() {
+                scope.extend(symbols);
+            }
+        });
+        core::mem::forget(self);
+    }
+
+    /// Removes the symbols of the scope from the stack of open scopes
+    fn pop(&self) -> Vec<Symbol> {
+        SCOPES.with(|scopes| {
+            let mut scopes = scopes.borrow_mut();
+            assert_eq!(
+                scopes.len(),
+                self.depth,
+                "symbol scopes must be ended innermost first"
+            );
+            scopes.pop().unwrap()
+        })
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let symbols = self.pop();
+        let mut interner = INTERNER.write();
+        for sym in symbols {
+            let text = ARENA
+                .take(sym.0.get() as usize - 1 - PREDEFINED.len())
+                .unwrap();
+            interner.ids.remove(text);
+            // SAFETY: The text was leaked from a box when the symbol was interned, and the
+            // contract of `Scope::enter` means nothing refers to it any more
+            drop(unsafe { Box::from_raw(text as *const str as *mut str) });
         }
     }
+}
 
-    pub fn as_str(&self) -> &str {
-        MAP.read().1[&self.0]
+/// The text of some symbols, in order. Ids depend on the order symbols are first interned in,
+/// so data that is stored refers to symbols by their index in a snapshot instead, and
+/// [`Snapshot::restore`] gives back the symbols with those indices in any later run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub strings: Vec<String>,
+}
+
+impl Snapshot {
+    pub fn take(symbols: &[Symbol]) -> Self {
+        Self {
+            strings: symbols.iter().map(|sym| sym.as_str().to_string()).collect(),
+        }
+    }
+
+    /// Interns the text of each symbol again, giving the symbols in the order they were taken
+    pub fn restore(&self) -> Vec<Symbol> {
+        self.strings
+            .iter()
+            .map(|text| Symbol::intern(text))
+            .collect()
     }
 }
 
@@ -160,14 +423,122 @@ impl Deref for Symbol {
     }
 }
 
-impl Borrow<str> for Symbol {
-    fn borrow(&self) -> &str {
+impl AsRef<str> for Symbol {
+    fn as_ref(&self) -> &str {
         self.as_str()
     }
 }
 
-impl AsRef<str> for Symbol {
-    fn as_ref(&self) -> &str {
-        self.as_str()
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keywords_are_constants() {
+        assert_eq!(Symbol::from("scene"), kw::SCENE);
+        assert_eq!(kw::WHILE.as_str(), "while");
+        assert_eq!(Symbol::from(""), Symbol::empty());
+        assert_eq!(kw::KEYWORDS.len() + 1, PREDEFINED.len());
+    }
+
+    #[test]
+    fn concurrent_interning() {
+        const THREADS: usize = 8;
+        // Enough symbols to fill several chunks while the threads race
+        const NAMES: usize = 3000;
+        let threads = (0..THREADS)
+            .map(|thread| {
+                std::thread::spawn(move || {
+                    let mut interned = Vec::new();
+                    for i in 0..NAMES {
+                        // Every thread interns the shared names, in a different order
+                        let shared = format!("concurrent-shared-{}", (i * (thread + 1)) % NAMES);
+                        let own = format!("concurrent-{}-{}", thread, i);
+                        for text in [shared, own] {
+                            let sym = Symbol::from(text.as_str());
+                            assert_eq!(sym.as_str(), text);
+                            interned.push((sym, text));
+                        }
+                        // Read back symbols interned earlier, which may be in chunks another
+                        // thread is still filling
+                        let (sym, text) = &interned[i / 2];
+                        assert_eq!(sym.as_str(), text);
+                    }
+                    interned
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut ids = FxHashMap::default();
+        for thread in threads {
+            for (sym, text) in thread.join().unwrap() {
+                assert_eq!(sym.as_str(), text);
+                assert_eq!(*ids.entry(text).or_insert(sym), sym);
+            }
+        }
+        assert_eq!(ids.len(), NAMES * (THREADS + 1));
+    }
+
+    fn is_interned(text: &str) -> bool {
+        INTERNER.read().ids.contains_key(text)
+    }
+
+    #[test]
+    fn scopes_free_what_was_first_interned_in_them() {
+        let before = Symbol::from("scope-before");
+        let scope = unsafe { Scope::enter() };
+        assert_eq!(Symbol::from("scope-before"), before);
+        let inner = Symbol::from("scope-inner");
+        assert_eq!(inner.as_str(), "scope-inner");
+        drop(scope);
+
+        assert_eq!(before.as_str(), "scope-before");
+        assert!(!is_interned("scope-inner"));
+        assert!(std::panic::catch_unwind(|| inner.as_str().len()).is_err());
+        // The freed id is not given out again
+        let again = Symbol::from("scope-inner");
+        assert_ne!(again, inner);
+        assert_eq!(again.as_str(), "scope-inner");
+    }
+
+    #[test]
+    fn kept_scopes_give_their_symbols_to_the_enclosing_one() {
+        let outer = unsafe { Scope::enter() };
+        let inner = unsafe { Scope::enter() };
+        let kept = Symbol::from("scope-kept");
+        inner.keep();
+        assert_eq!(kept.as_str(), "scope-kept");
+        drop(outer);
+        assert!(!is_interned("scope-kept"));
+
+        let scope = unsafe { Scope::enter() };
+        let kept = Symbol::from("scope-kept-outermost");
+        scope.keep();
+        assert_eq!(kept.as_str(), "scope-kept-outermost");
+    }
+
+    #[test]
+    fn scopes_only_hold_symbols_of_their_thread() {
+        let scope = unsafe { Scope::enter() };
+        let other = std::thread::spawn(|| Symbol::from("scope-other-thread"))
+            .join()
+            .unwrap();
+        drop(scope);
+        assert_eq!(other.as_str(), "scope-other-thread");
+    }
+
+    #[test]
+    #[should_panic(expected = "innermost first")]
+    fn scopes_end_innermost_first() {
+        let outer = unsafe { Scope::enter() };
+        let _inner = unsafe { Scope::enter() };
+        drop(outer);
+    }
+
+    #[test]
+    fn snapshots_restore_symbols_in_order() {
+        let symbols = ["snapshot-a", "snapshot-b", "snapshot-a"].map(Symbol::from);
+        let snapshot = Snapshot::take(&symbols);
+        assert_eq!(snapshot.strings, ["snapshot-a", "snapshot-b", "snapshot-a"]);
+        assert_eq!(snapshot.restore(), symbols);
     }
 }
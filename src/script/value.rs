@@ -0,0 +1,625 @@
+use core::{fmt, time::Duration};
+use std::{collections::BTreeMap, rc::Rc};
+
+use super::{
+    diag::Diagnostic,
+    markup::{self, Piece},
+    parse::{BinaryOp, Literal, UnaryOp},
+    span::Span,
+    symbol::Symbol,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Unit,
+    Int(i64),
+    Float(f64),
+    Duration(Duration),
+    Bool(bool),
+    String(String),
+    Symbol(Symbol),
+    List(Vec<Value>),
+    /// Keys are strings made at runtime, so they are not interned as symbols, which would
+    /// keep them for the life of the program
+    Map(BTreeMap<Rc<str>, Value>),
+    /// A character declared with `character name = ...`
+    Character(Symbol),
+    /// An image, by path
+    Image(Symbol),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Unit => "unit",
+            Self::Int(_) => "int",
+            Self::Float(_) => "float",
+            Self::Duration(_) => "duration",
+            Self::Bool(_) => "bool",
+            Self::String(_) => "string",
+            Self::Symbol(_) => "symbol",
+            Self::List(_) => "list",
+            Self::Map(_) => "map",
+            Self::Character(_) => "character",
+            Self::Image(_) => "image",
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Self::Unit => false,
+            Self::Bool(b) => *b,
+            Self::Int(i) => *i != 0,
+            Self::Float(f) => *f != 0.0,
+            Self::Duration(d) => !d.is_zero(),
+            Self::String(s) => !s.is_empty(),
+            Self::List(l) => !l.is_empty(),
+            Self::Map(m) => !m.is_empty(),
+            Self::Symbol(_) | Self::Character(_) | Self::Image(_) => true,
+        }
+    }
+}
+
+impl From<Literal> for Value {
+    fn from(lit: Literal) -> Self {
+        match lit {
+            Literal::Int(i) => Self::Int(i),
+            Literal::Float(f) => Self::Float(f),
+            Literal::Duration(d) => Self::Duration(d),
+            Literal::Bool(b) => Self::Bool(b),
+            Literal::Char(c) => Self::String(c.into()),
+            Literal::String(s) => Self::String(s),
+            Literal::Symbol(s) => Self::Symbol(s),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unit => f.write_str("()"),
+            Self::Int(i) => i.fmt(f),
+            Self::Float(x) => x.fmt(f),
+            Self::Duration(d) => write!(f, "{}s", d.as_secs_f64()),
+            Self::Bool(b) => b.fmt(f),
+            Self::String(s) => s.fmt(f),
+            Self::Symbol(s) => write!(f, "'{}", s),
+            Self::List(l) => {
+                f.write_str("[")?;
+                for (i, v) in l.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(", ")?;
+                    }
+                    v.fmt(f)?;
+                }
+                f.write_str("]")
+            }
+            Self::Map(m) => {
+                f.write_str("{")?;
+                for (i, (k, v)) in m.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: {}", k, v)?;
+                }
+                f.write_str("}")
+            }
+            Self::Character(c) => c.fmt(f),
+            Self::Image(path) => write!(f, "image({:?})", path),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    UndefinedName(Symbol, Span),
+    UndefinedScene(Symbol, Span),
+    /// The second span is the previous definition
+    DuplicateDefinition(Symbol, Span, Box<Span>),
+    /// An operator or builtin was applied to values of the wrong type
+    TypeMismatch(&'static str, Vec<&'static str>, Span),
+    DivideByZero(Span),
+    Overflow(Span),
+    IndexOutOfBounds(i64, Span),
+    NoSuchField(Symbol, Span),
+    NoSuchKey(Rc<str>, Span),
+    InvalidAssignTarget(Span),
+    NotCallable(Span),
+    WrongArgumentCount(usize, usize, Span),
+    /// `break` or `continue` outside of a loop
+    NotInLoop(Span),
+    /// `show` or `hide` named a layer that is not one of [`LAYERS`]
+    NoSuchLayer(Symbol, Span),
+    /// The script was resumed with an option that was not offered by the `choice` at the span
+    InvalidChoice(usize, Span),
+    /// The script was resumed with [`Input::Continue`] while waiting on the `choice` at the span
+    ChoiceRequired(Span),
+    /// The scene execution was started from does not exist
+    NoSuchEntryPoint(Symbol),
+    /// The text of the line of dialogue or option at the span has invalid markup
+    Markup(markup::Error, Span),
+    /// An instruction popped more values than were on the stack, which only corrupt bytecode
+    /// does
+    StackUnderflow(Span),
+    /// A markup tag in a string that is not the text of dialogue or an option
+    MisplacedMarkup(Span),
+    /// A markup tag with the name of a variable, which it could be meant to show
+    AmbiguousMarkup(Symbol, Span),
+}
+
+impl Error {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Self::UndefinedName(_, span)
+            | Self::UndefinedScene(_, span)
+            | Self::DuplicateDefinition(_, span, _)
+            | Self::TypeMismatch(_, _, span)
+            | Self::DivideByZero(span)
+            | Self::Overflow(span)
+            | Self::IndexOutOfBounds(_, span)
+            | Self::NoSuchField(_, span)
+            | Self::NoSuchKey(_, span)
+            | Self::InvalidAssignTarget(span)
+            | Self::NotCallable(span)
+            | Self::WrongArgumentCount(_, _, span)
+            | Self::NotInLoop(span)
+            | Self::NoSuchLayer(_, span)
+            | Self::InvalidChoice(_, span)
+            | Self::ChoiceRequired(span)
+            | Self::Markup(_, span)
+            | Self::StackUnderflow(span)
+            | Self::MisplacedMarkup(span)
+            | Self::AmbiguousMarkup(_, span) => Some(*span),
+            Self::NoSuchEntryPoint(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UndefinedName(name, _) => write!(f, "cannot find `{}` in this scope", name),
+            Self::UndefinedScene(name, _) => write!(f, "no scene named `{}`", name),
+            Self::DuplicateDefinition(name, _, _) => {
+                write!(f, "`{}` is defined multiple times", name)
+            }
+            Self::TypeMismatch(op, types, _) => {
+                write!(f, "cannot apply `{}` to {}", op, types.join(" and "))
+            }
+            Self::DivideByZero(_) => f.write_str("attempt to divide by zero"),
+            Self::Overflow(_) => f.write_str("arithmetic overflow"),
+            Self::IndexOutOfBounds(idx, _) => write!(f, "index {} is out of bounds", idx),
+            Self::NoSuchField(field, _) => write!(f, "no field `{}`", field),
+            Self::NoSuchKey(key, _) => write!(f, "no key {:?} in map", key),
+            Self::InvalidAssignTarget(_) => f.write_str("invalid left-hand side of assignment"),
+            Self::NotCallable(_) => f.write_str("expression is not callable"),
+            Self::WrongArgumentCount(expected, found, _) => write!(
+                f,
+                "expected {} argument(s), but {} were supplied",
+                expected, found
+            ),
+            Self::NotInLoop(_) => f.write_str("`break` or `continue` outside of a loop"),
+            Self::NoSuchLayer(name, _) => write!(f, "no layer named `{}`", name),
+            Self::InvalidChoice(idx, _) => write!(f, "option {} was not offered", idx),
+            Self::ChoiceRequired(_) => f.write_str("an option must be chosen"),
+            Self::NoSuchEntryPoint(name) => write!(f, "no scene named `{}` to start from", name),
+            Self::Markup(err, _) => err.fmt(f),
+            Self::StackUnderflow(_) => f.write_str("stack underflow"),
+            Self::MisplacedMarkup(_) => {
+                f.write_str("markup can only be used in the text of dialogue and options")
+            }
+            Self::AmbiguousMarkup(name, _) => {
+                write!(f, "`{{{}}}` is both a markup tag and a variable", name)
+            }
+        }
+    }
+}
+
+impl From<Error> for Diagnostic {
+    fn from(err: Error) -> Self {
+        let mut diag = Diagnostic::error(err.to_string());
+        if let Some(span) = err.span() {
+            diag = diag.with_label(span, "");
+        }
+        match err {
+            Error::DuplicateDefinition(_, _, prev) => {
+                diag.with_secondary(*prev, "previously defined here")
+            }
+            Error::IndexOutOfBounds(..) => {
+                diag.with_note("negative indices count back from the end")
+            }
+            Error::NotInLoop(_) => {
+                diag.with_note("a scene cannot be left with `break`, use `return`")
+            }
+            Error::NoSuchLayer(..) => diag.with_note(format!(
+                "the layers are, from the bottom up: {}",
+                LAYERS.join(", ")
+            )),
+            Error::AmbiguousMarkup(name, _) => diag.with_note(format!(
+                "write `{{({})}}` to show the variable, or rename the variable to use the tag",
+                name
+            )),
+            _ => diag,
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// A line of dialogue to show to the player
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    /// The display name of the speaker
+    pub name: Option<String>,
+    /// The text split into styled runs by its markup
+    pub markup: Vec<Piece>,
+}
+
+/// Why the script stopped running, and what the player should be shown
+#[derive(Clone, Debug, PartialEq)]
+pub enum Yield {
+    /// Resume with [`Input::Continue`] once the player has read the line
+    Dialogue(Line),
+    /// Resume with [`Input::Choose`] giving the index of the selected option. Each option is
+    /// split into styled runs by its markup.
+    Choice(Vec<Vec<Piece>>),
+    /// The script ran for [`STEP_BUDGET`] instructions without stopping. Resume with
+    /// [`Input::Continue`] to let it run for longer, once the event loop has had a turn.
+    Busy,
+    /// The entry scene has returned. Resuming does nothing.
+    Finished,
+}
+
+/// The most instructions a script runs for before it yields [`Yield::Busy`], so that a long or
+/// endless loop does not freeze the game
+pub const STEP_BUDGET: u32 = 100_000;
+
+/// The player's response to a [`Yield`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Input {
+    Continue,
+    Choose(usize),
+}
+
+pub fn op_name(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Rem => "%",
+        BinaryOp::BitAnd => "&",
+        BinaryOp::BitOr => "|",
+        BinaryOp::BitXor => "^",
+        BinaryOp::LogicAnd => "&&",
+        BinaryOp::LogicOr => "||",
+        BinaryOp::LeftShift => "<<",
+        BinaryOp::RightShift => ">>",
+        BinaryOp::Assign => "=",
+        BinaryOp::AddAssign => "+=",
+        BinaryOp::SubAssign => "-=",
+        BinaryOp::MulAssign => "*=",
+        BinaryOp::DivAssign => "/=",
+        BinaryOp::RemAssign => "%=",
+        BinaryOp::BitAndAssign => "&=",
+        BinaryOp::BitOrAssign => "|=",
+        BinaryOp::BitXorAssign => "^=",
+        BinaryOp::LeftShiftAssign => "<<=",
+        BinaryOp::RightShiftAssign => ">>=",
+        BinaryOp::CmpEq => "==",
+        BinaryOp::CmpNe => "!=",
+        BinaryOp::CmpLt => "<",
+        BinaryOp::CmpGt => ">",
+        BinaryOp::CmpLe => "<=",
+        BinaryOp::CmpGe => ">=",
+    }
+}
+
+/// Equality as seen by `==` and `match`. Ints and floats compare numerically.
+pub fn values_equal(lhs: &Value, rhs: &Value) -> bool {
+    match (lhs, rhs) {
+        (Value::Int(a), Value::Float(b)) | (Value::Float(b), Value::Int(a)) => *a as f64 == *b,
+        (Value::List(a), Value::List(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| values_equal(a, b))
+        }
+        (a, b) => a == b,
+    }
+}
+
+pub fn unary_op(op: UnaryOp, val: Value, span: Span) -> Result<Value> {
+    match (op, val) {
+        (UnaryOp::Neg, Value::Int(i)) => {
+            i.checked_neg().map(Value::Int).ok_or(Error::Overflow(span))
+        }
+        (UnaryOp::Neg, Value::Float(f)) => Ok(Value::Float(-f)),
+        (UnaryOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+        (UnaryOp::Not | UnaryOp::BitNot, Value::Int(i)) => Ok(Value::Int(!i)),
+        (UnaryOp::Neg, val) => Err(Error::TypeMismatch("-", vec![val.type_name()], span)),
+        (UnaryOp::Not, val) => Err(Error::TypeMismatch("!", vec![val.type_name()], span)),
+        (UnaryOp::BitNot, val) => Err(Error::TypeMismatch("~", vec![val.type_name()], span)),
+    }
+}
+
+pub fn binary_op(op: BinaryOp, lhs: Value, rhs: Value, span: Span) -> Result<Value> {
+    use Value::*;
+    let mismatch = |lhs: &Value, rhs: &Value| {
+        Error::TypeMismatch(op_name(op), vec![lhs.type_name(), rhs.type_name()], span)
+    };
+    match op {
+        BinaryOp::Add => match (lhs, rhs) {
+            (Int(a), Int(b)) => a.checked_add(b).map(Int).ok_or(Error::Overflow(span)),
+            (Duration(a), Duration(b)) => {
+                a.checked_add(b).map(Duration).ok_or(Error::Overflow(span))
+            }
+            (String(a), b) => Ok(String(a + &b.to_string())),
+            (a, String(b)) => Ok(String(a.to_string() + &b)),
+            (List(mut a), List(b)) => {
+                a.extend(b);
+                Ok(List(a))
+            }
+            (Map(mut a), Map(b)) => {
+                a.extend(b);
+                Ok(Map(a))
+            }
+            (a, b) => float_op(&a, &b, |a, b| a + b).ok_or_else(|| mismatch(&a, &b)),
+        },
+        BinaryOp::Sub => match (lhs, rhs) {
+            (Int(a), Int(b)) => a.checked_sub(b).map(Int).ok_or(Error::Overflow(span)),
+            (Duration(a), Duration(b)) => {
+                a.checked_sub(b).map(Duration).ok_or(Error::Overflow(span))
+            }
+            (a, b) => float_op(&a, &b, |a, b| a - b).ok_or_else(|| mismatch(&a, &b)),
+        },
+        BinaryOp::Mul => match (lhs, rhs) {
+            (Int(a), Int(b)) => a.checked_mul(b).map(Int).ok_or(Error::Overflow(span)),
+            (String(a), Int(n)) if n >= 0 => Ok(String(a.repeat(n as usize))),
+            (Duration(d), n) | (n, Duration(d)) if as_float(&n).is_some() => {
+                scale_duration(d, as_float(&n).unwrap(), span)
+            }
+            (a, b) => float_op(&a, &b, |a, b| a * b).ok_or_else(|| mismatch(&a, &b)),
+        },
+        BinaryOp::Div => match (lhs, rhs) {
+            (Int(_), Int(0)) => Err(Error::DivideByZero(span)),
+            (Int(a), Int(b)) => a.checked_div(b).map(Int).ok_or(Error::Overflow(span)),
+            (Duration(a), Duration(b)) => Ok(Float(a.as_secs_f64() / b.as_secs_f64())),
+            (Duration(d), n) if as_float(&n).is_some() => {
+                scale_duration(d, 1.0 / as_float(&n).unwrap(), span)
+            }
+            (a, b) => float_op(&a, &b, |a, b| a / b).ok_or_else(|| mismatch(&a, &b)),
+        },
+        BinaryOp::Rem => match (lhs, rhs) {
+            (Int(_), Int(0)) => Err(Error::DivideByZero(span)),
+            (Int(a), Int(b)) => a.checked_rem(b).map(Int).ok_or(Error::Overflow(span)),
+            (a, b) => float_op(&a, &b, |a, b| a % b).ok_or_else(|| mismatch(&a, &b)),
+        },
+        BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::BitXor => match (lhs, rhs) {
+            (Int(a), Int(b)) => Ok(Int(match op {
+                BinaryOp::BitAnd => a & b,
+                BinaryOp::BitOr => a | b,
+                _ => a ^ b,
+            })),
+            (Bool(a), Bool(b)) => Ok(Bool(match op {
+                BinaryOp::BitAnd => a & b,
+                BinaryOp::BitOr => a | b,
+                _ => a ^ b,
+            })),
+            (a, b) => Err(mismatch(&a, &b)),
+        },
+        BinaryOp::LeftShift | BinaryOp::RightShift => match (lhs, rhs) {
+            (Int(a), Int(b)) => {
+                let shift = u32::try_from(b).ok().filter(|&b| b < 64);
+                let res = match (op, shift) {
+                    (BinaryOp::LeftShift, Some(b)) => a.checked_shl(b),
+                    (_, Some(b)) => a.checked_shr(b),
+                    (_, None) => None,
+                };
+                res.map(Int).ok_or(Error::Overflow(span))
+            }
+            (a, b) => Err(mismatch(&a, &b)),
+        },
+        BinaryOp::LogicAnd | BinaryOp::LogicOr => match (lhs, rhs) {
+            (Bool(a), Bool(b)) => Ok(Bool(if op == BinaryOp::LogicAnd {
+                a && b
+            } else {
+                a || b
+            })),
+            (a, b) => Err(mismatch(&a, &b)),
+        },
+        BinaryOp::CmpEq => Ok(Bool(values_equal(&lhs, &rhs))),
+        BinaryOp::CmpNe => Ok(Bool(!values_equal(&lhs, &rhs))),
+        BinaryOp::CmpLt | BinaryOp::CmpGt | BinaryOp::CmpLe | BinaryOp::CmpGe => {
+            let ord = match (&lhs, &rhs) {
+                (Int(a), Int(b)) => Some(a.cmp(b)),
+                (Duration(a), Duration(b)) => Some(a.cmp(b)),
+                (String(a), String(b)) => Some(a.cmp(b)),
+                (Symbol(a), Symbol(b)) => Some(a.cmp(b)),
+                (a, b) => match (as_float(a), as_float(b)) {
+                    (Some(a), Some(b)) => a.partial_cmp(&b),
+                    _ => return Err(mismatch(a, b)),
+                },
+            };
+            Ok(Bool(match ord {
+                None => false, // NaN
+                Some(ord) => match op {
+                    BinaryOp::CmpLt => ord.is_lt(),
+                    BinaryOp::CmpGt => ord.is_gt(),
+                    BinaryOp::CmpLe => ord.is_le(),
+                    _ => ord.is_ge(),
+                },
+            }))
+        }
+        op => match op.compound_op() {
+            Some(inner) => binary_op(inner, lhs, rhs, span),
+            None => Ok(rhs), // `=`
+        },
+    }
+}
+
+/// Multiplies a duration by a number, which must leave it finite and non-negative
+fn scale_duration(d: Duration, by: f64, span: Span) -> Result<Value> {
+    Duration::try_from_secs_f64(d.as_secs_f64() * by)
+        .map(Value::Duration)
+        .map_err(|_| Error::Overflow(span))
+}
+
+fn as_float(val: &Value) -> Option<f64> {
+    match val {
+        Value::Int(i) => Some(*i as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+/// Applies `f` to the operands, promoting ints to floats. `None` if either is not numeric.
+fn float_op(lhs: &Value, rhs: &Value, f: impl FnOnce(f64, f64) -> f64) -> Option<Value> {
+    Some(Value::Float(f(as_float(lhs)?, as_float(rhs)?)))
+}
+
+fn list_index(len: usize, idx: i64, span: Span) -> Result<usize> {
+    // Negative indices count from the end
+    let real = if idx < 0 { len as i64 + idx } else { idx };
+    if real < 0 || real >= len as i64 {
+        Err(Error::IndexOutOfBounds(idx, span))
+    } else {
+        Ok(real as usize)
+    }
+}
+
+/// The key of a map indexed by `key`. A symbol indexes the same entry as a string of its text.
+fn map_key(key: Value, span: Span) -> Result<Rc<str>> {
+    match key {
+        Value::String(s) => Ok(s.into()),
+        Value::Symbol(s) => Ok(s.as_str().into()),
+        key => Err(Error::TypeMismatch(
+            "[]",
+            vec!["map", key.type_name()],
+            span,
+        )),
+    }
+}
+
+pub fn index(base: Value, idx: Value, span: Span) -> Result<Value> {
+    match (base, idx) {
+        (Value::List(mut l), Value::Int(i)) => {
+            let i = list_index(l.len(), i, span)?;
+            Ok(l.swap_remove(i))
+        }
+        (Value::String(s), Value::Int(i)) => {
+            let i = list_index(s.chars().count(), i, span)?;
+            Ok(Value::String(s.chars().nth(i).unwrap().into()))
+        }
+        (Value::Map(mut m), key) => {
+            let key = map_key(key, span)?;
+            m.remove(&key).ok_or(Error::NoSuchKey(key, span))
+        }
+        (base, idx) => Err(Error::TypeMismatch(
+            "[]",
+            vec![base.type_name(), idx.type_name()],
+            span,
+        )),
+    }
+}
+
+pub fn index_mut(base: &mut Value, idx: Value, span: Span) -> Result<&mut Value> {
+    match (base, idx) {
+        (Value::List(l), Value::Int(i)) => {
+            let i = list_index(l.len(), i, span)?;
+            Ok(&mut l[i])
+        }
+        (Value::Map(m), key) => Ok(m.entry(map_key(key, span)?).or_insert(Value::Unit)),
+        (base, idx) => Err(Error::TypeMismatch(
+            "[]",
+            vec![base.type_name(), idx.type_name()],
+            span,
+        )),
+    }
+}
+
+/// The layers `show` and `hide` name, from the bottom of the screen to the top
+pub const LAYERS: &[&str] = &["background", "characters", "ui", "overlay"];
+
+/// The functions that can be called from a script
+pub const BUILTINS: &[&str] = &["map", "len", "str", "int", "float", "image"];
+
+pub fn call_builtin(name: Symbol, args: Vec<Value>, callee: Span, span: Span) -> Result<Value> {
+    let mismatch = |name: &'static str| {
+        Error::TypeMismatch(name, args.iter().map(Value::type_name).collect(), span)
+    };
+    match (name.as_str(), &args[..]) {
+        ("map", []) => Ok(Value::Map(BTreeMap::new())),
+        ("len", [Value::List(l)]) => Ok(Value::Int(l.len() as i64)),
+        ("len", [Value::Map(m)]) => Ok(Value::Int(m.len() as i64)),
+        ("len", [Value::String(s)]) => Ok(Value::Int(s.chars().count() as i64)),
+        ("len", [_]) => Err(mismatch("len")),
+        ("str", [v]) => Ok(Value::String(v.to_string())),
+        ("int", [Value::Int(i)]) => Ok(Value::Int(*i)),
+        ("int", [Value::Float(f)]) => Ok(Value::Int(*f as i64)),
+        ("int", [Value::Bool(b)]) => Ok(Value::Int(*b as i64)),
+        ("int", [Value::String(s)]) => s
+            .trim()
+            .parse()
+            .map(Value::Int)
+            .map_err(|_| mismatch("int")),
+        ("int", [_]) => Err(mismatch("int")),
+        ("float", [Value::Duration(d)]) => Ok(Value::Float(d.as_secs_f64())),
+        ("float", [Value::String(s)]) => s
+            .trim()
+            .parse()
+            .map(Value::Float)
+            .map_err(|_| mismatch("float")),
+        ("float", [v]) => as_float(v)
+            .map(Value::Float)
+            .ok_or_else(|| mismatch("float")),
+        ("image", [Value::String(path)]) => Ok(Value::Image(path.as_str().into())),
+        ("image", [_]) => Err(mismatch("image")),
+        ("map", _) => Err(Error::WrongArgumentCount(0, args.len(), span)),
+        ("len" | "str" | "int" | "float" | "image", _) => {
+            Err(Error::WrongArgumentCount(1, args.len(), span))
+        }
+        _ => Err(Error::UndefinedName(name, callee)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::span::Pos;
+
+    fn span() -> Span {
+        Span::at(Pos::new(1, 1, 0, "test"))
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn strings_and_symbols_index_the_same_entry() {
+        let mut map = Value::Map(BTreeMap::new());
+        *index_mut(&mut map, string("key"), span()).unwrap() = Value::Int(1);
+        *index_mut(&mut map, Value::Symbol("key".into()), span()).unwrap() = Value::Int(2);
+        *index_mut(&mut map, string("other key"), span()).unwrap() = Value::Int(3);
+        assert_eq!(map.to_string(), "{key: 2, other key: 3}");
+        assert_eq!(
+            index(map.clone(), string("key"), span()).unwrap(),
+            Value::Int(2)
+        );
+        assert_eq!(
+            index(map, Value::Symbol("other key".into()), span()).unwrap(),
+            Value::Int(3)
+        );
+    }
+
+    #[test]
+    fn missing_and_invalid_keys() {
+        let map = Value::Map(BTreeMap::from([("a".into(), Value::Unit)]));
+        match index(map.clone(), string("b"), span()) {
+            Err(Error::NoSuchKey(key, _)) => assert_eq!(&*key, "b"),
+            res => panic!("{:?}", res),
+        }
+        match index(map, Value::Int(0), span()) {
+            Err(Error::TypeMismatch("[]", types, _)) => assert_eq!(types, ["map", "int"]),
+            res => panic!("{:?}", res),
+        }
+    }
+}
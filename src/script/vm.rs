@@ -0,0 +1,1157 @@
+use std::rc::Rc;
+
+use fxhash::FxHashMap;
+
+use super::{
+    bytecode::{Op, Place, Program, SceneInfo, Step, Var},
+    encode::{self, Decoder, Encoder},
+    markup,
+    parse::BinaryOp,
+    span::Span,
+    symbol::Symbol,
+    value::{
+        binary_op, call_builtin, index, index_mut, op_name, unary_op, values_equal, Error, Input,
+        Line, Result, Value, Yield, LAYERS, STEP_BUDGET,
+    },
+};
+
+/// A scene that is part way through running
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CallFrame {
+    /// An index into [`Program::scenes`], or into [`Program::inits`] while defining items
+    pub scene: u32,
+    /// The next instruction to run
+    pub pc: u32,
+    /// Where the local variables of the scene start on the stack
+    pub base: u32,
+}
+
+/// A `choice` waiting for the player
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingChoice {
+    /// The address of the [`Op::Choose`], for errors
+    pub pc: u32,
+    /// Where each option continues
+    pub targets: Vec<u32>,
+    /// The markup of each option
+    pub texts: Vec<String>,
+}
+
+/// Everything about a running script that changes as it runs. It is plain data, so it can be
+/// saved with [`Vm::save`] and picked up again later.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct State {
+    pub frames: Vec<CallFrame>,
+    pub stack: Vec<Value>,
+    pub globals: Vec<Value>,
+    /// The display name of each character
+    pub characters: Vec<Value>,
+    /// The options added since the last [`Op::Choose`], with where they continue
+    pub options: Vec<(String, u32)>,
+    pub pending: Option<PendingChoice>,
+    /// The line of dialogue waiting for the player, as the display name of its speaker and its
+    /// markup
+    pub line: Option<(Option<String>, String)>,
+    /// The image shown on each of [`LAYERS`], or unit where a layer is empty
+    pub layers: Vec<Value>,
+}
+
+/// The version of the format written by [`Vm::save`]
+const SAVE_VERSION: u32 = 3;
+
+/// The extension of a saved game, which is kept beside the entry script it was saved from
+pub const SAVE_EXTENSION: &str = "vnsave";
+
+/// Runs a compiled script one step at a time. Execution is driven by [`Vm::resume`], which
+/// runs until the player needs to be shown something, so the caller is never blocked waiting
+/// for input.
+pub struct Vm {
+    program: Rc<Program>,
+    characters: FxHashMap<Symbol, u32>,
+    state: State,
+}
+
+impl Vm {
+    /// Defines every character and global, reporting all of the errors found in doing so
+    pub fn new(program: Rc<Program>) -> core::result::Result<Self, Vec<Error>> {
+        let mut vm = Self {
+            characters: program.character_indices(),
+            state: State {
+                globals: vec![Value::Unit; program.globals.len()],
+                characters: vec![Value::Unit; program.characters.len()],
+                layers: vec![Value::Unit; LAYERS.len()],
+                ..State::default()
+            },
+            program,
+        };
+        let mut errors = Vec::new();
+        for idx in 0..vm.program.inits.len() {
+            let init = &vm.program.inits[idx];
+            vm.state.frames = vec![CallFrame {
+                scene: idx as u32,
+                pc: init.entry,
+                base: 0,
+            }];
+            vm.state.stack = vec![Value::Unit; init.locals as usize];
+            // Initializers are expressions, which cannot loop, so they need no budget
+            if let Err(e) = vm.run(u32::MAX) {
+                errors.push(e);
+            }
+        }
+        vm.state.frames.clear();
+        vm.state.stack.clear();
+        if errors.is_empty() {
+            Ok(vm)
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn program(&self) -> &Rc<Program> {
+        &self.program
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Discards any execution in progress and starts again at the beginning of the scene `name`.
+    /// Nothing runs until the first call to [`Vm::resume`].
+    pub fn start(&mut self, name: Symbol) -> Result<()> {
+        let scene = self
+            .program
+            .scene(name)
+            .ok_or(Error::NoSuchEntryPoint(name))?;
+        self.state.frames.clear();
+        self.state.stack.clear();
+        self.state.options.clear();
+        self.state.pending = None;
+        self.state.line = None;
+        self.state.layers.fill(Value::Unit);
+        self.enter(scene);
+        Ok(())
+    }
+
+    /// Whether the script has more to run before it shows the player anything, as after
+    /// [`Yield::Busy`], so it should be resumed without waiting for them
+    pub fn is_busy(&self) -> bool {
+        !self.state.frames.is_empty() && self.state.line.is_none() && self.state.pending.is_none()
+    }
+
+    /// The line or menu waiting for the player, such as after [`Vm::restore`]
+    pub fn shown(&self) -> Option<Yield> {
+        // The markup was checked when it was first shown, or restored
+        let parse = |text: &str| markup::parse(text).unwrap_or_default();
+        if let Some(pending) = &self.state.pending {
+            let options = pending.texts.iter().map(|text| parse(text)).collect();
+            return Some(Yield::Choice(options));
+        }
+        let (name, text) = self.state.line.as_ref()?;
+        Some(Yield::Dialogue(Line {
+            name: name.clone(),
+            markup: parse(text),
+        }))
+    }
+
+    /// Whether the script is waiting on [`Input::Choose`] rather than [`Input::Continue`]
+    pub fn awaiting_choice(&self) -> bool {
+        self.state.pending.is_some()
+    }
+
+    /// Runs until the next line of dialogue, menu, or the end of the script, or for at most
+    /// [`STEP_BUDGET`] instructions.
+    ///
+    /// `input` answers the previous [`Yield`], and is ignored if that was dialogue.
+    pub fn resume(&mut self, input: Input) -> Result<Yield> {
+        if let Some(pending) = self.state.pending.take() {
+            let span = self.program.spans[pending.pc as usize];
+            match input {
+                Input::Choose(idx) if idx < pending.targets.len() => {
+                    self.state.frames.last_mut().unwrap().pc = pending.targets[idx];
+                }
+                Input::Choose(idx) => {
+                    self.state.pending = Some(pending);
+                    return Err(Error::InvalidChoice(idx, span));
+                }
+                Input::Continue => {
+                    self.state.pending = Some(pending);
+                    return Err(Error::ChoiceRequired(span));
+                }
+            }
+        }
+        self.state.line = None;
+        Ok(self.run(STEP_BUDGET)?.unwrap_or(Yield::Finished))
+    }
+
+    /// Pushes a frame for the scene, with its local variables
+    fn enter(&mut self, scene: u32) {
+        let SceneInfo { entry, locals, .. } = self.program.scenes[scene as usize];
+        self.state.frames.push(CallFrame {
+            scene,
+            pc: entry,
+            base: self.state.stack.len() as u32,
+        });
+        let len = self.state.stack.len() + locals as usize;
+        self.state.stack.resize(len, Value::Unit);
+    }
+
+    /// Pops the current frame and its local variables
+    fn leave(&mut self) {
+        if let Some(frame) = self.state.frames.pop() {
+            self.state.stack.truncate(frame.base as usize);
+        }
+    }
+
+    /// Pops the top value for the instruction at `span`
+    fn pop(&mut self, span: Span) -> Result<Value> {
+        self.state.stack.pop().ok_or(Error::StackUnderflow(span))
+    }
+
+    /// Pops the top `n` values, in the order they were pushed
+    fn pop_n(&mut self, n: u32, span: Span) -> Result<Vec<Value>> {
+        let len = self.state.stack.len();
+        let at = len
+            .checked_sub(n as usize)
+            .ok_or(Error::StackUnderflow(span))?;
+        Ok(self.state.stack.split_off(at))
+    }
+
+    fn konst(&self, idx: u32) -> &Value {
+        &self.program.consts[idx as usize]
+    }
+
+    fn konst_symbol(&self, idx: u32) -> Symbol {
+        match self.konst(idx) {
+            Value::Symbol(sym) => *sym,
+            val => unreachable!("{:?} is not a symbol", val),
+        }
+    }
+
+    fn local(&mut self, slot: u32) -> &mut Value {
+        let base = self.state.frames.last().unwrap().base;
+        &mut self.state.stack[(base + slot) as usize]
+    }
+
+    /// Runs until the script yields, the last frame returns, or `budget` instructions have run
+    fn run(&mut self, budget: u32) -> Result<Option<Yield>> {
+        let program = self.program.clone();
+        for _ in 0..budget {
+            let Some(frame) = self.state.frames.last_mut() else {
+                return Ok(None);
+            };
+            let pc = frame.pc;
+            frame.pc += 1;
+            let span = program.spans[pc as usize];
+            match program.code[pc as usize] {
+                Op::Const(idx) => self.state.stack.push(self.konst(idx).clone()),
+                Op::Pop => {
+                    self.pop(span)?;
+                }
+                Op::LoadLocal(slot) => {
+                    let val = self.local(slot).clone();
+                    self.state.stack.push(val);
+                }
+                Op::StoreLocal(slot) => {
+                    let val = self.pop(span)?;
+                    *self.local(slot) = val;
+                }
+                Op::LoadGlobal(idx) => {
+                    let val = self.state.globals[idx as usize].clone();
+                    self.state.stack.push(val);
+                }
+                Op::StoreGlobal(idx) => self.state.globals[idx as usize] = self.pop(span)?,
+                Op::Assign(idx) => {
+                    let place = &program.places[idx as usize];
+                    let indices = self.pop_n(place.index_count(), span)?;
+                    let val = self.pop(span)?;
+                    *self.place(place, indices)? = val;
+                }
+                Op::Update(idx, op) => {
+                    let place = &program.places[idx as usize];
+                    let indices = self.pop_n(place.index_count(), span)?;
+                    let rhs = self.pop(span)?;
+                    let target = self.place(place, indices)?;
+                    *target = binary_op(op, target.clone(), rhs, span)?;
+                }
+                Op::List(n) => {
+                    let elems = self.pop_n(n, span)?;
+                    self.state.stack.push(Value::List(elems));
+                }
+                Op::Format(n) => {
+                    let text = self
+                        .pop_n(n, span)?
+                        .iter()
+                        .map(|part| part.to_string())
+                        .collect::<String>();
+                    self.state.stack.push(Value::String(text));
+                }
+                Op::Escape => {
+                    let text = markup::escape(&self.pop(span)?.to_string());
+                    self.state.stack.push(Value::String(text));
+                }
+                Op::Field(idx) => {
+                    let field = self.konst_symbol(idx);
+                    let base = self.pop(span)?;
+                    let val = self.field(base, field, span)?;
+                    self.state.stack.push(val);
+                }
+                Op::Index => {
+                    let idx = self.pop(span)?;
+                    let base = self.pop(span)?;
+                    self.state.stack.push(index(base, idx, span)?);
+                }
+                Op::Unary(op) => {
+                    let val = self.pop(span)?;
+                    self.state.stack.push(unary_op(op, val, span)?);
+                }
+                Op::Binary(op) => {
+                    let rhs = self.pop(span)?;
+                    let lhs = self.pop(span)?;
+                    self.state.stack.push(binary_op(op, lhs, rhs, span)?);
+                }
+                Op::ShortCircuit(op, target) => {
+                    let short_circuit = op == BinaryOp::LogicOr;
+                    match self.state.stack.last().unwrap() {
+                        Value::Bool(b) if *b == short_circuit => {
+                            self.state.frames.last_mut().unwrap().pc = target;
+                        }
+                        Value::Bool(_) => {
+                            self.pop(span)?;
+                        }
+                        val => {
+                            return Err(Error::TypeMismatch(
+                                op_name(op),
+                                vec![val.type_name()],
+                                span,
+                            ))
+                        }
+                    }
+                }
+                Op::CheckBool(op) => match self.state.stack.last().unwrap() {
+                    Value::Bool(_) => {}
+                    val => {
+                        return Err(Error::TypeMismatch(
+                            op_name(op),
+                            vec![val.type_name()],
+                            span,
+                        ))
+                    }
+                },
+                Op::Matches => {
+                    let pattern = self.pop(span)?;
+                    let val = self.pop(span)?;
+                    self.state
+                        .stack
+                        .push(Value::Bool(values_equal(&pattern, &val)));
+                }
+                Op::Builtin(name, n) => {
+                    let name = self.konst_symbol(name);
+                    let args = self.pop_n(n, span)?;
+                    self.state.stack.push(call_builtin(name, args, span, span)?);
+                }
+                Op::Jump(target) => self.state.frames.last_mut().unwrap().pc = target,
+                Op::JumpIfFalse(target) => {
+                    if !self.pop(span)?.is_truthy() {
+                        self.state.frames.last_mut().unwrap().pc = target;
+                    }
+                }
+                Op::Say(speaker) => {
+                    let text = self.pop(span)?.to_string();
+                    let markup = markup::parse(&text).map_err(|err| Error::Markup(err, span))?;
+                    let name = match speaker {
+                        Some(name) => {
+                            let name = self.konst_symbol(name);
+                            let id = match self.pop(span)? {
+                                Value::Character(id) => id,
+                                _ if self.characters.contains_key(&name) => name,
+                                _ => return Err(Error::UndefinedName(name, span)),
+                            };
+                            let display = &self.state.characters[self.characters[&id] as usize];
+                            Some(display.to_string())
+                        }
+                        None => None,
+                    };
+                    self.state.line = Some((name.clone(), text));
+                    return Ok(Some(Yield::Dialogue(Line { name, markup })));
+                }
+                Op::AddOption(target) => {
+                    let text = self.pop(span)?.to_string();
+                    self.state.options.push((text, target));
+                }
+                Op::Choose => {
+                    if self.state.options.is_empty() {
+                        continue;
+                    }
+                    let (texts, targets): (Vec<_>, _) = self.state.options.drain(..).unzip();
+                    let options = texts
+                        .iter()
+                        .map(|text| markup::parse(text).map_err(|err| Error::Markup(err, span)))
+                        .collect::<Result<_>>()?;
+                    self.state.pending = Some(PendingChoice { pc, targets, texts });
+                    return Ok(Some(Yield::Choice(options)));
+                }
+                Op::Call(scene) => self.enter(scene),
+                Op::Goto(scene) => {
+                    self.leave();
+                    self.enter(scene);
+                }
+                Op::Return => self.leave(),
+                Op::DefineCharacter(idx) => self.state.characters[idx as usize] = self.pop(span)?,
+                Op::Show(layer) => match self.pop(span)? {
+                    image @ Value::Image(_) => self.state.layers[layer as usize] = image,
+                    val => return Err(Error::TypeMismatch("show", vec![val.type_name()], span)),
+                },
+                Op::Hide(layer) => self.state.layers[layer as usize] = Value::Unit,
+            }
+        }
+        Ok(Some(Yield::Busy))
+    }
+
+    fn field(&self, base: Value, field: Symbol, span: Span) -> Result<Value> {
+        match (base, field.as_str()) {
+            (Value::Map(mut map), _) => map
+                .remove(field.as_str())
+                .ok_or(Error::NoSuchField(field, span)),
+            (Value::Character(id), "name") => {
+                Ok(self.state.characters[self.characters[&id] as usize].clone())
+            }
+            (Value::Character(id), "id") => Ok(Value::Symbol(id)),
+            (Value::List(l), "len") => Ok(Value::Int(l.len() as i64)),
+            (Value::String(s), "len") => Ok(Value::Int(s.chars().count() as i64)),
+            _ => Err(Error::NoSuchField(field, span)),
+        }
+    }
+
+    /// Resolves a place to the storage it names, given the values of its indices innermost
+    /// last
+    fn place(&mut self, place: &Place, indices: Vec<Value>) -> Result<&mut Value> {
+        let mut indices = indices.into_iter().rev();
+        let mut target = match place.var {
+            Var::Local(slot) => self.local(slot),
+            Var::Global(idx) => &mut self.state.globals[idx as usize],
+        };
+        for step in &place.steps {
+            target = match *step {
+                Step::Index(span) => index_mut(target, indices.next().unwrap(), span)?,
+                Step::Field(field, span) => match target {
+                    Value::Map(map) => map.entry(field.as_str().into()).or_insert(Value::Unit),
+                    _ => return Err(Error::NoSuchField(field, span)),
+                },
+            };
+        }
+        Ok(target)
+    }
+
+    /// Writes the execution state, so it can be restored with [`Vm::restore`]
+    pub fn save(&self) -> Vec<u8> {
+        let mut enc = Encoder::new();
+        enc.u32(SAVE_VERSION);
+        enc.u64(self.program.fingerprint());
+        let state = &self.state;
+        enc.len(state.frames.len());
+        for frame in &state.frames {
+            enc.u32(frame.scene);
+            enc.u32(frame.pc);
+            enc.u32(frame.base);
+        }
+        for values in [
+            &state.stack,
+            &state.globals,
+            &state.characters,
+            &state.layers,
+        ] {
+            enc.len(values.len());
+            for val in values {
+                enc.value(val);
+            }
+        }
+        enc.len(state.options.len());
+        for (text, target) in &state.options {
+            enc.str(text);
+            enc.u32(*target);
+        }
+        enc.bool(state.pending.is_some());
+        if let Some(pending) = &state.pending {
+            enc.u32(pending.pc);
+            enc.len(pending.targets.len());
+            for (target, text) in pending.targets.iter().zip(&pending.texts) {
+                enc.u32(*target);
+                enc.str(text);
+            }
+        }
+        enc.bool(state.line.is_some());
+        if let Some((name, text)) = &state.line {
+            enc.bool(name.is_some());
+            if let Some(name) = name {
+                enc.str(name);
+            }
+            enc.str(text);
+        }
+        enc.finish()
+    }
+
+    /// Picks up execution where [`Vm::save`] left it. The program must be the one the state was
+    /// saved with.
+    pub fn restore(program: Rc<Program>, bytes: &[u8]) -> encode::Result<Self> {
+        let characters = program.character_indices();
+        let mut dec = Decoder::new(bytes);
+        if dec.u32()? != SAVE_VERSION || dec.u64()? != program.fingerprint() {
+            return Err(encode::Error::WrongProgram);
+        }
+        let code_len = program.code.len() as u32;
+        let address = |addr: u32| match addr < code_len {
+            true => Ok(addr),
+            false => Err(encode::Error::InvalidValue("address")),
+        };
+
+        let mut state = State::default();
+        for _ in 0..dec.len()? {
+            let frame = CallFrame {
+                scene: dec.u32()?,
+                pc: address(dec.u32()?)?,
+                base: dec.u32()?,
+            };
+            if frame.scene as usize >= program.scenes.len() {
+                return Err(encode::Error::InvalidValue("scene"));
+            }
+            state.frames.push(frame);
+        }
+        for values in [
+            &mut state.stack,
+            &mut state.globals,
+            &mut state.characters,
+            &mut state.layers,
+        ] {
+            for _ in 0..dec.len()? {
+                values.push(dec.value()?);
+            }
+        }
+        for _ in 0..dec.len()? {
+            let text = dec.str()?.to_string();
+            state.options.push((text, address(dec.u32()?)?));
+        }
+        if dec.bool()? {
+            let pc = address(dec.u32()?)?;
+            let (mut targets, mut texts) = (Vec::new(), Vec::new());
+            for _ in 0..dec.len()? {
+                targets.push(address(dec.u32()?)?);
+                texts.push(dec.str()?.to_string());
+            }
+            state.pending = Some(PendingChoice { pc, targets, texts });
+        }
+        if dec.bool()? {
+            let name = match dec.bool()? {
+                true => Some(dec.str()?.to_string()),
+                false => None,
+            };
+            state.line = Some((name, dec.str()?.to_string()));
+        }
+
+        let frames_fit = state.frames.iter().all(|frame| {
+            let locals = program.scenes[frame.scene as usize].locals;
+            frame.base as usize + locals as usize <= state.stack.len()
+        });
+        let layers_ok = state
+            .layers
+            .iter()
+            .all(|layer| matches!(layer, Value::Unit | Value::Image(_)));
+        let characters_ok = [
+            &state.stack,
+            &state.globals,
+            &state.characters,
+            &state.layers,
+        ]
+        .into_iter()
+        .flatten()
+        .all(|val| characters_known(val, &characters));
+        let markup_ok = state
+            .line
+            .iter()
+            .map(|(_, text)| text)
+            .chain(state.pending.iter().flat_map(|pending| &pending.texts))
+            .all(|text| markup::parse(text).is_ok());
+        if !dec.is_empty()
+            || !frames_fit
+            || !characters_ok
+            || state.globals.len() != program.globals.len()
+            || state.characters.len() != program.characters.len()
+            || state.layers.len() != LAYERS.len()
+            || !layers_ok
+            || !markup_ok
+            || (state.line.is_some() && state.pending.is_some())
+        {
+            return Err(encode::Error::InvalidValue("state"));
+        }
+        Ok(Self {
+            characters,
+            program,
+            state,
+        })
+    }
+}
+
+/// Whether every character in `val` is one of `characters`, which running the script assumes
+pub fn characters_known(val: &Value, characters: &FxHashMap<Symbol, u32>) -> bool {
+    match val {
+        Value::Character(id) => characters.contains_key(id),
+        Value::List(l) => l.iter().all(|val| characters_known(val, characters)),
+        Value::Map(m) => m.values().all(|val| characters_known(val, characters)),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::{
+        compile, lex,
+        markup::{Piece, Run, Style},
+        parse,
+    };
+
+    fn program(src: &str) -> Rc<Program> {
+        let mut lexemes =
+            lex::lex(&mut src.chars(), "test").unwrap_or_else(|errs| panic!("{:?}", errs));
+        lex::filter_comments(&mut lexemes);
+        let items = parse::do_file(&mut parse::TokenStream::new(lexemes))
+            .unwrap_or_else(|errs| panic!("{:?}", errs));
+        Rc::new(compile::compile(&items).unwrap_or_else(|errs| panic!("{:?}", errs)))
+    }
+
+    fn start(src: &str) -> Vm {
+        let mut vm = Vm::new(program(src)).unwrap_or_else(|errs| panic!("{:?}", errs));
+        vm.start("main".into()).unwrap();
+        vm
+    }
+
+    /// The text of `pieces` without its markup
+    fn plain(pieces: &[Piece]) -> String {
+        pieces
+            .iter()
+            .filter_map(|piece| match piece {
+                Piece::Text(run) => Some(&*run.text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The text of a line of dialogue, prefixed by the speaker's name if it has one
+    fn said(shown: Result<Yield>) -> String {
+        match shown {
+            Ok(Yield::Dialogue(line)) => match line.name {
+                Some(name) => format!("{}: {}", name, plain(&line.markup)),
+                None => plain(&line.markup),
+            },
+            shown => panic!("expected dialogue, got {:?}", shown),
+        }
+    }
+
+    const MENU: &str = r#"
+        character alice = "Alice";
+        scene main {
+            "Hello."
+            choice {
+                "Left" => alice "You went left.",
+                "Right" => "You went right.",
+            }
+            "The end."
+        }
+    "#;
+
+    #[test]
+    fn resume_runs_to_each_line_and_choice() {
+        let mut vm = start(MENU);
+        assert_eq!(said(vm.resume(Input::Continue)), "Hello.");
+        assert!(!vm.awaiting_choice());
+        match vm.resume(Input::Continue) {
+            Ok(Yield::Choice(options)) => {
+                let options = options
+                    .iter()
+                    .map(|option| plain(option))
+                    .collect::<Vec<_>>();
+                assert_eq!(options, ["Left", "Right"]);
+            }
+            shown => panic!("{:?}", shown),
+        }
+        assert!(vm.awaiting_choice());
+        assert_eq!(said(vm.resume(Input::Choose(0))), "Alice: You went left.");
+        assert_eq!(said(vm.resume(Input::Continue)), "The end.");
+        assert!(matches!(vm.resume(Input::Continue), Ok(Yield::Finished)));
+        assert!(!vm.is_busy() && vm.shown().is_none());
+        assert!(matches!(vm.resume(Input::Continue), Ok(Yield::Finished)));
+    }
+
+    #[test]
+    fn choice_must_be_answered_with_an_offered_option() {
+        let mut vm = start(MENU);
+        vm.resume(Input::Continue).unwrap();
+        vm.resume(Input::Continue).unwrap();
+        assert!(matches!(
+            vm.resume(Input::Continue),
+            Err(Error::ChoiceRequired(_))
+        ));
+        assert!(matches!(
+            vm.resume(Input::Choose(2)),
+            Err(Error::InvalidChoice(2, _))
+        ));
+        // Neither mistake gives up on the choice
+        assert!(vm.awaiting_choice());
+        assert_eq!(said(vm.resume(Input::Choose(1))), "You went right.");
+    }
+
+    #[test]
+    fn choice_is_ignored_while_showing_dialogue() {
+        let mut vm = start(MENU);
+        assert_eq!(said(vm.resume(Input::Choose(5))), "Hello.");
+    }
+
+    /// Everything the script shows, answering each choice with the next of `choices`
+    fn run_from(vm: &mut Vm, choices: &[usize]) -> Vec<String> {
+        let mut choices = choices.iter();
+        let mut shown = Vec::new();
+        let mut input = Input::Continue;
+        loop {
+            match vm.resume(input).unwrap_or_else(|err| panic!("{}", err)) {
+                Yield::Dialogue(line) => {
+                    shown.push(said(Ok(Yield::Dialogue(line))));
+                    input = Input::Continue;
+                }
+                Yield::Choice(options) => {
+                    let options = options
+                        .iter()
+                        .map(|option| plain(option))
+                        .collect::<Vec<_>>();
+                    shown.push(format!("choice: {}", options.join(" | ")));
+                    input = Input::Choose(*choices.next().expect("no choice left"));
+                }
+                Yield::Busy => input = Input::Continue,
+                Yield::Finished => return shown,
+            }
+        }
+    }
+
+    fn run(src: &str, choices: &[usize]) -> Vec<String> {
+        run_from(&mut start(src), choices)
+    }
+
+    #[test]
+    fn long_runs_yield_to_the_caller() {
+        let src = r#"
+            scene main {
+                let n = 0;
+                while n < 100000 { n += 1; }
+                "{n}"
+                loop {}
+            }
+        "#;
+        let mut vm = start(src);
+        let mut busy = 0;
+        let line = loop {
+            match vm.resume(Input::Continue) {
+                Ok(Yield::Busy) => {
+                    assert!(vm.is_busy());
+                    busy += 1;
+                }
+                shown => break said(shown),
+            }
+        };
+        assert_eq!(line, "100000");
+        assert!(busy > 1 && !vm.is_busy());
+        // An endless loop keeps yielding rather than never returning
+        for _ in 0..3 {
+            assert!(matches!(vm.resume(Input::Continue), Ok(Yield::Busy)));
+        }
+    }
+
+    #[test]
+    fn popping_an_empty_stack_is_an_error() {
+        let mut program = (*program(r#"scene main { "a" }"#)).clone();
+        let entry = program.scenes[0].entry as usize;
+        assert!(matches!(program.code[entry], Op::Const(_)));
+        program.code[entry] = Op::Pop;
+        let mut vm = Vm::new(Rc::new(program)).unwrap();
+        vm.start("main".into()).unwrap();
+        assert!(matches!(
+            vm.resume(Input::Continue),
+            Err(Error::StackUnderflow(_))
+        ));
+    }
+
+    #[test]
+    fn loops_break_and_continue() {
+        let src = r#"
+            scene main {
+                let k = 0;
+                let odd = "";
+                while k < 10 {
+                    k += 1;
+                    if k % 2 == 0 { continue }
+                    if k > 7 { break }
+                    odd = odd + str(k);
+                }
+                "{odd} {k}"
+                let n = 0;
+                loop {
+                    n += 1;
+                    if n == 3 { break }
+                }
+                "{n}"
+            }
+        "#;
+        assert_eq!(run(src, &[]), ["1357 9", "3"]);
+    }
+
+    #[test]
+    fn match_or_patterns_and_guards() {
+        let src = r#"
+            scene main {
+                let xs = [0, 1, 5, 12, "a"];
+                let i = 0;
+                while i < len(xs) {
+                    match xs[i] {
+                        0 | 1 => "small",
+                        "a" => "letter",
+                        n if n > 10 => "big {n}",
+                        _ => "other",
+                    }
+                    i += 1;
+                }
+            }
+        "#;
+        assert_eq!(
+            run(src, &[]),
+            ["small", "small", "other", "big 12", "letter"]
+        );
+    }
+
+    #[test]
+    fn choices_offer_the_options_whose_guards_hold() {
+        let src = r#"
+            let seen = false;
+            scene main {
+                choice {
+                    "Again" if seen => "no",
+                    "Look" => { seen = true; "looked" }
+                    "Leave" => return,
+                }
+                choice {
+                    "Again" if seen => "again",
+                    "Leave" => return,
+                }
+                "end"
+            }
+        "#;
+        assert_eq!(
+            run(src, &[0, 0]),
+            [
+                "choice: Look | Leave",
+                "looked",
+                "choice: Again | Leave",
+                "again",
+                "end",
+            ]
+        );
+        assert_eq!(run(src, &[1]), ["choice: Look | Leave"]);
+    }
+
+    #[test]
+    fn call_returns_and_jump_replaces_the_scene() {
+        let src = r#"
+            scene main {
+                call helper;
+                "back"
+                jump other;
+                "never"
+            }
+            scene helper {
+                let local = "in helper";
+                "{local}"
+                return;
+                "not reached"
+            }
+            scene other { "other" }
+        "#;
+        assert_eq!(run(src, &[]), ["in helper", "back", "other"]);
+    }
+
+    #[test]
+    fn compound_assignment_to_places() {
+        let src = r#"
+            let g = [0, map()];
+            scene main {
+                let m = map();
+                m.count = 1;
+                m.count += 2;
+                m["name"] = "x";
+                m.name *= 3;
+                let l = [1, [2, 3]];
+                l[1][0] *= 10;
+                l[-1][1] -= 1;
+                g[0] += 5;
+                g[1].seen = true;
+                "{m.count} {m.name} {l} {g[0]} {g[1].seen}"
+            }
+        "#;
+        assert_eq!(run(src, &[]), ["3 xxx [1, [20, 2]] 5 true"]);
+    }
+
+    /// The errors compiling `src`
+    fn compile_errors(src: &str) -> Vec<String> {
+        let mut lexemes = lex::lex(&mut src.chars(), "test").unwrap();
+        lex::filter_comments(&mut lexemes);
+        let items = parse::do_file(&mut parse::TokenStream::new(lexemes)).unwrap();
+        let errs = compile::compile(&items).unwrap_err();
+        errs.iter().map(|err| err.to_string()).collect()
+    }
+
+    #[test]
+    fn markup_is_only_taken_from_tags() {
+        let src = r#"
+            scene main {
+                let n = "{{";
+                "{len(n)} {{b}} {b}bold{/b}"
+            }
+        "#;
+        let mut vm = start(src);
+        match vm.resume(Input::Continue) {
+            Ok(Yield::Dialogue(line)) => {
+                assert_eq!(plain(&line.markup), "1 {b} bold");
+                assert!(matches!(&line.markup[..], [_, Piece::Text(run)] if run.style.bold));
+            }
+            shown => panic!("{:?}", shown),
+        }
+    }
+
+    #[test]
+    fn embedded_values_are_plain_text() {
+        let src = r#"
+            scene main {
+                let name = "{{b}}Bob}}";
+                let m = map();
+                m.a = 1;
+                "{name} {m}"
+                choice { "{i}{name}{/i} {{x}}" => {} }
+            }
+        "#;
+        let mut vm = start(src);
+        match vm.resume(Input::Continue) {
+            Ok(Yield::Dialogue(line)) => assert_eq!(
+                line.markup,
+                [Piece::Text(Run {
+                    text: "{b}Bob} {a: 1}".to_string(),
+                    style: Style::default(),
+                })]
+            ),
+            shown => panic!("{:?}", shown),
+        }
+        let italic = Style {
+            italic: true,
+            ..Style::default()
+        };
+        match vm.resume(Input::Continue) {
+            Ok(Yield::Choice(options)) => assert_eq!(
+                options,
+                [[
+                    Piece::Text(Run {
+                        text: "{b}Bob}".to_string(),
+                        style: italic,
+                    }),
+                    Piece::Text(Run {
+                        text: " {x}".to_string(),
+                        style: Style::default(),
+                    }),
+                ]]
+            ),
+            shown => panic!("{:?}", shown),
+        }
+    }
+
+    #[test]
+    fn tags_named_like_variables_are_ambiguous() {
+        let src = r#"
+            let w = 1;
+            scene main {
+                let b = 2;
+                "{b}{i}{w=1}{/i}"
+                "{(b)} {nw}"
+            }
+        "#;
+        assert_eq!(
+            compile_errors(src),
+            ["`{b}` is both a markup tag and a variable"]
+        );
+        assert_eq!(run(&src.replace("\"{b}", "\"{(w)}"), &[]), ["1", "2 "]);
+    }
+
+    #[test]
+    fn markup_is_only_in_dialogue_and_options() {
+        let src = r#"
+            scene main {
+                let a = "{b}a";
+                "{/b}"
+                choice { "{i}x{/b}" => {} }
+            }
+        "#;
+        assert_eq!(
+            compile_errors(src),
+            [
+                "markup can only be used in the text of dialogue and options",
+                "`{/b}` closes no open tag",
+                "`{/b}` does not close the open `{i}`",
+            ]
+        );
+    }
+
+    const STORY: &str = r#"
+        character alice = "Alice";
+        let friend = alice;
+        let visits = 0;
+        scene main {
+            visits += 1;
+            call room;
+            "Back outside, {visits} visit."
+        }
+        scene room {
+            let things = ["lamp", "desk"];
+            friend "Look at the {things[0]}."
+            choice {
+                "Stay" => { things[1] = "chair"; "You sit on the {things[1]}." }
+                "Go" => "You leave the {things[1]}.",
+            }
+            show background image("room.png");
+            friend "Bye."
+        }
+    "#;
+
+    /// Runs the story `steps` yields in, saves, and checks that a script restored from the
+    /// save goes on to show what the original does
+    fn check_save_at(steps: usize, choices: &[usize]) {
+        let mut vm = start(STORY);
+        let mut input = Input::Continue;
+        let mut shown = None;
+        for _ in 0..steps {
+            shown = Some(vm.resume(input).unwrap());
+            if let Some(Yield::Choice(_)) = shown {
+                input = Input::Choose(choices[0]);
+            } else {
+                input = Input::Continue;
+            }
+        }
+        let bytes = vm.save();
+        let mut restored = Vm::restore(vm.program().clone(), &bytes).unwrap();
+        assert_eq!(restored.state(), vm.state());
+        // What the player was shown is shown again
+        let shown = shown.filter(|shown| !matches!(shown, Yield::Busy | Yield::Finished));
+        assert_eq!(restored.shown(), shown);
+        let rest = &choices[(input != Input::Continue) as usize..];
+        if let Input::Choose(idx) = input {
+            assert!(restored.awaiting_choice());
+            let line = said(restored.resume(Input::Choose(idx)));
+            assert_eq!(line, said(vm.resume(Input::Choose(idx))));
+        }
+        assert_eq!(run_from(&mut restored, rest), run_from(&mut vm, rest));
+        assert_eq!(restored.state(), vm.state());
+    }
+
+    #[test]
+    fn restored_scripts_resume_where_they_were_saved() {
+        assert_eq!(
+            run(STORY, &[0]),
+            [
+                "Alice: Look at the lamp.",
+                "choice: Stay | Go",
+                "You sit on the chair.",
+                "Alice: Bye.",
+                "Back outside, 1 visit.",
+            ]
+        );
+        for steps in 0..=5 {
+            check_save_at(steps, &[0]);
+            check_save_at(steps, &[1]);
+        }
+    }
+
+    #[test]
+    fn saves_keep_the_shown_images() {
+        let mut vm = start(STORY);
+        run_from(&mut vm, &[1]);
+        let restored = Vm::restore(vm.program().clone(), &vm.save()).unwrap();
+        assert!(matches!(
+            &restored.state().layers[0],
+            Value::Image(path) if path.as_str() == "room.png"
+        ));
+    }
+
+    #[test]
+    fn saves_are_only_restored_into_their_program() {
+        let mut vm = start(STORY);
+        vm.resume(Input::Continue).unwrap();
+        let bytes = vm.save();
+        let other = program(&STORY.replace("\"Bye.\"", "\"Bye.\" \"Later.\""));
+        assert_eq!(
+            Vm::restore(other, &bytes).err(),
+            Some(encode::Error::WrongProgram)
+        );
+        for len in 0..bytes.len() {
+            assert!(Vm::restore(vm.program().clone(), &bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn saves_naming_unknown_characters_are_refused() {
+        let mut vm = start(STORY);
+        vm.resume(Input::Continue).unwrap();
+        // The `friend` global holds the character `alice`, written by name
+        let mut bytes = vm.save();
+        let at = bytes
+            .windows(5)
+            .position(|window| window == b"alice")
+            .unwrap();
+        bytes[at..at + 5].copy_from_slice(b"alicf");
+        assert_eq!(
+            Vm::restore(vm.program().clone(), &bytes).err(),
+            Some(encode::Error::InvalidValue("state"))
+        );
+    }
+
+    #[test]
+    fn disassembly() {
+        let program = program(
+            r#"
+                character bob = "Bob";
+                let g = 1;
+                scene main {
+                    g += 2;
+                    if g > 2 { bob "Big." } else { jump main }
+                }
+            "#,
+        );
+        assert_eq!(
+            program.disassemble(),
+            r#"init 0 (0 locals):
+  0000  const "Bob"
+  0001  character bob
+  0002  return
+
+init 1 (0 locals):
+  0003  const 1
+  0004  store.global g
+  0005  return
+
+scene main (0 locals):
+  0006  const 2
+  0007  update global g +=
+  0008  load.global g
+  0009  const 2
+  0010  binary >
+  0011  jump.false -> 0016
+  0012  const bob
+  0013  const "Big."
+  0014  say 'bob
+  0015  jump -> 0017
+  0016  goto main
+  0017  return
+"#
+        );
+    }
+}